@@ -0,0 +1,104 @@
+//! The old floating point `convert_buf` path against the fixed point
+//! conversions that replaced it, at the sizes cameras usually send
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+#[allow(dead_code)]
+#[path = "../src/convert.rs"]
+mod convert;
+
+/// The conversions as `convert_buf` did them, copying the capture buffer
+/// first and growing the output pixel by pixel
+mod float {
+    pub fn convert_buf_yuyv(buf: &[u8]) -> Vec<u8> {
+        let buf = buf.to_vec();
+        let mut mat_buf = Vec::new();
+        for s in buf.chunks_exact(4) {
+            mat_buf.extend_from_slice(&yuv_to_bgr_pixel(s[0], s[1], s[3]));
+            mat_buf.extend_from_slice(&yuv_to_bgr_pixel(s[2], s[1], s[3]));
+        }
+        mat_buf
+    }
+
+    pub fn convert_buf_nv12(buf: &[u8], width: usize, height: usize) -> Vec<u8> {
+        let buf = buf.to_vec();
+        let (y_plane, uv_plane) = buf.split_at(width * height);
+        let uv_width = (width + 1) / 2 * 2;
+        let mut bgr = Vec::with_capacity(width * height * 3);
+        for row in 0..height {
+            for col in 0..width {
+                let i = (row / 2) * uv_width + (col / 2) * 2;
+                bgr.extend_from_slice(&yuv_to_bgr_pixel(
+                    y_plane[row * width + col],
+                    uv_plane[i],
+                    uv_plane[i + 1],
+                ));
+            }
+        }
+        bgr
+    }
+
+    fn yuv_to_bgr_pixel(y: u8, u: u8, v: u8) -> [u8; 3] {
+        let (y, u, v) = (y as f64, u as f64 - 128.0, v as f64 - 128.0);
+        [
+            (y + 1.772 * u) as u8,
+            (y - 0.34414 * u - 0.71414 * v) as u8,
+            (y + 1.402 * v) as u8,
+        ]
+    }
+}
+
+const SIZES: &[(usize, usize)] = &[(640, 480), (1920, 1080)];
+
+fn noise(len: usize) -> Vec<u8> {
+    let mut x: u32 = 0x1234_5678;
+    (0..len)
+        .map(|_| {
+            x = x.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (x >> 24) as u8
+        })
+        .collect()
+}
+
+fn yuyv(c: &mut Criterion) {
+    let mut group = c.benchmark_group("yuyv");
+    for &(width, height) in SIZES {
+        let src = noise(width * height * 2);
+        let size = format!("{}x{}", width, height);
+        group.throughput(Throughput::Elements((width * height) as u64));
+        group.bench_with_input(BenchmarkId::new("float", &size), &src, |b, src| {
+            b.iter(|| float::convert_buf_yuyv(black_box(src)))
+        });
+        group.bench_with_input(BenchmarkId::new("fixed", &size), &src, |b, src| {
+            b.iter(|| {
+                let mut dst = vec![0; width * height * 3];
+                convert::yuyv_to_bgr(black_box(src), width, height, width * 2, &mut dst);
+                dst
+            })
+        });
+    }
+    group.finish();
+}
+
+fn nv12(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nv12");
+    for &(width, height) in SIZES {
+        let src = noise(width * height * 3 / 2);
+        let size = format!("{}x{}", width, height);
+        group.throughput(Throughput::Elements((width * height) as u64));
+        group.bench_with_input(BenchmarkId::new("float", &size), &src, |b, src| {
+            b.iter(|| float::convert_buf_nv12(black_box(src), width, height))
+        });
+        group.bench_with_input(BenchmarkId::new("fixed", &size), &src, |b, src| {
+            b.iter(|| {
+                let mut dst = vec![0; width * height * 3];
+                convert::nv12_to_bgr(black_box(src), width, height, width, &mut dst);
+                dst
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, yuyv, nv12);
+criterion_main!(benches);
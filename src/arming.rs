@@ -0,0 +1,71 @@
+//! Arming modes and per-camera schedules deciding what motion does
+
+use crate::config::{self, parse_time, ArmAction, CameraConfig, ScheduleRule};
+
+use chrono::{DateTime, Datelike, Local, NaiveTime, Utc, Weekday};
+use log::info;
+use once_cell::sync::Lazy;
+use std::sync::RwLock;
+
+/// Set through the API, otherwise the config's default mode is used
+static MODE: Lazy<RwLock<Option<String>>> = Lazy::new(|| RwLock::new(None));
+
+pub fn mode() -> String {
+    match &*MODE.read().unwrap() {
+        Some(mode) => mode.clone(),
+        None => config::load_config(None).arming.default_mode(),
+    }
+}
+
+pub fn set_mode(mode: &str) {
+    info!("Arming mode set to {}", mode);
+    *MODE.write().unwrap() = Some(mode.to_string());
+}
+
+pub fn modes() -> Vec<String> {
+    config::load_config(None).arming.modes()
+}
+
+/// What motion on `camera` at `time` should do, given the current mode
+pub fn action(camera: &CameraConfig, time: DateTime<Utc>) -> ArmAction {
+    let rules = match &camera.schedule {
+        Some(rules) => rules,
+        None => return ArmAction::Event,
+    };
+    let mode = mode();
+    let local = time.with_timezone(&Local);
+
+    rules
+        .iter()
+        .find(|r| rule_matches(r, &mode, local.weekday(), local.time()))
+        .map(|r| r.action)
+        .unwrap_or(ArmAction::Event)
+}
+
+fn rule_matches(rule: &ScheduleRule, mode: &str, day: Weekday, time: NaiveTime) -> bool {
+    if let Some(modes) = &rule.modes {
+        if !modes.iter().any(|m| m == mode) {
+            return false;
+        }
+    }
+    if let Some(days) = &rule.days {
+        if !days.iter().any(|d| d.parse::<Weekday>().ok() == Some(day)) {
+            return false;
+        }
+    }
+
+    in_window(rule.start.as_deref(), rule.end.as_deref(), time)
+}
+
+/// Whether `time` falls between the `HH:MM` times `start` and `end`, either of
+/// which can be left open. A window ending before it starts runs past midnight
+pub fn in_window(start: Option<&str>, end: Option<&str>, time: NaiveTime) -> bool {
+    let start = start.and_then(parse_time);
+    let end = end.and_then(parse_time);
+    match (start, end) {
+        (Some(start), Some(end)) if end < start => time >= start || time < end,
+        (start, end) => {
+            start.map(|s| time >= s).unwrap_or(true) && end.map(|e| time < e).unwrap_or(true)
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use super::{AudioFrame, AUDIO_CHANNELS, AUDIO_SAMPLE_RATE};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use ffmpeg::{
+    codec, encoder, format, format::context::output::Output, frame, util::rational::Rational,
+    ChannelLayout, Packet,
+};
+use ffmpeg_next as ffmpeg;
+use log::trace;
+
+/// Frame size used when the codec doesn't dictate one
+const DEFAULT_FRAME_SIZE: usize = 960;
+/// How far the sample count may drift from the capture timestamps before
+/// the gap is filled with silence or the overlap dropped
+const MAX_DRIFT_MS: i64 = 100;
+
+/// Buffers `AudioFrame`s into codec-sized frames and encodes them
+pub struct AudioEncoder {
+    pub encoder: encoder::audio::Encoder,
+    format: format::Sample,
+    frame_size: usize,
+    buffer: Vec<i16>,
+    next_pts: Option<i64>,
+    start_time: Option<DateTime<Utc>>,
+}
+
+impl AudioEncoder {
+    /// Create an encoder, adding it as a stream to `octx` if given
+    pub fn new(id: codec::Id, octx: Option<&mut Output>, set_global_hdr: bool) -> Result<Self> {
+        let codec = encoder::find(id).ok_or(anyhow!("No encoder found for {:?}", id))?;
+        // Mono, so packed and planar float have the same layout:
+        let format = codec
+            .audio()
+            .ok()
+            .and_then(|a| a.formats())
+            .and_then(|mut formats| formats.find(|f| matches!(f, format::Sample::F32(_))))
+            .unwrap_or(format::Sample::F32(format::sample::Type::Planar));
+
+        let (mut audio, global_header) = match octx {
+            Some(octx) => {
+                let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+                let ost = octx.add_stream(codec)?;
+                (ost.codec().encoder().audio()?, global_header)
+            }
+            None => (codec::Context::new().encoder().audio()?, false),
+        };
+
+        audio.set_rate(AUDIO_SAMPLE_RATE as i32);
+        audio.set_channel_layout(ChannelLayout::MONO);
+        audio.set_channels(AUDIO_CHANNELS as i32);
+        audio.set_format(format);
+        audio.set_time_base(Rational::new(1, AUDIO_SAMPLE_RATE as i32));
+        if set_global_hdr && global_header {
+            audio.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+
+        let encoder = audio.open_as(codec)?;
+        let frame_size = match encoder.frame_size() as usize {
+            0 => DEFAULT_FRAME_SIZE,
+            n => n,
+        };
+
+        Ok(Self {
+            encoder,
+            format,
+            frame_size,
+            buffer: Vec::new(),
+            next_pts: None,
+            start_time: None,
+        })
+    }
+
+    /// Timestamps are relative to `start_time`; audio from before it is dropped
+    pub fn set_start_time(&mut self, start_time: DateTime<Utc>) {
+        self.start_time = Some(start_time);
+    }
+
+    pub fn time_base(&self) -> Rational {
+        Rational::new(1, AUDIO_SAMPLE_RATE as i32)
+    }
+
+    /// duration of one encoded frame in milliseconds
+    pub fn frame_duration_ms(&self) -> i64 {
+        (self.frame_size as i64 * 1000) / AUDIO_SAMPLE_RATE as i64
+    }
+
+    /// Timestamps follow the frames' capture times, so audio lost upstream
+    /// (e.g. a lagging receiver) leaves silence rather than pulling the rest
+    /// of the track early
+    pub fn send(&mut self, audio: &AudioFrame) -> Result<()> {
+        let start = *self.start_time.get_or_insert(audio.time());
+        let offset = (audio.time() - start).num_milliseconds();
+        if self.next_pts.is_none() {
+            if offset < 0 {
+                trace!("Dropping audio from before start time");
+                return Ok(());
+            }
+            self.next_pts = Some(offset * AUDIO_SAMPLE_RATE as i64 / 1000);
+        }
+
+        let mut samples = audio.samples();
+        let expected = offset * AUDIO_SAMPLE_RATE as i64 / 1000;
+        let position = self.next_pts.unwrap() + self.buffer.len() as i64;
+        let drift = expected - position;
+        if drift.abs() > MAX_DRIFT_MS * AUDIO_SAMPLE_RATE as i64 / 1000 {
+            if drift > 0 {
+                trace!("Filling {} samples of missing audio", drift);
+                self.buffer.resize(self.buffer.len() + drift as usize, 0);
+            } else {
+                let skip = (-drift as usize).min(samples.len());
+                trace!("Dropping {} samples of overlapping audio", skip);
+                samples = &samples[skip..];
+            }
+        }
+
+        self.buffer.extend_from_slice(samples);
+        while self.buffer.len() >= self.frame_size {
+            let chunk: Vec<i16> = self.buffer.drain(..self.frame_size).collect();
+            self.send_chunk(&chunk)?;
+        }
+        Ok(())
+    }
+
+    fn send_chunk(&mut self, chunk: &[i16]) -> Result<()> {
+        let mut frame = frame::Audio::new(self.format, chunk.len(), ChannelLayout::MONO);
+        frame.set_rate(AUDIO_SAMPLE_RATE);
+        let data = frame.data_mut(0);
+        for (i, s) in chunk.iter().enumerate() {
+            let f = *s as f32 / i16::MAX as f32;
+            data[i * 4..i * 4 + 4].copy_from_slice(&f.to_ne_bytes());
+        }
+
+        let pts = self.next_pts.unwrap_or(0);
+        frame.set_pts(Some(pts));
+        self.next_pts = Some(pts + chunk.len() as i64);
+        self.encoder.send_frame(&frame)?;
+        Ok(())
+    }
+
+    pub fn receive_packet(&mut self, packet: &mut Packet) -> bool {
+        self.encoder.receive_packet(packet).is_ok()
+    }
+
+    pub fn send_eof(&mut self) -> Result<()> {
+        self.encoder.send_eof()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    /// pts of every packet the encoder produces for `frames`
+    fn encoded_pts(frames: &[AudioFrame]) -> Vec<i64> {
+        ffmpeg::init().unwrap();
+        let mut encoder = AudioEncoder::new(codec::Id::OPUS, None, false).unwrap();
+        for frame in frames {
+            encoder.send(frame).unwrap();
+        }
+        encoder.send_eof().unwrap();
+        let mut pts = Vec::new();
+        let mut packet = Packet::empty();
+        while encoder.receive_packet(&mut packet) {
+            pts.extend(packet.pts());
+        }
+        pts
+    }
+
+    #[test]
+    fn missing_audio_leaves_a_gap() {
+        let start = Utc::now();
+        let frame_size = DEFAULT_FRAME_SIZE;
+        let frames = [
+            AudioFrame::new(vec![1000; frame_size], start),
+            AudioFrame::new(vec![1000; frame_size], start + Duration::seconds(1)),
+            AudioFrame::new(
+                vec![1000; frame_size],
+                start + Duration::seconds(1) + Duration::milliseconds(20),
+            ),
+        ];
+        let pts = encoded_pts(&frames);
+        let last = *pts.iter().max().unwrap();
+        assert!(
+            last >= AUDIO_SAMPLE_RATE as i64,
+            "last pts {} should be a second in",
+            last
+        );
+    }
+
+    #[test]
+    fn jitter_is_ignored() {
+        let start = Utc::now();
+        let frames: Vec<_> = (0..10)
+            .map(|i| {
+                // 20ms frames stamped up to 30ms late
+                let late = Duration::milliseconds((i % 3) * 15);
+                AudioFrame::new(
+                    vec![1000; DEFAULT_FRAME_SIZE],
+                    start + Duration::milliseconds(i * 20) + late,
+                )
+            })
+            .collect();
+        let pts = encoded_pts(&frames);
+        let last = *pts.iter().max().unwrap();
+        assert!(last < 10 * DEFAULT_FRAME_SIZE as i64, "last pts {}", last);
+    }
+}
@@ -0,0 +1,58 @@
+mod encoder;
+
+pub use encoder::AudioEncoder;
+
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Every audio source is resampled to this before being handed out
+pub const AUDIO_SAMPLE_RATE: u32 = 48000;
+pub const AUDIO_CHANNELS: u16 = 1;
+pub const AUDIO_BUFFER_SIZE: usize = 100;
+
+pub type AudioSender = broadcast::Sender<Arc<AudioFrame>>;
+pub type AudioReceiver = broadcast::Receiver<Arc<AudioFrame>>;
+
+/// Decoded mono PCM audio at `AUDIO_SAMPLE_RATE`
+#[derive(Debug, Clone)]
+pub struct AudioFrame {
+    samples: Vec<i16>,
+    time: DateTime<Utc>,
+}
+
+impl AudioFrame {
+    pub fn new(samples: Vec<i16>, time: DateTime<Utc>) -> Self {
+        Self { samples, time }
+    }
+
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    pub fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+
+    /// RMS level in dBFS, -inf for silence
+    pub fn loudness(&self) -> f64 {
+        if self.samples.is_empty() {
+            return f64::NEG_INFINITY;
+        }
+        let sum: f64 = self
+            .samples
+            .iter()
+            .map(|s| {
+                let s = *s as f64 / i16::MAX as f64;
+                s * s
+            })
+            .sum();
+        let rms = (sum / self.samples.len() as f64).sqrt();
+        20.0 * rms.log10()
+    }
+}
+
+pub fn audio_bus() -> AudioSender {
+    let (tx, _) = broadcast::channel(AUDIO_BUFFER_SIZE);
+    tx
+}
@@ -0,0 +1,88 @@
+//! Who viewed or changed what, appended to the audit log as JSON lines while
+//! auth is enabled
+
+use crate::auth::{self, User};
+use crate::config;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::error;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const AUDIT_EXTENSION: &str = "audit.log";
+
+/// Reopened if the configured path changes
+static LOG: Lazy<Mutex<Option<(PathBuf, File)>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    time: DateTime<Utc>,
+    user: &'a str,
+    address: Option<IpAddr>,
+    /// e.g. `stream` or `video`
+    action: &'a str,
+    camera: Option<&'a str>,
+    /// e.g. the recording's file name
+    detail: Option<&'a str>,
+}
+
+fn path() -> PathBuf {
+    match &config::load_config(None).auth.audit_log {
+        Some(p) => PathBuf::from(p),
+        None => config::config_path().with_extension(AUDIT_EXTENSION),
+    }
+}
+
+pub fn record(user: &User, action: &str, camera: Option<&str>, detail: Option<&str>) {
+    write(&Entry {
+        time: Utc::now(),
+        user: &user.name,
+        address: user.address,
+        action,
+        camera,
+        detail,
+    });
+}
+
+/// A login attempt that failed, by whoever `username` claimed to be
+pub fn record_failed_login(username: &str, address: Option<IpAddr>) {
+    write(&Entry {
+        time: Utc::now(),
+        user: username,
+        address,
+        action: "login_failed",
+        camera: None,
+        detail: None,
+    });
+}
+
+fn write(entry: &Entry) {
+    if !auth::enabled(&config::load_config(None).auth) {
+        return;
+    }
+    if let Err(e) = append(entry) {
+        error!("Failed to write audit log: {}", e);
+    }
+}
+
+fn append(entry: &Entry) -> Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+
+    let path = path();
+    let mut log = LOG.lock().unwrap();
+    if log.as_ref().map(|(p, _)| p != &path).unwrap_or(true) {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        *log = Some((path, file));
+    }
+    if let Some((_, file)) = log.as_mut() {
+        file.write_all(&line)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! Users, API tokens and login sessions for the web server. Accounts live in
+//! the config's `[auth]` section, so are looked up again for each request
+
+pub mod audit;
+
+use crate::config::{AuthConfig, Role, TokenConfig, UserConfig};
+
+use anyhow::{anyhow, Result};
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, SaltString};
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_SESSION_HOURS: u64 = 24;
+/// Name shown when auth is disabled
+const ANONYMOUS: &str = "anonymous";
+
+/// Checked against for unknown usernames, so they take as long to reject as
+/// a wrong password
+static DUMMY_PASSWORD_HASH: Lazy<String> =
+    Lazy::new(|| hash_password("").expect("Failed to hash dummy password"));
+
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))
+}
+
+pub fn is_password_hash(hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok()
+}
+
+/// Deliberately slow, so keep it off the async workers
+fn verify_password(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// A new random token and the hash to put in the config. Tokens carry enough
+/// entropy that a fast hash is fine, unlike passwords
+pub fn new_token() -> (String, String) {
+    let token = format!("{:032x}", rand::random::<u128>());
+    let hash = hash_token(&token);
+    (token, hash)
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub fn is_token_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Whoever made a request
+#[derive(Clone, Debug)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    /// `None` for every camera
+    pub cameras: Option<Vec<String>>,
+    pub address: Option<IpAddr>,
+}
+
+impl User {
+    /// Everyone is an admin when auth is disabled
+    pub fn anonymous(address: Option<IpAddr>) -> Self {
+        Self {
+            name: ANONYMOUS.to_string(),
+            role: Role::Admin,
+            cameras: None,
+            address,
+        }
+    }
+
+    fn from_user(user: &UserConfig, address: Option<IpAddr>) -> Self {
+        Self {
+            name: user.username.clone(),
+            role: user.role,
+            cameras: user.cameras.clone(),
+            address,
+        }
+    }
+
+    fn from_token(token: &TokenConfig, address: Option<IpAddr>) -> Self {
+        Self {
+            name: format!("token:{}", token.name),
+            role: token.role,
+            cameras: token.cameras.clone(),
+            address,
+        }
+    }
+
+    pub fn can_view(&self, label: &str) -> bool {
+        self.role == Role::Admin
+            || match &self.cameras {
+                Some(cameras) => cameras.iter().any(|c| c == label),
+                None => true,
+            }
+    }
+}
+
+pub fn enabled(config: &AuthConfig) -> bool {
+    config.enabled.unwrap_or(false)
+}
+
+/// Check a username and password, slowly
+pub fn login(
+    config: &AuthConfig,
+    username: &str,
+    password: &str,
+    address: Option<IpAddr>,
+) -> Option<User> {
+    let user = config
+        .users
+        .iter()
+        .flatten()
+        .find(|u| u.username == username);
+    match user {
+        Some(u) if verify_password(&u.password_hash, password) => Some(User::from_user(u, address)),
+        Some(_) => None,
+        None => {
+            verify_password(&DUMMY_PASSWORD_HASH, password);
+            None
+        }
+    }
+}
+
+pub fn token_user(config: &AuthConfig, token: &str, address: Option<IpAddr>) -> Option<User> {
+    let hash = hash_token(token);
+    config
+        .tokens
+        .iter()
+        .flatten()
+        .find(|t| t.token_hash.eq_ignore_ascii_case(&hash))
+        .map(|t| User::from_token(t, address))
+}
+
+struct LoginSession {
+    username: String,
+    started: Instant,
+}
+
+/// Browser logins by session cookie
+#[derive(Default)]
+pub struct LoginSessions {
+    sessions: Mutex<HashMap<String, LoginSession>>,
+}
+
+impl LoginSessions {
+    fn lifetime(config: &AuthConfig) -> Duration {
+        Duration::from_secs(config.session_hours.unwrap_or(DEFAULT_SESSION_HOURS) * 60 * 60)
+    }
+
+    /// Start a session for `username`, returning its ID
+    pub fn create(&self, config: &AuthConfig, username: &str) -> String {
+        let id = format!("{:032x}", rand::random::<u128>());
+        let lifetime = Self::lifetime(config);
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.started.elapsed() < lifetime);
+        sessions.insert(
+            id.clone(),
+            LoginSession {
+                username: username.to_string(),
+                started: Instant::now(),
+            },
+        );
+        id
+    }
+
+    /// The session's user as currently configured, `None` if the session
+    /// has expired or the user has since been removed
+    pub fn user(&self, config: &AuthConfig, id: &str, address: Option<IpAddr>) -> Option<User> {
+        let username = {
+            let sessions = self.sessions.lock().unwrap();
+            let session = sessions.get(id)?;
+            if session.started.elapsed() >= Self::lifetime(config) {
+                return None;
+            }
+            session.username.clone()
+        };
+        config
+            .users
+            .iter()
+            .flatten()
+            .find(|u| u.username == username)
+            .map(|u| User::from_user(u, address))
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
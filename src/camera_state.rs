@@ -0,0 +1,136 @@
+//! Camera changes made through the API. These are kept in their own file
+//! rather than written back to the config, so its formatting and comments
+//! are left alone
+
+use crate::config::{self, CameraConfig, Config};
+
+use anyhow::Result;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+const STATE_EXTENSION: &str = "state.json";
+
+/// Switches a running camera's motion detector checks for every frame
+#[derive(Debug)]
+pub struct CameraControl {
+    recording: AtomicBool,
+    detection: AtomicBool,
+    /// set once the frame reader has stopped with an error
+    failure: Mutex<Option<String>>,
+}
+
+impl CameraControl {
+    pub fn new(recording: bool, detection: bool) -> Self {
+        Self {
+            recording: AtomicBool::new(recording),
+            detection: AtomicBool::new(detection),
+            failure: Mutex::new(None),
+        }
+    }
+
+    pub fn recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    pub fn detection(&self) -> bool {
+        self.detection.load(Ordering::Relaxed)
+    }
+
+    pub fn set_recording(&self, enabled: bool) {
+        self.recording.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn set_detection(&self, enabled: bool) {
+        self.detection.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
+
+    pub fn set_failed(&self, reason: String) {
+        *self.failure.lock().unwrap() = Some(reason);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CameraState {
+    /// cameras added through the API
+    #[serde(default)]
+    pub added: Vec<CameraConfig>,
+    /// labels of config file cameras removed through the API
+    #[serde(default)]
+    pub removed: Vec<String>,
+    #[serde(default)]
+    pub recording_disabled: Vec<String>,
+    #[serde(default)]
+    pub detection_paused: Vec<String>,
+    /// arming mode last set through the API
+    pub arming_mode: Option<String>,
+}
+
+impl CameraState {
+    pub fn path() -> PathBuf {
+        match &config::load_config(None).state_path {
+            Some(p) => PathBuf::from(p),
+            None => config::config_path().with_extension(STATE_EXTENSION),
+        }
+    }
+
+    /// Load the saved state, starting empty if there isn't any
+    pub fn load() -> Self {
+        let path = Self::path();
+        let json = match fs::read(&path) {
+            Ok(j) => j,
+            Err(_) => return Self::default(),
+        };
+        match serde_json::from_slice(&json) {
+            Ok(state) => state,
+            Err(e) => {
+                error!("Ignoring camera state in {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        // Written alongside and renamed so a crash can't leave it half written:
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// The config file's cameras less those removed, plus those added
+    pub fn cameras(&self, config: &Config) -> Vec<CameraConfig> {
+        let mut cameras: Vec<CameraConfig> = config
+            .cameras
+            .iter()
+            .filter(|c| !self.removed.contains(&c.label))
+            .cloned()
+            .collect();
+        for camera in &self.added {
+            if cameras.iter().any(|c| c.label == camera.label) {
+                warn!(
+                    "Camera {} is now in the config file -- ignoring the one added through the API",
+                    camera.label
+                );
+                continue;
+            }
+            cameras.push(camera.clone());
+        }
+        cameras
+    }
+
+    pub fn control(&self, label: &str) -> CameraControl {
+        CameraControl::new(
+            !self.recording_disabled.iter().any(|l| l == label),
+            !self.detection_paused.iter().any(|l| l == label),
+        )
+    }
+}
@@ -0,0 +1,151 @@
+//! Command line subcommands that run instead of the camera pipelines
+
+use crate::auth;
+use crate::config;
+use crate::onvif;
+
+use std::env;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const CHECK_CONFIG_USAGE: &str = "usage: smartcam [--config <path>] check-config [<path>]";
+const DISCOVER_USAGE: &str =
+    "usage: smartcam discover [--timeout <seconds>] [--username <user>] [--password <password>]";
+/// read when `--password` isn't given, to keep it out of the process list
+const ONVIF_PASSWORD_ENV: &str = "SMARTCAM_ONVIF_PASSWORD";
+const HASH_PASSWORD_USAGE: &str = "usage: echo <password> | smartcam hash-password";
+
+/// Probe the LAN for ONVIF cameras and print `settings.toml` entries for them,
+/// returning the process exit code
+pub fn discover(args: &[String]) -> i32 {
+    let mut timeout = onvif::DEFAULT_DISCOVERY_TIMEOUT;
+    let mut username = None;
+    let mut password = env::var(ONVIF_PASSWORD_ENV).ok();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = match args.next() {
+            Some(v) => v.clone(),
+            None => {
+                eprintln!("{}", DISCOVER_USAGE);
+                return 2;
+            }
+        };
+        match arg.as_str() {
+            "--timeout" => match value.parse() {
+                Ok(secs) => timeout = Duration::from_secs(secs),
+                Err(_) => {
+                    eprintln!("Invalid timeout: {}", value);
+                    return 2;
+                }
+            },
+            "--username" => username = Some(value),
+            "--password" => password = Some(value),
+            _ => {
+                eprintln!("{}", DISCOVER_USAGE);
+                return 2;
+            }
+        }
+    }
+
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Failed to start runtime: {}", e);
+            return 1;
+        }
+    };
+    match runtime.block_on(onvif::discover(timeout, username, password)) {
+        Ok(cameras) if cameras.is_empty() => {
+            eprintln!("No ONVIF cameras found");
+            1
+        }
+        Ok(cameras) => {
+            print!("{}", onvif::config_toml(&cameras));
+            0
+        }
+        Err(e) => {
+            eprintln!("Discovery failed: {}", e);
+            1
+        }
+    }
+}
+
+/// Remove `--config <path>` from `args`, returning the path
+pub fn take_config_path(args: &mut Vec<String>) -> Result<Option<String>, String> {
+    let i = match args.iter().position(|a| a == "--config") {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    if i + 1 >= args.len() {
+        return Err("--config requires a path".to_string());
+    }
+    let path = args.remove(i + 1);
+    args.remove(i);
+    Ok(Some(path))
+}
+
+/// Validate the config given by `--config`, the positional path or the usual
+/// lookup, without starting anything, returning the process exit code
+pub fn check_config(config_path: Option<PathBuf>, args: &[String]) -> i32 {
+    let path = match (config_path, args) {
+        (Some(path), []) => path,
+        (None, []) => config::config_path(),
+        (None, [path]) => PathBuf::from(path),
+        _ => {
+            eprintln!("{}", CHECK_CONFIG_USAGE);
+            return 2;
+        }
+    };
+
+    match config::read_config(&path) {
+        Ok(config) => {
+            println!("{}: OK, {} camera(s)", path.display(), config.cameras.len());
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+/// Hash a password read from stdin for an `[[auth.users]]` entry, returning
+/// the process exit code
+pub fn hash_password(args: &[String]) -> i32 {
+    if !args.is_empty() {
+        eprintln!("{}", HASH_PASSWORD_USAGE);
+        return 2;
+    }
+
+    let mut password = String::new();
+    if let Err(e) = io::stdin().lock().read_line(&mut password) {
+        eprintln!("Failed to read password: {}", e);
+        return 1;
+    }
+    let password = password.trim_end_matches(|c| c == '\r' || c == '\n');
+    if password.is_empty() {
+        eprintln!("{}", HASH_PASSWORD_USAGE);
+        return 2;
+    }
+
+    match auth::hash_password(password) {
+        Ok(hash) => {
+            println!("password_hash = \"{}\"", hash);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+/// Print a new API token, and the hash for its `[[auth.tokens]]` entry
+pub fn new_token() -> i32 {
+    let (token, hash) = auth::new_token();
+    eprintln!("Token (shown once): {}", token);
+    println!("token_hash = \"{}\"", hash);
+    0
+}
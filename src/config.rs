@@ -1,11 +1,22 @@
+use crate::auth;
+use crate::frame::Colorspace;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{NaiveTime, Weekday};
+use ffmpeg::codec;
 use ffmpeg::util::log::level::Level as FfLevel;
 use ffmpeg_next as ffmpeg;
 use log::{Level, LevelFilter};
-use once_cell::sync::Lazy;
-use serde::Deserialize;
-use std::fs::File;
-use std::io::Read;
-use std::sync::Arc;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use url::Url;
 
 #[derive(Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "lowercase")]
@@ -14,7 +25,7 @@ pub enum FileSourceType {
     S3,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum LogLevel {
     Error,
@@ -56,7 +67,7 @@ impl LogLevel {
     }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum VideoFileType {
     Matroska,
@@ -72,76 +83,1008 @@ impl VideoFileType {
             VideoFileType::WebM => &"webm",
         }
     }
+
+    pub fn mime_type(&self) -> &str {
+        match *self {
+            VideoFileType::Matroska => &"video/x-matroska",
+            VideoFileType::Mp4 => &"video/mp4",
+            VideoFileType::WebM => &"video/webm",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        [
+            VideoFileType::Matroska,
+            VideoFileType::Mp4,
+            VideoFileType::WebM,
+        ]
+        .iter()
+        .find(|t| t.extension() == extension)
+        .cloned()
+    }
+
+    pub fn audio_codec(&self) -> codec::Id {
+        match *self {
+            VideoFileType::WebM => codec::Id::OPUS,
+            _ => codec::Id::AAC,
+        }
+    }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HlsSegmentType {
+    MpegTs,
+    Fmp4,
+}
+
+impl HlsSegmentType {
+    /// ffmpeg muxer writing this type of segment
+    pub fn muxer(&self) -> &str {
+        match *self {
+            HlsSegmentType::MpegTs => &"mpegts",
+            HlsSegmentType::Fmp4 => &"mp4",
+        }
+    }
+
+    pub fn extension(&self) -> &str {
+        match *self {
+            HlsSegmentType::MpegTs => &"ts",
+            HlsSegmentType::Fmp4 => &"m4s",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub cameras: Vec<CameraConfig>,
     pub cloud: CloudConfig,
     pub motion: MotionConfig,
     pub display: DisplayConfig,
+    #[serde(default)]
+    pub hls: HlsConfig,
+    #[serde(default)]
+    pub rtsp_server: RtspServerConfig,
+    #[serde(default)]
+    pub web: WebConfig,
+    #[serde(default)]
+    pub webrtc: WebRTCConfig,
+    #[serde(default)]
+    pub arming: ArmingConfig,
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub export: ExportConfig,
+    #[serde(default)]
+    pub thumbnails: ThumbnailConfig,
+    #[serde(default)]
+    pub osd: OsdConfig,
     pub storage: StorageConfig,
     pub log_level: LogLevel,
     pub ffmpeg_level: LogLevel,
+    /// where cameras added or changed through the API are kept, defaults to
+    /// `<config>.state.json` next to the config file
+    pub state_path: Option<String>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct CameraConfig {
     pub label: String,
     pub camera_type: String,
     pub source: Option<String>,
+    pub audio: Option<bool>,
+    /// loudness in dBFS above which audio is treated as motion
+    pub audio_threshold: Option<f64>,
+    pub backchannel: Option<BackchannelConfig>,
+    pub onvif: Option<OnvifConfig>,
+    /// checked in order, the first matching rule decides what motion does
+    pub schedule: Option<Vec<ScheduleRule>>,
+    /// blanked as frames are read, before anything else sees them
+    pub privacy_masks: Option<Vec<PrivacyMaskConfig>>,
+    pub v4l: Option<V4lConfig>,
+}
+
+/// What to ask a v4l device for, anything left out keeps the device's
+/// current setting
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct V4lConfig {
+    /// fourcc, e.g. `MJPG`, `YUYV`, `NV12`, `YU12`, `GREY`, `RGB3` or `BGR3`
+    pub pixel_format: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ArmAction {
+    /// record and notify
+    Event,
+    /// record without notifying
+    Record,
+    /// notify without recording
+    Notify,
+    Ignore,
+}
+
+impl ArmAction {
+    pub fn records(&self) -> bool {
+        matches!(self, ArmAction::Event | ArmAction::Record)
+    }
+
+    pub fn notifies(&self) -> bool {
+        matches!(self, ArmAction::Event | ArmAction::Notify)
+    }
+}
+
+/// A region of a camera's view that is never recorded, streamed or used
+/// for motion detection
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PrivacyMaskConfig {
+    /// polygon corners as `[x, y]` fractions of the frame's width and
+    /// height, so masks stay put if the resolution changes
+    pub points: Vec<[f64; 2]>,
+    pub style: Option<MaskStyle>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskStyle {
+    /// solid black
+    Fill,
+    /// coarse blocks that still show something is there
+    Pixelate,
+}
+
+/// A rule applying during a window of time, each part is optional
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleRule {
+    /// arming modes the rule applies in, e.g. `["home"]`
+    pub modes: Option<Vec<String>>,
+    /// e.g. `["sat", "sun"]`
+    pub days: Option<Vec<String>>,
+    /// local time, `HH:MM`; a window ending before it starts runs past midnight
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub action: ArmAction,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ArmingConfig {
+    /// defaults to home, away and night
+    pub modes: Option<Vec<String>>,
+    /// mode used until one is set through the API, defaults to away
+    pub default_mode: Option<String>,
+}
+
+impl ArmingConfig {
+    pub fn modes(&self) -> Vec<String> {
+        match &self.modes {
+            Some(modes) => modes.clone(),
+            None => vec!["home".to_string(), "away".to_string(), "night".to_string()],
+        }
+    }
+
+    pub fn default_mode(&self) -> String {
+        self.default_mode
+            .clone()
+            .unwrap_or_else(|| "away".to_string())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OnvifConfig {
+    /// device service url, e.g. `http://192.168.1.10/onvif/device_service`
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// media profile used for PTZ, defaults to the first with PTZ configured
+    pub profile_token: Option<String>,
+    /// preset to return to once a motion event has ended
+    pub return_preset: Option<String>,
+    /// seconds to wait after the motion event before returning
+    pub return_delay: Option<u64>,
+    /// subscribe to the camera's events and use them as triggers
+    pub events: Option<bool>,
+    /// only topics containing one of these are used, e.g. `LineDetector`
+    pub event_topics: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackchannelType {
+    /// ONVIF RTSP backchannel on the camera's stream
+    Rtsp,
+    /// audio streamed in an HTTP request body
+    Http,
+    /// writes received audio to WAV files in place of a camera, one for
+    /// each time a viewer starts talking
+    Loopback,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackchannelCodec {
+    Pcmu,
+    Pcma,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BackchannelConfig {
+    pub backchannel_type: BackchannelType,
+    /// defaults to the camera source for rtsp; the directory files are
+    /// written to for loopback
+    pub url: Option<String>,
+    pub codec: Option<BackchannelCodec>,
+    pub http_method: Option<String>,
+    pub content_type: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct CloudConfig {
     pub enabled: Option<bool>,
     pub bucket: String,
     pub region: Option<String>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct MotionConfig {
     pub min_threshold_size: i32,
     pub draw_contours: Option<bool>,
     pub draw_rectangles: Option<bool>,
+    /// seconds an external trigger started without a stop can hold an
+    /// event open, defaults to 600
+    pub max_event_duration: Option<u64>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct DisplayConfig {
     pub enabled: Option<bool>,
+    pub mjpeg_max_fps: Option<u32>,
+    pub jpeg_quality: Option<i32>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct HlsConfig {
+    pub enabled: Option<bool>,
+    pub path: Option<String>,
+    pub segment_type: Option<HlsSegmentType>,
+    pub segment_duration: Option<u32>,
+    /// seconds per LL-HLS partial segment, defaults to 0.5
+    pub part_duration: Option<f64>,
+    pub playlist_size: Option<u32>,
+    /// frames between keyframes of the live encoder while HLS is enabled,
+    /// as segments can only start on one
+    pub keyframe_interval: Option<u32>,
+}
+
+/// Text drawn onto frames before they're encoded, set separately for
+/// recordings and live streams. Neither has any unless configured
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct OsdConfig {
+    pub recording: Option<OverlayConfig>,
+    pub live: Option<OverlayConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct OverlayConfig {
+    /// strftime format, with `{label}` replaced by the camera's label
+    pub format: Option<String>,
+    pub position: Option<OverlayPosition>,
+    pub font_scale: Option<f64>,
+    /// RGB
+    pub color: Option<[u8; 3]>,
+    /// show UTC rather than local time
+    pub utc: Option<bool>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlayPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// Pictures of each recording for the video list, written next to it
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ThumbnailConfig {
+    pub enabled: Option<bool>,
+    pub width: Option<u32>,
+    pub quality: Option<i32>,
+    /// also write a short animation of the recording
+    pub preview: Option<PreviewType>,
+    pub preview_width: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PreviewType {
+    WebP,
+    Gif,
+}
+
+impl PreviewType {
+    pub fn extension(&self) -> &str {
+        match *self {
+            PreviewType::WebP => &"webp",
+            PreviewType::Gif => &"gif",
+        }
+    }
+
+    pub fn mime_type(&self) -> &str {
+        match *self {
+            PreviewType::WebP => &"image/webp",
+            PreviewType::Gif => &"image/gif",
+        }
+    }
+}
+
+/// Clips cut from recordings through the API
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ExportConfig {
+    pub path: Option<String>,
+    /// how long exports are kept after they start
+    pub keep_hours: Option<u64>,
+    /// exports running at once, more are turned away
+    pub max_running: Option<usize>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RtspServerConfig {
+    pub enabled: Option<bool>,
+    pub bind_address: Option<String>,
+    pub annotated: Option<bool>,
+}
+
+/// Settings not given here fall back to Rocket's own config
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct WebConfig {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain
+    pub cert: String,
+    /// PEM private key
+    pub key: String,
+    /// generate a self-signed cert at `cert` and `key` if they don't exist
+    pub self_signed: Option<bool>,
+    /// names the self-signed cert is for, defaults to `localhost`
+    pub hostnames: Option<Vec<String>>,
+    /// also listen on this port over plain HTTP, redirecting to HTTPS
+    pub redirect_port: Option<u16>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IceServerConfig {
+    pub urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct WebRTCConfig {
+    /// defaults to a public STUN server; set to an empty list to disable
+    pub ice_servers: Option<Vec<IceServerConfig>>,
+    pub udp_port_min: Option<u16>,
+    pub udp_port_max: Option<u16>,
+    pub nat_1to1_ips: Option<Vec<String>>,
+    pub interfaces: Option<Vec<String>>,
+}
+
+impl WebRTCConfig {
+    pub fn ice_servers(&self) -> Vec<IceServerConfig> {
+        match &self.ice_servers {
+            Some(servers) => servers.clone(),
+            None => vec![IceServerConfig {
+                urls: vec!["stun:stun.l.google.com:19302".to_string()],
+                username: None,
+                credential: None,
+            }],
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationConfig {
+    pub enabled: Option<bool>,
+    /// seconds before another event on the same camera is notified
+    pub min_interval: Option<u64>,
+    /// local `HH:MM` window during which nothing is sent
+    pub quiet_start: Option<String>,
+    pub quiet_end: Option<String>,
+    /// notify when events end as well as when they start, defaults to true
+    pub notify_end: Option<bool>,
+    pub snapshot_width: Option<u32>,
+    pub webhooks: Option<Vec<WebhookConfig>>,
+    /// publish events to the `[mqtt]` broker
+    pub mqtt: Option<bool>,
+    pub email: Option<EmailConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub headers: Option<HashMap<String, String>>,
+    /// leave the snapshot out of the payload
+    pub no_snapshot: Option<bool>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    None,
+    StartTls,
+    Tls,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    /// defaults to starttls
+    pub security: Option<SmtpSecurity>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub client_id: Option<String>,
+    /// defaults to `smartcam`
+    pub topic_prefix: Option<String>,
+    /// send Home Assistant discovery messages, defaults to true
+    pub discovery: Option<bool>,
+    /// defaults to `homeassistant`
+    pub discovery_prefix: Option<String>,
+}
+
+/// Users and API tokens for the web server, read for each request so changes
+/// apply on reload
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    pub enabled: Option<bool>,
+    /// how long a login lasts, defaults to 24
+    pub session_hours: Option<u64>,
+    /// who viewed what, as JSON lines. Defaults to `<config>.audit.log` next
+    /// to the config file
+    pub audit_log: Option<String>,
+    pub users: Option<Vec<UserConfig>>,
+    pub tokens: Option<Vec<TokenConfig>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// live streams and recordings
+    Viewer,
+    /// also PTZ, triggers, recording and detection switches and the arming mode
+    Operator,
+    /// also adding and removing cameras, and every camera regardless of `cameras`
+    Admin,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub username: String,
+    /// from `smartcam hash-password`
+    pub password_hash: String,
+    pub role: Role,
+    /// labels the user can see, defaults to all of them
+    pub cameras: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    /// who the token is for, shown in the audit log
+    pub name: String,
+    /// from `smartcam new-token`
+    pub token_hash: String,
+    pub role: Role,
+    pub cameras: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct StorageConfig {
     pub storage_type: FileSourceType,
     pub path: String,
     pub video_file_type: VideoFileType,
 }
 
-static GLOBAL_DATA: Lazy<Arc<Config>> = Lazy::new(|| {
-    let mut config_toml = String::new();
+/// Read when `--config` isn't given
+pub const CONFIG_PATH_ENV: &str = "SMARTCAM_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "settings.toml";
+
+static CONFIG_PATH: OnceCell<PathBuf> = OnceCell::new();
+static GLOBAL_DATA: OnceCell<RwLock<Arc<Config>>> = OnceCell::new();
+
+impl Config {
+    /// Sections other than `cameras` and `motion` that differ from `other`.
+    /// These are read at startup, so only take full effect after a restart
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        let mut sections = Vec::new();
+        if self.cloud != other.cloud {
+            sections.push("cloud");
+        }
+        if self.display != other.display {
+            sections.push("display");
+        }
+        if self.hls != other.hls {
+            sections.push("hls");
+        }
+        if self.rtsp_server != other.rtsp_server {
+            sections.push("rtsp_server");
+        }
+        if self.web != other.web {
+            sections.push("web");
+        }
+        if self.webrtc != other.webrtc {
+            sections.push("webrtc");
+        }
+        if self.storage != other.storage {
+            sections.push("storage");
+        }
+        if self.mqtt != other.mqtt {
+            sections.push("mqtt");
+        }
+        if self.state_path != other.state_path {
+            sections.push("state_path");
+        }
+        if self.log_level != other.log_level || self.ffmpeg_level != other.ffmpeg_level {
+            sections.push("log_level");
+        }
+        sections
+    }
+}
+
+/// A config file that couldn't be read, parsed or validated
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    /// each problem found, with its line number where known
+    pub problems: Vec<(Option<usize>, String)>,
+}
+
+impl ConfigError {
+    fn new(path: &Path, line: Option<usize>, message: String) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            problems: vec![(line, message)],
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (line, message)) in self.problems.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            match line {
+                Some(l) => write!(f, "{}:{}: {}", self.path.display(), l, message)?,
+                None => write!(f, "{}: {}", self.path.display(), message)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Use `path` instead of `SMARTCAM_CONFIG` or `settings.toml`. Only has an
+/// effect before the config is first loaded
+pub fn set_config_path(path: PathBuf) {
+    let _ = CONFIG_PATH.set(path);
+}
+
+pub fn config_path() -> PathBuf {
+    CONFIG_PATH
+        .get()
+        .cloned()
+        .or_else(|| env::var_os(CONFIG_PATH_ENV).map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+}
+
+/// Read and validate the config at `path` without loading it
+pub fn read_config(path: &Path) -> Result<Config, ConfigError> {
+    let config_toml = fs::read_to_string(path)
+        .map_err(|e| ConfigError::new(path, None, format!("could not read config: {}", e)))?;
+
+    let config: Config = toml::from_str(&config_toml).map_err(|e| {
+        let line = e.line_col().map(|(line, _)| line + 1);
+        // The position is reported separately:
+        let mut message = e.to_string();
+        if let Some(i) = message.rfind(" at line ") {
+            message.truncate(i);
+        }
+        ConfigError::new(path, line, message)
+    })?;
+
+    let problems = validate(&config, &config_toml);
+    if !problems.is_empty() {
+        return Err(ConfigError {
+            path: path.to_path_buf(),
+            problems,
+        });
+    }
+    Ok(config)
+}
+
+/// Checks serde can't express, e.g. duplicate labels
+fn validate(config: &Config, config_toml: &str) -> Vec<(Option<usize>, String)> {
+    let mut problems = Vec::new();
+
+    let mut labels = HashSet::new();
+    for (i, camera) in config.cameras.iter().enumerate() {
+        let line = table_line(config_toml, "[[cameras]]", i);
+        if !labels.insert(camera.label.as_str()) {
+            problems.push((line, format!("duplicate camera label '{}'", camera.label)));
+        }
+        problems.extend(validate_camera(camera).into_iter().map(|p| (line, p)));
+
+        let modes = config.arming.modes();
+        for rule in camera.schedule.iter().flatten() {
+            for mode in rule.modes.iter().flatten() {
+                if !modes.contains(mode) {
+                    problems.push((line, format!("unknown arming mode '{}'", mode)));
+                }
+            }
+        }
+    }
+
+    if !config
+        .arming
+        .modes()
+        .contains(&config.arming.default_mode())
+    {
+        problems.push((
+            table_line(config_toml, "[arming]", 0),
+            format!(
+                "default_mode '{}' is not one of the arming modes",
+                config.arming.default_mode()
+            ),
+        ));
+    }
+
+    if let Some(quality) = config.display.jpeg_quality {
+        if !(0..=100).contains(&quality) {
+            problems.push((
+                table_line(config_toml, "[display]", 0),
+                format!("jpeg_quality must be between 0 and 100, got {}", quality),
+            ));
+        }
+    }
+    match (config.webrtc.udp_port_min, config.webrtc.udp_port_max) {
+        (Some(min), Some(max)) if min > max => problems.push((
+            table_line(config_toml, "[webrtc]", 0),
+            format!("udp_port_min {} is above udp_port_max {}", min, max),
+        )),
+        (Some(_), None) | (None, Some(_)) => problems.push((
+            table_line(config_toml, "[webrtc]", 0),
+            "udp_port_min and udp_port_max must be set together".to_string(),
+        )),
+        _ => (),
+    }
+    let notifications = &config.notifications;
+    let line = table_line(config_toml, "[notifications]", 0);
+    for time in notifications
+        .quiet_start
+        .iter()
+        .chain(notifications.quiet_end.iter())
+    {
+        if parse_time(time).is_none() {
+            problems.push((
+                line,
+                format!("invalid quiet time '{}', expected HH:MM", time),
+            ));
+        }
+    }
+    for (i, webhook) in notifications.webhooks.iter().flatten().enumerate() {
+        if let Err(e) = Url::parse(&webhook.url) {
+            problems.push((
+                table_line(config_toml, "[[notifications.webhooks]]", i),
+                format!("invalid webhook url '{}': {}", webhook.url, e),
+            ));
+        }
+    }
+    if notifications.mqtt.unwrap_or(false) && config.mqtt.is_none() {
+        problems.push((
+            line,
+            "mqtt notifications need an [mqtt] section".to_string(),
+        ));
+    }
+
+    let line = table_line(config_toml, "[web]", 0);
+    if let Some(address) = &config.web.address {
+        if address.parse::<IpAddr>().is_err() {
+            problems.push((line, format!("invalid web address '{}'", address)));
+        }
+    }
+    if let Some(tls) = &config.web.tls {
+        let line = table_line(config_toml, "[web.tls]", 0);
+        if tls.redirect_port.is_some() && tls.redirect_port == config.web.port {
+            problems.push((
+                line,
+                "redirect_port must differ from the web port".to_string(),
+            ));
+        }
+        if !tls.self_signed.unwrap_or(false) {
+            for path in &[&tls.cert, &tls.key] {
+                if !Path::new(path).exists() {
+                    problems.push((line, format!("{} does not exist", path)));
+                }
+            }
+        }
+    }
 
-    // let path = match path {
-    //     Some(path) => path,
-    //     None => "settings.toml".to_string(),
-    // };
+    let line = table_line(config_toml, "[auth]", 0);
+    if config.auth.enabled.unwrap_or(false)
+        && config.auth.users.iter().flatten().next().is_none()
+        && config.auth.tokens.iter().flatten().next().is_none()
+    {
+        problems.push((line, "auth is enabled with no users or tokens".to_string()));
+    }
+    let mut usernames = HashSet::new();
+    for (i, user) in config.auth.users.iter().flatten().enumerate() {
+        let line = table_line(config_toml, "[[auth.users]]", i);
+        if !usernames.insert(user.username.as_str()) {
+            problems.push((line, format!("duplicate username '{}'", user.username)));
+        }
+        if !auth::is_password_hash(&user.password_hash) {
+            problems.push((
+                line,
+                format!("invalid password_hash for '{}'", user.username),
+            ));
+        }
+    }
+    for (i, token) in config.auth.tokens.iter().flatten().enumerate() {
+        if !auth::is_token_hash(&token.token_hash) {
+            problems.push((
+                table_line(config_toml, "[[auth.tokens]]", i),
+                format!("invalid token_hash for '{}'", token.name),
+            ));
+        }
+    }
 
-    let path = "settings.toml".to_string();
+    if config.hls.segment_duration == Some(0) {
+        problems.push((
+            table_line(config_toml, "[hls]", 0),
+            "segment_duration must be above 0".to_string(),
+        ));
+    }
+    if let Some(part) = config.hls.part_duration {
+        let segment = config.hls.segment_duration.map(f64::from);
+        if part <= 0.0 || segment.map_or(false, |s| part > s) {
+            problems.push((
+                table_line(config_toml, "[hls]", 0),
+                format!(
+                    "part_duration must be above 0 and at most segment_duration, got {}",
+                    part
+                ),
+            ));
+        }
+    }
 
-    let mut file = match File::open(&path) {
-        Ok(file) => file,
-        Err(_) => {
-            panic!("Could not find config file!");
+    for (header, overlay) in &[
+        ("[osd.recording]", &config.osd.recording),
+        ("[osd.live]", &config.osd.live),
+    ] {
+        let overlay = match overlay {
+            Some(o) => o,
+            None => continue,
+        };
+        let line = table_line(config_toml, header, 0);
+        if let Some(format) = &overlay.format {
+            if StrftimeItems::new(format).any(|i| i == Item::Error) {
+                problems.push((line, format!("invalid time format '{}'", format)));
+            }
+        }
+        if let Some(scale) = overlay.font_scale {
+            if scale <= 0.0 {
+                problems.push((line, "font_scale must be above 0".to_string()));
+            }
         }
-    };
+    }
 
-    file.read_to_string(&mut config_toml)
-        .unwrap_or_else(|err| panic!("Error while reading config: [{}]", err));
+    problems
+}
 
-    let cfg = toml::from_str(&config_toml).unwrap();
-    Arc::new(cfg)
-});
+/// Problems with a single camera's settings
+fn valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+pub fn validate_camera(camera: &CameraConfig) -> Vec<String> {
+    let mut problems = Vec::new();
 
-pub fn load_config(_path: Option<String>) -> Arc<Config> {
-    Arc::clone(&GLOBAL_DATA)
+    // Labels end up in file paths and urls:
+    if !valid_label(&camera.label) {
+        problems.push(format!(
+            "invalid camera label '{}', only letters, digits, '_' and '-' are allowed",
+            camera.label
+        ));
+    }
+    match camera.camera_type.as_str() {
+        "rtsp" if camera.source.is_none() => {
+            problems.push(format!("rtsp camera '{}' has no source", camera.label))
+        }
+        "rtsp" | "v4l" => (),
+        other => problems.push(format!(
+            "unknown camera_type '{}', expected rtsp or v4l",
+            other
+        )),
+    }
+    if let Some(backchannel) = &camera.backchannel {
+        if backchannel.backchannel_type == BackchannelType::Http && backchannel.url.is_none() {
+            problems.push(format!(
+                "http backchannel for '{}' has no url",
+                camera.label
+            ));
+        }
+    }
+    if let Some(onvif) = &camera.onvif {
+        if let Err(e) = Url::parse(&onvif.url) {
+            problems.push(format!("invalid onvif url '{}': {}", onvif.url, e));
+        }
+    }
+    for rule in camera.schedule.iter().flatten() {
+        for time in rule.start.iter().chain(rule.end.iter()) {
+            if parse_time(time).is_none() {
+                problems.push(format!("invalid schedule time '{}', expected HH:MM", time));
+            }
+        }
+        for day in rule.days.iter().flatten() {
+            if day.parse::<Weekday>().is_err() {
+                problems.push(format!("invalid schedule day '{}'", day));
+            }
+        }
+    }
+    if let Some(format) = camera.v4l.as_ref().and_then(|v| v.pixel_format.as_ref()) {
+        if format.len() != 4 || Colorspace::str(format).is_err() {
+            problems.push(format!(
+                "unsupported pixel_format '{}' for '{}'",
+                format, camera.label
+            ));
+        }
+    }
+    for mask in camera.privacy_masks.iter().flatten() {
+        if mask.points.len() < 3 {
+            problems.push(format!(
+                "privacy mask for '{}' needs at least 3 points",
+                camera.label
+            ));
+        }
+        if mask
+            .points
+            .iter()
+            .flatten()
+            .any(|c| !(0.0..=1.0).contains(c))
+        {
+            problems.push(format!(
+                "privacy mask points for '{}' must be between 0 and 1",
+                camera.label
+            ));
+        }
+    }
+
+    problems
+}
+
+/// `HH:MM` as used in schedule rules
+pub fn parse_time(time: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M").ok()
+}
+
+/// Line number of the `index`th occurrence of a table header
+fn table_line(config_toml: &str, header: &str, index: usize) -> Option<usize> {
+    config_toml
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| l.trim_start().starts_with(header))
+        .nth(index)
+        .map(|(i, _)| i + 1)
+}
+
+/// Read and load the config, which must happen before anything calls `load_config`
+/// for errors to be reported rather than panicking
+pub fn init() -> Result<Arc<Config>, ConfigError> {
+    let config = Arc::new(read_config(&config_path())?);
+    let _ = GLOBAL_DATA.set(RwLock::new(Arc::clone(&config)));
+    Ok(load_config(None))
+}
+
+/// Re-read the config file, replacing the loaded config if it's valid
+pub fn reload() -> Result<Arc<Config>, ConfigError> {
+    let config = Arc::new(read_config(&config_path())?);
+    *global().write().unwrap() = Arc::clone(&config);
+    Ok(config)
+}
+
+fn global() -> &'static RwLock<Arc<Config>> {
+    GLOBAL_DATA.get_or_init(|| match read_config(&config_path()) {
+        Ok(config) => RwLock::new(Arc::new(config)),
+        Err(e) => panic!("Invalid config:\n{}", e),
+    })
+}
+
+/// The current config. `path` is used if the config hasn't been loaded yet
+pub fn load_config(path: Option<String>) -> Arc<Config> {
+    if let Some(path) = path {
+        set_config_path(PathBuf::from(path));
+    }
+    Arc::clone(&global().read().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn camera_labels() {
+        for label in &["front", "Back_door-2", "0"] {
+            assert!(valid_label(label), "{}", label);
+        }
+        for label in &[
+            "",
+            ".",
+            "..",
+            "a/b",
+            "../etc",
+            "front door",
+            "caméra",
+            "a\\b",
+        ] {
+            assert!(!valid_label(label), "{}", label);
+        }
+    }
 }
@@ -0,0 +1,306 @@
+//! Uncompressed pixel formats converted into packed BGR. Each conversion
+//! writes into a buffer that's already the right size and works a
+//! fixed-size chunk of pixels at a time, in integer math, so the compiler
+//! can vectorize it. Source rows are `stride` bytes apart.
+//!
+//! Kept free of the rest of the crate so the benchmarks can build it alone.
+
+/// `height` rows of `row_len` bytes from `buf`, each `stride` bytes apart
+fn rows(buf: &[u8], stride: usize, row_len: usize, height: usize) -> impl Iterator<Item = &[u8]> {
+    buf.chunks(stride).take(height).map(move |r| &r[..row_len])
+}
+
+/// Bytes per row of NV12's interleaved chroma, given the luma stride
+pub fn nv12_chroma_stride(stride: usize) -> usize {
+    (stride + 1) / 2 * 2
+}
+
+/// Bytes per row of each of YUV420's chroma planes, given the luma stride
+pub fn yuv420_chroma_stride(stride: usize) -> usize {
+    (stride + 1) / 2
+}
+
+pub fn bgr_copy(src: &[u8], width: usize, height: usize, stride: usize, dst: &mut [u8]) {
+    for (s, d) in rows(src, stride, width * 3, height).zip(dst.chunks_exact_mut(width * 3)) {
+        d.copy_from_slice(s);
+    }
+}
+
+/// `src` RGB copied into `dst` as BGR
+pub fn rgb_to_bgr(src: &[u8], width: usize, height: usize, stride: usize, dst: &mut [u8]) {
+    for (s_row, d_row) in rows(src, stride, width * 3, height).zip(dst.chunks_exact_mut(width * 3))
+    {
+        for (s, d) in s_row.chunks_exact(3).zip(d_row.chunks_exact_mut(3)) {
+            d[0] = s[2];
+            d[1] = s[1];
+            d[2] = s[0];
+        }
+    }
+}
+
+/*
+BT.601, with Cr aka V aka red and Cb aka U aka blue:
+R = Y + 1.402 (Cr-128.0)
+G = Y - 0.34414 (Cb-128.0) - 0.71414 (Cr-128.0)
+B = Y + 1.772 (Cb-128.0)
+in 16.16 fixed point:
+*/
+const FIX_SHIFT: i32 = 16;
+const FIX_HALF: i32 = 1 << (FIX_SHIFT - 1);
+const R_V: i32 = 91_881;
+const G_U: i32 = 22_554;
+const G_V: i32 = 46_802;
+const B_U: i32 = 116_130;
+
+/// Pixels converted together, enough to fill the vector registers
+const BLOCK: usize = 16;
+
+/// Back from fixed point, clamped rather than wrapped at either end
+#[inline(always)]
+fn fix_to_u8(x: i32) -> u8 {
+    ((x + FIX_HALF) >> FIX_SHIFT).max(0).min(255) as u8
+}
+
+/// One pixel written into `dst` as BGR, for the ends of rows too short to
+/// make a block
+#[inline(always)]
+fn yuv_to_bgr_pixel(y: u8, u: u8, v: u8, dst: &mut [u8]) {
+    let y = (y as i32) << FIX_SHIFT;
+    let (u, v) = (u as i32 - 128, v as i32 - 128);
+    dst[0] = fix_to_u8(y + B_U * u);
+    dst[1] = fix_to_u8(y - G_U * u - G_V * v);
+    dst[2] = fix_to_u8(y + R_V * v);
+}
+
+/// A block of pixels, each with its own samples, written into `dst` as BGR.
+/// Each channel is worked out for the whole block before they're
+/// interleaved, which is what lets the math vectorize
+#[inline(always)]
+fn yuv_block_to_bgr(y: &[u8; BLOCK], u: &[u8; BLOCK], v: &[u8; BLOCK], dst: &mut [u8]) {
+    let (mut b, mut g, mut r) = ([0; BLOCK], [0; BLOCK], [0; BLOCK]);
+    for i in 0..BLOCK {
+        let y = (y[i] as i32) << FIX_SHIFT;
+        let (u, v) = (u[i] as i32 - 128, v[i] as i32 - 128);
+        b[i] = fix_to_u8(y + B_U * u);
+        g[i] = fix_to_u8(y - G_U * u - G_V * v);
+        r[i] = fix_to_u8(y + R_V * v);
+    }
+    for (i, d) in dst[..BLOCK * 3].chunks_exact_mut(3).enumerate() {
+        d[0] = b[i];
+        d[1] = g[i];
+        d[2] = r[i];
+    }
+}
+
+/// YUYV pairs of pixels into `dst`. With an odd `width` the last pixel of
+/// each row is the first of a pair
+pub fn yuyv_to_bgr(src: &[u8], width: usize, height: usize, stride: usize, dst: &mut [u8]) {
+    let row_len = ((width + 1) / 2) * 4;
+    for (s_row, d_row) in rows(src, stride, row_len, height).zip(dst.chunks_exact_mut(width * 3)) {
+        let s_blocks = s_row.chunks_exact(BLOCK * 2);
+        let d_blocks = d_row.chunks_exact_mut(BLOCK * 3);
+        for (s, d) in s_blocks.zip(d_blocks) {
+            let (mut y, mut u, mut v) = ([0; BLOCK], [0; BLOCK], [0; BLOCK]);
+            for (i, p) in s.chunks_exact(4).enumerate() {
+                y[i * 2] = p[0];
+                y[i * 2 + 1] = p[2];
+                u[i * 2] = p[1];
+                u[i * 2 + 1] = p[1];
+                v[i * 2] = p[3];
+                v[i * 2 + 1] = p[3];
+            }
+            yuv_block_to_bgr(&y, &u, &v, d);
+        }
+
+        let start = width - width % BLOCK;
+        for (col, d) in (start..).zip(d_row[start * 3..].chunks_exact_mut(3)) {
+            let p = &s_row[col / 2 * 4..col / 2 * 4 + 4];
+            yuv_to_bgr_pixel(p[col % 2 * 2], p[1], p[3], d);
+        }
+    }
+}
+
+/// `y_plane` at full resolution with chroma shared by each 2x2 block, taken
+/// a row at a time from `chroma_rows(row)`, which gives the U and V of the
+/// row's blocks
+fn planar_to_bgr<'a>(
+    y_plane: &[u8],
+    width: usize,
+    height: usize,
+    stride: usize,
+    dst: &mut [u8],
+    chroma_rows: impl Fn(usize) -> (&'a [u8], &'a [u8], usize),
+) {
+    let rows = rows(y_plane, stride, width, height)
+        .zip(dst.chunks_exact_mut(width * 3))
+        .enumerate();
+    for (row, (y_row, dst_row)) in rows {
+        // `step` is how far apart each block's samples are in the row:
+        let (u_row, v_row, step) = chroma_rows(row / 2);
+        let mut y_blocks = y_row.chunks_exact(BLOCK);
+        let mut d_blocks = dst_row.chunks_exact_mut(BLOCK * 3);
+        for (n, (ys, d)) in (&mut y_blocks).zip(&mut d_blocks).enumerate() {
+            let mut y = [0; BLOCK];
+            y.copy_from_slice(ys);
+            let (mut u, mut v) = ([0; BLOCK], [0; BLOCK]);
+            for i in 0..BLOCK / 2 {
+                let j = (n * BLOCK / 2 + i) * step;
+                u[i * 2] = u_row[j];
+                u[i * 2 + 1] = u_row[j];
+                v[i * 2] = v_row[j];
+                v[i * 2 + 1] = v_row[j];
+            }
+            yuv_block_to_bgr(&y, &u, &v, d);
+        }
+
+        let start = width - y_blocks.remainder().len();
+        let tail = y_blocks
+            .remainder()
+            .iter()
+            .zip(d_blocks.into_remainder().chunks_exact_mut(3));
+        for (col, (&y, d)) in (start..).zip(tail) {
+            let i = col / 2 * step;
+            yuv_to_bgr_pixel(y, u_row[i], v_row[i], d);
+        }
+    }
+}
+
+pub fn nv12_to_bgr(buf: &[u8], width: usize, height: usize, stride: usize, dst: &mut [u8]) {
+    let (y_plane, uv_plane) = buf.split_at(stride * height);
+    let uv_stride = nv12_chroma_stride(stride);
+    let uv_width = (width + 1) / 2 * 2;
+    planar_to_bgr(y_plane, width, height, stride, dst, move |row| {
+        let uv_row = &uv_plane[row * uv_stride..row * uv_stride + uv_width];
+        (uv_row, &uv_row[1..], 2)
+    })
+}
+
+pub fn yuv420_to_bgr(buf: &[u8], width: usize, height: usize, stride: usize, dst: &mut [u8]) {
+    let (y_plane, chroma) = buf.split_at(stride * height);
+    let chroma_stride = yuv420_chroma_stride(stride);
+    let chroma_width = (width + 1) / 2;
+    let (u_plane, v_plane) = chroma.split_at(chroma_stride * ((height + 1) / 2));
+    planar_to_bgr(y_plane, width, height, stride, dst, move |row| {
+        let range = row * chroma_stride..row * chroma_stride + chroma_width;
+        (&u_plane[range.clone()], &v_plane[range], 1)
+    })
+}
+
+pub fn grey_to_bgr(src: &[u8], width: usize, height: usize, stride: usize, dst: &mut [u8]) {
+    for (s_row, d_row) in rows(src, stride, width, height).zip(dst.chunks_exact_mut(width * 3)) {
+        for (&g, d) in s_row.iter().zip(d_row.chunks_exact_mut(3)) {
+            d[0] = g;
+            d[1] = g;
+            d[2] = g;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// BT.601 in floating point, which the fixed point math stands in for
+    fn reference(y: u8, u: u8, v: u8) -> [f64; 3] {
+        let (y, u, v) = (y as f64, u as f64 - 128.0, v as f64 - 128.0);
+        [y + 1.772 * u, y - 0.34414 * u - 0.71414 * v, y + 1.402 * v]
+    }
+
+    fn assert_close(got: &[u8], y: u8, u: u8, v: u8) {
+        for (g, want) in got.iter().zip(&reference(y, u, v)) {
+            assert!(
+                (*g as f64 - want.max(0.0).min(255.0)).abs() <= 1.0,
+                "YUV {} {} {} gave {:?}, expected {:?}",
+                y,
+                u,
+                v,
+                got,
+                reference(y, u, v)
+            );
+        }
+    }
+
+    /// Bytes that look random enough to cover the YUV range
+    fn noise(len: usize) -> Vec<u8> {
+        let mut x: u32 = 0x1234_5678;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (x >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_pixel_within_one_of_float() {
+        let mut px = [0; 3];
+        for y in 0..=255 {
+            for u in 0..=255 {
+                for v in 0..=255 {
+                    yuv_to_bgr_pixel(y, u, v, &mut px);
+                    assert_close(&px, y, u, v);
+                }
+            }
+        }
+    }
+
+    // Sizes that leave part of each row to convert after the last block:
+
+    #[test]
+    fn yuyv_within_one_of_float() {
+        let (width, height) = (72, 48);
+        let src = noise(width * height * 2);
+        let mut dst = vec![0; width * height * 3];
+        yuyv_to_bgr(&src, width, height, width * 2, &mut dst);
+        for (s, d) in src.chunks_exact(4).zip(dst.chunks_exact(6)) {
+            assert_close(&d[..3], s[0], s[1], s[3]);
+            assert_close(&d[3..], s[2], s[1], s[3]);
+        }
+    }
+
+    #[test]
+    fn yuyv_odd_width_within_one_of_float() {
+        let (width, height) = (71, 5);
+        let row_len = (width + 1) / 2 * 4;
+        let src = noise(row_len * height);
+        // Anything left unwritten fails the comparison:
+        let mut dst = vec![0xAA; width * height * 3];
+        yuyv_to_bgr(&src, width, height, row_len, &mut dst);
+        for (s_row, d_row) in src.chunks_exact(row_len).zip(dst.chunks_exact(width * 3)) {
+            for (col, d) in d_row.chunks_exact(3).enumerate() {
+                let p = &s_row[col / 2 * 4..];
+                assert_close(d, p[col % 2 * 2], p[1], p[3]);
+            }
+        }
+    }
+
+    #[test]
+    fn nv12_within_one_of_float() {
+        let (width, height) = (72, 47);
+        let src = noise(width * height + width * ((height + 1) / 2));
+        let mut dst = vec![0; width * height * 3];
+        nv12_to_bgr(&src, width, height, width, &mut dst);
+        let uv_plane = &src[width * height..];
+        for (i, d) in dst.chunks_exact(3).enumerate() {
+            let (row, col) = (i / width, i % width);
+            let uv = (row / 2) * width + (col / 2) * 2;
+            assert_close(d, src[i], uv_plane[uv], uv_plane[uv + 1]);
+        }
+    }
+
+    #[test]
+    fn yuv420_within_one_of_float() {
+        let (width, height) = (45, 31);
+        let chroma_width = (width + 1) / 2;
+        let chroma_size = chroma_width * ((height + 1) / 2);
+        let src = noise(width * height + chroma_size * 2);
+        let mut dst = vec![0; width * height * 3];
+        yuv420_to_bgr(&src, width, height, width, &mut dst);
+        let (u_plane, v_plane) = src[width * height..].split_at(chroma_size);
+        for (i, d) in dst.chunks_exact(3).enumerate() {
+            let (row, col) = (i / width, i % width);
+            let c = (row / 2) * chroma_width + col / 2;
+            assert_close(d, src[i], u_plane[c], v_plane[c]);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Recording events: what triggers them and the metadata stored alongside each recording
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+/// Extension appended to a recording's file name for its metadata
+pub const METADATA_EXTENSION: &str = "json";
+
+/// What opened or extended an event
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum EventSource {
+    Motion,
+    Audio,
+    /// ONVIF notification, e.g. `tns1:RuleEngine/LineDetector/Crossed`
+    Onvif {
+        topic: String,
+    },
+    /// `POST /api/cameras/<label>/trigger`
+    Webhook {
+        name: Option<String>,
+    },
+    /// `<prefix>/<label>/trigger/set`
+    Mqtt,
+}
+
+impl EventSource {
+    /// Matches the serialized `type`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            EventSource::Motion => "motion",
+            EventSource::Audio => "audio",
+            EventSource::Onvif { .. } => "onvif",
+            EventSource::Webhook { .. } => "webhook",
+            EventSource::Mqtt => "mqtt",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerState {
+    /// hold the event open until the matching `Stop`
+    Start,
+    Stop,
+    /// extend the event once, like a single frame of motion
+    Pulse,
+}
+
+/// A trigger from outside the motion detector
+#[derive(Clone, Debug)]
+pub struct ExternalTrigger {
+    pub source: EventSource,
+    pub state: TriggerState,
+    pub time: DateTime<Utc>,
+}
+
+pub type TriggerSender = mpsc::Sender<ExternalTrigger>;
+pub type TriggerReceiver = mpsc::Receiver<ExternalTrigger>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EventTrigger {
+    pub source: EventSource,
+    pub time: DateTime<Utc>,
+}
+
+/// Written next to each recording once it's closed
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EventMetadata {
+    pub label: String,
+    pub file_name: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    /// each source's first trigger during the event
+    pub triggers: Vec<EventTrigger>,
+}
+
+impl EventMetadata {
+    pub fn path_for(video: &Path) -> PathBuf {
+        let mut name = video.as_os_str().to_owned();
+        name.push(".");
+        name.push(METADATA_EXTENSION);
+        PathBuf::from(name)
+    }
+
+    /// Write the metadata for `video`, returning where it went
+    pub fn write(&self, video: &Path) -> Result<PathBuf> {
+        let path = Self::path_for(video);
+        fs::write(&path, serde_json::to_vec_pretty(self)?)?;
+        Ok(path)
+    }
+}
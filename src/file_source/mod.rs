@@ -1,6 +1,8 @@
-use crate::config;
+use crate::config::{self, VideoFileType};
+use crate::event::{EventMetadata, EventTrigger};
 use anyhow::Result;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use once_cell::sync::Lazy;
 use serde::Serialize;
 use std::path::{Path, PathBuf};
@@ -16,25 +18,53 @@ pub fn load() -> Arc<dyn FileSource + Send + Sync> {
 
 #[derive(Serialize, Clone, Debug)]
 pub struct VideoFile {
-    // timestamp: DateTime<Utc>,
-    // label: String,
-    // link: String,
+    /// what the recording is fetched by, currently its file name
+    pub id: String,
+    pub label: String,
     pub file_name: String,
+    pub start_time: DateTime<Utc>,
     // length?
     // resolution?
 }
 
+/// A recording with what's known about the event it covers
+#[derive(Serialize, Clone, Debug)]
+pub struct Recording {
+    #[serde(flatten)]
+    pub file: VideoFile,
+    pub end_time: DateTime<Utc>,
+    /// what opened or extended the event, empty for recordings made before
+    /// metadata was written
+    pub triggers: Vec<EventTrigger>,
+}
+
 #[async_trait]
 pub trait FileSource {
     async fn list_files_by_label(&self, label: &str) -> Result<Vec<VideoFile>>;
-    // async fn list_files_by_label_since_time(&self, label: &str, since_time: &str) -> Vec<VideoFile>;
-    // async fn list_files_by_label_before_time(&self, label: &str, before_time: &str) -> Vec<VideoFile>;
-    // async fn list_files_by_label_between_times(
-    //     &self,
-    //     label: &str,
-    //     begin_time: &str,
-    //     end_time: &str,
-    // ) -> Vec<VideoFile>;
+    /// Where to read recording `id` of camera `label` from, `None` if the
+    /// camera has no such recording
+    async fn file_path(&self, label: &str, id: &str) -> Result<Option<PathBuf>>;
+    /// Recordings of `label` overlapping `begin_time` to `end_time`, oldest first
+    async fn list_recordings_between_times(
+        &self,
+        label: &str,
+        begin_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<Recording>>;
+}
+
+/// The start time of a recording named `<label>-<start time>.<extension>`,
+/// `None` if `file_name` isn't one of `label`'s recordings. Parsing the time
+/// keeps e.g. `front` from matching `front-yard`'s recordings
+fn recording_start(file_name: &str, label: &str) -> Option<DateTime<Utc>> {
+    let (stem, extension) = file_name.rsplit_once('.')?;
+    if VideoFileType::from_extension(extension).is_none() {
+        return None;
+    }
+    let time = stem.strip_prefix(label)?.strip_prefix('-')?;
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
 }
 
 struct LocalFileSource {
@@ -58,13 +88,70 @@ impl FileSource for LocalFileSource {
         let mut entries = fs::read_dir(&self.path).await?;
 
         while let Some(entry) = entries.next_entry().await? {
-            println!("{:?}", entry.path());
-            if entry.file_name().to_string_lossy().contains(label) {
+            let file_name = match entry.file_name().into_string() {
+                Ok(f) => f,
+                Err(_) => continue,
+            };
+            if let Some(start_time) = recording_start(&file_name, label) {
                 v.push(VideoFile {
-                    file_name: entry.file_name().into_string().unwrap(),
+                    id: file_name.clone(),
+                    label: label.to_string(),
+                    file_name,
+                    start_time,
                 });
             }
         }
+        v.sort_by_key(|f| f.start_time);
         Ok(v)
     }
+
+    async fn file_path(&self, label: &str, id: &str) -> Result<Option<PathBuf>> {
+        // Only ever a name from the listing, never a path from the request:
+        Ok(self
+            .list_files_by_label(label)
+            .await?
+            .into_iter()
+            .find(|f| f.id == id)
+            .map(|f| self.path.join(f.file_name)))
+    }
+
+    async fn list_recordings_between_times(
+        &self,
+        label: &str,
+        begin_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<Recording>> {
+        let mut recordings = Vec::new();
+        for file in self.list_files_by_label(label).await? {
+            if file.start_time >= end_time {
+                continue;
+            }
+            let path = self.path.join(&file.file_name);
+            let metadata = fs::read(EventMetadata::path_for(&path))
+                .await
+                .ok()
+                .and_then(|json| serde_json::from_slice::<EventMetadata>(&json).ok());
+            let recording = match metadata {
+                Some(metadata) => Recording {
+                    file,
+                    end_time: metadata.end_time,
+                    triggers: metadata.triggers,
+                },
+                // Still being recorded, or made before metadata was written. The
+                // file was last written as the recording ended, or just now:
+                None => {
+                    let modified = fs::metadata(&path).await?.modified()?;
+                    Recording {
+                        file,
+                        end_time: DateTime::<Utc>::from(modified),
+                        triggers: Vec::new(),
+                    }
+                }
+            };
+            if recording.end_time > begin_time {
+                recordings.push(recording);
+            }
+        }
+        Ok(recordings)
+    }
 }
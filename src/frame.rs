@@ -1,7 +1,16 @@
+use crate::convert::{
+    bgr_copy, grey_to_bgr, nv12_chroma_stride, nv12_to_bgr, rgb_to_bgr, yuv420_chroma_stride,
+    yuv420_to_bgr, yuyv_to_bgr,
+};
+use crate::event::EventTrigger;
+use anyhow::anyhow;
 use chrono::{DateTime, Utc};
 use opencv::{
-    core::Size_, core::BORDER_DEFAULT, imgproc::cvt_color, imgproc::gaussian_blur,
-    imgproc::COLOR_BGR2GRAY, prelude::*, Result,
+    core::Mat_AUTO_STEP, core::Size, core::Size_, core::BORDER_DEFAULT, core::CV_8UC1,
+    core::CV_8UC3, imgcodecs::imdecode, imgcodecs::imencode, imgcodecs::IMREAD_COLOR,
+    imgcodecs::IMWRITE_JPEG_QUALITY, imgproc::cvt_color, imgproc::gaussian_blur, imgproc::resize,
+    imgproc::COLOR_BGR2GRAY, imgproc::INTER_AREA, prelude::*, types::VectorOfi32,
+    types::VectorOfu8, Result,
 };
 use std::str::FromStr;
 use std::sync::Arc;
@@ -9,11 +18,23 @@ use std::time::SystemTime;
 use strum::ParseError;
 use strum_macros::{Display, EnumString};
 
-#[derive(Display, EnumString, Copy, Clone, Debug)]
+/// Pixel formats frames are read in, named as their V4L2 fourcc where they
+/// have one
+#[derive(Display, EnumString, Copy, Clone, Debug, PartialEq)]
 pub enum Colorspace {
+    #[strum(to_string = "RGB", serialize = "RGB3")]
     RGB,
+    #[strum(to_string = "BGR", serialize = "BGR3")]
     BGR,
     YUYV,
+    /// motion JPEG, each buffer a complete JPEG
+    MJPG,
+    /// Y plane then interleaved U and V at half resolution
+    NV12,
+    /// Y, U and V planes, U and V at half resolution
+    #[strum(to_string = "YUV420", serialize = "YU12")]
+    YUV420,
+    GREY,
 }
 
 impl Colorspace {
@@ -21,88 +42,109 @@ impl Colorspace {
         Colorspace::from_str(input)
     }
 
-    pub fn convert_buf(&self, buf: Vec<u8>, target: Colorspace) -> Vec<u8> {
+    /// Bytes in one row of the first plane of a `width` pixel frame with no
+    /// padding, 0 for compressed formats
+    pub fn packed_stride(&self, width: usize) -> usize {
         match self {
-            Self::RGB => match target {
-                Colorspace::RGB => buf,
-                Colorspace::BGR => {
-                    let mut c = buf.clone();
-                    rgb_bgr_swap(&mut c);
-                    c
-                }
-                Colorspace::YUYV => panic!("{} to {} conversion not supported", self, target),
-            },
-            Self::BGR => match target {
-                Colorspace::BGR => buf,
-                Colorspace::RGB => {
-                    let mut c = buf.clone();
-                    rgb_bgr_swap(&mut c);
-                    c
-                }
-                Colorspace::YUYV => panic!("{} to {} conversion not supported", self, target),
-            },
-            Self::YUYV => match target {
-                Colorspace::YUYV => buf,
-                Colorspace::BGR => {
-                    trace!("buf len is {}", buf.len());
-                    yuyv_to_bgr(&buf)
-                }
-                Colorspace::RGB => panic!("{} to {} conversion not supported", self, target),
-            },
+            Self::RGB | Self::BGR => width * 3,
+            Self::YUYV => ((width + 1) / 2) * 4,
+            Self::NV12 | Self::YUV420 | Self::GREY => width,
+            Self::MJPG => 0,
         }
     }
-}
 
-/// swap red and blue
-pub fn rgb_bgr_swap(buf: &mut [u8]) {
-    let mut i = 0;
+    /// Bytes in a `width` x `height` frame whose rows are `stride` bytes
+    /// apart, `None` for compressed formats
+    pub fn frame_size(&self, width: usize, height: usize, stride: usize) -> Option<usize> {
+        let chroma_height = (height + 1) / 2;
+        match self {
+            Self::RGB | Self::BGR | Self::YUYV | Self::GREY => Some(stride * height),
+            Self::NV12 => Some(stride * height + nv12_chroma_stride(stride) * chroma_height),
+            Self::YUV420 => {
+                Some(stride * height + yuv420_chroma_stride(stride) * chroma_height * 2)
+            }
+            Self::MJPG => None,
+        }
+    }
 
-    while i < buf.len() {
-        let temp = buf[i];
-        buf[i] = buf[i + 2];
-        buf[i + 2] = temp;
+    /// Convert a `width` x `height` frame straight into a new BGR `Mat`,
+    /// the one copy made of it. Rows are `stride` bytes apart, as drivers
+    /// may pad them; a smaller stride, e.g. 0, is taken as no padding
+    pub fn to_bgr_mat(
+        &self,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        stride: usize,
+    ) -> anyhow::Result<Mat> {
+        let (w, h) = (width as usize, height as usize);
+        if w == 0 || h == 0 {
+            return Err(anyhow!("{} frame has no pixels", self));
+        }
+        let stride = stride.max(self.packed_stride(w));
+        if let Some(size) = self.frame_size(w, h, stride) {
+            if buf.len() < size {
+                return Err(anyhow!(
+                    "{} frame of {}x{} needs {} bytes, got {}",
+                    self,
+                    w,
+                    h,
+                    size,
+                    buf.len()
+                ));
+            }
+        }
+        // Decoding already makes a new image:
+        if *self == Self::MJPG {
+            return mjpeg_to_bgr(buf, w, h);
+        }
 
-        i = i + 3;
+        // Every byte of it is written below:
+        let mut img = unsafe { Mat::new_rows_cols(height as i32, width as i32, CV_8UC3)? };
+        let dst = img.data_bytes_mut()?;
+        match self {
+            Self::BGR => bgr_copy(buf, w, h, stride, dst),
+            Self::RGB => rgb_to_bgr(buf, w, h, stride, dst),
+            Self::YUYV => yuyv_to_bgr(buf, w, h, stride, dst),
+            Self::NV12 => nv12_to_bgr(buf, w, h, stride, dst),
+            Self::YUV420 => yuv420_to_bgr(buf, w, h, stride, dst),
+            Self::GREY => grey_to_bgr(buf, w, h, stride, dst),
+            Self::MJPG => unreachable!(),
+        }
+        Ok(img)
     }
 }
 
-pub fn yuyv_to_bgr(buf: &[u8]) -> Vec<u8> {
-    /*
-    Cr aka V aka red
-    Cb aka U aka blue
-    R = Y + 1.402 (Cr-128.0)
-    G = Y - 0.34414 (Cb-128.0) - 0.71414 (Cr-128.0)
-    B = Y + 1.772 (Cb-128.0)
-    */
-
-    let mut mat_buf = Vec::new();
-    let mut i = 0;
-    while i < buf.len() {
-        let y1 = buf[i] as f64;
-        let u = buf[i + 1] as f64;
-        let y2 = buf[i + 2] as f64;
-        let v = buf[i + 3] as f64;
-
-        let p1_b = (y1 + (1.772 * (u - 128.0))) as u8;
-        let p1_g = (y1 - (0.34414 * (u - 128.0)) - (0.71414 * (v - 128.0))) as u8;
-        let p1_r = (y1 + 1.402 * (v - 128.0)) as u8;
-
-        let p2_b = (y2 + (1.772 * (u - 128.0))) as u8;
-        let p2_g = (y2 - (0.34414 * (u - 128.0)) - (0.71414 * (v - 128.0))) as u8;
-        let p2_r = (y2 + 1.402 * (v - 128.0)) as u8;
-
-        mat_buf.push(p1_b);
-        mat_buf.push(p1_g);
-        mat_buf.push(p1_r);
-        mat_buf.push(p2_b);
-        mat_buf.push(p2_g);
-        mat_buf.push(p2_r);
-
-        i = i + 4;
+/// Decode one motion JPEG frame, which must be `width` x `height`
+pub fn mjpeg_to_bgr(buf: &[u8], width: usize, height: usize) -> anyhow::Result<Mat> {
+    let jpeg = unsafe {
+        Mat::new_rows_cols_with_data(
+            1,
+            buf.len() as i32,
+            CV_8UC1,
+            // Note: this data is not copied, only read by `imdecode`:
+            buf.as_ptr() as *mut std::os::raw::c_void,
+            Mat_AUTO_STEP,
+        )?
+    };
+    let img = imdecode(&jpeg, IMREAD_COLOR)?;
+    if img.empty()? {
+        return Err(anyhow!("Failed to decode MJPEG frame"));
+    }
+    if img.cols() as usize != width || img.rows() as usize != height {
+        return Err(anyhow!(
+            "MJPEG frame is {}x{}, expected {}x{}",
+            img.cols(),
+            img.rows(),
+            width,
+            height
+        ));
     }
-    mat_buf
+    Ok(img)
 }
 
+pub const DEFAULT_JPEG_QUALITY: i32 = 80;
+
 unsafe impl Send for Frame {}
 unsafe impl Sync for Frame {}
 
@@ -119,6 +161,10 @@ pub struct VideoFrame {
     pub frame: Arc<Frame>,
     pub is_start: bool,
     pub is_end: bool,
+    /// triggers first seen since the previous frame
+    pub triggers: Vec<EventTrigger>,
+    /// area of the largest moving contour, 0 for none
+    pub motion: f64,
 }
 
 impl Frame {
@@ -206,6 +252,36 @@ impl Frame {
     pub fn downsample(&self) -> Result<Frame> {
         self.grayscale()?.blur()
     }
+
+    /// encode frame as jpeg, optionally scaled down to `width` keeping aspect ratio
+    pub fn to_jpeg(&self, width: Option<u32>, quality: Option<i32>) -> Result<Vec<u8>> {
+        let resized;
+        let img = match width {
+            Some(w) if w > 0 && w < self.width => {
+                let h = (self.height as f64 * w as f64 / self.width as f64).round() as i32;
+                let mut dst = Mat::default();
+                resize(
+                    &self.img,
+                    &mut dst,
+                    Size::new(w as i32, h.max(1)),
+                    0.0,
+                    0.0,
+                    INTER_AREA,
+                )?;
+                resized = dst;
+                &resized
+            }
+            _ => &self.img,
+        };
+
+        let params = VectorOfi32::from(vec![
+            IMWRITE_JPEG_QUALITY,
+            quality.unwrap_or(DEFAULT_JPEG_QUALITY).clamp(1, 100),
+        ]);
+        let mut buf = VectorOfu8::new();
+        imencode(".jpg", img, &mut buf, &params)?;
+        Ok(buf.to_vec())
+    }
 }
 
 impl Clone for Frame {
@@ -225,3 +301,194 @@ impl Clone for Frame {
 //         debug!("Dropping frame with time {}", self.time);
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::Scalar;
+
+    /// YUV samples and the BGR they convert to
+    const COLORS: &[(&str, [u8; 3], [u8; 3])] = &[
+        ("black", [0, 128, 128], [0, 0, 0]),
+        ("white", [255, 128, 128], [255, 255, 255]),
+        ("red", [76, 85, 255], [0, 0, 254]),
+        ("green", [150, 44, 21], [1, 255, 0]),
+        ("blue", [29, 255, 107], [254, 0, 0]),
+    ];
+
+    fn convert(
+        colorspace: Colorspace,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        stride: usize,
+    ) -> Vec<u8> {
+        let img = colorspace.to_bgr_mat(buf, width, height, stride).unwrap();
+        assert_eq!((img.cols() as u32, img.rows() as u32), (width, height));
+        img.data_bytes().unwrap().to_vec()
+    }
+
+    fn solid(bgr: [u8; 3], pixels: usize) -> Vec<u8> {
+        bgr.iter().cloned().cycle().take(pixels * 3).collect()
+    }
+
+    #[test]
+    fn yuyv_colors() {
+        for (name, [y, u, v], bgr) in COLORS {
+            let buf = [*y, *u, *y, *v, *y, *u, *y, *v];
+            assert_eq!(
+                convert(Colorspace::YUYV, &buf, 2, 2, 0),
+                solid(*bgr, 4),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn nv12_colors() {
+        for (name, [y, u, v], bgr) in COLORS {
+            let buf = [*y, *y, *y, *y, *u, *v];
+            assert_eq!(
+                convert(Colorspace::NV12, &buf, 2, 2, 0),
+                solid(*bgr, 4),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn yuv420_colors() {
+        for (name, [y, u, v], bgr) in COLORS {
+            let buf = [*y, *y, *y, *y, *u, *v];
+            assert_eq!(
+                convert(Colorspace::YUV420, &buf, 2, 2, 0),
+                solid(*bgr, 4),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn rgb_bgr_and_grey() {
+        let rgb = [255, 0, 0, 0, 255, 0, 0, 0, 255, 10, 20, 30];
+        assert_eq!(
+            convert(Colorspace::RGB, &rgb, 2, 2, 0),
+            vec![0, 0, 255, 0, 255, 0, 255, 0, 0, 30, 20, 10]
+        );
+        assert_eq!(convert(Colorspace::BGR, &rgb, 2, 2, 0), rgb.to_vec());
+        assert_eq!(
+            convert(Colorspace::GREY, &[0, 255, 128, 7], 2, 2, 0),
+            vec![0, 0, 0, 255, 255, 255, 128, 128, 128, 7, 7, 7]
+        );
+    }
+
+    #[test]
+    fn out_of_range_yuv_clamps() {
+        assert_eq!(
+            convert(Colorspace::YUYV, &[255, 255, 255, 255], 2, 1, 0),
+            vec![255, 121, 255, 255, 121, 255]
+        );
+        assert_eq!(
+            convert(Colorspace::YUYV, &[0, 0, 0, 0], 2, 1, 0),
+            vec![0, 135, 0, 0, 135, 0]
+        );
+    }
+
+    /// Each 2x2 block of a 4x4 frame has its own chroma
+    #[test]
+    fn chroma_blocks() {
+        let colors: Vec<_> = COLORS[1..].iter().collect();
+        let y_plane: Vec<u8> = (0..16)
+            .map(|i| colors[(i / 8) * 2 + (i % 4) / 2].1[0])
+            .collect();
+        let expected: Vec<u8> = (0..16)
+            .flat_map(|i| colors[(i / 8) * 2 + (i % 4) / 2].2.to_vec())
+            .collect();
+
+        let mut nv12 = y_plane.clone();
+        nv12.extend(colors.iter().flat_map(|c| vec![c.1[1], c.1[2]]));
+        assert_eq!(convert(Colorspace::NV12, &nv12, 4, 4, 0), expected);
+
+        let mut yuv420 = y_plane;
+        yuv420.extend(colors.iter().map(|c| c.1[1]));
+        yuv420.extend(colors.iter().map(|c| c.1[2]));
+        assert_eq!(convert(Colorspace::YUV420, &yuv420, 4, 4, 0), expected);
+    }
+
+    /// The last row of an odd height frame has chroma to itself
+    #[test]
+    fn odd_height() {
+        let (_, [y1, u1, v1], bgr1) = COLORS[2];
+        let (_, [y2, u2, v2], bgr2) = COLORS[4];
+        let mut expected = solid(bgr1, 4);
+        expected.extend(solid(bgr2, 2));
+
+        let nv12 = [y1, y1, y1, y1, y2, y2, u1, v1, u2, v2];
+        assert_eq!(convert(Colorspace::NV12, &nv12, 2, 3, 0), expected);
+        let yuv420 = [y1, y1, y1, y1, y2, y2, u1, u2, v1, v2];
+        assert_eq!(convert(Colorspace::YUV420, &yuv420, 2, 3, 0), expected);
+
+        let yuyv = [y1, u1, y1, v1, y1, u1, y1, v1, y2, u2, y2, v2];
+        assert_eq!(convert(Colorspace::YUYV, &yuyv, 2, 3, 0), expected);
+    }
+
+    /// Padding at the end of each row is skipped, not read as pixels
+    #[test]
+    fn padded_rows() {
+        let (_, [y, u, v], bgr) = COLORS[3];
+        const PAD: u8 = 0xAA;
+
+        let yuyv = [y, u, y, v, PAD, PAD, y, u, y, v, PAD, PAD];
+        assert_eq!(convert(Colorspace::YUYV, &yuyv, 2, 2, 6), solid(bgr, 4));
+
+        // Chroma rows are padded by half as much as the luma stride:
+        let yuv420 = [y, y, PAD, PAD, y, y, PAD, PAD, u, PAD, v, PAD];
+        assert_eq!(convert(Colorspace::YUV420, &yuv420, 2, 2, 4), solid(bgr, 4));
+
+        let nv12 = [y, y, PAD, PAD, y, y, PAD, PAD, u, v, PAD, PAD];
+        assert_eq!(convert(Colorspace::NV12, &nv12, 2, 2, 4), solid(bgr, 4));
+
+        let grey = [y, y, PAD, y, y, PAD];
+        assert_eq!(
+            convert(Colorspace::GREY, &grey, 2, 2, 3),
+            solid([y, y, y], 4)
+        );
+    }
+
+    #[test]
+    fn short_buffers_are_rejected() {
+        assert!(Colorspace::YUYV.to_bgr_mat(&[0; 7], 2, 2, 0).is_err());
+        assert!(Colorspace::NV12.to_bgr_mat(&[0; 5], 2, 2, 0).is_err());
+        assert!(Colorspace::YUV420.to_bgr_mat(&[0; 9], 2, 3, 0).is_err());
+        assert!(Colorspace::RGB.to_bgr_mat(&[0; 11], 2, 2, 0).is_err());
+        assert!(Colorspace::GREY.to_bgr_mat(&[0; 4], 2, 2, 3).is_err());
+        assert!(Colorspace::GREY.to_bgr_mat(&[0; 4], 0, 2, 0).is_err());
+    }
+
+    #[test]
+    fn mjpeg() {
+        let bgr = [40.0, 200.0, 90.0];
+        let img = Mat::new_rows_cols_with_default(
+            16,
+            16,
+            CV_8UC3,
+            Scalar::new(bgr[0], bgr[1], bgr[2], 0.0),
+        )
+        .unwrap();
+        let jpeg = Frame::new(img, Colorspace::BGR, None)
+            .to_jpeg(None, Some(100))
+            .unwrap();
+
+        let decoded = convert(Colorspace::MJPG, &jpeg, 16, 16, 0);
+        for px in decoded.chunks_exact(3) {
+            for (got, want) in px.iter().zip(&bgr) {
+                assert!((*got as f64 - want).abs() <= 2.0, "{:?}", px);
+            }
+        }
+        assert!(Colorspace::MJPG.to_bgr_mat(&jpeg, 8, 8, 0).is_err());
+        assert!(Colorspace::MJPG.to_bgr_mat(&jpeg[..10], 16, 16, 0).is_err());
+    }
+}
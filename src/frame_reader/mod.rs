@@ -4,9 +4,13 @@ mod v4l;
 
 pub use self::rtsp::RTSPFrameReader;
 pub use self::v4l::V4LFrameReader;
+use crate::audio::AudioSender;
 use crate::config::CameraConfig;
 use crate::frame::Frame;
-use anyhow::Result;
+use crate::privacy::PrivacyMask;
+use anyhow::{anyhow, Result};
+use log::warn;
+use std::sync::atomic::AtomicBool;
 use std::sync::{mpsc::Sender, Arc};
 use tokio::sync::mpsc::Sender as AsyncSender;
 
@@ -16,27 +20,35 @@ pub trait FrameReader {
         senders: Vec<Sender<Arc<Frame>>>,
         web_tx: Option<AsyncSender<Arc<Frame>>>,
         source: Option<&str>,
-    );
+        running: &AtomicBool,
+    ) -> Result<()>;
 }
 
 pub fn start_frame_reader(
     camera: Arc<CameraConfig>,
     senders: Vec<Sender<Arc<Frame>>>,
     web_tx: Option<AsyncSender<Arc<Frame>>>,
+    audio_tx: Option<AudioSender>,
+    running: Arc<AtomicBool>,
 ) -> Result<()> {
     match camera.camera_type.as_str() {
         "rtsp" => {
-            let frame_reader = RTSPFrameReader {};
-            frame_reader.read_frames(senders, web_tx, camera.source.as_deref());
+            let frame_reader = RTSPFrameReader {
+                audio_tx,
+                privacy_mask: PrivacyMask::for_camera(&camera),
+            };
+            frame_reader.read_frames(senders, web_tx, camera.source.as_deref(), &running)
         }
         "v4l" => {
-            let frame_reader = V4LFrameReader {};
-            frame_reader.read_frames(senders, web_tx, camera.source.as_deref());
+            if audio_tx.is_some() {
+                warn!("Audio capture is not supported for v4l cameras");
+            }
+            let frame_reader = V4LFrameReader {
+                config: camera.v4l.clone().unwrap_or_default(),
+                privacy_mask: PrivacyMask::for_camera(&camera),
+            };
+            frame_reader.read_frames(senders, web_tx, camera.source.as_deref(), &running)
         }
-        _ => {
-            panic!("Unknown camera type");
-        }
-    };
-
-    Ok(())
+        t => Err(anyhow!("Unknown camera type {}", t)),
+    }
 }
@@ -1,24 +1,45 @@
 extern crate ffmpeg_next as ffmpeg;
 use super::FrameReader;
-use anyhow::Result;
-use ffmpeg::format::{input, Pixel};
+use crate::audio::{AudioFrame, AudioSender, AUDIO_SAMPLE_RATE};
+use anyhow::{anyhow, Result};
+use ffmpeg::format::{input_with_dictionary, sample, Pixel, Sample};
 use ffmpeg::media::Type;
+use ffmpeg::software::resampling;
 use ffmpeg::software::scaling::{context::Context, flag::Flags};
+use ffmpeg::util::frame::audio::Audio;
 use ffmpeg::util::frame::video::Video;
+use ffmpeg::{ChannelLayout, Dictionary};
 use ffmpeg_next::codec::packet::packet::Packet;
 use log::{debug, error, warn};
-use opencv::core::Mat_AUTO_STEP;
 use opencv::core::CV_8UC3;
 use opencv::prelude::*;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc::channel, mpsc::Receiver, mpsc::Sender, Arc};
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::mpsc::Sender as AsyncSender;
 
 use crate::frame::{Colorspace, Frame};
+use crate::privacy::PrivacyMask;
 
 use std::thread;
 
-pub struct RTSPFrameReader {}
+/// How long a camera can go without sending anything before reads give up,
+/// so a stalled camera can't keep its reader from stopping
+const READ_TIMEOUT_SECS: u64 = 10;
+/// A lost stream is reopened after this, doubling with each failure in a
+/// row up to `MAX_RECONNECT_DELAY`
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// Failures in a row before the camera is given up on
+const MAX_RECONNECT_ATTEMPTS: u32 = 8;
+/// How often a reconnect delay checks whether the reader has been stopped
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct RTSPFrameReader {
+    pub audio_tx: Option<AudioSender>,
+    pub privacy_mask: Option<PrivacyMask>,
+}
 
 struct DecoderThread {
     packet_rx: Receiver<Packet>,
@@ -26,6 +47,9 @@ struct DecoderThread {
     senders: Vec<Sender<Arc<Frame>>>,
     web_tx: Option<AsyncSender<Arc<Frame>>>,
     scaler: Context,
+    /// scaler output, reused for every frame
+    bgr_frame: Video,
+    privacy_mask: Option<PrivacyMask>,
 }
 
 impl DecoderThread {
@@ -34,7 +58,8 @@ impl DecoderThread {
         decoder: ffmpeg::decoder::Video,
         senders: Vec<Sender<Arc<Frame>>>,
         web_tx: Option<AsyncSender<Arc<Frame>>>,
-    ) -> Self {
+        privacy_mask: Option<PrivacyMask>,
+    ) -> Result<Self> {
         let scaler = Context::get(
             decoder.format(),
             decoder.width(),
@@ -43,19 +68,22 @@ impl DecoderThread {
             decoder.width(),
             decoder.height(),
             Flags::BILINEAR,
-        )
-        .unwrap();
+        )?;
+        let bgr_frame = Video::new(Pixel::BGR24, decoder.width(), decoder.height());
 
-        Self {
+        Ok(Self {
             packet_rx,
             decoder,
             senders,
             web_tx,
             scaler,
-        }
+            bgr_frame,
+            privacy_mask,
+        })
     }
 
-    pub fn start(&mut self) {
+    /// Decode until the reader or the frame receivers go away
+    pub fn start(&mut self) -> Result<()> {
         debug!(
             "Original dimensions are {} x {}",
             self.decoder.width(),
@@ -65,9 +93,10 @@ impl DecoderThread {
         loop {
             let packet = match self.packet_rx.recv() {
                 Ok(packet) => packet,
-                Err(error) => {
-                    error!("Failed to receive packet: {:?}", error);
-                    continue;
+                // The reader has stopped:
+                Err(_) => {
+                    debug!("Packet channel closed -- stopping decoder");
+                    return Ok(());
                 }
             };
             match self.decoder.send_packet(&packet) {
@@ -78,79 +107,286 @@ impl DecoderThread {
                 }
             }
 
-            self.receive_and_process_decoded_frames().unwrap();
+            if !self.receive_and_process_decoded_frames()? {
+                debug!("Frame receivers are gone -- stopping decoder");
+                return Ok(());
+            }
         }
     }
 
-    fn receive_and_process_decoded_frames(&mut self) -> Result<()> {
+    /// Returns false once the frames have nowhere to go
+    fn receive_and_process_decoded_frames(&mut self) -> Result<bool> {
         let mut decoded = Video::empty();
 
         while self.decoder.receive_frame(&mut decoded).is_ok() {
-            let mut rgb_frame = Video::empty();
-
-            self.scaler.run(&decoded, &mut rgb_frame)?;
+            self.scaler.run(&decoded, &mut self.bgr_frame)?;
 
-            let rgb_frame = rgb_frame.data(0);
             let img = unsafe {
                 Mat::new_rows_cols_with_data(
-                    self.decoder.height() as _,
-                    self.decoder.width() as _,
+                    self.bgr_frame.height() as _,
+                    self.bgr_frame.width() as _,
                     CV_8UC3,
                     // Note: this data is not copied:
-                    rgb_frame.as_ptr() as *mut std::os::raw::c_void,
-                    Mat_AUTO_STEP,
+                    self.bgr_frame.data(0).as_ptr() as *mut std::os::raw::c_void,
+                    // Rows may be padded:
+                    self.bgr_frame.stride(0),
                 )?
             };
-            let frame = Frame::new(img.clone(), Colorspace::BGR, Some(SystemTime::now().into()));
+            // The one copy, as `bgr_frame` is overwritten by the next frame:
+            let mut frame =
+                Frame::new(img.clone(), Colorspace::BGR, Some(SystemTime::now().into()));
+            // Before anything else gets the frame:
+            if let Some(mask) = &self.privacy_mask {
+                mask.apply(&mut frame);
+            }
             let a = Arc::new(frame);
-            for s in &self.senders {
-                s.send(Arc::clone(&a))?;
+            // Receivers go away as the pipeline stops:
+            if self.senders.iter().any(|s| s.send(Arc::clone(&a)).is_err()) {
+                return Ok(false);
             }
             if let Some(s) = &self.web_tx {
-                s.blocking_send(Arc::clone(&a))?;
+                if s.blocking_send(Arc::clone(&a)).is_err() {
+                    return Ok(false);
+                }
             }
         }
+        Ok(true)
+    }
+}
+
+/// Decodes camera audio and resamples it to mono `AUDIO_SAMPLE_RATE` PCM
+struct AudioDecoderThread {
+    packet_rx: Receiver<Packet>,
+    decoder: ffmpeg::decoder::Audio,
+    audio_tx: AudioSender,
+    resampler: resampling::Context,
+}
+
+impl AudioDecoderThread {
+    pub fn new(
+        packet_rx: Receiver<Packet>,
+        mut decoder: ffmpeg::decoder::Audio,
+        audio_tx: AudioSender,
+    ) -> Result<Self> {
+        // G.711 streams usually don't specify a layout:
+        if decoder.channel_layout().is_empty() {
+            decoder.set_channel_layout(ChannelLayout::default(decoder.channels() as i32));
+        }
+        let resampler = decoder.resampler(
+            Sample::I16(sample::Type::Packed),
+            ChannelLayout::MONO,
+            AUDIO_SAMPLE_RATE,
+        )?;
+
+        Ok(Self {
+            packet_rx,
+            decoder,
+            audio_tx,
+            resampler,
+        })
+    }
+
+    pub fn start(&mut self) {
+        debug!(
+            "Original audio is {:?} at {} Hz, {} channels",
+            self.decoder.id(),
+            self.decoder.rate(),
+            self.decoder.channels()
+        );
+
+        loop {
+            let packet = match self.packet_rx.recv() {
+                Ok(packet) => packet,
+                Err(error) => {
+                    error!("Failed to receive audio packet: {:?}", error);
+                    return;
+                }
+            };
+            if let Err(e) = self.decoder.send_packet(&packet) {
+                warn!("Error decoding audio packet: {} -- dropping", e);
+                continue;
+            }
+
+            if let Err(e) = self.receive_and_process_decoded_frames() {
+                error!("Failed to process audio: {}", e);
+            }
+        }
+    }
+
+    fn receive_and_process_decoded_frames(&mut self) -> Result<()> {
+        let mut decoded = Audio::empty();
+
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let mut resampled = Audio::empty();
+            self.resampler.run(&decoded, &mut resampled)?;
+
+            let n = resampled.samples();
+            let samples = resampled.data(0)[..n * 2]
+                .chunks_exact(2)
+                .map(|b| i16::from_ne_bytes([b[0], b[1]]))
+                .collect();
+
+            // Only fails when nothing is listening:
+            let _ = self
+                .audio_tx
+                .send(Arc::new(AudioFrame::new(samples, SystemTime::now().into())));
+        }
         Ok(())
     }
 }
 
-impl FrameReader for RTSPFrameReader {
-    fn read_frames(
+/// How reading a stream stopped
+enum StreamEnd {
+    /// `running` was cleared or the frames have nowhere to go
+    Stopped,
+    /// the camera stopped sending, after this many video packets
+    Lost(usize),
+}
+
+impl RTSPFrameReader {
+    /// Open `source` and read it until it's lost or reading is stopped
+    fn read_stream(
         &self,
         senders: Vec<Sender<Arc<Frame>>>,
         web_tx: Option<AsyncSender<Arc<Frame>>>,
-        source: Option<&str>,
-    ) {
+        source: &str,
+        running: &AtomicBool,
+    ) -> Result<StreamEnd> {
+        // Both in microseconds; `stimeout` is the RTSP demuxer's own:
+        let timeout = (READ_TIMEOUT_SECS * 1_000_000).to_string();
+        let mut opts = Dictionary::new();
+        opts.set("rw_timeout", &timeout);
+        opts.set("stimeout", &timeout);
         // AVFormatContext
-        let mut ictx = input(&source.unwrap()).unwrap();
+        let mut ictx = input_with_dictionary(source, opts)?;
         // Stream (Context -> AVFormatContext)
-        let input = ictx.streams().best(Type::Video).unwrap();
+        let input = ictx
+            .streams()
+            .best(Type::Video)
+            .ok_or(anyhow!("No video stream in {}", source))?;
         let video_stream_index = input.index();
         let ff_decoder = input
             // AVCodecContext
             .codec()
             // Docoder(AVCodecContext)
             .decoder()
-            .video()
-            .unwrap();
+            .video()?;
 
         let (packet_tx, packet_rx) = channel();
-        let _decoder_thread = thread::spawn(move || -> () {
-            let mut dec = DecoderThread::new(packet_rx, ff_decoder, senders, web_tx);
-            dec.start();
+        let privacy_mask = self.privacy_mask.clone();
+        let decoder_thread = thread::spawn(move || -> Result<()> {
+            DecoderThread::new(packet_rx, ff_decoder, senders, web_tx, privacy_mask)?.start()
         });
 
-        loop {
-            for (stream, packet) in ictx.packets() {
-                if stream.index() == video_stream_index {
-                    if let Err(e) = packet_tx.send(packet) {
-                        error!("Packet send failed: {}", e);
-                        continue;
+        let audio_packet_tx = match (&self.audio_tx, ictx.streams().best(Type::Audio)) {
+            (Some(audio_tx), Some(audio)) => {
+                let audio_stream_index = audio.index();
+                let ff_decoder = audio.codec().decoder().audio()?;
+                let (audio_packet_tx, audio_packet_rx) = channel();
+                let audio_tx = audio_tx.clone();
+                thread::spawn(move || -> () {
+                    match AudioDecoderThread::new(audio_packet_rx, ff_decoder, audio_tx) {
+                        Ok(mut dec) => dec.start(),
+                        Err(e) => error!("Failed to start audio decoder: {}", e),
+                    }
+                });
+                Some((audio_stream_index, audio_packet_tx))
+            }
+            (Some(_), None) => {
+                warn!("Audio enabled but source has no audio stream");
+                None
+            }
+            _ => None,
+        };
+
+        let mut video_packets = 0;
+        let mut decoder_stopped = false;
+        // Ends on a read error, which includes timing out:
+        for (stream, packet) in ictx.packets() {
+            if !running.load(Ordering::Relaxed) {
+                break;
+            }
+            if stream.index() == video_stream_index {
+                // Only fails once the decoder has stopped:
+                if packet_tx.send(packet).is_err() {
+                    decoder_stopped = true;
+                    break;
+                }
+                video_packets += 1;
+            } else if let Some((audio_stream_index, audio_packet_tx)) = &audio_packet_tx {
+                if stream.index() == *audio_stream_index {
+                    if let Err(e) = audio_packet_tx.send(packet) {
+                        error!("Audio packet send failed: {}", e);
+                    }
+                }
+            }
+        }
+
+        // The decoder finishes what it has once the channel closes:
+        drop(packet_tx);
+        match decoder_thread.join() {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => return Err(e),
+            Err(panic) => panic::resume_unwind(panic),
+        }
+        // Without an error, the decoder only stops early once the frame
+        // receivers are gone:
+        if decoder_stopped || !running.load(Ordering::Relaxed) {
+            return Ok(StreamEnd::Stopped);
+        }
+        Ok(StreamEnd::Lost(video_packets))
+    }
+}
+
+impl FrameReader for RTSPFrameReader {
+    fn read_frames(
+        &self,
+        senders: Vec<Sender<Arc<Frame>>>,
+        web_tx: Option<AsyncSender<Arc<Frame>>>,
+        source: Option<&str>,
+        running: &AtomicBool,
+    ) -> Result<()> {
+        let source = source.ok_or(anyhow!("No source given"))?;
+        // Reconnect attempts since the stream last delivered anything:
+        let mut failures = 0;
+        while running.load(Ordering::Relaxed) {
+            match self.read_stream(senders.clone(), web_tx.clone(), source, running) {
+                Ok(StreamEnd::Stopped) => break,
+                Ok(StreamEnd::Lost(packets)) => {
+                    if packets > 0 {
+                        failures = 0;
+                    }
+                    failures += 1;
+                    if failures > MAX_RECONNECT_ATTEMPTS {
+                        return Err(anyhow!("{} keeps dropping the connection", source));
+                    }
+                    warn!("Lost {} -- reconnecting", source);
+                }
+                Err(e) => {
+                    failures += 1;
+                    if failures > MAX_RECONNECT_ATTEMPTS {
+                        return Err(e);
                     }
+                    warn!("Failed to read {}: {} -- retrying", source, e);
                 }
             }
-            warn!("Input packet iterator returned None -- restarting");
+            wait(reconnect_delay(failures), running);
         }
+        debug!("Stopped reading {}", source);
+        Ok(())
+    }
+}
+
+fn reconnect_delay(failures: u32) -> Duration {
+    let delay = RECONNECT_DELAY * 2u32.pow(failures.saturating_sub(1).min(16));
+    delay.min(MAX_RECONNECT_DELAY)
+}
+
+/// Sleep for `duration`, returning early if `running` is cleared
+fn wait(duration: Duration, running: &AtomicBool) {
+    let start = Instant::now();
+    while running.load(Ordering::Relaxed) && start.elapsed() < duration {
+        thread::sleep(STOP_POLL_INTERVAL.min(duration));
     }
 }
 
@@ -161,4 +397,18 @@ fn save_file(frame: &Video, index: usize) -> std::result::Result<(), std::io::Er
     file.write_all(frame.data(0))?;
     Ok(())
 }
-*/
\ No newline at end of file
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_backs_off() {
+        assert_eq!(reconnect_delay(1), RECONNECT_DELAY);
+        assert_eq!(reconnect_delay(2), RECONNECT_DELAY * 2);
+        assert_eq!(reconnect_delay(3), RECONNECT_DELAY * 4);
+        assert_eq!(reconnect_delay(10), MAX_RECONNECT_DELAY);
+        assert_eq!(reconnect_delay(u32::MAX), MAX_RECONNECT_DELAY);
+    }
+}
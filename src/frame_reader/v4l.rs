@@ -1,19 +1,96 @@
 use super::FrameReader;
-use log::{debug, info};
-use opencv::core::Mat_AUTO_STEP;
-use opencv::core::CV_8UC3;
-use opencv::prelude::*;
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc::Sender, Arc};
 use std::time::SystemTime;
 use tokio::sync::mpsc::Sender as AsyncSender;
 use v4l::buffer::Type;
 use v4l::io::traits::CaptureStream;
 use v4l::prelude::*;
+use v4l::video::capture::Parameters;
 use v4l::video::Capture;
+use v4l::{Format, FourCC};
 
+use crate::config::V4lConfig;
 use crate::frame::{Colorspace, Frame};
+use crate::privacy::PrivacyMask;
 
-pub struct V4LFrameReader {}
+pub struct V4LFrameReader {
+    pub config: V4lConfig,
+    pub privacy_mask: Option<PrivacyMask>,
+}
+
+/// How frames in `fourcc` are converted, `None` if they can't be
+fn colorspace(fourcc: &FourCC) -> Option<Colorspace> {
+    fourcc.str().ok().and_then(|f| Colorspace::str(f).ok())
+}
+
+/// Ask the device for the configured format, size and frame rate, returning
+/// the format it settled on. Without a configured pixel format the current
+/// one is kept if it can be converted, otherwise the first that can be
+fn negotiate(dev: &Device, config: &V4lConfig) -> Result<Format> {
+    let mut format = dev.format()?;
+    let fourcc = match &config.pixel_format {
+        Some(f) => {
+            let bytes: [u8; 4] = f
+                .as_bytes()
+                .try_into()
+                .map_err(|_| anyhow!("invalid pixel_format '{}'", f))?;
+            FourCC::new(&bytes)
+        }
+        None if colorspace(&format.fourcc).is_some() => format.fourcc,
+        None => {
+            let offered = dev.enum_formats()?;
+            offered
+                .iter()
+                .map(|d| d.fourcc)
+                .find(|f| colorspace(f).is_some())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "no supported pixel format, device offers {:?}",
+                        offered.iter().map(|d| d.fourcc).collect::<Vec<_>>()
+                    )
+                })?
+        }
+    };
+
+    let wanted = Format::new(
+        config.width.unwrap_or(format.width),
+        config.height.unwrap_or(format.height),
+        fourcc,
+    );
+    if wanted.fourcc != format.fourcc
+        || wanted.width != format.width
+        || wanted.height != format.height
+    {
+        format = dev.set_format(&wanted)?;
+        if format.fourcc != wanted.fourcc
+            || format.width != wanted.width
+            || format.height != wanted.height
+        {
+            warn!(
+                "Asked for {} {}x{}, device chose {} {}x{}",
+                wanted.fourcc,
+                wanted.width,
+                wanted.height,
+                format.fourcc,
+                format.width,
+                format.height
+            );
+        }
+    }
+    if colorspace(&format.fourcc).is_none() {
+        return Err(anyhow!("unsupported pixel format {}", format.fourcc));
+    }
+
+    if let Some(fps) = config.fps {
+        let params = dev.set_params(&Parameters::with_fps(fps))?;
+        debug!("Frame interval: {:?}", params.interval);
+    }
+    Ok(format)
+}
 
 impl FrameReader for V4LFrameReader {
     fn read_frames(
@@ -21,7 +98,8 @@ impl FrameReader for V4LFrameReader {
         senders: Vec<Sender<Arc<Frame>>>,
         web_tx: Option<AsyncSender<Arc<Frame>>>,
         source: Option<&str>,
-    ) {
+        running: &AtomicBool,
+    ) -> Result<()> {
         if senders.len() == 0 {
             panic!("No frame recipients specified");
         }
@@ -36,47 +114,63 @@ impl FrameReader for V4LFrameReader {
 
         // Allocate 4 buffers by default
         let buffer_count = 4;
-        let mut dev = Device::with_path(path).unwrap();
-        let format = Device::with_path(path).unwrap().format().unwrap();
-        let fourcc = format.fourcc;
-        debug!("fourcc: {}", fourcc);
+        let mut dev =
+            Device::with_path(path).map_err(|e| anyhow!("Failed to open {}: {}", path, e))?;
+        let format = negotiate(&dev, &self.config)
+            .map_err(|e| anyhow!("Failed to set up {}: {}", path, e))?;
+        // Checked by `negotiate`:
+        let pixel_format = colorspace(&format.fourcc).unwrap();
+        debug!("fourcc: {}", format.fourcc);
         debug!("width: {}", format.width);
         debug!("height: {}", format.height);
-        let mut stream =
-            MmapStream::with_buffers(&mut dev, Type::VideoCapture, buffer_count).unwrap();
+        debug!("stride: {}", format.stride);
+        let mut stream = MmapStream::with_buffers(&mut dev, Type::VideoCapture, buffer_count)
+            .map_err(|e| anyhow!("Failed to start capture on {}: {}", path, e))?;
 
-        loop {
-            let (buf, _meta) = stream.next().unwrap();
+        while running.load(Ordering::Relaxed) {
+            let (buf, meta) = stream
+                .next()
+                .map_err(|e| anyhow!("Failed to read frame from {}: {}", path, e))?;
+            // Compressed frames don't fill the buffer:
+            let buf = &buf[..(meta.bytesused as usize).min(buf.len())];
             if buf.len() == 0 {
                 continue;
             }
 
-            let mut bgr_buf = Colorspace::str(&fourcc.str().unwrap())
-                .unwrap()
-                .convert_buf(buf.to_vec(), Colorspace::BGR);
-
-            let img = unsafe {
-                Mat::new_rows_cols_with_data(
-                    format.height as _,
-                    format.width as _,
-                    CV_8UC3,
-                    bgr_buf.as_mut_ptr() as *mut std::os::raw::c_void,
-                    Mat_AUTO_STEP,
-                )
-                .unwrap()
+            // Straight from the capture buffer into the frame's own image:
+            let img = match pixel_format.to_bgr_mat(
+                buf,
+                format.width,
+                format.height,
+                format.stride as usize,
+            ) {
+                Ok(img) => img,
+                Err(e) => {
+                    warn!("Dropping frame: {}", e);
+                    continue;
+                }
             };
-            let frame = Frame::new(img.clone(), Colorspace::BGR, Some(SystemTime::now().into()));
+            let mut frame = Frame::new(img, Colorspace::BGR, Some(SystemTime::now().into()));
             if frame.width() == 0 {
                 continue;
             }
+            if let Some(mask) = &self.privacy_mask {
+                mask.apply(&mut frame);
+            }
 
             let a = Arc::new(frame);
-            for s in &senders {
-                s.send(Arc::clone(&a)).unwrap();
+            // Receivers go away as the pipeline stops:
+            if senders.iter().any(|s| s.send(Arc::clone(&a)).is_err()) {
+                debug!("Frame receiver for {} is gone, stopping", path);
+                break;
             }
             if let Some(s) = &web_tx {
-                s.blocking_send(Arc::clone(&a)).unwrap();
+                if s.blocking_send(Arc::clone(&a)).is_err() {
+                    debug!("Web frame receiver for {} is gone, stopping", path);
+                    break;
+                }
             }
         }
+        Ok(())
     }
 }
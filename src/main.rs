@@ -1,33 +1,105 @@
+mod arming;
+mod audio;
+mod auth;
+mod camera_state;
+mod cli;
 mod config;
+mod convert;
+mod event;
 mod file_source;
 mod frame;
 mod frame_reader;
 mod logger;
 mod motion_detection;
+mod mqtt;
+mod notify;
+mod onvif;
+mod pipeline;
+mod privacy;
+mod rtsp_server;
+mod talkback;
 mod upload;
 mod video;
 mod web;
 
-use self::motion_detection::MotionDetector;
-use crate::frame::Frame;
 pub(crate) use config::FileSourceType;
 use log::debug;
+use pipeline::Pipelines;
+use std::env;
+use std::path::PathBuf;
 use std::process;
-use std::sync::{mpsc::channel, Arc};
+use std::sync::{mpsc::channel, Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
-use tokio::sync::mpsc::{channel as async_channel, Receiver as AsyncReceiver};
+use tokio::sync::mpsc::unbounded_channel;
 
 #[macro_use]
 extern crate rocket;
+
 fn main() -> () {
-    logger::init().unwrap();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let config_path = match cli::take_config_path(&mut args) {
+        Ok(path) => path.map(PathBuf::from),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    if let Some(path) = &config_path {
+        config::set_config_path(path.clone());
+    }
+    match args.first().map(String::as_str) {
+        Some("discover") => process::exit(cli::discover(&args[1..])),
+        Some("check-config") => process::exit(cli::check_config(config_path, &args[1..])),
+        Some("hash-password") => process::exit(cli::hash_password(&args[1..])),
+        Some("new-token") => process::exit(cli::new_token()),
+        _ => (),
+    }
 
-    let config = config::load_config(None);
+    let config = match config::init() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    logger::init().unwrap();
     debug!("Config: {:?}", config);
 
     let display_enabled = config.display.enabled.unwrap_or(true);
-    let (mut threads, web_rx_vec) = launch(config.cameras.clone(), display_enabled);
+    let annotated_enabled = config.rtsp_server.enabled.unwrap_or(false)
+        && config.rtsp_server.annotated.unwrap_or(false);
+    let (feed_tx, feed_rx) = if display_enabled {
+        let (t, r) = unbounded_channel();
+        (Some(t), Some(r))
+    } else {
+        (None, None)
+    };
+    let (mqtt_client, mqtt_eventloop) = match &config.mqtt {
+        Some(c) => {
+            let (client, eventloop) = mqtt::client(c);
+            (Some(client), Some(eventloop))
+        }
+        None => (None, None),
+    };
+    let notices = notify::start(mqtt_client.clone());
+    let pipelines = Arc::new(Mutex::new(Pipelines::start(
+        &config,
+        display_enabled && annotated_enabled,
+        feed_tx,
+        notices,
+    )));
+    let mut threads = vec![pipeline::watch_config(Arc::clone(&pipelines))];
+    if let (Some(mqtt_config), Some(client), Some(eventloop)) =
+        (&config.mqtt, mqtt_client, mqtt_eventloop)
+    {
+        threads.push(mqtt::start(
+            mqtt_config.clone(),
+            client,
+            eventloop,
+            Arc::clone(&pipelines),
+        ));
+    }
 
     let (tx, rx) = channel();
     let ctrlc_thread = thread::spawn(move || -> () {
@@ -38,61 +110,15 @@ fn main() -> () {
     });
     threads.push(ctrlc_thread);
 
-    if display_enabled {
+    if let Some(feed_rx) = feed_rx {
         tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .unwrap()
-            .block_on(web::start(web_rx_vec.unwrap(), config.cameras.clone()));
+            .block_on(web::start(feed_rx, pipelines));
     }
 
     threads.into_iter().for_each(|t: JoinHandle<()>| {
         t.join().unwrap();
     });
 }
-
-fn launch(
-    cameras: Vec<config::CameraConfig>,
-    display_enabled: bool,
-) -> (Vec<JoinHandle<()>>, Option<Vec<AsyncReceiver<Arc<Frame>>>>) {
-    let mut threads = Vec::new();
-    let mut web_rx_vec = if display_enabled {
-        Some(Vec::new())
-    } else {
-        None
-    };
-
-    cameras
-        .into_iter()
-        .for_each(|camera: config::CameraConfig| {
-            let camera = Arc::new(camera);
-            let (motion_tx, motion_rx) = channel::<Arc<Frame>>();
-            let (web_tx, web_rx) = if display_enabled {
-                let (t, r) = async_channel::<Arc<Frame>>(1000);
-                (Some(t), Some(r))
-            } else {
-                (None, None)
-            };
-
-            let tx_vec = vec![motion_tx];
-
-            let cam = Arc::clone(&camera);
-            let frame_reader_thread = thread::spawn(move || -> () {
-                frame_reader::start_frame_reader(cam, tx_vec, web_tx).unwrap();
-            });
-
-            let cam = Arc::clone(&camera);
-            let motion_detector_thread = thread::spawn(move || -> () {
-                let mut md = MotionDetector::new(cam, motion_rx);
-                md.start();
-            });
-
-            threads.push(frame_reader_thread);
-            threads.push(motion_detector_thread);
-            if let Some(v) = &mut web_rx_vec {
-                v.push(web_rx.unwrap());
-            }
-        });
-
-    (threads, web_rx_vec)
-}
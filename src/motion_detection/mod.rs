@@ -13,15 +13,48 @@ use opencv::{
 };
 use std::error::Error;
 use std::sync::{mpsc::Receiver, mpsc::Sender, Arc};
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender as AsyncSender;
 
+use crate::arming;
+use crate::audio::{AudioReceiver, AudioSender};
+use crate::camera_state::CameraControl;
 use crate::config::load_config;
-use crate::config::CameraConfig;
+use crate::config::{ArmAction, CameraConfig};
+use crate::event::{EventSource, EventTrigger, TriggerReceiver, TriggerState};
 use crate::frame::{Frame, VideoFrame};
+use crate::notify::{self, EventNotice, EventStage, NoticeSender, Zone};
+use crate::onvif::{self, MotionEvent};
+use crate::privacy::PrivacyMask;
 use crate::video;
 
+const DEFAULT_MAX_EVENT_DURATION_SECS: u64 = 600;
+
 pub struct MotionDetector {
     receiver: Receiver<Arc<Frame>>,
     video_tx: Option<Sender<VideoFrame>>,
+    annotated_tx: Option<AsyncSender<Arc<Frame>>>,
+    audio_tx: Option<AudioSender>,
+    audio_rx: Option<AudioReceiver>,
+    audio_threshold: Option<f64>,
+    preset_tx: Option<Sender<MotionEvent>>,
+    trigger_rx: TriggerReceiver,
+    /// recording and detection switches from the API
+    control: Arc<CameraControl>,
+    notice_tx: NoticeSender,
+    /// what the arming rules decided for the current event
+    event_action: ArmAction,
+    /// external triggers that have started but not yet stopped, and when
+    held_triggers: Vec<(EventSource, DateTime<Utc>)>,
+    /// held triggers are let go after this, in case their stop never comes
+    max_event_duration: Duration,
+    /// sources that have triggered during the current event
+    event_sources: Vec<EventSource>,
+    /// everywhere motion was seen during the current event
+    event_zone: Option<Zone>,
+    /// triggers to attach to the next frame sent to the writer
+    pending_triggers: Vec<EventTrigger>,
     in_motion: bool,
     in_motion_window: bool,
     last_motion_time: DateTime<Utc>,
@@ -29,6 +62,8 @@ pub struct MotionDetector {
     camera: Arc<CameraConfig>,
     draw_contours: bool,
     draw_rectangles: bool,
+    /// regions never counted as motion
+    privacy_mask: Option<PrivacyMask>,
 }
 
 fn absdiff(img1: &Mat, img2: &Mat) -> Result<Mat, Box<dyn Error>> {
@@ -70,11 +105,43 @@ fn find_contours(img: &Mat) -> Result<VectorOfMat, Box<dyn Error>> {
 }
 
 impl MotionDetector {
-    pub fn new(camera: Arc<CameraConfig>, receiver: Receiver<Arc<Frame>>) -> Self {
+    pub fn new(
+        camera: Arc<CameraConfig>,
+        receiver: Receiver<Arc<Frame>>,
+        annotated_tx: Option<AsyncSender<Arc<Frame>>>,
+        audio_tx: Option<AudioSender>,
+        trigger_rx: TriggerReceiver,
+        control: Arc<CameraControl>,
+        notice_tx: NoticeSender,
+    ) -> Self {
         let cfg = load_config(None);
+        let audio_threshold = camera.audio_threshold;
+        let audio_rx = match (&audio_tx, audio_threshold) {
+            (Some(tx), Some(_)) => Some(tx.subscribe()),
+            _ => None,
+        };
         Self {
             receiver,
             video_tx: None,
+            annotated_tx,
+            audio_tx,
+            audio_rx,
+            audio_threshold,
+            preset_tx: onvif::start_preset_return(&camera),
+            privacy_mask: PrivacyMask::for_camera(&camera),
+            trigger_rx,
+            control,
+            notice_tx,
+            event_action: ArmAction::Event,
+            held_triggers: Vec::new(),
+            max_event_duration: Duration::seconds(
+                cfg.motion
+                    .max_event_duration
+                    .unwrap_or(DEFAULT_MAX_EVENT_DURATION_SECS) as i64,
+            ),
+            event_sources: Vec::new(),
+            event_zone: None,
+            pending_triggers: Vec::new(),
             in_motion: false,
             in_motion_window: false,
             last_motion_time: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(61, 0), Utc),
@@ -90,17 +157,24 @@ impl MotionDetector {
 
         // Dump first images:
         for _ in 1..20 {
-            self.receiver.recv().unwrap();
+            if self.receiver.recv().is_err() {
+                return;
+            }
         }
 
-        let mut previous = self.receiver.recv().unwrap().downsample().unwrap();
+        let mut previous = match self.receiver.recv() {
+            Ok(frame) => frame.downsample().unwrap(),
+            Err(_) => return,
+        };
 
         loop {
             let org_frame = match self.receiver.recv() {
                 Ok(frame) => frame,
-                Err(error) => {
-                    error!("Failed to receieve frame: {:?}", error);
-                    continue;
+                // The frame reader has stopped, and any recording is closed when
+                // its sender is dropped along with this:
+                Err(_) => {
+                    debug!("Stopping motion detector for {}", self.camera.label);
+                    return;
                 }
             };
             let frame = match org_frame.downsample() {
@@ -111,19 +185,34 @@ impl MotionDetector {
                 }
             };
 
-            let delta = absdiff(&previous.img(), &frame.img()).unwrap();
-            let thresh = threshold(&delta).unwrap();
-            let dilated = dilate(&thresh).unwrap();
-            let contours = find_contours(&dilated);
+            let detecting = self.control.detection();
+            let contours = if detecting {
+                let delta = absdiff(&previous.img(), &frame.img()).unwrap();
+                let mut thresh = threshold(&delta).unwrap();
+                if let Some(mask) = &self.privacy_mask {
+                    if let Err(e) = mask.clear(&mut thresh) {
+                        error!("Failed to exclude privacy masks from detection: {}", e);
+                    }
+                }
+                let dilated = dilate(&thresh).unwrap();
+                find_contours(&dilated)
+            } else {
+                Ok(VectorOfMat::new())
+            };
             if let Err(e) = contours {
                 error!("Failed to find contours: {:?}", e);
                 continue;
             }
             let contours = contours.unwrap();
 
-            let mut contour_frame = Arc::new((*org_frame).clone());
+            // Only copied if something gets drawn on it:
+            let mut contour_frame = Arc::clone(&org_frame);
 
             let mut frame_sent = false;
+            let mut motion_found = false;
+            // Largest moving area, picking the frame a recording's thumbnail shows:
+            let mut motion_area = 0.0;
+            let mut motion_zone: Option<Zone> = None;
             for c in contours.iter() {
                 trace!("Contours: {:?}", c);
                 let area = match imgproc::contour_area(&c, false) {
@@ -134,36 +223,64 @@ impl MotionDetector {
                     }
                 };
 
+                if area > motion_area {
+                    motion_area = area;
+                }
                 if area as i32 >= self.min_threshold_size {
+                    match bounding_rect(&c) {
+                        Ok(r) => {
+                            let zone = Zone::from_pixels(
+                                r.x,
+                                r.y,
+                                r.width,
+                                r.height,
+                                frame.width(),
+                                frame.height(),
+                            );
+                            motion_zone = Some(match motion_zone {
+                                Some(z) => zone.union(z),
+                                None => zone,
+                            });
+                        }
+                        Err(e) => error!("Failed to get bounding rectangle: {}", e),
+                    }
+                }
+                if area as i32 >= self.min_threshold_size && !motion_found {
                     // Motion detected:
                     if self.draw_contours {
-                        match Arc::get_mut(&mut contour_frame) {
-                            Some(f) => draw_contours(f, &contours),
-                            None => warn!("Unable to get contour_frame mutable ref"),
-                        }
+                        draw_contours(Arc::make_mut(&mut contour_frame), &contours);
                     }
                     if self.draw_rectangles {
-                        match Arc::get_mut(&mut contour_frame) {
-                            Some(f) => draw_rectangles(f, &contours),
-                            None => warn!("Unable to get contour_frame mutable ref"),
-                        }
+                        draw_rectangles(Arc::make_mut(&mut contour_frame), &contours);
                     }
-                    // send first frame:
-                    if !self.in_motion {
-                        self.send_frame(VideoFrame {
-                            frame: Arc::clone(&contour_frame),
-                            is_start: true,
-                            is_end: false,
+                    motion_found = true;
+                }
+            }
+
+            // Always drained so they don't back up while there's visual motion:
+            let mut sources = self.external_triggers(frame.time());
+            if self.audio_triggered() && detecting {
+                sources.insert(0, EventSource::Audio);
+            }
+            if motion_found {
+                sources.insert(0, EventSource::Motion);
+            }
+            if !sources.is_empty() {
+                // Arming rules are checked as each event opens:
+                if !self.in_motion_window {
+                    self.event_action = arming::action(&self.camera, frame.time());
+                }
+                if self.event_action == ArmAction::Ignore {
+                    trace!("Ignoring {:?} on {}", sources, self.camera.label);
+                } else {
+                    if let Some(zone) = motion_zone {
+                        self.event_zone = Some(match self.event_zone {
+                            Some(z) => z.union(zone),
+                            None => zone,
                         });
-                        frame_sent = true;
                     }
-                    self.in_motion = true;
-                    self.in_motion_window = true;
-                    self.last_motion_time = frame.time();
-
-                    debug!("Motion detected at {:?}", self.last_motion_time);
-
-                    break;
+                    frame_sent =
+                        self.motion_detected(&contour_frame, frame.time(), sources, motion_area);
                 }
             }
 
@@ -171,37 +288,208 @@ impl MotionDetector {
                 if !check_in_motion_window(frame.time(), self.last_motion_time) {
                     debug!("Motion window closing.");
                     self.in_motion_window = false;
+                    self.notify_preset_return(MotionEvent::Ended);
+                    self.notify(EventStage::End, &contour_frame, frame.time());
                     self.send_frame(VideoFrame {
                         frame: Arc::clone(&contour_frame),
                         is_start: false,
                         is_end: true,
+                        triggers: Vec::new(),
+                        motion: motion_area,
                     });
                     self.video_tx = None;
+                    self.event_sources.clear();
+                    self.event_zone = None;
                 } else {
                     self.send_frame(VideoFrame {
                         frame: Arc::clone(&contour_frame),
                         is_start: false,
                         is_end: false,
+                        triggers: Vec::new(),
+                        motion: motion_area,
                     });
                 }
             }
 
+            if let Some(tx) = &self.annotated_tx {
+                // Live outputs shouldn't hold up detection, so drop frames if they fall behind:
+                if let Err(e) = tx.try_send(Arc::clone(&contour_frame)) {
+                    trace!("Dropping annotated frame: {}", e);
+                }
+            }
+
             previous = frame;
         }
     }
 
-    fn send_frame(&mut self, frame: VideoFrame) -> () {
+    /// Open or extend the motion window, returns whether `contour_frame` was sent
+    fn motion_detected(
+        &mut self,
+        contour_frame: &Arc<Frame>,
+        time: DateTime<Utc>,
+        sources: Vec<EventSource>,
+        motion: f64,
+    ) -> bool {
+        for source in sources {
+            if !self.event_sources.contains(&source) {
+                debug!("Event triggered by {:?}", source);
+                self.event_sources.push(source.clone());
+                self.pending_triggers.push(EventTrigger { source, time });
+            }
+        }
+
+        let mut frame_sent = false;
+        // send first frame:
+        if !self.in_motion {
+            self.send_frame(VideoFrame {
+                frame: Arc::clone(contour_frame),
+                is_start: true,
+                is_end: false,
+                triggers: Vec::new(),
+                motion,
+            });
+            frame_sent = true;
+        }
+        if !self.in_motion_window {
+            self.notify_preset_return(MotionEvent::Started);
+            self.notify(EventStage::Start, contour_frame, time);
+        }
+        self.in_motion = true;
+        self.in_motion_window = true;
+        self.last_motion_time = time;
+
+        debug!("Motion detected at {:?}", self.last_motion_time);
+
+        frame_sent
+    }
+
+    /// Drain pending audio, returns true if any of it was louder than the threshold
+    fn audio_triggered(&mut self) -> bool {
+        let (rx, threshold) = match (&mut self.audio_rx, self.audio_threshold) {
+            (Some(rx), Some(t)) => (rx, t),
+            _ => return false,
+        };
+
+        let mut triggered = false;
+        loop {
+            match rx.try_recv() {
+                Ok(audio) => {
+                    let loudness = audio.loudness();
+                    trace!("Audio loudness {:.1} dBFS", loudness);
+                    if loudness >= threshold {
+                        triggered = true;
+                    }
+                }
+                Err(TryRecvError::Lagged(n)) => {
+                    trace!("Motion detector skipped {} audio frames", n);
+                }
+                Err(_) => break,
+            }
+        }
+        triggered
+    }
+
+    /// Drain external triggers, returns every source currently triggering
+    fn external_triggers(&mut self, now: DateTime<Utc>) -> Vec<EventSource> {
+        let mut sources = Vec::new();
+        while let Ok(t) = self.trigger_rx.try_recv() {
+            trace!("External trigger {:?} from {:?}", t.state, t.source);
+            match t.state {
+                TriggerState::Start => {
+                    // Starting again restarts the clock:
+                    self.held_triggers.retain(|(s, _)| s != &t.source);
+                    self.held_triggers.push((t.source.clone(), now));
+                    sources.push(t.source);
+                }
+                TriggerState::Stop => self.held_triggers.retain(|(s, _)| s != &t.source),
+                TriggerState::Pulse => sources.push(t.source),
+            }
+        }
+
+        let max = self.max_event_duration;
+        let label = &self.camera.label;
+        self.held_triggers.retain(|(s, since)| {
+            let held = now - *since < max;
+            if !held {
+                warn!(
+                    "{:?} on {} started {}s ago without stopping -- releasing it",
+                    s,
+                    label,
+                    max.num_seconds()
+                );
+            }
+            held
+        });
+        for (s, _) in &self.held_triggers {
+            if !sources.contains(s) {
+                sources.push(s.clone());
+            }
+        }
+        sources
+    }
+
+    fn notify(&self, stage: EventStage, frame: &Frame, time: DateTime<Utc>) {
+        let notify = self.event_action.notifies();
+        let snapshot = match stage {
+            EventStage::Start => notify::snapshot(frame, notify),
+            EventStage::End => None,
+        };
+        let notice = EventNotice {
+            camera: self.camera.label.clone(),
+            stage,
+            time,
+            triggers: self.event_sources.clone(),
+            zone: self.event_zone,
+            snapshot,
+            notify,
+        };
+        match self.notice_tx.try_send(notice) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "Notifications are backed up -- dropping one for {}",
+                    self.camera.label
+                )
+            }
+            Err(TrySendError::Closed(_)) => warn!("Notification thread has stopped"),
+        }
+    }
+
+    fn notify_preset_return(&mut self, event: MotionEvent) {
+        if let Some(tx) = &self.preset_tx {
+            if tx.send(event).is_err() {
+                warn!("Preset return thread for {} has stopped", self.camera.label);
+                self.preset_tx = None;
+            }
+        }
+    }
+
+    fn send_frame(&mut self, mut frame: VideoFrame) -> () {
+        frame.triggers = std::mem::take(&mut self.pending_triggers);
         match &self.video_tx {
             Some(v) => {
                 v.send(frame).unwrap();
             }
+            None if !self.control.recording() || !self.event_action.records() => {
+                trace!(
+                    "Recording disabled for {} -- dropping frame",
+                    self.camera.label
+                );
+            }
             None => {
+                if !frame.is_start {
+                    debug!(
+                        "Recording enabled for {} mid-event -- starting a new file",
+                        self.camera.label
+                    );
+                }
                 let f = &frame.frame;
                 let v = video::start_video_writer(
                     Arc::clone(&self.camera),
                     f.time(),
                     f.width(),
                     f.height(),
+                    self.audio_tx.as_ref().map(|tx| tx.subscribe()),
                 );
                 v.send(frame).unwrap();
                 self.video_tx = Some(v);
@@ -0,0 +1,509 @@
+//! Connection to the broker configured in `[mqtt]`, publishing each camera's
+//! state with Home Assistant discovery and taking commands on `.../set` topics
+//!
+//! Under the topic prefix:
+//! - `status`: `online` or `offline`
+//! - `arming`: the arming mode, set through `arming/set`
+//! - `<label>/motion`: `ON` or `OFF`, with the event's first frame on `<label>/snapshot`
+//! - `<label>/recording_enabled` and `<label>/detection`: `ON` or `OFF`, set
+//!   through `.../set`. Whether an event is being written is `<label>/motion`
+//! - `<label>/trigger/set`: `ON` and `OFF` hold an event open and close it,
+//!   `PULSE` extends it once
+
+use crate::arming;
+use crate::config::MqttConfig;
+use crate::event::{EventSource, ExternalTrigger, TriggerState};
+use crate::notify::{EventNotice, EventStage};
+use crate::pipeline::{CameraStatus, SharedPipelines};
+
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+use std::collections::HashMap;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::task;
+
+const DEFAULT_PORT: u16 = 1883;
+const DEFAULT_TOPIC_PREFIX: &str = "smartcam";
+const DEFAULT_CLIENT_ID: &str = "smartcam";
+const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How often recording, detection and the arming mode are checked for changes
+const STATE_INTERVAL: Duration = Duration::from_secs(2);
+/// Room for snapshots, the default is only 10 KiB
+const MAX_PACKET_SIZE: usize = 4 * 1024 * 1024;
+const REQUEST_CAPACITY: usize = 64;
+
+const ON: &str = "ON";
+const OFF: &str = "OFF";
+const PULSE: &str = "PULSE";
+
+/// Home Assistant components and keys making up each camera's device
+const ENTITIES: [(&str, &str); 5] = [
+    ("binary_sensor", "motion"),
+    ("camera", "snapshot"),
+    ("switch", "recording_enabled"),
+    ("switch", "detection"),
+    ("button", "trigger"),
+];
+
+pub fn topic_prefix(config: &MqttConfig) -> String {
+    config
+        .topic_prefix
+        .clone()
+        .unwrap_or_else(|| DEFAULT_TOPIC_PREFIX.to_string())
+}
+
+fn client_id(config: &MqttConfig) -> String {
+    config
+        .client_id
+        .clone()
+        .unwrap_or_else(|| DEFAULT_CLIENT_ID.to_string())
+}
+
+fn on_off(on: bool) -> String {
+    let state = if on { ON } else { OFF };
+    state.to_string()
+}
+
+/// Home Assistant IDs only allow letters, digits, `_` and `-`
+fn object_id(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// A client for the broker. Nothing is sent until the event loop is passed
+/// to `start`; requests made while disconnected are queued until it reconnects
+pub fn client(config: &MqttConfig) -> (AsyncClient, EventLoop) {
+    let mut options = MqttOptions::new(
+        client_id(config),
+        config.host.clone(),
+        config.port.unwrap_or(DEFAULT_PORT),
+    );
+    options.set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username.clone(), password.clone());
+    }
+    options.set_last_will(LastWill::new(
+        format!("{}/status", topic_prefix(config)),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+
+    AsyncClient::new(options, REQUEST_CAPACITY)
+}
+
+/// Publish whether `notice`'s camera is in motion, along with its snapshot
+/// when the event starts
+pub async fn publish_motion(
+    client: &AsyncClient,
+    prefix: &str,
+    notice: &EventNotice,
+) -> Result<(), rumqttc::ClientError> {
+    let topic = format!("{}/{}", prefix, notice.camera);
+    if let Some(jpeg) = &notice.snapshot {
+        client
+            .publish(
+                format!("{}/snapshot", topic),
+                QoS::AtMostOnce,
+                true,
+                jpeg.clone(),
+            )
+            .await?;
+    }
+    client
+        .publish(
+            format!("{}/motion", topic),
+            QoS::AtLeastOnce,
+            true,
+            on_off(notice.stage == EventStage::Start),
+        )
+        .await
+}
+
+/// Start a thread polling the connection, which publishes state as it
+/// changes and runs commands against `pipelines`
+pub fn start(
+    config: MqttConfig,
+    client: AsyncClient,
+    mut eventloop: EventLoop,
+    pipelines: SharedPipelines,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Failed to start MQTT runtime: {}", e);
+                return;
+            }
+        };
+
+        runtime.block_on(async {
+            let (states_tx, mut states) = unbounded_channel();
+            let mut bridge = Bridge::new(&config, client, pipelines, states_tx);
+            let mut interval = tokio::time::interval(STATE_INTERVAL);
+
+            loop {
+                tokio::select! {
+                    event = eventloop.poll() => match event {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            info!("Connected to MQTT broker {}", config.host);
+                            bridge.connected();
+                        }
+                        Ok(Event::Incoming(Packet::Publish(publish))) => {
+                            bridge.command(&publish.topic, &publish.payload);
+                        }
+                        Ok(_) => (),
+                        Err(e) => {
+                            warn!("MQTT connection to {} failed: {} -- retrying", config.host, e);
+                            tokio::time::sleep(RECONNECT_DELAY).await;
+                        }
+                    },
+                    _ = interval.tick() => bridge.refresh(),
+                    Some(state) = states.recv() => bridge.publish_state(state),
+                }
+            }
+        })
+    })
+}
+
+/// What's published about the cameras and arming, read off the event loop
+/// since the pipelines lock can be held while cameras start and stop
+struct State {
+    cameras: Vec<CameraStatus>,
+    mode: String,
+    modes: Vec<String>,
+}
+
+fn read_state(pipelines: &SharedPipelines) -> State {
+    State {
+        cameras: pipelines.lock().unwrap().cameras(),
+        mode: arming::mode(),
+        modes: arming::modes(),
+    }
+}
+
+struct Message {
+    topic: String,
+    payload: Vec<u8>,
+    retain: bool,
+}
+
+/// Publishes state and runs commands for the thread polling the connection
+struct Bridge {
+    client: AsyncClient,
+    pipelines: SharedPipelines,
+    /// states read by `refresh` and commands, for `publish_state`
+    states: UnboundedSender<State>,
+    prefix: String,
+    node_id: String,
+    /// `None` when discovery is off
+    discovery_prefix: Option<String>,
+    /// cameras and arming modes the discovery messages were sent for
+    discovered: Vec<String>,
+    modes: Vec<String>,
+    /// last payload per state topic, so only changes are sent
+    published: HashMap<String, String>,
+}
+
+impl Bridge {
+    fn new(
+        config: &MqttConfig,
+        client: AsyncClient,
+        pipelines: SharedPipelines,
+        states: UnboundedSender<State>,
+    ) -> Self {
+        let discovery_prefix = if config.discovery.unwrap_or(true) {
+            Some(
+                config
+                    .discovery_prefix
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_DISCOVERY_PREFIX.to_string()),
+            )
+        } else {
+            None
+        };
+        Self {
+            client,
+            pipelines,
+            states,
+            prefix: topic_prefix(config),
+            node_id: object_id(&client_id(config)),
+            discovery_prefix,
+            discovered: Vec::new(),
+            modes: Vec::new(),
+            published: HashMap::new(),
+        }
+    }
+
+    /// Queue `messages` from a task of their own, since waiting for room in
+    /// the request queue here would stop the event loop that empties it
+    fn send(&self, messages: Vec<Message>) {
+        if messages.is_empty() {
+            return;
+        }
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            for m in messages {
+                if let Err(e) = client
+                    .publish(&m.topic, QoS::AtLeastOnce, m.retain, m.payload)
+                    .await
+                {
+                    error!("Failed to publish {}: {}", m.topic, e);
+                }
+            }
+        });
+    }
+
+    /// Subscribe to commands and send everything again, as the broker may
+    /// have lost it
+    fn connected(&mut self) {
+        let client = self.client.clone();
+        let topics = vec![
+            format!("{}/+/+/set", self.prefix),
+            format!("{}/arming/set", self.prefix),
+        ];
+        tokio::spawn(async move {
+            for topic in topics {
+                if let Err(e) = client.subscribe(&topic, QoS::AtLeastOnce).await {
+                    error!("Failed to subscribe to {}: {}", topic, e);
+                }
+            }
+        });
+
+        self.discovered.clear();
+        self.modes.clear();
+        self.published.clear();
+        self.send(vec![Message {
+            topic: format!("{}/status", self.prefix),
+            payload: b"online".to_vec(),
+            retain: true,
+        }]);
+        self.refresh();
+    }
+
+    /// Read the state on a blocking thread, to be published when it arrives
+    fn refresh(&self) {
+        let pipelines = self.pipelines.clone();
+        let states = self.states.clone();
+        task::spawn_blocking(move || {
+            let _ = states.send(read_state(&pipelines));
+        });
+    }
+
+    fn publish_state(&mut self, state: State) {
+        let State {
+            cameras,
+            mode,
+            modes,
+        } = state;
+        let mut messages = Vec::new();
+
+        if self.discovery_prefix.is_some() {
+            let gone: Vec<String> = self
+                .discovered
+                .iter()
+                .filter(|l| !cameras.iter().any(|c| &c.label == *l))
+                .cloned()
+                .collect();
+            for label in gone {
+                messages.extend(self.undiscover(&label));
+                self.discovered.retain(|l| l != &label);
+            }
+            for camera in &cameras {
+                if !self.discovered.contains(&camera.label) {
+                    messages.extend(self.discover(&camera.label));
+                    self.discovered.push(camera.label.clone());
+                }
+            }
+            if modes != self.modes {
+                messages.push(self.discover_arming(&modes));
+                self.modes = modes;
+            }
+        }
+
+        for camera in &cameras {
+            let topic = format!("{}/{}", self.prefix, camera.label);
+            messages.extend(self.changed(
+                format!("{}/recording_enabled", topic),
+                on_off(camera.recording),
+            ));
+            messages.extend(self.changed(format!("{}/detection", topic), on_off(camera.detection)));
+        }
+        messages.extend(self.changed(format!("{}/arming", self.prefix), mode));
+
+        self.send(messages);
+    }
+
+    fn changed(&mut self, topic: String, payload: String) -> Option<Message> {
+        if self.published.get(&topic) == Some(&payload) {
+            return None;
+        }
+        self.published.insert(topic.clone(), payload.clone());
+        Some(Message {
+            topic,
+            payload: payload.into_bytes(),
+            retain: true,
+        })
+    }
+
+    fn discovery_topic(&self, component: &str, object_id: &str) -> String {
+        format!(
+            "{}/{}/{}/{}/config",
+            self.discovery_prefix
+                .as_deref()
+                .unwrap_or(DEFAULT_DISCOVERY_PREFIX),
+            component,
+            self.node_id,
+            object_id
+        )
+    }
+
+    fn discover(&self, label: &str) -> Vec<Message> {
+        let id = object_id(label);
+        let topic = format!("{}/{}", self.prefix, label);
+        let device = json!({
+            "identifiers": [format!("{}_{}", self.node_id, id)],
+            "name": label,
+            "manufacturer": "smartcam",
+        });
+
+        ENTITIES
+            .iter()
+            .map(|(component, key)| {
+                let mut config = json!({
+                    "name": format!("{} {}", label, key),
+                    "unique_id": format!("{}_{}_{}", self.node_id, id, key),
+                    "availability_topic": format!("{}/status", self.prefix),
+                    "device": device,
+                });
+                match *key {
+                    "motion" => {
+                        config["state_topic"] = json!(format!("{}/motion", topic));
+                        config["device_class"] = json!("motion");
+                    }
+                    "snapshot" => config["topic"] = json!(format!("{}/snapshot", topic)),
+                    "trigger" => {
+                        config["command_topic"] = json!(format!("{}/trigger/set", topic));
+                        config["payload_press"] = json!(PULSE);
+                    }
+                    _ => {
+                        config["state_topic"] = json!(format!("{}/{}", topic, key));
+                        config["command_topic"] = json!(format!("{}/{}/set", topic, key));
+                    }
+                }
+                Message {
+                    topic: self.discovery_topic(component, &format!("{}_{}", id, key)),
+                    payload: config.to_string().into_bytes(),
+                    retain: true,
+                }
+            })
+            .collect()
+    }
+
+    /// An empty config removes the entity from Home Assistant
+    fn undiscover(&self, label: &str) -> Vec<Message> {
+        let id = object_id(label);
+        ENTITIES
+            .iter()
+            .map(|(component, key)| Message {
+                topic: self.discovery_topic(component, &format!("{}_{}", id, key)),
+                payload: Vec::new(),
+                retain: true,
+            })
+            .collect()
+    }
+
+    fn discover_arming(&self, modes: &[String]) -> Message {
+        let config = json!({
+            "name": "smartcam arming mode",
+            "unique_id": format!("{}_arming", self.node_id),
+            "availability_topic": format!("{}/status", self.prefix),
+            "state_topic": format!("{}/arming", self.prefix),
+            "command_topic": format!("{}/arming/set", self.prefix),
+            "options": modes,
+        });
+        Message {
+            topic: self.discovery_topic("select", "arming"),
+            payload: config.to_string().into_bytes(),
+            retain: true,
+        }
+    }
+
+    /// Run a command on a blocking thread, publishing the state once it's done
+    fn command(&self, topic: &str, payload: &[u8]) {
+        let payload = String::from_utf8_lossy(payload).trim().to_string();
+        let command = match topic
+            .strip_prefix(&self.prefix)
+            .and_then(|t| t.strip_prefix('/'))
+            .and_then(|t| t.strip_suffix("/set"))
+        {
+            Some(c) => c.to_string(),
+            None => return,
+        };
+        debug!("MQTT command {} {}", command, payload);
+
+        let pipelines = self.pipelines.clone();
+        let states = self.states.clone();
+        task::spawn_blocking(move || {
+            let mut guard = pipelines.lock().unwrap();
+            let result = if command == "arming" {
+                guard.set_arming_mode(&payload)
+            } else {
+                let (label, key) = match command.rsplit_once('/') {
+                    Some(c) => c,
+                    None => return,
+                };
+                match (key, payload.as_str()) {
+                    ("recording_enabled", ON) | ("recording_enabled", OFF) => {
+                        guard.set_recording(label, payload == ON)
+                    }
+                    ("detection", ON) | ("detection", OFF) => {
+                        guard.set_detection(label, payload == ON)
+                    }
+                    ("trigger", ON) | ("trigger", OFF) | ("trigger", PULSE) => {
+                        let state = match payload.as_str() {
+                            ON => TriggerState::Start,
+                            OFF => TriggerState::Stop,
+                            _ => TriggerState::Pulse,
+                        };
+                        guard.trigger(
+                            label,
+                            ExternalTrigger {
+                                source: EventSource::Mqtt,
+                                state,
+                                time: Utc::now(),
+                            },
+                        )
+                    }
+                    _ => {
+                        warn!("Unknown MQTT command {} {}", command, payload);
+                        return;
+                    }
+                }
+            };
+            drop(guard);
+
+            match result {
+                Ok(()) => {
+                    let _ = states.send(read_state(&pipelines));
+                }
+                Err(e) => warn!("MQTT command {} {} failed: {}", command, payload, e),
+            }
+        });
+    }
+}
@@ -0,0 +1,47 @@
+use super::{Notification, EMAIL_TIMEOUT};
+use crate::config::{EmailConfig, SmtpSecurity};
+
+use anyhow::Result;
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+/// Send the notification with its snapshot attached
+pub async fn send(config: &EmailConfig, notification: &Notification) -> Result<()> {
+    let mut builder = match config.security.unwrap_or(SmtpSecurity::StartTls) {
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+        SmtpSecurity::StartTls => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+        }
+        // e.g. a local relay:
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+    };
+    if let Some(port) = config.port {
+        builder = builder.port(port);
+    }
+    builder = builder.timeout(Some(EMAIL_TIMEOUT));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    let transport = builder.build();
+
+    let mut message = Message::builder()
+        .from(config.from.parse()?)
+        .subject(notification.subject());
+    for to in &config.to {
+        message = message.to(to.parse()?);
+    }
+
+    let text = serde_json::to_string_pretty(&notification.to_json(false))?;
+    let mut body = MultiPart::mixed().singlepart(SinglePart::plain(text));
+    if let Some(jpeg) = &notification.snapshot {
+        body = body.singlepart(
+            Attachment::new(format!("{}.jpg", notification.camera))
+                .body(jpeg.clone(), ContentType::parse("image/jpeg")?),
+        );
+    }
+
+    transport.send(message.multipart(body)?).await?;
+    Ok(())
+}
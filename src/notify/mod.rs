@@ -0,0 +1,509 @@
+//! Notifications when events start and end, sent to webhooks, the MQTT
+//! broker and email. Motion state and snapshots are also published over MQTT
+//! here, whether or not notifications are enabled
+
+mod email;
+mod webhook;
+
+use crate::arming;
+use crate::config::{self, NotificationConfig};
+use crate::event::EventSource;
+use crate::frame::Frame;
+use crate::mqtt;
+
+use chrono::{DateTime, Local, Utc};
+use log::{debug, error};
+use rumqttc::{AsyncClient, QoS};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+const DEFAULT_MIN_INTERVAL_SECS: u64 = 60;
+const DEFAULT_SNAPSHOT_WIDTH: u32 = 640;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const EMAIL_TIMEOUT: Duration = Duration::from_secs(30);
+/// Notices waiting to be sent, beyond which detectors drop them rather than
+/// wait on slow targets
+const NOTICE_CAPACITY: usize = 32;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventStage {
+    Start,
+    End,
+}
+
+/// Where in the frame motion was seen, as fractions of its width and height
+/// like privacy mask points
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Zone {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Zone {
+    /// A rectangle in pixels of a `frame_width` by `frame_height` frame
+    pub fn from_pixels(
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+        frame_width: u32,
+        frame_height: u32,
+    ) -> Self {
+        let fw = frame_width.max(1) as f64;
+        let fh = frame_height.max(1) as f64;
+        Self {
+            x: x as f64 / fw,
+            y: y as f64 / fh,
+            width: width as f64 / fw,
+            height: height as f64 / fh,
+        }
+    }
+
+    /// The smallest zone covering both
+    pub fn union(self, other: Zone) -> Zone {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Zone {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+/// Sent by a motion detector as an event opens or closes
+pub struct EventNotice {
+    pub camera: String,
+    pub stage: EventStage,
+    pub time: DateTime<Utc>,
+    pub triggers: Vec<EventSource>,
+    /// motion so far, `None` if the event only had other triggers
+    pub zone: Option<Zone>,
+    /// JPEG, taken as the event starts
+    pub snapshot: Option<Vec<u8>>,
+    /// false when the arming rules say to only record
+    pub notify: bool,
+}
+
+pub type NoticeSender = Sender<EventNotice>;
+
+/// The snapshot for an event starting on `frame`, `None` when nothing would
+/// send it
+pub fn snapshot(frame: &Frame, notify: bool) -> Option<Vec<u8>> {
+    let app_config = config::load_config(None);
+    let config = &app_config.notifications;
+    if !(notify && config.enabled.unwrap_or(false)) && app_config.mqtt.is_none() {
+        return None;
+    }
+
+    let width = config.snapshot_width.unwrap_or(DEFAULT_SNAPSHOT_WIDTH);
+    match frame.to_jpeg(Some(width), app_config.display.jpeg_quality) {
+        Ok(jpeg) => Some(jpeg),
+        Err(e) => {
+            error!("Failed to encode snapshot: {}", e);
+            None
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Notification {
+    pub camera: String,
+    pub event: EventStage,
+    pub time: DateTime<Utc>,
+    pub triggers: Vec<EventSource>,
+    /// covers the motion at the start, and all of it by the end
+    pub zone: Option<Zone>,
+    /// what was detected, e.g. `motion` or `audio`
+    pub classes: Vec<String>,
+    /// JPEG
+    #[serde(skip)]
+    pub snapshot: Option<Vec<u8>>,
+}
+
+impl Notification {
+    /// The snapshot, if included, is base64 encoded
+    pub fn to_json(&self, with_snapshot: bool) -> serde_json::Value {
+        let mut json = serde_json::json!(self);
+        if let (true, Some(jpeg)) = (with_snapshot, &self.snapshot) {
+            json["snapshot"] = serde_json::Value::String(base64::encode(jpeg));
+        }
+        json
+    }
+
+    pub fn subject(&self) -> String {
+        let time = self.time.with_timezone(&Local).format("%H:%M:%S");
+        match self.event {
+            EventStage::Start => {
+                format!("{} on {} at {}", self.classes.join(", "), self.camera, time)
+            }
+            EventStage::End => format!("Event on {} ended at {}", self.camera, time),
+        }
+    }
+}
+
+/// Start the thread sending notifications, which checks the config as each
+/// notice arrives so can be turned on and off without a restart
+pub fn start(mqtt_client: Option<AsyncClient>) -> NoticeSender {
+    let (tx, rx) = channel(NOTICE_CAPACITY);
+
+    thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Failed to start notification runtime: {}", e);
+                return;
+            }
+        };
+        runtime.block_on(dispatch(rx, mqtt_client));
+    });
+
+    tx
+}
+
+async fn dispatch(mut rx: Receiver<EventNotice>, mqtt_client: Option<AsyncClient>) {
+    let http = match reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+        Ok(client) => Some(client),
+        Err(e) => {
+            error!(
+                "Failed to create webhook client -- webhooks won't be sent: {}",
+                e
+            );
+            None
+        }
+    };
+    let mut limiter = RateLimiter::default();
+
+    while let Some(notice) = rx.recv().await {
+        let app_config = config::load_config(None);
+        let config = &app_config.notifications;
+        let notifying =
+            notice.notify && config.enabled.unwrap_or(false) && limiter.allow(config, &notice);
+
+        if let (Some(client), Some(mqtt_config)) = (&mqtt_client, &app_config.mqtt) {
+            let prefix = mqtt::topic_prefix(mqtt_config);
+            if let Err(e) = mqtt::publish_motion(client, &prefix, &notice).await {
+                error!("Failed to publish motion state: {}", e);
+            }
+        }
+        if !notifying {
+            continue;
+        }
+
+        let notification = Arc::new(notification(notice));
+        debug!(
+            "Notifying {:?} on {}",
+            notification.event, notification.camera
+        );
+
+        // Each target in a task of its own, so a slow one doesn't hold up the others:
+        for webhook in config.webhooks.iter().flatten() {
+            let http = match &http {
+                Some(client) => client.clone(),
+                None => continue,
+            };
+            let webhook = webhook.clone();
+            let notification = Arc::clone(&notification);
+            tokio::spawn(async move {
+                if let Err(e) = webhook::send(&http, &webhook, &notification).await {
+                    error!("Webhook {} failed: {}", webhook.url, e);
+                }
+            });
+        }
+
+        if let (true, Some(client), Some(mqtt_config)) =
+            (config.mqtt.unwrap_or(false), &mqtt_client, &app_config.mqtt)
+        {
+            let client = client.clone();
+            let prefix = mqtt::topic_prefix(mqtt_config);
+            let notification = Arc::clone(&notification);
+            tokio::spawn(async move {
+                if let Err(e) = publish(&client, &prefix, &notification).await {
+                    error!("MQTT notification failed: {}", e);
+                }
+            });
+        }
+
+        if let Some(email_config) = &config.email {
+            let email_config = email_config.clone();
+            let notification = Arc::clone(&notification);
+            tokio::spawn(async move {
+                if let Err(e) = email::send(&email_config, &notification).await {
+                    error!("Email notification failed: {}", e);
+                }
+            });
+        }
+    }
+}
+
+fn notification(notice: EventNotice) -> Notification {
+    let mut classes: Vec<String> = Vec::new();
+    for t in &notice.triggers {
+        if !classes.iter().any(|c| c == t.kind()) {
+            classes.push(t.kind().to_string());
+        }
+    }
+
+    Notification {
+        camera: notice.camera,
+        event: notice.stage,
+        time: notice.time,
+        triggers: notice.triggers,
+        zone: notice.zone,
+        classes,
+        snapshot: notice.snapshot,
+    }
+}
+
+/// The event as JSON on `<prefix>/<camera>/event`, the snapshot having
+/// already gone out with the motion state
+async fn publish(
+    client: &AsyncClient,
+    prefix: &str,
+    notification: &Notification,
+) -> Result<(), rumqttc::ClientError> {
+    client
+        .publish(
+            format!("{}/{}/event", prefix, notification.camera),
+            QoS::AtLeastOnce,
+            false,
+            notification.to_json(false).to_string(),
+        )
+        .await
+}
+
+/// One notified event per camera per `min_interval`, none during quiet hours.
+/// An event's end is only notified if its start was
+#[derive(Default)]
+struct RateLimiter {
+    last_start: HashMap<String, Instant>,
+    open: HashSet<String>,
+}
+
+impl RateLimiter {
+    fn allow(&mut self, config: &NotificationConfig, notice: &EventNotice) -> bool {
+        match notice.stage {
+            EventStage::Start => {
+                let quiet = (config.quiet_start.is_some() || config.quiet_end.is_some())
+                    && arming::in_window(
+                        config.quiet_start.as_deref(),
+                        config.quiet_end.as_deref(),
+                        notice.time.with_timezone(&Local).time(),
+                    );
+                if quiet {
+                    debug!("Quiet hours -- not notifying for {}", notice.camera);
+                    return false;
+                }
+
+                let min_interval =
+                    Duration::from_secs(config.min_interval.unwrap_or(DEFAULT_MIN_INTERVAL_SECS));
+                if let Some(last) = self.last_start.get(&notice.camera) {
+                    if last.elapsed() < min_interval {
+                        debug!("Rate limited notification for {}", notice.camera);
+                        return false;
+                    }
+                }
+                self.last_start
+                    .insert(notice.camera.clone(), Instant::now());
+                self.open.insert(notice.camera.clone());
+                true
+            }
+            EventStage::End => {
+                self.open.remove(&notice.camera) && config.notify_end.unwrap_or(true)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{EmailConfig, SmtpSecurity, WebhookConfig};
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    const JPEG: [u8; 4] = [0xff, 0xd8, 0xff, 0xd9];
+
+    fn test_notification() -> Notification {
+        Notification {
+            camera: "front".to_string(),
+            event: EventStage::Start,
+            time: Utc::now(),
+            triggers: vec![EventSource::Motion],
+            zone: Some(Zone {
+                x: 0.25,
+                y: 0.5,
+                width: 0.5,
+                height: 0.25,
+            }),
+            classes: vec!["motion".to_string()],
+            snapshot: Some(JPEG.to_vec()),
+        }
+    }
+
+    /// Answer one HTTP request with 200, returning its head and body
+    async fn serve_http(listener: TcpListener) -> (String, Vec<u8>) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(stream);
+
+        let mut head = String::new();
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+            head.push_str(&line);
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).await.unwrap();
+
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        (head, body)
+    }
+
+    /// Accept one message over plain SMTP, returning what was sent after DATA
+    async fn serve_smtp(listener: TcpListener) -> String {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(stream);
+        reader
+            .get_mut()
+            .write_all(b"220 localhost ESMTP\r\n")
+            .await
+            .unwrap();
+
+        let mut data = String::new();
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.unwrap() == 0 {
+                break;
+            }
+            let reply: &[u8] = if in_data {
+                if line == ".\r\n" {
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else {
+                    data.push_str(&line);
+                    continue;
+                }
+            } else {
+                match line.get(..4).map(|c| c.to_ascii_uppercase()).as_deref() {
+                    Some("DATA") => {
+                        in_data = true;
+                        b"354 go ahead\r\n"
+                    }
+                    Some("QUIT") => {
+                        let _ = reader.get_mut().write_all(b"221 bye\r\n").await;
+                        break;
+                    }
+                    _ => b"250 OK\r\n",
+                }
+            };
+            reader.get_mut().write_all(reply).await.unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn zone_union_covers_both() {
+        let a = Zone::from_pixels(10, 20, 30, 40, 100, 100);
+        let b = Zone::from_pixels(50, 0, 10, 10, 100, 100);
+        let u = a.union(b);
+        assert!((u.x - 0.1).abs() < 1e-9);
+        assert!((u.y - 0.0).abs() < 1e-9);
+        assert!((u.width - 0.5).abs() < 1e-9);
+        assert!((u.height - 0.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn json_leaves_out_snapshot_unless_asked() {
+        let notification = test_notification();
+        assert!(notification.to_json(false).get("snapshot").is_none());
+        assert_eq!(notification.to_json(true)["snapshot"], base64::encode(JPEG));
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_payload() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve_http(listener));
+
+        let mut headers = HashMap::new();
+        headers.insert("X-Token".to_string(), "secret".to_string());
+        let config = WebhookConfig {
+            url: format!("http://127.0.0.1:{}/hook", port),
+            headers: Some(headers),
+            no_snapshot: None,
+        };
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .unwrap();
+        webhook::send(&client, &config, &test_notification())
+            .await
+            .unwrap();
+
+        let (head, body) = server.await.unwrap();
+        assert!(head.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(head.to_ascii_lowercase().contains("x-token: secret\r\n"));
+
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["camera"], "front");
+        assert_eq!(json["event"], "start");
+        assert_eq!(json["classes"], serde_json::json!(["motion"]));
+        assert_eq!(json["zone"]["x"], 0.25);
+        assert_eq!(json["zone"]["height"], 0.25);
+        assert_eq!(json["triggers"][0]["type"], "motion");
+        assert_eq!(json["snapshot"], base64::encode(JPEG));
+    }
+
+    #[tokio::test]
+    async fn email_sends_message_with_snapshot() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve_smtp(listener));
+
+        let config = EmailConfig {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            security: Some(SmtpSecurity::None),
+            username: None,
+            password: None,
+            from: "smartcam@example.com".to_string(),
+            to: vec!["owner@example.com".to_string()],
+        };
+        email::send(&config, &test_notification()).await.unwrap();
+
+        let data = server.await.unwrap();
+        assert!(data.contains("From: smartcam@example.com\r\n"));
+        assert!(data.contains("To: owner@example.com\r\n"));
+        assert!(data.contains("Subject: motion on front at "));
+        assert!(data.contains("\"camera\": \"front\""));
+        assert!(data.contains("\"zone\": {"));
+        assert!(data.contains("filename=\"front.jpg\""));
+        assert!(data.contains("Content-Type: image/jpeg"));
+    }
+}
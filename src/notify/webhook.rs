@@ -0,0 +1,20 @@
+use super::Notification;
+use crate::config::WebhookConfig;
+
+use anyhow::Result;
+
+/// POST the notification as JSON
+pub async fn send(
+    client: &reqwest::Client,
+    config: &WebhookConfig,
+    notification: &Notification,
+) -> Result<()> {
+    let mut request = client
+        .post(&config.url)
+        .json(&notification.to_json(!config.no_snapshot.unwrap_or(false)));
+    for (name, value) in config.headers.iter().flatten() {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
@@ -0,0 +1,182 @@
+//! ONVIF PullPoint event subscriptions, forwarded as recording triggers
+
+use super::{child, child_text, rebase, OnvifClient};
+use crate::config::{CameraConfig, OnvifConfig};
+use crate::event::{EventSource, ExternalTrigger, TriggerSender, TriggerState};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use roxmltree::Document;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const SUBSCRIPTION_TERMINATION: &str = "PT60S";
+const RENEW_INTERVAL: Duration = Duration::from_secs(30);
+/// Kept under the client's request timeout
+const PULL_TIMEOUT: &str = "PT5S";
+const MESSAGE_LIMIT: u32 = 32;
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+struct Notification {
+    topic: String,
+    state: TriggerState,
+    time: DateTime<Utc>,
+}
+
+impl OnvifClient {
+    /// Returns the subscription's address
+    async fn create_pull_point(&self) -> Result<String> {
+        let events_url = self
+            .events_url
+            .as_deref()
+            .ok_or(anyhow!("{} has no event service", self.device_url))?;
+        let body = format!(
+            "<tev:CreatePullPointSubscription><tev:InitialTerminationTime>{}</tev:InitialTerminationTime></tev:CreatePullPointSubscription>",
+            SUBSCRIPTION_TERMINATION
+        );
+        let response = self
+            .call(
+                events_url,
+                "http://www.onvif.org/ver10/events/wsdl/EventPortType/CreatePullPointSubscriptionRequest",
+                &body,
+            )
+            .await?;
+        let doc = Document::parse(&response)?;
+        doc.descendants()
+            .find(|n| n.tag_name().name() == "SubscriptionReference")
+            .and_then(|n| child_text(n, "Address"))
+            .map(|a| rebase(&a, &self.device_url))
+            .ok_or(anyhow!("No subscription address in response"))
+    }
+
+    async fn pull_messages(&self, subscription: &str) -> Result<Vec<Notification>> {
+        let body = format!(
+            "<tev:PullMessages><tev:Timeout>{}</tev:Timeout><tev:MessageLimit>{}</tev:MessageLimit></tev:PullMessages>",
+            PULL_TIMEOUT, MESSAGE_LIMIT
+        );
+        let response = self
+            .call_addressed(
+                subscription,
+                "http://www.onvif.org/ver10/events/wsdl/PullPointSubscription/PullMessagesRequest",
+                &body,
+            )
+            .await?;
+        let doc = Document::parse(&response)?;
+
+        Ok(doc
+            .descendants()
+            .filter(|n| n.tag_name().name() == "NotificationMessage")
+            .filter_map(|n| {
+                let topic = child_text(n, "Topic")?;
+                let message = child(n, "Message").and_then(|m| child(m, "Message"))?;
+                let time = message
+                    .attribute("UtcTime")
+                    .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                    .map(|t| t.with_timezone(&Utc))
+                    .unwrap_or_else(Utc::now);
+                // Stateful events carry a boolean, e.g. IsMotion or IsTamper:
+                let state = child(message, "Data")
+                    .into_iter()
+                    .flat_map(|d| d.children())
+                    .filter(|i| i.tag_name().name() == "SimpleItem")
+                    .find_map(|i| match i.attribute("Value")?.to_lowercase().as_str() {
+                        "true" => Some(TriggerState::Start),
+                        "false" => Some(TriggerState::Stop),
+                        _ => None,
+                    })
+                    .unwrap_or(TriggerState::Pulse);
+                Some(Notification { topic, state, time })
+            })
+            .collect())
+    }
+
+    async fn renew(&self, subscription: &str) -> Result<()> {
+        let body = format!(
+            "<wsnt:Renew><wsnt:TerminationTime>{}</wsnt:TerminationTime></wsnt:Renew>",
+            SUBSCRIPTION_TERMINATION
+        );
+        self.call_addressed(
+            subscription,
+            "http://docs.oasis-open.org/wsn/bw-2/SubscriptionManager/RenewRequest",
+            &body,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Start a thread forwarding the camera's ONVIF events to `tx`, if enabled
+pub fn start_event_listener(camera: &CameraConfig, tx: TriggerSender) -> Option<JoinHandle<()>> {
+    let config = camera.onvif.clone()?;
+    if !config.events.unwrap_or(false) {
+        return None;
+    }
+    let label = camera.label.clone();
+
+    Some(thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Failed to start ONVIF event runtime for {}: {}", label, e);
+                return;
+            }
+        };
+
+        runtime.block_on(async {
+            loop {
+                match listen(&label, &config, &tx).await {
+                    Ok(_) => return,
+                    Err(e) => warn!(
+                        "ONVIF event subscription for {} failed: {} -- retrying",
+                        label, e
+                    ),
+                }
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        })
+    }))
+}
+
+/// Pull events until the trigger receiver goes away
+async fn listen(label: &str, config: &OnvifConfig, tx: &TriggerSender) -> Result<()> {
+    let client = OnvifClient::connect(
+        &config.url,
+        config.username.clone(),
+        config.password.clone(),
+    )
+    .await?;
+    let subscription = client.create_pull_point().await?;
+    info!("Subscribed to ONVIF events for {}", label);
+    let mut last_renew = Instant::now();
+
+    loop {
+        for n in client.pull_messages(&subscription).await? {
+            let wanted = match &config.event_topics {
+                Some(topics) => topics.iter().any(|t| n.topic.contains(t.as_str())),
+                None => true,
+            };
+            if !wanted {
+                continue;
+            }
+            debug!("ONVIF event {} ({:?}) from {}", n.topic, n.state, label);
+
+            let trigger = ExternalTrigger {
+                source: EventSource::Onvif { topic: n.topic },
+                state: n.state,
+                time: n.time,
+            };
+            if tx.send(trigger).is_err() {
+                return Ok(());
+            }
+        }
+
+        if last_renew.elapsed() >= RENEW_INTERVAL {
+            client.renew(&subscription).await?;
+            last_renew = Instant::now();
+        }
+    }
+}
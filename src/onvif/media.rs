@@ -0,0 +1,94 @@
+use super::{child, child_text, xml_escape, OnvifClient};
+
+use anyhow::Result;
+use roxmltree::Document;
+use serde::Serialize;
+
+/// A media profile, i.e. one of the camera's main or sub streams
+#[derive(Serialize, Clone, Debug)]
+pub struct MediaProfile {
+    pub token: String,
+    pub name: String,
+    pub encoding: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frame_rate: Option<u32>,
+    pub stream_uri: Option<String>,
+    /// whether the profile has a PTZ configuration
+    pub ptz: bool,
+}
+
+impl MediaProfile {
+    pub fn pixels(&self) -> u32 {
+        self.width.unwrap_or(0) * self.height.unwrap_or(0)
+    }
+}
+
+impl OnvifClient {
+    pub async fn get_profiles(&self) -> Result<Vec<MediaProfile>> {
+        let response = self
+            .call(
+                self.media_url()?,
+                "http://www.onvif.org/ver10/media/wsdl/GetProfiles",
+                "<trt:GetProfiles/>",
+            )
+            .await?;
+        let doc = Document::parse(&response)?;
+
+        Ok(doc
+            .descendants()
+            .filter(|n| n.tag_name().name() == "Profiles")
+            .filter_map(|p| {
+                let token = p.attribute("token")?.to_string();
+                let encoder = child(p, "VideoEncoderConfiguration");
+                let resolution = encoder.and_then(|e| child(e, "Resolution"));
+                let dimension = |name: &str| {
+                    resolution
+                        .and_then(|r| child_text(r, name))
+                        .and_then(|v| v.parse().ok())
+                };
+                Some(MediaProfile {
+                    name: child_text(p, "Name").unwrap_or_else(|| token.clone()),
+                    token,
+                    encoding: encoder.and_then(|e| child_text(e, "Encoding")),
+                    width: dimension("Width"),
+                    height: dimension("Height"),
+                    frame_rate: encoder
+                        .and_then(|e| child(e, "RateControl"))
+                        .and_then(|r| child_text(r, "FrameRateLimit"))
+                        .and_then(|v| v.parse().ok()),
+                    stream_uri: None,
+                    ptz: child(p, "PTZConfiguration").is_some(),
+                })
+            })
+            .collect())
+    }
+
+    /// RTSP uri for a profile's stream
+    pub async fn get_stream_uri(&self, profile_token: &str) -> Result<String> {
+        let body = format!(
+            concat!(
+                "<trt:GetStreamUri><trt:StreamSetup>",
+                "<tt:Stream>RTP-Unicast</tt:Stream>",
+                "<tt:Transport><tt:Protocol>RTSP</tt:Protocol></tt:Transport>",
+                "</trt:StreamSetup><trt:ProfileToken>{}</trt:ProfileToken></trt:GetStreamUri>"
+            ),
+            xml_escape(profile_token)
+        );
+        let response = self
+            .call(
+                self.media_url()?,
+                "http://www.onvif.org/ver10/media/wsdl/GetStreamUri",
+                &body,
+            )
+            .await?;
+        let doc = Document::parse(&response)?;
+        doc.descendants()
+            .find(|n| n.tag_name().name() == "MediaUri")
+            .and_then(|n| child_text(n, "Uri"))
+            .ok_or(anyhow::anyhow!(
+                "No stream uri for profile {}",
+                profile_token
+            ))
+    }
+}
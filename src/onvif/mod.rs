@@ -0,0 +1,254 @@
+//! Minimal ONVIF client, covering only the SOAP calls smartcam uses.
+
+mod discovery;
+mod events;
+mod media;
+mod ptz;
+
+pub use discovery::{config_toml, discover, DiscoveredCamera, DEFAULT_DISCOVERY_TIMEOUT};
+pub use events::start_event_listener;
+pub use media::MediaProfile;
+pub use ptz::{start_preset_return, MotionEvent, Preset, PtzController, PtzVector};
+
+use anyhow::{anyhow, Result};
+use chrono::{Duration, TimeZone, Utc};
+use log::{debug, warn};
+use roxmltree::{Document, Node};
+use url::Url;
+
+const SOAP_NAMESPACES: &str = concat!(
+    r#"xmlns:s="http://www.w3.org/2003/05/soap-envelope" "#,
+    r#"xmlns:tds="http://www.onvif.org/ver10/device/wsdl" "#,
+    r#"xmlns:trt="http://www.onvif.org/ver10/media/wsdl" "#,
+    r#"xmlns:tptz="http://www.onvif.org/ver20/ptz/wsdl" "#,
+    r#"xmlns:tev="http://www.onvif.org/ver10/events/wsdl" "#,
+    r#"xmlns:wsnt="http://docs.oasis-open.org/wsn/b-2" "#,
+    r#"xmlns:wsa="http://www.w3.org/2005/08/addressing" "#,
+    r#"xmlns:tt="http://www.onvif.org/ver10/schema""#,
+);
+const PASSWORD_DIGEST: &str =
+    "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-username-token-profile-1.0#PasswordDigest";
+const BASE64_BINARY: &str =
+    "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-soap-message-security-1.0#Base64Binary";
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Connection to a single device's ONVIF services
+pub struct OnvifClient {
+    http: reqwest::Client,
+    device_url: String,
+    username: Option<String>,
+    password: Option<String>,
+    /// camera clock minus ours, as WS-Security timestamps are checked against it
+    time_offset: Duration,
+    media_url: Option<String>,
+    ptz_url: Option<String>,
+    events_url: Option<String>,
+}
+
+impl OnvifClient {
+    /// Connect to the device service at `device_url` and look up its other services
+    pub async fn connect(
+        device_url: &str,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> Result<Self> {
+        let mut client = Self {
+            http: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+            device_url: device_url.to_string(),
+            username,
+            password,
+            time_offset: Duration::zero(),
+            media_url: None,
+            ptz_url: None,
+            events_url: None,
+        };
+
+        match client.system_time_offset().await {
+            Ok(offset) => client.time_offset = offset,
+            Err(e) => warn!("Failed to get time from {}: {}", device_url, e),
+        }
+
+        let response = client
+            .call(
+                device_url,
+                "http://www.onvif.org/ver10/device/wsdl/GetCapabilities",
+                "<tds:GetCapabilities><tds:Category>All</tds:Category></tds:GetCapabilities>",
+            )
+            .await?;
+        let doc = Document::parse(&response)?;
+        let service_url = |name: &str| {
+            doc.descendants()
+                .find(|n| n.tag_name().name() == name)
+                .and_then(|n| child_text(n, "XAddr"))
+                .map(|xaddr| rebase(&xaddr, device_url))
+        };
+        client.media_url = service_url("Media");
+        client.ptz_url = service_url("PTZ");
+        client.events_url = service_url("Events");
+        debug!(
+            "ONVIF services for {}: media {:?}, ptz {:?}, events {:?}",
+            device_url, client.media_url, client.ptz_url, client.events_url
+        );
+
+        Ok(client)
+    }
+
+    async fn system_time_offset(&self) -> Result<Duration> {
+        // Unauthenticated, so it can be called before the offset is known
+        let response = self
+            .post(
+                &self.device_url,
+                "http://www.onvif.org/ver10/device/wsdl/GetSystemDateAndTime",
+                &envelope("", "<tds:GetSystemDateAndTime/>"),
+            )
+            .await?;
+        let doc = Document::parse(&response)?;
+        let utc = doc
+            .descendants()
+            .find(|n| n.tag_name().name() == "UTCDateTime")
+            .ok_or(anyhow!("No UTCDateTime in response"))?;
+        let field = |name: &str| -> Result<u32> {
+            utc.descendants()
+                .find(|n| n.tag_name().name() == name)
+                .and_then(|n| n.text())
+                .and_then(|t| t.trim().parse().ok())
+                .ok_or(anyhow!("Missing {} in UTCDateTime", name))
+        };
+        let camera_time = Utc
+            .ymd(field("Year")? as i32, field("Month")?, field("Day")?)
+            .and_hms(field("Hour")?, field("Minute")?, field("Second")?);
+        Ok(camera_time - Utc::now())
+    }
+
+    pub fn device_url(&self) -> &str {
+        &self.device_url
+    }
+
+    fn media_url(&self) -> Result<&str> {
+        self.media_url
+            .as_deref()
+            .ok_or(anyhow!("{} has no media service", self.device_url))
+    }
+
+    /// Send an authenticated SOAP request, returning the response envelope
+    pub async fn call(&self, url: &str, action: &str, body: &str) -> Result<String> {
+        self.post(url, action, &envelope(&self.security_header(), body))
+            .await
+    }
+
+    /// As `call`, with the WS-Addressing headers some services require
+    pub async fn call_addressed(&self, url: &str, action: &str, body: &str) -> Result<String> {
+        let header = format!(
+            "{}<wsa:Action>{}</wsa:Action><wsa:To>{}</wsa:To>",
+            self.security_header(),
+            action,
+            xml_escape(url)
+        );
+        self.post(url, action, &envelope(&header, body)).await
+    }
+
+    async fn post(&self, url: &str, action: &str, envelope: &str) -> Result<String> {
+        let response = self
+            .http
+            .post(url)
+            .header(
+                "Content-Type",
+                format!("application/soap+xml; charset=utf-8; action=\"{}\"", action),
+            )
+            .body(envelope.to_string())
+            .send()
+            .await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            let reason = Document::parse(&text)
+                .ok()
+                .and_then(|doc| {
+                    doc.descendants()
+                        .find(|n| n.tag_name().name() == "Reason")
+                        .and_then(|n| child_text(n, "Text"))
+                })
+                .unwrap_or_else(|| status.to_string());
+            return Err(anyhow!("{} failed: {}", action, reason));
+        }
+        Ok(text)
+    }
+
+    /// WS-Security UsernameToken with a password digest
+    fn security_header(&self) -> String {
+        let (username, password) = match (&self.username, &self.password) {
+            (Some(u), p) => (u, p.as_deref().unwrap_or("")),
+            _ => return String::new(),
+        };
+        let nonce: [u8; 16] = rand::random();
+        let created = (Utc::now() + self.time_offset)
+            .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+            .to_string();
+
+        let mut digest = sha1::Sha1::new();
+        digest.update(&nonce);
+        digest.update(created.as_bytes());
+        digest.update(password.as_bytes());
+
+        format!(
+            concat!(
+                r#"<wsse:Security s:mustUnderstand="1" "#,
+                r#"xmlns:wsse="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd" "#,
+                r#"xmlns:wsu="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-utility-1.0.xsd">"#,
+                "<wsse:UsernameToken><wsse:Username>{}</wsse:Username>",
+                r#"<wsse:Password Type="{}">{}</wsse:Password>"#,
+                r#"<wsse:Nonce EncodingType="{}">{}</wsse:Nonce>"#,
+                "<wsu:Created>{}</wsu:Created></wsse:UsernameToken></wsse:Security>"
+            ),
+            xml_escape(username),
+            PASSWORD_DIGEST,
+            base64::encode(digest.digest().bytes()),
+            BASE64_BINARY,
+            base64::encode(nonce),
+            created
+        )
+    }
+}
+
+fn envelope(header: &str, body: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><s:Envelope {}><s:Header>{}</s:Header><s:Body>{}</s:Body></s:Envelope>"#,
+        SOAP_NAMESPACES, header, body
+    )
+}
+
+/// Cameras behind NAT often report service addresses using their internal
+/// address, so reuse the host we reached the device service on
+fn rebase(xaddr: &str, device_url: &str) -> String {
+    match (Url::parse(xaddr), Url::parse(device_url)) {
+        (Ok(mut x), Ok(d)) => {
+            if x.set_host(d.host_str()).is_ok() && x.set_port(d.port()).is_ok() {
+                x.to_string()
+            } else {
+                xaddr.to_string()
+            }
+        }
+        _ => xaddr.to_string(),
+    }
+}
+
+pub(crate) fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// First direct child with the given local name
+pub(crate) fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.tag_name().name() == name)
+}
+
+pub(crate) fn child_text(node: Node, name: &str) -> Option<String> {
+    child(node, name)
+        .and_then(|n| n.text())
+        .map(|t| t.trim().to_string())
+}
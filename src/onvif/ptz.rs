@@ -0,0 +1,354 @@
+use super::{child_text, xml_escape, OnvifClient};
+use crate::config::{CameraConfig, OnvifConfig};
+
+use anyhow::{anyhow, Result};
+use log::{debug, error, info};
+use roxmltree::Document;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+const DEFAULT_RETURN_DELAY_SECS: u64 = 30;
+
+/// Pan, tilt and zoom, each in the camera's normalized -1.0 to 1.0 range
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default)]
+pub struct PtzVector {
+    pub pan: Option<f32>,
+    pub tilt: Option<f32>,
+    pub zoom: Option<f32>,
+}
+
+impl PtzVector {
+    /// `tt:PTZVector` / `tt:PTZSpeed` contents; omitted axes aren't moved
+    fn to_xml(&self) -> String {
+        let clamp = |v: f32| v.max(-1.0).min(1.0);
+        let mut xml = String::new();
+        if self.pan.is_some() || self.tilt.is_some() {
+            xml.push_str(&format!(
+                r#"<tt:PanTilt x="{}" y="{}"/>"#,
+                clamp(self.pan.unwrap_or(0.0)),
+                clamp(self.tilt.unwrap_or(0.0))
+            ));
+        }
+        if let Some(zoom) = self.zoom {
+            xml.push_str(&format!(r#"<tt:Zoom x="{}"/>"#, clamp(zoom)));
+        }
+        xml
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Preset {
+    pub token: String,
+    pub name: Option<String>,
+}
+
+impl OnvifClient {
+    fn ptz_url(&self) -> Result<&str> {
+        self.ptz_url
+            .as_deref()
+            .ok_or(anyhow!("{} has no PTZ service", self.device_url))
+    }
+
+    pub async fn continuous_move(
+        &self,
+        profile_token: &str,
+        velocity: &PtzVector,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let timeout = timeout
+            .map(|t| format!("<tptz:Timeout>PT{:.3}S</tptz:Timeout>", t.as_secs_f64()))
+            .unwrap_or_default();
+        let body = format!(
+            "<tptz:ContinuousMove><tptz:ProfileToken>{}</tptz:ProfileToken><tptz:Velocity>{}</tptz:Velocity>{}</tptz:ContinuousMove>",
+            xml_escape(profile_token),
+            velocity.to_xml(),
+            timeout
+        );
+        self.call(
+            self.ptz_url()?,
+            "http://www.onvif.org/ver20/ptz/wsdl/ContinuousMove",
+            &body,
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn relative_move(&self, profile_token: &str, translation: &PtzVector) -> Result<()> {
+        let body = format!(
+            "<tptz:RelativeMove><tptz:ProfileToken>{}</tptz:ProfileToken><tptz:Translation>{}</tptz:Translation></tptz:RelativeMove>",
+            xml_escape(profile_token),
+            translation.to_xml()
+        );
+        self.call(
+            self.ptz_url()?,
+            "http://www.onvif.org/ver20/ptz/wsdl/RelativeMove",
+            &body,
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn stop(&self, profile_token: &str) -> Result<()> {
+        let body = format!(
+            "<tptz:Stop><tptz:ProfileToken>{}</tptz:ProfileToken><tptz:PanTilt>true</tptz:PanTilt><tptz:Zoom>true</tptz:Zoom></tptz:Stop>",
+            xml_escape(profile_token)
+        );
+        self.call(
+            self.ptz_url()?,
+            "http://www.onvif.org/ver20/ptz/wsdl/Stop",
+            &body,
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_presets(&self, profile_token: &str) -> Result<Vec<Preset>> {
+        let body = format!(
+            "<tptz:GetPresets><tptz:ProfileToken>{}</tptz:ProfileToken></tptz:GetPresets>",
+            xml_escape(profile_token)
+        );
+        let response = self
+            .call(
+                self.ptz_url()?,
+                "http://www.onvif.org/ver20/ptz/wsdl/GetPresets",
+                &body,
+            )
+            .await?;
+        let doc = Document::parse(&response)?;
+        Ok(doc
+            .descendants()
+            .filter(|n| n.tag_name().name() == "Preset")
+            .filter_map(|p| {
+                Some(Preset {
+                    token: p.attribute("token")?.to_string(),
+                    name: child_text(p, "Name"),
+                })
+            })
+            .collect())
+    }
+
+    pub async fn goto_preset(&self, profile_token: &str, preset_token: &str) -> Result<()> {
+        let body = format!(
+            "<tptz:GotoPreset><tptz:ProfileToken>{}</tptz:ProfileToken><tptz:PresetToken>{}</tptz:PresetToken></tptz:GotoPreset>",
+            xml_escape(profile_token),
+            xml_escape(preset_token)
+        );
+        self.call(
+            self.ptz_url()?,
+            "http://www.onvif.org/ver20/ptz/wsdl/GotoPreset",
+            &body,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Save the current position, overwriting `preset_token` if given.
+    /// Returns the preset's token
+    pub async fn set_preset(
+        &self,
+        profile_token: &str,
+        name: Option<&str>,
+        preset_token: Option<&str>,
+    ) -> Result<String> {
+        let mut body = format!(
+            "<tptz:SetPreset><tptz:ProfileToken>{}</tptz:ProfileToken>",
+            xml_escape(profile_token)
+        );
+        if let Some(name) = name {
+            body.push_str(&format!(
+                "<tptz:PresetName>{}</tptz:PresetName>",
+                xml_escape(name)
+            ));
+        }
+        if let Some(token) = preset_token {
+            body.push_str(&format!(
+                "<tptz:PresetToken>{}</tptz:PresetToken>",
+                xml_escape(token)
+            ));
+        }
+        body.push_str("</tptz:SetPreset>");
+
+        let response = self
+            .call(
+                self.ptz_url()?,
+                "http://www.onvif.org/ver20/ptz/wsdl/SetPreset",
+                &body,
+            )
+            .await?;
+        let doc = Document::parse(&response)?;
+        doc.descendants()
+            .find(|n| n.tag_name().name() == "PresetToken")
+            .and_then(|n| n.text())
+            .map(|t| t.trim().to_string())
+            .ok_or(anyhow!("No preset token in SetPreset response"))
+    }
+}
+
+struct PtzConnection {
+    client: OnvifClient,
+    profile_token: String,
+}
+
+/// PTZ control for one camera. The device is connected to on first use, and
+/// again after any failure, so cameras that are offline at startup still work
+pub struct PtzController {
+    label: String,
+    config: OnvifConfig,
+    connection: Mutex<Option<Arc<PtzConnection>>>,
+}
+
+impl PtzController {
+    pub fn new(label: &str, config: OnvifConfig) -> Self {
+        Self {
+            label: label.to_string(),
+            config,
+            connection: Mutex::new(None),
+        }
+    }
+
+    async fn connection(&self) -> Result<Arc<PtzConnection>> {
+        let mut connection = self.connection.lock().await;
+        if let Some(c) = connection.as_ref() {
+            return Ok(Arc::clone(c));
+        }
+
+        let client = OnvifClient::connect(
+            &self.config.url,
+            self.config.username.clone(),
+            self.config.password.clone(),
+        )
+        .await?;
+        let profile_token = match &self.config.profile_token {
+            Some(t) => t.clone(),
+            None => client
+                .get_profiles()
+                .await?
+                .into_iter()
+                .find(|p| p.ptz)
+                .map(|p| p.token)
+                .ok_or(anyhow!("No media profile with PTZ on {}", self.label))?,
+        };
+        debug!("Using PTZ profile {} for {}", profile_token, self.label);
+
+        let c = Arc::new(PtzConnection {
+            client,
+            profile_token,
+        });
+        *connection = Some(Arc::clone(&c));
+        Ok(c)
+    }
+
+    /// Drop the connection if `result` failed, so the next call reconnects
+    async fn check<T>(&self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            *self.connection.lock().await = None;
+        }
+        result
+    }
+
+    pub async fn continuous_move(
+        &self,
+        velocity: &PtzVector,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let c = self.connection().await?;
+        let result = c
+            .client
+            .continuous_move(&c.profile_token, velocity, timeout)
+            .await;
+        self.check(result).await
+    }
+
+    pub async fn relative_move(&self, translation: &PtzVector) -> Result<()> {
+        let c = self.connection().await?;
+        let result = c.client.relative_move(&c.profile_token, translation).await;
+        self.check(result).await
+    }
+
+    pub async fn stop(&self) -> Result<()> {
+        let c = self.connection().await?;
+        let result = c.client.stop(&c.profile_token).await;
+        self.check(result).await
+    }
+
+    pub async fn presets(&self) -> Result<Vec<Preset>> {
+        let c = self.connection().await?;
+        let result = c.client.get_presets(&c.profile_token).await;
+        self.check(result).await
+    }
+
+    pub async fn goto_preset(&self, preset_token: &str) -> Result<()> {
+        let c = self.connection().await?;
+        let result = c.client.goto_preset(&c.profile_token, preset_token).await;
+        self.check(result).await
+    }
+
+    pub async fn set_preset(
+        &self,
+        name: Option<&str>,
+        preset_token: Option<&str>,
+    ) -> Result<String> {
+        let c = self.connection().await?;
+        let result = c
+            .client
+            .set_preset(&c.profile_token, name, preset_token)
+            .await;
+        self.check(result).await
+    }
+}
+
+pub enum MotionEvent {
+    Started,
+    Ended,
+}
+
+/// Start a thread sending the camera back to its `return_preset` once motion
+/// has been over for `return_delay`, if one is configured
+pub fn start_preset_return(camera: &CameraConfig) -> Option<Sender<MotionEvent>> {
+    let config = camera.onvif.clone()?;
+    let preset = config.return_preset.clone()?;
+    let delay = Duration::from_secs(config.return_delay.unwrap_or(DEFAULT_RETURN_DELAY_SECS));
+    let label = camera.label.clone();
+    let (tx, rx) = channel();
+
+    thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Failed to start PTZ runtime for {}: {}", label, e);
+                return;
+            }
+        };
+        let controller = PtzController::new(&label, config);
+
+        while let Ok(event) = rx.recv() {
+            if let MotionEvent::Started = event {
+                continue;
+            }
+            // Wait out the delay, starting over if motion resumes:
+            loop {
+                match rx.recv_timeout(delay) {
+                    Ok(MotionEvent::Started) => break,
+                    Ok(MotionEvent::Ended) => continue,
+                    Err(RecvTimeoutError::Timeout) => {
+                        info!("Returning {} to preset {}", label, preset);
+                        if let Err(e) = runtime.block_on(controller.goto_preset(&preset)) {
+                            error!("Failed to return {} to preset {}: {}", label, preset, e);
+                        }
+                        break;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        }
+    });
+
+    Some(tx)
+}
@@ -0,0 +1,523 @@
+//! Per-camera capture, detection and recording threads, started and stopped
+//! as cameras are added to, changed in or removed from the config
+
+use crate::arming;
+use crate::audio;
+use crate::camera_state::{CameraControl, CameraState};
+use crate::config::{self, CameraConfig, Config, MotionConfig};
+use crate::event::{ExternalTrigger, TriggerSender};
+use crate::frame::Frame;
+use crate::frame_reader;
+use crate::motion_detection::MotionDetector;
+use crate::notify::NoticeSender;
+use crate::onvif;
+use crate::web::{FeedUpdate, LiveFeed};
+
+use log::{error, info, warn};
+use serde::Serialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::channel as async_channel;
+use tokio::sync::mpsc::UnboundedSender;
+
+const ANNOTATED_BUFFER_SIZE: usize = 10;
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a restarted camera waits for its old frame reader to let go of
+/// the camera before starting anyway
+const STOP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The threads for a single camera
+struct Pipeline {
+    camera: CameraConfig,
+    control: Arc<CameraControl>,
+    running: Arc<AtomicBool>,
+    frame_reader: JoinHandle<()>,
+    triggers: TriggerSender,
+}
+
+impl Pipeline {
+    fn start(
+        camera: CameraConfig,
+        control: Arc<CameraControl>,
+        notice_tx: NoticeSender,
+        display_enabled: bool,
+        annotated_enabled: bool,
+        previous: Option<Receiver<()>>,
+    ) -> (Self, Option<LiveFeed>) {
+        info!("Starting camera {}", camera.label);
+        let running = Arc::new(AtomicBool::new(true));
+        let shared_camera = Arc::new(camera.clone());
+        let (motion_tx, motion_rx) = channel::<Arc<Frame>>();
+        let (web_tx, web_rx) = if display_enabled {
+            let (t, r) = async_channel::<Arc<Frame>>(1000);
+            (Some(t), Some(r))
+        } else {
+            (None, None)
+        };
+        let (annotated_tx, annotated_rx) = if annotated_enabled {
+            let (t, r) = async_channel::<Arc<Frame>>(ANNOTATED_BUFFER_SIZE);
+            (Some(t), Some(r))
+        } else {
+            (None, None)
+        };
+
+        let audio_tx = if camera.audio.unwrap_or(false) {
+            Some(audio::audio_bus())
+        } else {
+            None
+        };
+
+        // Stops by itself once the motion detector drops the receiver:
+        let (trigger_tx, trigger_rx) = channel::<ExternalTrigger>();
+        onvif::start_event_listener(&camera, trigger_tx.clone());
+
+        let tx_vec = vec![motion_tx];
+
+        let cam = Arc::clone(&shared_camera);
+        let reader_audio_tx = audio_tx.clone();
+        let reader_running = Arc::clone(&running);
+        let reader_control = Arc::clone(&control);
+        let frame_reader = thread::spawn(move || -> () {
+            // A restarted camera's old reader may still have it open:
+            if let Some(previous) = previous {
+                if let Err(RecvTimeoutError::Timeout) = previous.recv_timeout(STOP_TIMEOUT) {
+                    warn!(
+                        "Previous frame reader for {} hasn't stopped -- starting anyway",
+                        cam.label
+                    );
+                }
+            }
+            let label = cam.label.clone();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                frame_reader::start_frame_reader(
+                    cam,
+                    tx_vec,
+                    web_tx,
+                    reader_audio_tx,
+                    reader_running,
+                )
+            }));
+            let reason = match result {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => e.to_string(),
+                Err(panic) => panic_message(panic),
+            };
+            error!("Frame reader for {} failed: {}", label, reason);
+            reader_control.set_failed(reason);
+        });
+
+        // Stops once the frame reader does:
+        let cam = Arc::clone(&shared_camera);
+        let motion_audio_tx = audio_tx.clone();
+        let motion_control = Arc::clone(&control);
+        thread::spawn(move || -> () {
+            let mut md = MotionDetector::new(
+                cam,
+                motion_rx,
+                annotated_tx,
+                motion_audio_tx,
+                trigger_rx,
+                motion_control,
+                notice_tx,
+            );
+            md.start();
+        });
+
+        let feed = web_rx.map(|frames| LiveFeed {
+            frames,
+            annotated: annotated_rx,
+            audio: audio_tx,
+            triggers: trigger_tx.clone(),
+        });
+
+        (
+            Self {
+                camera,
+                control,
+                running,
+                frame_reader,
+                triggers: trigger_tx,
+            },
+            feed,
+        )
+    }
+
+    /// Stop reading frames; detection and recording wind down as their inputs close.
+    /// Doesn't wait, as a stalled camera can take a while to notice -- the
+    /// returned receiver hears once the frame reader has finished
+    fn stop(self) -> Receiver<()> {
+        info!("Stopping camera {}", self.camera.label);
+        self.running.store(false, Ordering::Relaxed);
+        let (done_tx, done_rx) = channel();
+        let frame_reader = self.frame_reader;
+        thread::spawn(move || {
+            // Failures are caught and recorded on the camera's control:
+            let _ = frame_reader.join();
+            let _ = done_tx.send(());
+        });
+        done_rx
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "frame reader panicked".to_string(),
+        },
+    }
+}
+
+pub type SharedPipelines = Arc<Mutex<Pipelines>>;
+
+#[derive(Debug)]
+pub enum CameraError {
+    NotFound,
+    Exists,
+    Invalid(Vec<String>),
+    /// the change couldn't be saved, so wasn't made
+    Save(anyhow::Error),
+}
+
+impl fmt::Display for CameraError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CameraError::NotFound => write!(f, "no such camera"),
+            CameraError::Exists => write!(f, "a camera with that label already exists"),
+            CameraError::Invalid(problems) => write!(f, "{}", problems.join("; ")),
+            CameraError::Save(e) => write!(f, "failed to save camera state: {}", e),
+        }
+    }
+}
+
+impl Error for CameraError {}
+
+#[derive(Serialize, Debug)]
+pub struct CameraStatus {
+    pub label: String,
+    pub camera_type: String,
+    pub recording: bool,
+    pub detection: bool,
+    /// added through the API rather than the config file
+    pub added: bool,
+    /// why the camera stopped sending frames, if it has; cleared when the
+    /// camera restarts, so not saved with the camera state
+    pub failed: Option<String>,
+}
+
+/// The running pipelines, one per configured camera
+pub struct Pipelines {
+    running: Vec<Pipeline>,
+    /// frame readers still finishing, by camera
+    stopping: HashMap<String, Receiver<()>>,
+    /// cameras from the config file, as of the last reload
+    configured: Vec<CameraConfig>,
+    state: CameraState,
+    /// detectors read this when they start, so changes restart every camera
+    motion: MotionConfig,
+    display_enabled: bool,
+    annotated_enabled: bool,
+    feeds: Option<UnboundedSender<FeedUpdate>>,
+    notice_tx: NoticeSender,
+}
+
+impl Pipelines {
+    /// Start every camera in `config` and the saved camera state, sending
+    /// live feeds to `feeds` if given
+    pub fn start(
+        config: &Config,
+        annotated_enabled: bool,
+        feeds: Option<UnboundedSender<FeedUpdate>>,
+        notice_tx: NoticeSender,
+    ) -> Self {
+        let mut pipelines = Self {
+            running: Vec::new(),
+            stopping: HashMap::new(),
+            configured: config.cameras.clone(),
+            state: CameraState::load(),
+            motion: config.motion.clone(),
+            display_enabled: feeds.is_some(),
+            annotated_enabled,
+            feeds,
+            notice_tx,
+        };
+        if let Some(mode) = &pipelines.state.arming_mode {
+            arming::set_mode(mode);
+        }
+        pipelines
+            .state
+            .cameras(config)
+            .into_iter()
+            .for_each(|camera| pipelines.start_camera(camera));
+        pipelines
+    }
+
+    fn start_camera(&mut self, camera: CameraConfig) {
+        let control = Arc::new(self.state.control(&camera.label));
+        let (pipeline, feed) = Pipeline::start(
+            camera.clone(),
+            control,
+            self.notice_tx.clone(),
+            self.display_enabled,
+            self.annotated_enabled,
+            self.stopping.remove(&camera.label),
+        );
+        if let (Some(tx), Some(feed)) = (&self.feeds, feed) {
+            // Only fails once the web server has gone:
+            let _ = tx.send(FeedUpdate::Added(camera, feed));
+        }
+        self.running.push(pipeline);
+    }
+
+    fn stop_camera(&mut self, label: &str) {
+        if let Some(i) = self.running.iter().position(|p| p.camera.label == label) {
+            let done = self.running.remove(i).stop();
+            self.stopping
+                .retain(|_, d| d.try_recv() == Err(TryRecvError::Empty));
+            self.stopping.insert(label.to_string(), done);
+            if let Some(tx) = &self.feeds {
+                let _ = tx.send(FeedUpdate::Removed(label.to_string()));
+            }
+        }
+    }
+
+    /// Restart the cameras that changed, stopping removed ones and starting new ones
+    pub fn apply(&mut self, config: &Config) {
+        let motion_changed = config.motion != self.motion;
+        self.motion = config.motion.clone();
+        self.configured = config.cameras.clone();
+        let cameras = self.state.cameras(config);
+
+        let stop: Vec<String> = self
+            .running
+            .iter()
+            .filter(|p| motion_changed || !cameras.contains(&p.camera))
+            .map(|p| p.camera.label.clone())
+            .collect();
+        for label in stop {
+            self.stop_camera(&label);
+        }
+
+        for camera in cameras {
+            if !self.running.iter().any(|p| p.camera == camera) {
+                self.start_camera(camera);
+            }
+        }
+    }
+
+    pub fn cameras(&self) -> Vec<CameraStatus> {
+        self.running
+            .iter()
+            .map(|p| CameraStatus {
+                label: p.camera.label.clone(),
+                camera_type: p.camera.camera_type.clone(),
+                recording: p.control.recording(),
+                detection: p.control.detection(),
+                added: self.state.added.iter().any(|c| c.label == p.camera.label),
+                failed: p.control.failure(),
+            })
+            .collect()
+    }
+
+    /// Save `state`, only keeping it if that worked
+    fn save_state(&mut self, state: CameraState) -> Result<(), CameraError> {
+        state.save().map_err(CameraError::Save)?;
+        self.state = state;
+        Ok(())
+    }
+
+    pub fn add_camera(&mut self, camera: CameraConfig) -> Result<(), CameraError> {
+        let problems = config::validate_camera(&camera);
+        if !problems.is_empty() {
+            return Err(CameraError::Invalid(problems));
+        }
+        if self.running.iter().any(|p| p.camera.label == camera.label) {
+            return Err(CameraError::Exists);
+        }
+
+        // A config file camera that was removed stays removed, so this takes its place:
+        let mut state = self.state.clone();
+        state.added.push(camera.clone());
+        self.save_state(state)?;
+
+        self.start_camera(camera);
+        Ok(())
+    }
+
+    pub fn remove_camera(&mut self, label: &str) -> Result<(), CameraError> {
+        if !self.running.iter().any(|p| p.camera.label == label) {
+            return Err(CameraError::NotFound);
+        }
+
+        let mut state = self.state.clone();
+        state.added.retain(|c| c.label != label);
+        if self.configured.iter().any(|c| c.label == label)
+            && !state.removed.iter().any(|l| l == label)
+        {
+            state.removed.push(label.to_string());
+        }
+        state.recording_disabled.retain(|l| l != label);
+        state.detection_paused.retain(|l| l != label);
+        self.save_state(state)?;
+
+        self.stop_camera(label);
+        Ok(())
+    }
+
+    fn control(&self, label: &str) -> Result<Arc<CameraControl>, CameraError> {
+        self.running
+            .iter()
+            .find(|p| p.camera.label == label)
+            .map(|p| Arc::clone(&p.control))
+            .ok_or(CameraError::NotFound)
+    }
+
+    /// Record motion events or not, detection carries on either way
+    pub fn set_recording(&mut self, label: &str, enabled: bool) -> Result<(), CameraError> {
+        let control = self.control(label)?;
+        let mut state = self.state.clone();
+        state.recording_disabled.retain(|l| l != label);
+        if !enabled {
+            state.recording_disabled.push(label.to_string());
+        }
+        self.save_state(state)?;
+        control.set_recording(enabled);
+        Ok(())
+    }
+
+    pub fn set_arming_mode(&mut self, mode: &str) -> Result<(), CameraError> {
+        if !arming::modes().iter().any(|m| m == mode) {
+            return Err(CameraError::Invalid(vec![format!(
+                "unknown arming mode '{}'",
+                mode
+            )]));
+        }
+        let mut state = self.state.clone();
+        state.arming_mode = Some(mode.to_string());
+        self.save_state(state)?;
+        arming::set_mode(mode);
+        Ok(())
+    }
+
+    /// Pass a trigger to the camera's motion detector
+    pub fn trigger(&self, label: &str, trigger: ExternalTrigger) -> Result<(), CameraError> {
+        let pipeline = self
+            .running
+            .iter()
+            .find(|p| p.camera.label == label)
+            .ok_or(CameraError::NotFound)?;
+        // The detector only goes once its camera is stopped:
+        let _ = pipeline.triggers.send(trigger);
+        Ok(())
+    }
+
+    /// Pause or resume motion and audio detection; external triggers still work
+    pub fn set_detection(&mut self, label: &str, enabled: bool) -> Result<(), CameraError> {
+        let control = self.control(label)?;
+        let mut state = self.state.clone();
+        state.detection_paused.retain(|l| l != label);
+        if !enabled {
+            state.detection_paused.push(label.to_string());
+        }
+        self.save_state(state)?;
+        control.set_detection(enabled);
+        Ok(())
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Start a thread reloading the config on SIGHUP or when its file changes,
+/// restarting only the pipelines affected
+pub fn watch_config(pipelines: SharedPipelines) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Failed to start config reload runtime: {}", e);
+                return;
+            }
+        };
+
+        runtime.block_on(async {
+            let path = config::config_path();
+            let mut modified = modified_time(&path);
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    error!(
+                        "Failed to listen for SIGHUP -- only watching the file: {}",
+                        e
+                    );
+                    None
+                }
+            };
+            let mut interval = tokio::time::interval(CONFIG_POLL_INTERVAL);
+
+            loop {
+                tokio::select! {
+                    Some(_) = async {
+                        match &mut hangup {
+                            Some(h) => h.recv().await,
+                            None => futures::future::pending().await,
+                        }
+                    } => info!("Received SIGHUP -- reloading config"),
+                    _ = interval.tick() => {
+                        if modified_time(&path) == modified {
+                            continue;
+                        }
+                        info!("{} changed -- reloading config", path.display());
+                    }
+                }
+                modified = modified_time(&path);
+
+                let previous = config::load_config(None);
+                match config::reload() {
+                    Ok(config) => {
+                        for section in previous.restart_required(&config) {
+                            warn!("Changes to {} take full effect after a restart", section);
+                        }
+                        pipelines.lock().unwrap().apply(&config);
+                    }
+                    Err(e) => error!("Keeping the current config, reload failed:\n{}", e),
+                }
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panic_messages() {
+        let message = |f: fn()| panic_message(panic::catch_unwind(f).unwrap_err());
+        assert_eq!(
+            message(|| panic!("Unknown camera type")),
+            "Unknown camera type"
+        );
+        assert_eq!(message(|| panic!("bad source {}", 1)), "bad source 1");
+        assert_eq!(
+            message(|| panic::resume_unwind(Box::new(1))),
+            "frame reader panicked"
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! Privacy masks, blanking parts of a camera's view as its frames are read so
+//! nothing downstream ever has the pixels under them
+
+use crate::config::{CameraConfig, MaskStyle, PrivacyMaskConfig};
+use crate::frame::Frame;
+
+use log::error;
+use opencv::core::{Point, Scalar, Size, CV_8UC1};
+use opencv::imgproc::{self, INTER_AREA, INTER_NEAREST, LINE_8};
+use opencv::prelude::*;
+use opencv::types::{VectorOfPoint, VectorOfVectorOfPoint};
+use opencv::Result;
+
+/// Size in pixels of the blocks pixelated masks are made of
+const PIXEL_BLOCK: i32 = 16;
+
+#[derive(Clone, Debug)]
+pub struct PrivacyMask {
+    masks: Vec<PrivacyMaskConfig>,
+}
+
+impl PrivacyMask {
+    /// `None` if `camera` has no masks
+    pub fn for_camera(camera: &CameraConfig) -> Option<Self> {
+        match &camera.privacy_masks {
+            Some(masks) if !masks.is_empty() => Some(Self {
+                masks: masks.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Mask polygons of `style`, in pixels for an image of `size`
+    fn polygons(&self, size: Size, style: Option<MaskStyle>) -> VectorOfVectorOfPoint {
+        self.masks
+            .iter()
+            .filter(|m| style.is_none() || m.style.unwrap_or(MaskStyle::Fill) == style.unwrap())
+            .map(|m| {
+                m.points
+                    .iter()
+                    .map(|[x, y]| {
+                        Point::new(
+                            (x * size.width as f64).round() as i32,
+                            (y * size.height as f64).round() as i32,
+                        )
+                    })
+                    .collect::<VectorOfPoint>()
+            })
+            .collect()
+    }
+
+    /// Blank the masked regions of a freshly read frame. A frame that can't
+    /// be masked is blanked entirely rather than let through
+    pub fn apply(&self, frame: &mut Frame) {
+        if let Err(e) = self.mask(frame.img_mut()) {
+            error!("Failed to apply privacy mask -- blanking frame: {}", e);
+            if let Err(e) = frame.img_mut().set_to(&Scalar::all(0.0), &Mat::default()) {
+                error!("Failed to blank frame: {}", e);
+            }
+        }
+    }
+
+    fn mask(&self, img: &mut Mat) -> Result<()> {
+        let size = img.size()?;
+
+        let pixelated = self.polygons(size, Some(MaskStyle::Pixelate));
+        if !pixelated.is_empty() {
+            let mut small = Mat::default();
+            imgproc::resize(
+                img,
+                &mut small,
+                Size::new(
+                    (size.width / PIXEL_BLOCK).max(1),
+                    (size.height / PIXEL_BLOCK).max(1),
+                ),
+                0.0,
+                0.0,
+                INTER_AREA,
+            )?;
+            let mut blocks = Mat::default();
+            imgproc::resize(&small, &mut blocks, size, 0.0, 0.0, INTER_NEAREST)?;
+
+            let mut region = Mat::new_rows_cols_with_default(
+                size.height,
+                size.width,
+                CV_8UC1,
+                Scalar::all(0.0),
+            )?;
+            fill(&mut region, &pixelated, Scalar::all(255.0))?;
+            blocks.copy_to_masked(img, &region)?;
+        }
+
+        let filled = self.polygons(size, Some(MaskStyle::Fill));
+        if !filled.is_empty() {
+            fill(img, &filled, Scalar::all(0.0))?;
+        }
+        Ok(())
+    }
+
+    /// Zero every masked region of a motion detection image, so pixelated
+    /// masks don't register as motion either
+    pub fn clear(&self, img: &mut Mat) -> Result<()> {
+        let polygons = self.polygons(img.size()?, None);
+        fill(img, &polygons, Scalar::all(0.0))
+    }
+}
+
+fn fill(img: &mut Mat, polygons: &VectorOfVectorOfPoint, color: Scalar) -> Result<()> {
+    imgproc::fill_poly(img, polygons, color, LINE_8, 0, Point::new(0, 0))
+}
@@ -0,0 +1,625 @@
+//! Minimal RTSP server re-publishing each camera's live H.264 packets.
+//!
+//! Only RTP over the RTSP TCP connection (interleaved) is supported, which
+//! every common client and NVR can fall back to.
+//!
+//! While auth is enabled clients log in with Basic auth as a user, or with an
+//! API token as the password, and only see the cameras they're allowed to.
+
+use crate::auth::{self, audit, User};
+use crate::config;
+use crate::video::{EncodedPacket, VideoRTCStream};
+use crate::web::Streams;
+
+use anyhow::{anyhow, Result};
+use bytes::{BufMut, BytesMut};
+use log::{debug, error, info, trace, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use webrtc::rtp::codecs::h264::H264Payloader;
+use webrtc::rtp::header::Header;
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp::packetizer::Payloader;
+use webrtc::util::marshal::Marshal;
+
+const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0:8554";
+const ANNOTATED_SUFFIX: &str = "motion";
+const RTP_MTU: usize = 1400;
+const RTP_HEADER_SIZE: usize = 12;
+const RTP_PAYLOAD_TYPE: u8 = 96;
+const RTP_CLOCK_RATE: i64 = 90000;
+const SESSION_TIMEOUT: u32 = 60;
+const AUTH_REALM: &str = "smartcam";
+/// Limits on what's read of a request, which comes in before any auth
+const MAX_LINE_LENGTH: usize = 4096;
+const MAX_HEADERS: usize = 64;
+const MAX_BODY_SIZE: usize = 4096;
+
+/// Path the motion-annotated variant of `label` is published under
+pub fn annotated_path(label: &str) -> String {
+    format!("{}/{}", label, ANNOTATED_SUFFIX)
+}
+
+/// The camera a stream path shows, whether annotated or not
+fn camera_label(path: &str) -> &str {
+    path.strip_suffix(&format!("/{}", ANNOTATED_SUFFIX))
+        .unwrap_or(path)
+}
+
+/// Username and password from a Basic `Authorization` header
+fn basic_credentials(header: &str) -> Option<(String, String)> {
+    let encoded = header.strip_prefix("Basic ")?.trim();
+    let decoded = String::from_utf8(base64::decode(encoded).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+pub async fn start(streams: Streams) -> () {
+    let config = config::load_config(None);
+    let addr = config
+        .rtsp_server
+        .bind_address
+        .clone()
+        .unwrap_or(DEFAULT_BIND_ADDRESS.to_string());
+
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Failed to bind RTSP server to {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("RTSP server listening on {}", addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((socket, peer)) => {
+                debug!("RTSP connection from {}", peer);
+                let streams = Arc::clone(&streams);
+                tokio::spawn(async move {
+                    if let Err(e) = RtspConnection::new(socket, peer, streams).run().await {
+                        warn!("RTSP connection from {} closed: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept RTSP connection: {}", e),
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    url: String,
+    headers: HashMap<String, String>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|s| s.as_str())
+    }
+
+    fn content_length(&self) -> usize {
+        self.header("content-length")
+            .and_then(|l| l.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Stream path from the request url, e.g. `front` from `rtsp://host:8554/front/trackID=0`
+    fn path(&self) -> String {
+        let path = match self.url.find("://") {
+            Some(i) => {
+                let rest = &self.url[i + 3..];
+                rest.find('/').map(|j| &rest[j..]).unwrap_or("")
+            }
+            None => self.url.as_str(),
+        };
+        let path = path.trim_matches('/');
+        path.strip_suffix("/trackID=0")
+            .unwrap_or(path)
+            .trim_matches('/')
+            .to_string()
+    }
+}
+
+struct RtspConnection {
+    socket: Option<TcpStream>,
+    peer: SocketAddr,
+    /// who the client logged in as, and the `Authorization` header they did
+    /// it with, so the password isn't checked again for every request
+    user: Option<(String, User)>,
+    writer: Option<Arc<Mutex<OwnedWriteHalf>>>,
+    streams: Streams,
+    session_id: String,
+    stream: Option<Arc<VideoRTCStream>>,
+    channel: u8,
+    forwarder: Option<JoinHandle<()>>,
+}
+
+impl RtspConnection {
+    fn new(socket: TcpStream, peer: SocketAddr, streams: Streams) -> Self {
+        Self {
+            socket: Some(socket),
+            peer,
+            user: None,
+            writer: None,
+            streams,
+            session_id: format!("{:016X}", rand::random::<u64>()),
+            stream: None,
+            channel: 0,
+            forwarder: None,
+        }
+    }
+
+    async fn run(mut self) -> Result<()> {
+        let (reader, writer) = self.socket.take().unwrap().into_split();
+        self.writer = Some(Arc::new(Mutex::new(writer)));
+        let mut reader = BufReader::new(reader);
+
+        let result = loop {
+            let buf = reader.fill_buf().await?;
+            if buf.is_empty() {
+                break Ok(());
+            }
+
+            // Interleaved RTCP from the client -- not used, so skip it:
+            if buf[0] == b'$' {
+                let mut hdr = [0u8; 4];
+                reader.read_exact(&mut hdr).await?;
+                let len = u16::from_be_bytes([hdr[2], hdr[3]]) as usize;
+                let mut discard = vec![0u8; len];
+                reader.read_exact(&mut discard).await?;
+                continue;
+            }
+
+            let request = read_request(&mut reader).await?;
+            trace!("RTSP request {} {}", request.method, request.url);
+            // Bodies are never needed for the supported methods:
+            let len = request.content_length();
+            if len > MAX_BODY_SIZE {
+                let cseq = request.header("cseq").unwrap_or("0");
+                self.respond(413, "Request Entity Too Large", cseq, &[], None)
+                    .await?;
+                break Ok(());
+            }
+            let mut discard = vec![0u8; len];
+            reader.read_exact(&mut discard).await?;
+            if !self.handle(request).await? {
+                break Ok(());
+            }
+        };
+
+        self.stop_forwarder();
+        result
+    }
+
+    /// Respond to a single request, returns false once the session is torn down
+    async fn handle(&mut self, request: Request) -> Result<bool> {
+        let cseq = request.header("cseq").unwrap_or("0").to_string();
+
+        match request.method.as_str() {
+            "OPTIONS" => {
+                self.respond(
+                    200,
+                    "OK",
+                    &cseq,
+                    &[(
+                        "Public",
+                        "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER",
+                    )],
+                    None,
+                )
+                .await?;
+            }
+            "DESCRIBE" => {
+                let path = request.path();
+                if self.authorize(&request, &cseq, &path).await?.is_none() {
+                    return Ok(true);
+                }
+                if !self.streams.read().unwrap().contains_key(&path) {
+                    self.respond(404, "Not Found", &cseq, &[], None).await?;
+                    return Ok(true);
+                }
+                let sdp = session_description(&path);
+                let base = format!("{}/", request.url.trim_end_matches('/'));
+                self.respond(
+                    200,
+                    "OK",
+                    &cseq,
+                    &[("Content-Type", "application/sdp"), ("Content-Base", &base)],
+                    Some(&sdp),
+                )
+                .await?;
+            }
+            "SETUP" => {
+                if self
+                    .authorize(&request, &cseq, &request.path())
+                    .await?
+                    .is_none()
+                {
+                    return Ok(true);
+                }
+                // Not held across the response below:
+                let stream = self
+                    .streams
+                    .read()
+                    .unwrap()
+                    .get(&request.path())
+                    .map(Arc::clone);
+                let stream = match stream {
+                    Some(s) => s,
+                    None => {
+                        self.respond(404, "Not Found", &cseq, &[], None).await?;
+                        return Ok(true);
+                    }
+                };
+                let transport = request.header("transport").unwrap_or("");
+                if !transport.contains("RTP/AVP/TCP") {
+                    self.respond(461, "Unsupported Transport", &cseq, &[], None)
+                        .await?;
+                    return Ok(true);
+                }
+                self.channel = interleaved_channel(transport).unwrap_or(0);
+                self.stream = Some(stream);
+
+                let transport = format!(
+                    "RTP/AVP/TCP;unicast;interleaved={}-{}",
+                    self.channel,
+                    self.channel + 1
+                );
+                let session = format!("{};timeout={}", self.session_id, SESSION_TIMEOUT);
+                self.respond(
+                    200,
+                    "OK",
+                    &cseq,
+                    &[("Transport", &transport), ("Session", &session)],
+                    None,
+                )
+                .await?;
+            }
+            "PLAY" => {
+                let stream = match &self.stream {
+                    Some(s) => Arc::clone(s),
+                    None => {
+                        self.respond(455, "Method Not Valid in This State", &cseq, &[], None)
+                            .await?;
+                        return Ok(true);
+                    }
+                };
+                let user = match self.authorize(&request, &cseq, stream.label()).await? {
+                    Some(u) => u,
+                    None => return Ok(true),
+                };
+                let session_id = self.session_id.clone();
+                self.respond(
+                    200,
+                    "OK",
+                    &cseq,
+                    &[("Session", &session_id), ("Range", "npt=0.000-")],
+                    None,
+                )
+                .await?;
+
+                if self.forwarder.is_none() {
+                    info!("RTSP client playing {}", stream.label());
+                    audit::record(
+                        &user,
+                        "rtsp_stream",
+                        Some(camera_label(stream.label())),
+                        None,
+                    );
+                    let writer = Arc::clone(self.writer.as_ref().unwrap());
+                    let rx = stream.subscribe_packets();
+                    let channel = self.channel;
+                    self.forwarder = Some(tokio::spawn(async move {
+                        if let Err(e) = forward_packets(rx, writer, channel).await {
+                            debug!("RTSP forwarding stopped: {}", e);
+                        }
+                    }));
+                }
+            }
+            "TEARDOWN" => {
+                self.stop_forwarder();
+                let session_id = self.session_id.clone();
+                self.respond(200, "OK", &cseq, &[("Session", &session_id)], None)
+                    .await?;
+                return Ok(false);
+            }
+            "GET_PARAMETER" | "SET_PARAMETER" => {
+                // Used by clients as a keepalive:
+                let session_id = self.session_id.clone();
+                self.respond(200, "OK", &cseq, &[("Session", &session_id)], None)
+                    .await?;
+            }
+            _ => {
+                self.respond(501, "Not Implemented", &cseq, &[], None)
+                    .await?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// The user behind `request` if they may see the stream at `path`,
+    /// otherwise `None` once the client has been told why
+    async fn authorize(
+        &mut self,
+        request: &Request,
+        cseq: &str,
+        path: &str,
+    ) -> Result<Option<User>> {
+        let user = match self.authenticate(request).await? {
+            Some(u) => u,
+            None => {
+                let challenge = format!("Basic realm=\"{}\"", AUTH_REALM);
+                self.respond(
+                    401,
+                    "Unauthorized",
+                    cseq,
+                    &[("WWW-Authenticate", &challenge)],
+                    None,
+                )
+                .await?;
+                return Ok(None);
+            }
+        };
+        if !user.can_view(camera_label(path)) {
+            self.respond(403, "Forbidden", cseq, &[], None).await?;
+            return Ok(None);
+        }
+        Ok(Some(user))
+    }
+
+    /// Anyone is an admin while auth is disabled, as on the web server.
+    /// Only Basic auth is offered, as Digest needs the plain password, which
+    /// isn't kept
+    async fn authenticate(&mut self, request: &Request) -> Result<Option<User>> {
+        let config = config::load_config(None);
+        let address = Some(self.peer.ip());
+        if !auth::enabled(&config.auth) {
+            return Ok(Some(User::anonymous(address)));
+        }
+
+        let header = request.header("authorization").unwrap_or("").to_string();
+        if let Some((h, user)) = &self.user {
+            if *h == header {
+                return Ok(Some(user.clone()));
+            }
+        }
+        let (username, password) = match basic_credentials(&header) {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        let name = username.clone();
+        let auth_config = config.auth.clone();
+        // Checking passwords is deliberately slow:
+        let user = tokio::task::spawn_blocking(move || {
+            auth::login(&auth_config, &username, &password, address)
+                .or_else(|| auth::token_user(&auth_config, &password, address))
+        })
+        .await?;
+        match user {
+            Some(user) => {
+                self.user = Some((header, user.clone()));
+                Ok(Some(user))
+            }
+            None => {
+                audit::record_failed_login(&name, address);
+                Ok(None)
+            }
+        }
+    }
+
+    async fn respond(
+        &self,
+        code: u16,
+        reason: &str,
+        cseq: &str,
+        headers: &[(&str, &str)],
+        body: Option<&str>,
+    ) -> Result<()> {
+        let mut response = format!("RTSP/1.0 {} {}\r\nCSeq: {}\r\n", code, reason, cseq);
+        response.push_str("Server: smartcam\r\n");
+        for (k, v) in headers {
+            response.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        match body {
+            Some(b) => {
+                response.push_str(&format!("Content-Length: {}\r\n\r\n", b.len()));
+                response.push_str(b);
+            }
+            None => response.push_str("\r\n"),
+        }
+
+        let writer = self.writer.as_ref().ok_or(anyhow!("Connection not started"))?;
+        writer.lock().await.write_all(response.as_bytes()).await?;
+        Ok(())
+    }
+
+    fn stop_forwarder(&mut self) {
+        if let Some(f) = self.forwarder.take() {
+            f.abort();
+        }
+    }
+}
+
+/// Read the request line and headers, leaving any body unread
+async fn read_request<R>(reader: &mut R) -> Result<Request>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = String::new();
+    read_line(reader, &mut line).await?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().ok_or(anyhow!("Empty request line"))?.to_string();
+    let url = parts.next().unwrap_or("*").to_string();
+
+    let mut headers = HashMap::new();
+    for _ in 0..=MAX_HEADERS {
+        if read_line(reader, &mut line).await? == 0 {
+            return Err(anyhow!("Connection closed mid-request"));
+        }
+        let l = line.trim_end();
+        if l.is_empty() {
+            return Ok(Request {
+                method,
+                url,
+                headers,
+            });
+        }
+        if let Some((k, v)) = l.split_once(':') {
+            headers.insert(k.trim().to_lowercase(), v.trim().to_string());
+        }
+    }
+    Err(anyhow!("More than {} headers", MAX_HEADERS))
+}
+
+/// Replace `line` with the next line, failing on one over `MAX_LINE_LENGTH`
+async fn read_line<R>(reader: &mut R, line: &mut String) -> Result<usize>
+where
+    R: AsyncBufRead + Unpin,
+{
+    line.clear();
+    let n = (&mut *reader)
+        .take(MAX_LINE_LENGTH as u64)
+        .read_line(line)
+        .await?;
+    if n == MAX_LINE_LENGTH && !line.ends_with('\n') {
+        return Err(anyhow!("Line longer than {} bytes", MAX_LINE_LENGTH));
+    }
+    Ok(n)
+}
+
+fn interleaved_channel(transport: &str) -> Option<u8> {
+    transport
+        .split(';')
+        .find_map(|p| p.trim().strip_prefix("interleaved="))
+        .and_then(|p| p.split('-').next())
+        .and_then(|c| c.parse().ok())
+}
+
+fn session_description(path: &str) -> String {
+    format!(
+        "v=0\r\n\
+         o=- 0 0 IN IP4 0.0.0.0\r\n\
+         s=smartcam {}\r\n\
+         c=IN IP4 0.0.0.0\r\n\
+         t=0 0\r\n\
+         a=control:*\r\n\
+         m=video 0 RTP/AVP {}\r\n\
+         a=rtpmap:{} H264/{}\r\n\
+         a=fmtp:{} packetization-mode=1\r\n\
+         a=control:trackID=0\r\n",
+        path, RTP_PAYLOAD_TYPE, RTP_PAYLOAD_TYPE, RTP_CLOCK_RATE, RTP_PAYLOAD_TYPE
+    )
+}
+
+async fn forward_packets(
+    mut rx: Receiver<Arc<EncodedPacket>>,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    channel: u8,
+) -> Result<()> {
+    let mut payloader = H264Payloader::default();
+    let ssrc = rand::random::<u32>();
+    let mut sequence_number = rand::random::<u16>();
+    let mut started = false;
+
+    loop {
+        let p = match rx.recv().await {
+            Ok(p) => p,
+            Err(RecvError::Lagged(n)) => {
+                warn!("RTSP client lagged by {} packets -- waiting for keyframe", n);
+                started = false;
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        // Clients can't decode anything before the first keyframe:
+        if !started {
+            if !p.is_key {
+                continue;
+            }
+            started = true;
+        }
+
+        let pts = p.pts.unwrap_or(0);
+        let timestamp = (pts * RTP_CLOCK_RATE * p.time_base.numerator() as i64
+            / p.time_base.denominator() as i64) as u32;
+
+        let payloads = payloader.payload(RTP_MTU - RTP_HEADER_SIZE, &p.data)?;
+        let num_payloads = payloads.len();
+        let mut buf = BytesMut::new();
+        for (i, payload) in payloads.into_iter().enumerate() {
+            let packet = Packet {
+                header: Header {
+                    version: 2,
+                    marker: i == num_payloads - 1,
+                    payload_type: RTP_PAYLOAD_TYPE,
+                    sequence_number,
+                    timestamp,
+                    ssrc,
+                    ..Default::default()
+                },
+                payload,
+            };
+            sequence_number = sequence_number.wrapping_add(1);
+
+            let raw = packet.marshal()?;
+            buf.put_u8(b'$');
+            buf.put_u8(channel);
+            buf.put_u16(raw.len() as u16);
+            buf.extend_from_slice(&raw);
+        }
+
+        writer.lock().await.write_all(&buf).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_request() {
+        let mut input: &[u8] =
+            b"OPTIONS rtsp://cam/front RTSP/1.0\r\nCSeq: 2\r\nContent-Length: 3\r\n\r\nabc";
+        let request = read_request(&mut input).await.unwrap();
+        assert_eq!(request.method, "OPTIONS");
+        assert_eq!(request.path(), "front");
+        assert_eq!(request.header("CSeq"), Some("2"));
+        assert_eq!(request.content_length(), 3);
+        // The body is left for the caller:
+        assert_eq!(input, b"abc");
+    }
+
+    #[tokio::test]
+    async fn rejects_long_lines() {
+        let long = format!("OPTIONS {} RTSP/1.0\r\n\r\n", "a".repeat(MAX_LINE_LENGTH));
+        assert!(read_request(&mut long.as_bytes()).await.is_err());
+
+        let long = format!(
+            "OPTIONS * RTSP/1.0\r\nX: {}\r\n\r\n",
+            "a".repeat(MAX_LINE_LENGTH)
+        );
+        assert!(read_request(&mut long.as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_too_many_headers() {
+        let mut request = "OPTIONS * RTSP/1.0\r\n".to_string();
+        for i in 0..MAX_HEADERS {
+            request.push_str(&format!("X-{}: 1\r\n", i));
+        }
+        let mut complete = format!("{}\r\n", request);
+        assert!(read_request(&mut complete.as_bytes()).await.is_ok());
+        complete = format!("{}X: 1\r\n\r\n", request);
+        assert!(read_request(&mut complete.as_bytes()).await.is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! G.711 companding, the codec practically every camera speaker accepts
+
+use crate::config::BackchannelCodec;
+
+const ULAW_BIAS: i32 = 0x84;
+const ULAW_CLIP: i32 = 32635;
+
+/// RTP static payload type for the codec
+pub fn payload_type(codec: BackchannelCodec) -> u8 {
+    match codec {
+        BackchannelCodec::Pcmu => 0,
+        BackchannelCodec::Pcma => 8,
+    }
+}
+
+/// MIME type used when posting raw G.711 over HTTP
+pub fn content_type(codec: BackchannelCodec) -> &'static str {
+    match codec {
+        BackchannelCodec::Pcmu => "audio/basic",
+        BackchannelCodec::Pcma => "audio/x-alaw-basic",
+    }
+}
+
+pub fn encode(codec: BackchannelCodec, samples: &[i16]) -> Vec<u8> {
+    match codec {
+        BackchannelCodec::Pcmu => samples.iter().map(|s| linear_to_ulaw(*s)).collect(),
+        BackchannelCodec::Pcma => samples.iter().map(|s| linear_to_alaw(*s)).collect(),
+    }
+}
+
+pub fn decode(codec: BackchannelCodec, data: &[u8]) -> Vec<i16> {
+    match codec {
+        BackchannelCodec::Pcmu => data.iter().map(|b| ulaw_to_linear(*b)).collect(),
+        BackchannelCodec::Pcma => data.iter().map(|b| alaw_to_linear(*b)).collect(),
+    }
+}
+
+/// Position of the highest set bit between bits 7 and 14, as a segment number
+fn segment(s: i32) -> i32 {
+    let mut exponent = 7;
+    let mut mask = 0x4000;
+    while exponent > 0 && (s & mask) == 0 {
+        exponent -= 1;
+        mask >>= 1;
+    }
+    exponent
+}
+
+fn linear_to_ulaw(sample: i16) -> u8 {
+    let mut s = sample as i32;
+    let sign = if s < 0 {
+        s = -s;
+        0x80
+    } else {
+        0
+    };
+    s = s.min(ULAW_CLIP) + ULAW_BIAS;
+
+    let exponent = segment(s);
+    let mantissa = (s >> (exponent + 3)) & 0x0F;
+    !(sign | (exponent << 4) | mantissa) as u8
+}
+
+fn ulaw_to_linear(u: u8) -> i16 {
+    let u = !u;
+    let exponent = ((u >> 4) & 0x07) as i32;
+    let mantissa = (u & 0x0F) as i32;
+    let s = ((((mantissa << 3) + ULAW_BIAS) << exponent) - ULAW_BIAS) as i16;
+    if u & 0x80 != 0 {
+        -s
+    } else {
+        s
+    }
+}
+
+fn linear_to_alaw(sample: i16) -> u8 {
+    let mut s = sample as i32;
+    let sign = if s >= 0 {
+        0x80
+    } else {
+        s = -s - 1;
+        0
+    };
+
+    let (exponent, mantissa) = if s >= 256 {
+        let exponent = segment(s);
+        (exponent, (s >> (exponent + 3)) & 0x0F)
+    } else {
+        (0, s >> 4)
+    };
+    ((sign | (exponent << 4) | mantissa) ^ 0x55) as u8
+}
+
+fn alaw_to_linear(a: u8) -> i16 {
+    let a = a ^ 0x55;
+    let exponent = ((a >> 4) & 0x07) as i32;
+    let mantissa = (a & 0x0F) as i32;
+    let s = match exponent {
+        0 => (mantissa << 4) + 8,
+        e => ((mantissa << 4) + 0x108) << (e - 1),
+    } as i16;
+    if a & 0x80 != 0 {
+        s
+    } else {
+        -s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_round_trip() {
+        for b in 0..=255u8 {
+            // Negative zero comes back positive:
+            if b != 0x7f {
+                assert_eq!(linear_to_ulaw(ulaw_to_linear(b)), b, "ulaw {:#04x}", b);
+            }
+            assert_eq!(linear_to_alaw(alaw_to_linear(b)), b, "alaw {:#04x}", b);
+        }
+    }
+
+    #[test]
+    fn samples_round_trip_within_a_step() {
+        // Clipping aside, the error is at most half a segment's step:
+        for x in -32000..=32000i32 {
+            let ulaw = ulaw_to_linear(linear_to_ulaw(x as i16)) as i32;
+            assert!(
+                (ulaw - x).abs() <= x.abs() / 16 + 16,
+                "ulaw {} -> {}",
+                x,
+                ulaw
+            );
+            let alaw = alaw_to_linear(linear_to_alaw(x as i16)) as i32;
+            assert!(
+                (alaw - x).abs() <= x.abs() / 32 + 16,
+                "alaw {} -> {}",
+                x,
+                alaw
+            );
+        }
+    }
+
+    #[test]
+    fn known_codes() {
+        assert_eq!(linear_to_ulaw(0), 0xff);
+        assert_eq!(linear_to_alaw(0), 0xd5);
+        assert_eq!(linear_to_ulaw(i16::MIN), 0x00);
+        assert_eq!(ulaw_to_linear(0x00), -32124);
+        assert_eq!(alaw_to_linear(0x2a), -32256);
+    }
+
+    #[test]
+    fn encode_decode_by_codec() {
+        let samples = [0, 1000, -1000, 20000, -20000];
+        for codec in [BackchannelCodec::Pcmu, BackchannelCodec::Pcma].iter() {
+            let encoded = encode(*codec, &samples);
+            assert_eq!(encoded.len(), samples.len());
+            let decoded = decode(*codec, &encoded);
+            for (s, d) in samples.iter().zip(&decoded) {
+                assert!((*s as i32 - *d as i32).abs() <= (*s as i32).abs() / 16 + 16);
+            }
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use super::{g711, Backchannel};
+use crate::config::BackchannelCodec;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::SinkExt;
+use log::{debug, error};
+use reqwest::{Body, Method};
+use tokio::task::JoinHandle;
+
+/// Chunks buffered before the request falls behind and `send` fails
+const BODY_BUFFER_SIZE: usize = 50;
+
+/// Streams G.711 audio as a chunked HTTP request body, as used by e.g.
+/// Axis (`/axis-cgi/audio/transmit.cgi`) and Hikvision (`/ISAPI/System/TwoWayAudio/...`)
+pub struct HttpBackchannel {
+    codec: BackchannelCodec,
+    body_tx: Option<mpsc::Sender<Result<Vec<u8>, std::io::Error>>>,
+    request: Option<JoinHandle<()>>,
+}
+
+impl HttpBackchannel {
+    pub fn new(
+        url: &str,
+        method: &str,
+        content_type: Option<&str>,
+        credentials: Option<(String, Option<String>)>,
+        codec: BackchannelCodec,
+    ) -> Result<Self> {
+        let method = Method::from_bytes(method.to_uppercase().as_bytes())?;
+        let (body_tx, body_rx) = mpsc::channel(BODY_BUFFER_SIZE);
+
+        let mut request = reqwest::Client::new()
+            .request(method, url)
+            .header(
+                "Content-Type",
+                content_type.unwrap_or(g711::content_type(codec)),
+            )
+            .body(Body::wrap_stream(body_rx));
+        if let Some((username, password)) = credentials {
+            request = request.basic_auth(username, password);
+        }
+
+        let url = url.to_string();
+        let request = tokio::spawn(async move {
+            match request.send().await {
+                Ok(r) if r.status().is_success() => {
+                    debug!("Backchannel request to {} finished", url)
+                }
+                Ok(r) => error!("Backchannel request to {} failed: {}", url, r.status()),
+                Err(e) => error!("Backchannel request to {} failed: {}", url, e),
+            }
+        });
+
+        Ok(Self {
+            codec,
+            body_tx: Some(body_tx),
+            request: Some(request),
+        })
+    }
+}
+
+#[async_trait]
+impl Backchannel for HttpBackchannel {
+    async fn send(&mut self, samples: &[i16]) -> Result<()> {
+        let tx = self
+            .body_tx
+            .as_mut()
+            .ok_or(anyhow!("Backchannel is closed"))?;
+        tx.send(Ok(g711::encode(self.codec, samples)))
+            .await
+            .map_err(|_| anyhow!("Backchannel request ended"))
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        // Dropping the sender ends the request body:
+        self.body_tx.take();
+        if let Some(request) = self.request.take() {
+            request.await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+use super::{g711, Backchannel};
+use crate::config::BackchannelCodec;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use log::{debug, info};
+use std::io::{SeekFrom, Write};
+use std::path::PathBuf;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+
+const WAV_HEADER_SIZE: u32 = 44;
+
+/// Stands in for a camera: audio goes through the same G.711 encoding a
+/// camera would receive, then is decoded back into a WAV file
+pub struct LoopbackBackchannel {
+    path: PathBuf,
+    codec: BackchannelCodec,
+    sample_rate: u32,
+    writer: BufWriter<File>,
+    data_len: u32,
+}
+
+impl LoopbackBackchannel {
+    pub async fn new(path: PathBuf, codec: BackchannelCodec, sample_rate: u32) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(&path).await?);
+        writer.write_all(&wav_header(sample_rate, 0)?).await?;
+        info!("Writing talkback audio to {}", path.display());
+        Ok(Self {
+            path,
+            codec,
+            sample_rate,
+            writer,
+            data_len: 0,
+        })
+    }
+}
+
+#[async_trait]
+impl Backchannel for LoopbackBackchannel {
+    async fn send(&mut self, samples: &[i16]) -> Result<()> {
+        let encoded = g711::encode(self.codec, samples);
+        let mut pcm = Vec::with_capacity(samples.len() * 2);
+        for s in g711::decode(self.codec, &encoded) {
+            pcm.extend_from_slice(&s.to_le_bytes());
+        }
+        self.writer.write_all(&pcm).await?;
+        self.data_len += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        // Rewrite the header now the length is known:
+        self.writer.seek(SeekFrom::Start(0)).await?;
+        let header = wav_header(self.sample_rate, self.data_len)?;
+        self.writer.write_all(&header).await?;
+        self.writer.flush().await?;
+        debug!(
+            "Wrote {} bytes of talkback audio to {}",
+            self.data_len,
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+/// 16-bit mono PCM
+fn wav_header(sample_rate: u32, data_len: u32) -> Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(WAV_HEADER_SIZE as usize);
+    write_wav_header(&mut header, sample_rate, data_len)?;
+    Ok(header)
+}
+
+fn write_wav_header<W: Write>(w: &mut W, sample_rate: u32, data_len: u32) -> Result<()> {
+    w.write_all(b"RIFF")?;
+    w.write_all(&(WAV_HEADER_SIZE - 8 + data_len).to_le_bytes())?;
+    w.write_all(b"WAVEfmt ")?;
+    w.write_all(&16u32.to_le_bytes())?;
+    w.write_all(&1u16.to_le_bytes())?;
+    w.write_all(&1u16.to_le_bytes())?;
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&(sample_rate * 2).to_le_bytes())?;
+    w.write_all(&2u16.to_le_bytes())?;
+    w.write_all(&16u16.to_le_bytes())?;
+    w.write_all(b"data")?;
+    w.write_all(&data_len.to_le_bytes())?;
+    Ok(())
+}
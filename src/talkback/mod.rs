@@ -0,0 +1,408 @@
+//! Two-way audio: the viewer's microphone, received as an Opus WebRTC track,
+//! is decoded and forwarded to the camera's speaker over its backchannel.
+//! The backchannel is only open while the viewer is talking, so a muted
+//! microphone doesn't keep the camera's speaker.
+
+mod g711;
+mod http;
+mod loopback;
+mod rtsp;
+
+use crate::config::{BackchannelCodec, BackchannelType, CameraConfig};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use ffmpeg::format::{sample, Sample};
+use ffmpeg::software::resampling;
+use ffmpeg::util::frame::audio::Audio;
+use ffmpeg::{codec, decoder, ChannelLayout, Packet};
+use ffmpeg_next as ffmpeg;
+use log::{debug, error, info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc::{channel as async_channel, Sender as AsyncSender};
+use webrtc::rtp;
+use webrtc::track::track_remote::TrackRemote;
+
+/// G.711 is always 8kHz
+pub const BACKCHANNEL_SAMPLE_RATE: u32 = 8000;
+const PCM_BUFFER_SIZE: usize = 50;
+const DEFAULT_HTTP_METHOD: &str = "POST";
+const DEFAULT_LOOPBACK_DIR: &str = "/tmp";
+/// The backchannel is closed after this long without sound from the viewer
+const MUTE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Opus packets no bigger than this carry no sound, e.g. DTX or the
+/// silence a browser sends for a muted track
+const SILENT_PAYLOAD_SIZE: usize = 3;
+
+/// Somewhere to send mono PCM at `BACKCHANNEL_SAMPLE_RATE`
+#[async_trait]
+pub trait Backchannel: Send {
+    async fn send(&mut self, samples: &[i16]) -> Result<()>;
+    async fn close(&mut self) -> Result<()>;
+}
+
+/// Whether the camera has a backchannel configured
+pub fn enabled(camera: &CameraConfig) -> bool {
+    camera.backchannel.is_some()
+}
+
+/// Where a loopback backchannel writes, one file for each time `session`
+/// starts talking
+fn loopback_path(dir: &Path, label: &str, session: &str, span: u32) -> PathBuf {
+    dir.join(format!(
+        "smartcam-talkback-{}-{}-{}.wav",
+        label, session, span
+    ))
+}
+
+async fn connect(camera: &CameraConfig, session: &str, span: u32) -> Result<Box<dyn Backchannel>> {
+    let config = camera
+        .backchannel
+        .as_ref()
+        .ok_or(anyhow!("No backchannel configured for {}", camera.label))?;
+    let codec = config.codec.unwrap_or(BackchannelCodec::Pcmu);
+    let credentials = config
+        .username
+        .clone()
+        .map(|u| (u, config.password.clone()));
+
+    Ok(match config.backchannel_type {
+        BackchannelType::Rtsp => {
+            let url = config
+                .url
+                .as_deref()
+                .or(camera.source.as_deref())
+                .ok_or(anyhow!("No RTSP url for {} backchannel", camera.label))?;
+            Box::new(rtsp::RtspBackchannel::connect(url, credentials).await?)
+        }
+        BackchannelType::Http => {
+            let url = config
+                .url
+                .as_deref()
+                .ok_or(anyhow!("No url for {} HTTP backchannel", camera.label))?;
+            Box::new(http::HttpBackchannel::new(
+                url,
+                config.http_method.as_deref().unwrap_or(DEFAULT_HTTP_METHOD),
+                config.content_type.as_deref(),
+                credentials,
+                codec,
+            )?)
+        }
+        BackchannelType::Loopback => {
+            let dir = config.url.as_deref().unwrap_or(DEFAULT_LOOPBACK_DIR);
+            let path = loopback_path(Path::new(dir), &camera.label, session, span);
+            Box::new(
+                loopback::LoopbackBackchannel::new(path, codec, BACKCHANNEL_SAMPLE_RATE).await?,
+            )
+        }
+    })
+}
+
+/// Forward a viewer's audio track to the camera until the track ends
+pub async fn forward_track(camera: CameraConfig, session: String, track: Arc<TrackRemote>) {
+    let mime_type = track.codec().await.capability.mime_type;
+    if !mime_type.eq_ignore_ascii_case("audio/opus") {
+        warn!("Ignoring {} talkback track for {}", mime_type, camera.label);
+        return;
+    }
+
+    let (payload_tx, payload_rx) = channel::<Bytes>();
+    let label = camera.label.clone();
+    tokio::spawn(async move {
+        while let Ok((packet, _)) = track.read_rtp().await {
+            if let Some(payload) = audio_payload(&packet) {
+                if payload_tx.send(payload).is_err() {
+                    break;
+                }
+            }
+        }
+        debug!("Talkback track for {} ended", label);
+    });
+
+    forward(camera, session, payload_rx).await;
+}
+
+/// The Opus payload of `packet`, if it has any sound
+fn audio_payload(packet: &rtp::packet::Packet) -> Option<Bytes> {
+    if packet.payload.len() <= SILENT_PAYLOAD_SIZE {
+        None
+    } else {
+        Some(packet.payload.clone())
+    }
+}
+
+/// Decode Opus payloads and send them to the camera until `payload_rx` hangs
+/// up, opening the backchannel as sound arrives and closing it once the
+/// viewer has been quiet for `MUTE_TIMEOUT`
+async fn forward(camera: CameraConfig, session: String, payload_rx: Receiver<Bytes>) {
+    let (pcm_tx, mut pcm_rx) = async_channel(PCM_BUFFER_SIZE);
+    let label = camera.label.clone();
+    thread::spawn(move || match OpusDecoderThread::new(payload_rx, pcm_tx) {
+        Ok(mut dec) => dec.start(),
+        Err(e) => error!("Failed to start talkback decoder for {}: {}", label, e),
+    });
+
+    let mut backchannel: Option<Box<dyn Backchannel>> = None;
+    let mut spans = 0;
+    loop {
+        let samples = match tokio::time::timeout(MUTE_TIMEOUT, pcm_rx.recv()).await {
+            Ok(Some(samples)) => samples,
+            Ok(None) => break,
+            // Muted, or just not talking:
+            Err(_) => {
+                if let Some(b) = backchannel.take() {
+                    close(b, &camera.label).await;
+                }
+                continue;
+            }
+        };
+
+        if backchannel.is_none() {
+            spans += 1;
+            match connect(&camera, &session, spans).await {
+                Ok(b) => {
+                    info!("Talkback started for {}", camera.label);
+                    backchannel = Some(b);
+                }
+                Err(e) => {
+                    error!("Failed to open backchannel for {}: {}", camera.label, e);
+                    return;
+                }
+            }
+        }
+        if let Err(e) = backchannel.as_mut().unwrap().send(&samples).await {
+            error!("Backchannel for {} failed: {}", camera.label, e);
+            break;
+        }
+    }
+    if let Some(b) = backchannel.take() {
+        close(b, &camera.label).await;
+    }
+}
+
+async fn close(mut backchannel: Box<dyn Backchannel>, label: &str) {
+    if let Err(e) = backchannel.close().await {
+        warn!("Failed to close backchannel for {}: {}", label, e);
+    }
+    info!("Talkback ended for {}", label);
+}
+
+/// Decodes Opus RTP payloads to mono PCM at `BACKCHANNEL_SAMPLE_RATE`
+struct OpusDecoderThread {
+    payload_rx: Receiver<Bytes>,
+    decoder: decoder::Audio,
+    pcm_tx: AsyncSender<Vec<i16>>,
+    resampler: Option<resampling::Context>,
+}
+
+impl OpusDecoderThread {
+    fn new(payload_rx: Receiver<Bytes>, pcm_tx: AsyncSender<Vec<i16>>) -> Result<Self> {
+        let codec = decoder::find(codec::Id::OPUS).ok_or(anyhow!("No Opus decoder found"))?;
+        let decoder = codec::Context::new().decoder().open_as(codec)?.audio()?;
+        Ok(Self {
+            payload_rx,
+            decoder,
+            pcm_tx,
+            resampler: None,
+        })
+    }
+
+    fn start(&mut self) {
+        // Ends once the track reader hangs up:
+        while let Ok(payload) = self.payload_rx.recv() {
+            if let Err(e) = self.decoder.send_packet(&Packet::copy(&payload)) {
+                warn!("Error decoding talkback packet: {} -- dropping", e);
+                continue;
+            }
+            match self.receive_and_process_decoded_frames() {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(e) => error!("Failed to process talkback audio: {}", e),
+            }
+        }
+    }
+
+    /// Returns false once the backchannel has gone away
+    fn receive_and_process_decoded_frames(&mut self) -> Result<bool> {
+        let mut decoded = Audio::empty();
+
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            // The layout is only known once a frame has been decoded:
+            if decoded.channel_layout().is_empty() {
+                decoded.set_channel_layout(ChannelLayout::default(decoded.channels() as i32));
+            }
+            if self.resampler.is_none() {
+                self.resampler = Some(resampling::Context::get(
+                    decoded.format(),
+                    decoded.channel_layout(),
+                    decoded.rate(),
+                    Sample::I16(sample::Type::Packed),
+                    ChannelLayout::MONO,
+                    BACKCHANNEL_SAMPLE_RATE,
+                )?);
+            }
+
+            let mut resampled = Audio::empty();
+            self.resampler
+                .as_mut()
+                .unwrap()
+                .run(&decoded, &mut resampled)?;
+
+            let n = resampled.samples();
+            let samples = resampled.data(0)[..n * 2]
+                .chunks_exact(2)
+                .map(|b| i16::from_ne_bytes([b[0], b[1]]))
+                .collect();
+            if self.pcm_tx.blocking_send(samples).is_err() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{AudioEncoder, AudioFrame, AUDIO_SAMPLE_RATE};
+
+    use chrono::Utc;
+    use std::f64::consts::PI;
+    use std::fs;
+    use webrtc::rtp::header::Header;
+
+    const WAV_HEADER_SIZE: usize = 44;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("smartcam-talkback-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn loopback_camera(dir: &Path) -> CameraConfig {
+        toml::from_str(&format!(
+            r#"
+            label = "door"
+            camera_type = "rtsp"
+
+            [backchannel]
+            backchannel_type = "loopback"
+            url = "{}"
+            codec = "pcma"
+            "#,
+            dir.display()
+        ))
+        .unwrap()
+    }
+
+    /// RTP packets carrying a second of a 440Hz tone, as a browser would send
+    fn tone_packets() -> Vec<rtp::packet::Packet> {
+        ffmpeg::init().unwrap();
+        let mut encoder = AudioEncoder::new(codec::Id::OPUS, None, false).unwrap();
+        let samples = (0..AUDIO_SAMPLE_RATE)
+            .map(|i| {
+                ((i as f64 * 440.0 * 2.0 * PI / AUDIO_SAMPLE_RATE as f64).sin() * 8000.0) as i16
+            })
+            .collect();
+        encoder.send(&AudioFrame::new(samples, Utc::now())).unwrap();
+        encoder.send_eof().unwrap();
+
+        let mut packets = Vec::new();
+        let mut encoded = Packet::empty();
+        while encoder.receive_packet(&mut encoded) {
+            let n = packets.len();
+            packets.push(rtp::packet::Packet {
+                header: Header {
+                    version: 2,
+                    payload_type: 111,
+                    sequence_number: n as u16,
+                    timestamp: n as u32 * 960,
+                    ..Default::default()
+                },
+                payload: Bytes::copy_from_slice(encoded.data().unwrap()),
+            });
+        }
+        packets
+    }
+
+    /// The samples in a finished WAV file
+    fn read_wav(path: &Path) -> Vec<i16> {
+        let wav = fs::read(path).unwrap();
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        let data_len = u32::from_le_bytes([wav[40], wav[41], wav[42], wav[43]]) as usize;
+        assert_eq!(data_len, wav.len() - WAV_HEADER_SIZE);
+        wav[WAV_HEADER_SIZE..]
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn silent_payloads_are_dropped() {
+        let silence = rtp::packet::Packet {
+            header: Header::default(),
+            payload: Bytes::from_static(&[0xf8, 0xff, 0xfe]),
+        };
+        assert_eq!(audio_payload(&silence), None);
+    }
+
+    #[tokio::test]
+    async fn rtp_reaches_the_backchannel() {
+        let dir = test_dir("forward");
+        let (payload_tx, payload_rx) = channel();
+        for packet in tone_packets() {
+            payload_tx.send(audio_payload(&packet).unwrap()).unwrap();
+        }
+        drop(payload_tx);
+
+        forward(loopback_camera(&dir), "a1".to_string(), payload_rx).await;
+
+        let samples = read_wav(&loopback_path(&dir, "door", "a1", 1));
+        // About a second at 8kHz, less what the decoder and resampler hold back:
+        let rate = BACKCHANNEL_SAMPLE_RATE as usize;
+        assert!(
+            samples.len() > rate * 3 / 4 && samples.len() <= rate * 11 / 10,
+            "{} samples",
+            samples.len()
+        );
+        let peak = samples.iter().map(|s| s.unsigned_abs()).max().unwrap();
+        assert!(peak > 4000, "peak {}", peak);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn quiet_viewer_closes_backchannel() {
+        let dir = test_dir("mute");
+        let packets = tone_packets();
+        let (first, second) = packets.split_at(packets.len() / 2);
+
+        let (payload_tx, payload_rx) = channel();
+        let forwarding = tokio::spawn(forward(loopback_camera(&dir), "b2".to_string(), payload_rx));
+        for packet in first {
+            payload_tx.send(audio_payload(packet).unwrap()).unwrap();
+        }
+
+        // Muted, so the first file is finished:
+        tokio::time::sleep(MUTE_TIMEOUT + Duration::from_secs(1)).await;
+        assert!(!read_wav(&loopback_path(&dir, "door", "b2", 1)).is_empty());
+        assert!(!loopback_path(&dir, "door", "b2", 2).exists());
+
+        // Talking again opens another:
+        for packet in second {
+            payload_tx.send(audio_payload(packet).unwrap()).unwrap();
+        }
+        drop(payload_tx);
+        forwarding.await.unwrap();
+        assert!(!read_wav(&loopback_path(&dir, "door", "b2", 2)).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
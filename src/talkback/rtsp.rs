@@ -0,0 +1,478 @@
+//! ONVIF RTSP backchannel client.
+//!
+//! The camera advertises a `sendonly` audio media in its SDP when the
+//! `www.onvif.org/ver20/backchannel` requirement is given; it's set up over
+//! TCP interleaved transport and RTP is sent on that channel.
+
+use super::{g711, Backchannel, BACKCHANNEL_SAMPLE_RATE};
+use crate::config::BackchannelCodec;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use log::{debug, info, trace};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use url::Url;
+use webrtc::rtp::header::Header;
+use webrtc::rtp::packet::Packet;
+use webrtc::util::marshal::Marshal;
+
+const DEFAULT_RTSP_PORT: u16 = 554;
+const REQUIRE_BACKCHANNEL: &str = "www.onvif.org/ver20/backchannel";
+/// 20ms per packet
+const PACKET_SAMPLES: usize = BACKCHANNEL_SAMPLE_RATE as usize / 50;
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == &name.to_lowercase())
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+enum Auth {
+    Basic(String),
+    Digest {
+        realm: String,
+        nonce: String,
+        opaque: Option<String>,
+    },
+}
+
+/// The backchannel media found in the camera's SDP
+struct BackchannelMedia {
+    control: String,
+    payload_type: u8,
+    codec: BackchannelCodec,
+}
+
+pub struct RtspBackchannel {
+    url: String,
+    credentials: Option<(String, String)>,
+    auth: Option<Auth>,
+    reader: Option<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    drain: Option<JoinHandle<()>>,
+    cseq: u32,
+    session: Option<String>,
+    channel: u8,
+    payload_type: u8,
+    codec: BackchannelCodec,
+    ssrc: u32,
+    sequence_number: u16,
+    timestamp: u32,
+    buffer: Vec<i16>,
+    last_keepalive: Instant,
+}
+
+impl RtspBackchannel {
+    pub async fn connect(url: &str, credentials: Option<(String, Option<String>)>) -> Result<Self> {
+        let mut parsed = Url::parse(url)?;
+        let credentials = match credentials {
+            Some((u, p)) => Some((u, p.unwrap_or_default())),
+            None if !parsed.username().is_empty() => Some((
+                parsed.username().to_string(),
+                parsed.password().unwrap_or("").to_string(),
+            )),
+            None => None,
+        };
+        // Credentials never go in request urls:
+        let _ = parsed.set_username("");
+        let _ = parsed.set_password(None);
+
+        let host = parsed.host_str().ok_or(anyhow!("No host in {}", parsed))?;
+        let port = parsed.port().unwrap_or(DEFAULT_RTSP_PORT);
+        let (reader, writer) = TcpStream::connect((host, port)).await?.into_split();
+
+        let mut backchannel = Self {
+            url: parsed.to_string(),
+            credentials,
+            auth: None,
+            reader: Some(BufReader::new(reader)),
+            writer,
+            drain: None,
+            cseq: 0,
+            session: None,
+            channel: 0,
+            payload_type: 0,
+            codec: BackchannelCodec::Pcmu,
+            ssrc: rand::random(),
+            sequence_number: rand::random(),
+            timestamp: rand::random(),
+            buffer: Vec::new(),
+            last_keepalive: Instant::now(),
+        };
+        backchannel.setup().await?;
+        Ok(backchannel)
+    }
+
+    async fn setup(&mut self) -> Result<()> {
+        let url = self.url.clone();
+        let describe = self
+            .request("DESCRIBE", &url, &[("Accept", "application/sdp")])
+            .await?;
+        if describe.status != 200 {
+            return Err(anyhow!("DESCRIBE failed with {}", describe.status));
+        }
+        let base = describe.header("content-base").unwrap_or(&url).to_string();
+
+        let media = find_backchannel(&describe.body)
+            .ok_or(anyhow!("Camera doesn't offer an audio backchannel"))?;
+        self.payload_type = media.payload_type;
+        self.codec = media.codec;
+        debug!(
+            "Audio backchannel {:?} at {} with payload type {}",
+            media.codec, media.control, media.payload_type
+        );
+
+        let control = control_url(&base, &media.control);
+        let setup = self
+            .request(
+                "SETUP",
+                &control,
+                &[("Transport", "RTP/AVP/TCP;unicast;interleaved=0-1")],
+            )
+            .await?;
+        if setup.status != 200 {
+            return Err(anyhow!("SETUP failed with {}", setup.status));
+        }
+        self.session = setup
+            .header("session")
+            .and_then(|s| s.split(';').next())
+            .map(|s| s.trim().to_string());
+        self.channel = setup
+            .header("transport")
+            .and_then(|t| {
+                t.split(';')
+                    .find_map(|p| p.trim().strip_prefix("interleaved="))
+                    .and_then(|p| p.split('-').next())
+                    .and_then(|c| c.parse().ok())
+            })
+            .unwrap_or(0);
+
+        let play = self
+            .request("PLAY", &base, &[("Range", "npt=0.000-")])
+            .await?;
+        if play.status != 200 {
+            return Err(anyhow!("PLAY failed with {}", play.status));
+        }
+        info!("Audio backchannel to {} started", url);
+
+        // Nothing more is read, but the camera still sends RTCP and keepalive responses:
+        if let Some(mut reader) = self.reader.take() {
+            self.drain = Some(tokio::spawn(async move {
+                let mut buf = [0u8; 1500];
+                while let Ok(n) = reader.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    /// Send a request, retrying once with credentials on 401
+    async fn request(
+        &mut self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<Response> {
+        let response = self.send_request(method, url, headers, true).await?;
+        if response.status != 401 || self.auth.is_some() || self.credentials.is_none() {
+            return Ok(response);
+        }
+
+        self.auth = response
+            .headers
+            .iter()
+            .filter(|(k, _)| k == "www-authenticate")
+            .filter_map(|(_, v)| parse_challenge(v, self.credentials.as_ref().unwrap()))
+            // Digest if offered, as some cameras refuse basic over plain RTSP:
+            .max_by_key(|a| matches!(a, Auth::Digest { .. }));
+        if self.auth.is_none() {
+            return Err(anyhow!("Unsupported authentication challenge"));
+        }
+        self.send_request(method, url, headers, true).await
+    }
+
+    async fn send_request(
+        &mut self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        wait_for_response: bool,
+    ) -> Result<Response> {
+        self.cseq += 1;
+        let mut request = format!("{} {} RTSP/1.0\r\nCSeq: {}\r\n", method, url, self.cseq);
+        request.push_str("User-Agent: smartcam\r\n");
+        request.push_str(&format!("Require: {}\r\n", REQUIRE_BACKCHANNEL));
+        if let Some(session) = &self.session {
+            request.push_str(&format!("Session: {}\r\n", session));
+        }
+        if let Some(auth) = self.authorization(method, url) {
+            request.push_str(&format!("Authorization: {}\r\n", auth));
+        }
+        for (k, v) in headers {
+            request.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        request.push_str("\r\n");
+        trace!("RTSP backchannel request {} {}", method, url);
+        self.writer.write_all(request.as_bytes()).await?;
+
+        match (&mut self.reader, wait_for_response) {
+            (Some(reader), true) => read_response(reader).await,
+            _ => Ok(Response {
+                status: 0,
+                headers: Vec::new(),
+                body: String::new(),
+            }),
+        }
+    }
+
+    fn authorization(&self, method: &str, url: &str) -> Option<String> {
+        let (username, password) = self.credentials.as_ref()?;
+        match self.auth.as_ref()? {
+            Auth::Basic(header) => Some(header.clone()),
+            Auth::Digest {
+                realm,
+                nonce,
+                opaque,
+            } => {
+                let ha1 = md5::compute(format!("{}:{}:{}", username, realm, password));
+                let ha2 = md5::compute(format!("{}:{}", method, url));
+                let response = md5::compute(format!("{:x}:{}:{:x}", ha1, nonce, ha2));
+                let mut header = format!(
+                    "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{:x}\"",
+                    username, realm, nonce, url, response
+                );
+                if let Some(opaque) = opaque {
+                    header.push_str(&format!(", opaque=\"{}\"", opaque));
+                }
+                Some(header)
+            }
+        }
+    }
+
+    async fn write_packet(&mut self, samples: &[i16]) -> Result<()> {
+        let packet = Packet {
+            header: Header {
+                version: 2,
+                payload_type: self.payload_type,
+                sequence_number: self.sequence_number,
+                timestamp: self.timestamp,
+                ssrc: self.ssrc,
+                ..Default::default()
+            },
+            payload: Bytes::from(g711::encode(self.codec, samples)),
+        };
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(samples.len() as u32);
+
+        let raw = packet.marshal()?;
+        let mut buf = BytesMut::with_capacity(raw.len() + 4);
+        buf.put_u8(b'$');
+        buf.put_u8(self.channel);
+        buf.put_u16(raw.len() as u16);
+        buf.extend_from_slice(&raw);
+        self.writer.write_all(&buf).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Backchannel for RtspBackchannel {
+    async fn send(&mut self, samples: &[i16]) -> Result<()> {
+        self.buffer.extend_from_slice(samples);
+        while self.buffer.len() >= PACKET_SAMPLES {
+            let chunk: Vec<i16> = self.buffer.drain(..PACKET_SAMPLES).collect();
+            self.write_packet(&chunk).await?;
+        }
+
+        if self.last_keepalive.elapsed() >= KEEPALIVE_INTERVAL {
+            let url = self.url.clone();
+            self.send_request("GET_PARAMETER", &url, &[], false).await?;
+            self.last_keepalive = Instant::now();
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        let url = self.url.clone();
+        let result = self.send_request("TEARDOWN", &url, &[], false).await;
+        if let Some(drain) = self.drain.take() {
+            drain.abort();
+        }
+        result.map(|_| ())
+    }
+}
+
+async fn read_response(reader: &mut BufReader<OwnedReadHalf>) -> Result<Response> {
+    let mut line = String::new();
+
+    // Skip any interleaved data that arrives ahead of the response:
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            return Err(anyhow!("Connection closed"));
+        }
+        if buf[0] != b'$' {
+            break;
+        }
+        let mut hdr = [0u8; 4];
+        reader.read_exact(&mut hdr).await?;
+        let mut discard = vec![0u8; u16::from_be_bytes([hdr[2], hdr[3]]) as usize];
+        reader.read_exact(&mut discard).await?;
+    }
+
+    reader.read_line(&mut line).await?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or(anyhow!("Malformed status line: {}", line.trim_end()))?;
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("Connection closed mid-response"));
+        }
+        let l = line.trim_end();
+        if l.is_empty() {
+            break;
+        }
+        if let Some((k, v)) = l.split_once(':') {
+            headers.push((k.trim().to_lowercase(), v.trim().to_string()));
+        }
+    }
+
+    let len = headers
+        .iter()
+        .find(|(k, _)| k == "content-length")
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+
+    Ok(Response {
+        status,
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+fn parse_challenge(header: &str, credentials: &(String, String)) -> Option<Auth> {
+    let (scheme, params) = header.split_once(' ').unwrap_or((header, ""));
+    match scheme.to_lowercase().as_str() {
+        "basic" => {
+            let token = base64::encode(format!("{}:{}", credentials.0, credentials.1));
+            Some(Auth::Basic(format!("Basic {}", token)))
+        }
+        "digest" => {
+            let params: HashMap<String, String> = params
+                .split(',')
+                .filter_map(|p| p.trim().split_once('='))
+                .map(|(k, v)| {
+                    (
+                        k.trim().to_lowercase(),
+                        v.trim().trim_matches('"').to_string(),
+                    )
+                })
+                .collect();
+            Some(Auth::Digest {
+                realm: params.get("realm")?.clone(),
+                nonce: params.get("nonce")?.clone(),
+                opaque: params.get("opaque").cloned(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// First `sendonly` audio media, which ONVIF uses to mark the backchannel
+fn find_backchannel(sdp: &str) -> Option<BackchannelMedia> {
+    let mut found = None;
+    let mut in_audio = false;
+    let mut sendonly = false;
+    let mut control = String::new();
+    let mut payload_type = None;
+    let mut rtpmap = HashMap::new();
+
+    for line in sdp.lines().map(str::trim) {
+        if let Some(m) = line.strip_prefix("m=") {
+            if in_audio && found.is_none() {
+                found = backchannel_media(sendonly, &control, payload_type, &rtpmap);
+            }
+            in_audio = m.starts_with("audio");
+            sendonly = false;
+            control.clear();
+            rtpmap.clear();
+            payload_type = m.split_whitespace().nth(3).and_then(|p| p.parse().ok());
+        } else if !in_audio {
+            continue;
+        } else if line == "a=sendonly" {
+            sendonly = true;
+        } else if let Some(c) = line.strip_prefix("a=control:") {
+            control = c.to_string();
+        } else if let Some(r) = line.strip_prefix("a=rtpmap:") {
+            if let Some((pt, name)) = r.split_once(' ') {
+                if let Ok(pt) = pt.parse() {
+                    rtpmap.insert(pt, name.to_string());
+                }
+            }
+        }
+    }
+    if in_audio && found.is_none() {
+        found = backchannel_media(sendonly, &control, payload_type, &rtpmap);
+    }
+    found
+}
+
+fn backchannel_media(
+    sendonly: bool,
+    control: &str,
+    payload_type: Option<u8>,
+    rtpmap: &HashMap<u8, String>,
+) -> Option<BackchannelMedia> {
+    let pt = payload_type?;
+    if !sendonly {
+        return None;
+    }
+    let codec = match rtpmap.get(&pt).map(|s| s.to_uppercase()) {
+        Some(name) if name.starts_with("PCMU/") => BackchannelCodec::Pcmu,
+        Some(name) if name.starts_with("PCMA/") => BackchannelCodec::Pcma,
+        None if pt == 0 => BackchannelCodec::Pcmu,
+        None if pt == 8 => BackchannelCodec::Pcma,
+        _ => return None,
+    };
+    Some(BackchannelMedia {
+        control: control.to_string(),
+        payload_type: pt,
+        codec,
+    })
+}
+
+fn control_url(base: &str, control: &str) -> String {
+    if control.starts_with("rtsp://") || control.starts_with("rtsps://") {
+        control.to_string()
+    } else if control.is_empty() || control == "*" {
+        base.to_string()
+    } else {
+        format!("{}/{}", base.trim_end_matches('/'), control)
+    }
+}
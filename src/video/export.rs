@@ -0,0 +1,659 @@
+//! Clips cut from recordings: the parts of a camera's recordings covering a
+//! time range, joined into one file by a background job
+
+use super::osd::Overlay;
+use super::{init_encoder, VideoProc};
+use crate::config::{self, ExportConfig, OverlayConfig};
+use crate::frame::{Colorspace, Frame};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use ffmpeg::format::{self, context::output::Output, Pixel};
+use ffmpeg::media::Type;
+use ffmpeg::software::scaling::{context::Context, flag::Flags};
+use ffmpeg::util::frame::video::Video;
+use ffmpeg::{codec, rescale, Packet, Rational, Rescale, Stream};
+use ffmpeg_next as ffmpeg;
+use log::{error, info, warn};
+use opencv::core::CV_8UC3;
+use opencv::prelude::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const DEFAULT_EXPORT_PATH: &str = "/tmp/smartcam-exports";
+const DEFAULT_KEEP_HOURS: u64 = 24;
+const DEFAULT_MAX_RUNNING: usize = 2;
+/// Time base of re-encoded video, as for recordings
+const ENCODER_FPS: i32 = 90000;
+/// Assumed frame interval when a recording's last frame has no duration
+const DEFAULT_FRAME_US: i64 = 40_000;
+const VIDEO_STREAM_INDEX: usize = 0;
+const AUDIO_STREAM_INDEX: usize = 1;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportState {
+    Running,
+    Done,
+    Failed,
+    /// deleted while running: still counts toward `max_running` until the
+    /// worker notices and stops, then it's forgotten
+    Cancelled,
+}
+
+/// A recording to take part of
+pub struct ExportSource {
+    pub path: PathBuf,
+    pub start_time: DateTime<Utc>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ExportJob {
+    pub id: String,
+    pub label: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    /// whether the time is burned into the video, which means re-encoding it
+    pub timestamp: bool,
+    pub state: ExportState,
+    /// from 0 to 1
+    pub progress: f32,
+    pub error: Option<String>,
+    /// who asked for it, only they and admins can see it
+    pub owner: String,
+    pub created: DateTime<Utc>,
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+impl ExportJob {
+    /// What the download is saved as
+    pub fn file_name(&self) -> String {
+        format!(
+            "{}-{}.{}",
+            self.label,
+            self.start_time.format("%Y%m%dT%H%M%SZ"),
+            self.path
+                .extension()
+                .map(|e| e.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        )
+    }
+}
+
+/// Export jobs by ID, kept for `keep_hours` after they start
+#[derive(Default)]
+pub struct Exports {
+    jobs: Arc<Mutex<HashMap<String, ExportJob>>>,
+}
+
+impl Exports {
+    /// Start cutting `start_time` to `end_time` out of `sources`, which are
+    /// oldest first. `None` if too many exports are already running
+    pub fn start(
+        &self,
+        owner: &str,
+        label: &str,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+        timestamp: bool,
+        sources: Vec<ExportSource>,
+    ) -> Option<ExportJob> {
+        let config = config::load_config(None);
+        let id = format!("{:032x}", rand::random::<u128>());
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap();
+            remove_expired(&mut jobs, &config.export);
+            let running = jobs
+                .values()
+                .filter(|j| j.state == ExportState::Running || j.state == ExportState::Cancelled)
+                .count();
+            if running >= config.export.max_running.unwrap_or(DEFAULT_MAX_RUNNING) {
+                return None;
+            }
+
+            let job = ExportJob {
+                id: id.clone(),
+                label: label.to_string(),
+                start_time,
+                end_time,
+                timestamp,
+                state: ExportState::Running,
+                progress: 0.0,
+                error: None,
+                owner: owner.to_string(),
+                created: Utc::now(),
+                path: Path::new(config.export.path.as_deref().unwrap_or(DEFAULT_EXPORT_PATH)).join(
+                    format!("{}.{}", id, config.storage.video_file_type.extension()),
+                ),
+            };
+            jobs.insert(id.clone(), job.clone());
+            job
+        };
+
+        info!(
+            "Exporting {} from {} to {} as {}",
+            label, start_time, end_time, id
+        );
+        let jobs = Arc::clone(&self.jobs);
+        let path = job.path.clone();
+        let label = label.to_string();
+        thread::spawn(move || {
+            let progress_jobs = Arc::clone(&jobs);
+            let progress_id = id.clone();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                fs::create_dir_all(path.parent().unwrap_or(Path::new("/")))
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| {
+                        let mut exporter =
+                            Exporter::new(&path, &label, start_time, end_time, timestamp);
+                        exporter.export(&sources, &mut |progress| {
+                            let mut jobs = progress_jobs.lock().unwrap();
+                            match jobs.get_mut(&progress_id) {
+                                Some(job) if job.state == ExportState::Running => {
+                                    job.progress = progress;
+                                    true
+                                }
+                                // Deleted while running
+                                _ => false,
+                            }
+                        })
+                    })
+            }))
+            .unwrap_or_else(|_| Err(anyhow!("Export panicked")));
+
+            let mut jobs = jobs.lock().unwrap();
+            let job = match jobs.get_mut(&id) {
+                Some(job) if job.state == ExportState::Running => job,
+                _ => {
+                    info!("Export {} cancelled", id);
+                    jobs.remove(&id);
+                    let _ = fs::remove_file(&path);
+                    return;
+                }
+            };
+            match result {
+                Ok(()) => {
+                    info!("Export {} finished", id);
+                    job.state = ExportState::Done;
+                    job.progress = 1.0;
+                }
+                Err(e) => {
+                    error!("Export {} failed: {}", id, e);
+                    job.state = ExportState::Failed;
+                    job.error = Some(e.to_string());
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        });
+
+        Some(job)
+    }
+
+    pub fn get(&self, id: &str) -> Option<ExportJob> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|job| job.state != ExportState::Cancelled)
+            .cloned()
+    }
+
+    /// Newest first
+    pub fn list(&self) -> Vec<ExportJob> {
+        let mut jobs: Vec<ExportJob> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.state != ExportState::Cancelled)
+            .cloned()
+            .collect();
+        jobs.sort_by(|a, b| b.created.cmp(&a.created));
+        jobs
+    }
+
+    /// Forget an export and delete its file. One still running is cancelled,
+    /// and deleted once its worker stops
+    pub fn remove(&self, id: &str) -> Option<ExportJob> {
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.get(id)?.state {
+            ExportState::Cancelled => return None,
+            ExportState::Running => {
+                let job = jobs.get_mut(id)?;
+                job.state = ExportState::Cancelled;
+                return Some(job.clone());
+            }
+            ExportState::Done | ExportState::Failed => {}
+        }
+        let job = jobs.remove(id)?;
+        if job.state == ExportState::Done {
+            if let Err(e) = fs::remove_file(&job.path) {
+                warn!("Failed to delete export {}: {}", job.path.display(), e);
+            }
+        }
+        Some(job)
+    }
+}
+
+fn remove_expired(jobs: &mut HashMap<String, ExportJob>, config: &ExportConfig) {
+    let keep = Duration::hours(config.keep_hours.unwrap_or(DEFAULT_KEEP_HOURS) as i64);
+    let now = Utc::now();
+    jobs.retain(|_, job| {
+        if job.state == ExportState::Running
+            || job.state == ExportState::Cancelled
+            || now - job.created < keep
+        {
+            return true;
+        }
+        if job.state == ExportState::Done {
+            if let Err(e) = fs::remove_file(&job.path) {
+                warn!("Failed to delete export {}: {}", job.path.display(), e);
+            }
+        }
+        false
+    });
+}
+
+/// Where the export's video comes from
+enum Muxer {
+    /// packets copied as recorded, so the export starts at the keyframe
+    /// before the start time
+    Copy(Output),
+    /// decoded, stamped with the time and encoded again, so cut at the frame
+    Encode(VideoProc),
+}
+
+impl Muxer {
+    fn octx_mut(&mut self) -> &mut Output {
+        match self {
+            Muxer::Copy(octx) => octx,
+            Muxer::Encode(proc) => proc.octx_mut(),
+        }
+    }
+}
+
+/// Where one recording's packets go in the export
+struct Placement {
+    start_time: DateTime<Utc>,
+    /// recording times to keep, in microseconds
+    from_us: i64,
+    until_us: i64,
+    /// added to a recording time to get the export time, set by the first
+    /// video frame kept. Gaps between recordings are left out
+    shift_us: Option<i64>,
+    /// where the next recording starts in the export
+    end_us: i64,
+}
+
+struct Exporter<'a> {
+    path: &'a Path,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    /// when the time is burned in: the recording overlay's settings, or its
+    /// defaults when recordings have none
+    overlay: Option<Overlay>,
+    /// opened with the first recording, whose streams it copies
+    muxer: Option<Muxer>,
+    has_audio: bool,
+    /// length of the export so far
+    written_us: i64,
+}
+
+impl<'a> Exporter<'a> {
+    fn new(
+        path: &'a Path,
+        label: &str,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+        timestamp: bool,
+    ) -> Self {
+        let overlay = if timestamp {
+            let config = config::load_config(None);
+            Some(Overlay::new(
+                config
+                    .osd
+                    .recording
+                    .as_ref()
+                    .unwrap_or(&OverlayConfig::default()),
+                label,
+            ))
+        } else {
+            None
+        };
+        Self {
+            path,
+            start_time,
+            end_time,
+            overlay,
+            muxer: None,
+            has_audio: false,
+            written_us: 0,
+        }
+    }
+
+    /// `progress` returns false to stop the export
+    fn export(
+        &mut self,
+        sources: &[ExportSource],
+        progress: &mut dyn FnMut(f32) -> bool,
+    ) -> Result<()> {
+        for source in sources {
+            self.add(source, progress)?;
+        }
+
+        let muxer = self
+            .muxer
+            .as_mut()
+            .ok_or_else(|| anyhow!("Nothing was recorded in that time"))?;
+        if let Muxer::Encode(proc) = muxer {
+            proc.encoder.send_eof()?;
+            write_encoded(proc)?;
+        }
+        muxer.octx_mut().write_trailer()?;
+        Ok(())
+    }
+
+    fn open(&mut self, video: &Stream, audio: Option<&Stream>) -> Result<()> {
+        let mut octx = format::output(&self.path)?;
+        let encoder = if self.overlay.is_some() {
+            let decoder = video.codec().decoder().video()?;
+            Some(init_encoder(
+                decoder.width(),
+                decoder.height(),
+                &mut octx,
+                ENCODER_FPS,
+                true,
+                None,
+            ))
+        } else {
+            add_stream_like(&mut octx, video)?;
+            None
+        };
+        if let Some(audio) = audio {
+            add_stream_like(&mut octx, audio)?;
+            self.has_audio = true;
+        }
+        format::context::output::dump(&octx, 0, self.path.to_str());
+        octx.write_header()?;
+
+        self.muxer = Some(match encoder {
+            Some(encoder) => Muxer::Encode(VideoProc::new(ENCODER_FPS, octx, encoder)),
+            None => Muxer::Copy(octx),
+        });
+        Ok(())
+    }
+
+    fn add(&mut self, source: &ExportSource, progress: &mut dyn FnMut(f32) -> bool) -> Result<()> {
+        let mut ictx = format::input(&source.path)?;
+        let (video_index, mut decoder) = {
+            let video = ictx
+                .streams()
+                .best(Type::Video)
+                .ok_or_else(|| anyhow!("{} has no video", source.path.display()))?;
+            let audio = ictx.streams().best(Type::Audio);
+            if self.muxer.is_none() {
+                self.open(&video, audio.as_ref())?;
+            }
+            let decoder = if self.overlay.is_some() {
+                Some(video.codec().decoder().video()?)
+            } else {
+                None
+            };
+            (video.index(), decoder)
+        };
+        let audio_index = ictx.streams().best(Type::Audio).map(|s| s.index());
+        let mut scaler = match &decoder {
+            Some(d) => Some(Context::get(
+                d.format(),
+                d.width(),
+                d.height(),
+                Pixel::BGR24,
+                d.width(),
+                d.height(),
+                Flags::BILINEAR,
+            )?),
+            None => None,
+        };
+
+        let mut placement = Placement {
+            start_time: source.start_time,
+            from_us: micros(self.start_time - source.start_time).max(0),
+            until_us: micros(self.end_time - source.start_time),
+            shift_us: None,
+            end_us: self.written_us,
+        };
+        if placement.from_us > 0 {
+            // Lands on the keyframe before, there being nothing to decode from otherwise:
+            ictx.seek(placement.from_us, ..placement.from_us)?;
+        }
+
+        for (stream, mut packet) in ictx.packets() {
+            let time_base = stream.time_base();
+            let pts_us = match packet.pts().or(packet.dts()) {
+                Some(p) => p.rescale(time_base, rescale::TIME_BASE),
+                None => continue,
+            };
+
+            if stream.index() == video_index {
+                if pts_us > placement.until_us {
+                    break;
+                }
+                if !progress(self.progress(source.start_time, pts_us)) {
+                    return Err(anyhow!("Export cancelled"));
+                }
+                match (&mut decoder, &mut scaler) {
+                    (Some(decoder), Some(scaler)) => {
+                        if let Err(e) = decoder.send_packet(&packet) {
+                            warn!("Error decoding packet: {} -- dropping", e);
+                            continue;
+                        }
+                        self.encode_frames(decoder, scaler, &mut placement)?;
+                    }
+                    _ => {
+                        if placement.shift_us.is_none() {
+                            if !packet.is_key() {
+                                continue;
+                            }
+                            placement.shift_us = Some(self.written_us - pts_us);
+                        }
+                        let duration_us = match packet.duration() {
+                            d if d > 0 => d.rescale(time_base, rescale::TIME_BASE),
+                            _ => DEFAULT_FRAME_US,
+                        };
+                        self.write_copied(&mut packet, time_base, VIDEO_STREAM_INDEX, &placement)?;
+                        let end_us = pts_us + placement.shift_us.unwrap_or(0) + duration_us;
+                        placement.end_us = placement.end_us.max(end_us);
+                    }
+                }
+            } else if Some(stream.index()) == audio_index && self.has_audio {
+                // Audio starts with the first video frame kept:
+                let shift_us = match placement.shift_us {
+                    Some(s) => s,
+                    None => continue,
+                };
+                if pts_us + shift_us < self.written_us || pts_us > placement.until_us {
+                    continue;
+                }
+                self.write_copied(&mut packet, time_base, AUDIO_STREAM_INDEX, &placement)?;
+            }
+        }
+
+        if let (Some(decoder), Some(scaler)) = (&mut decoder, &mut scaler) {
+            decoder.send_eof()?;
+            self.encode_frames(decoder, scaler, &mut placement)?;
+        }
+        self.written_us = placement.end_us;
+        Ok(())
+    }
+
+    /// Share of the export's time range covered by reaching `pts_us` into a
+    /// recording started at `start_time`
+    fn progress(&self, start_time: DateTime<Utc>, pts_us: i64) -> f32 {
+        let total = micros(self.end_time - self.start_time) as f32;
+        let done = micros(start_time - self.start_time) + pts_us;
+        (done as f32 / total).max(0.0).min(1.0)
+    }
+
+    fn write_copied(
+        &mut self,
+        packet: &mut Packet,
+        time_base: Rational,
+        index: usize,
+        placement: &Placement,
+    ) -> Result<()> {
+        let shift = placement
+            .shift_us
+            .unwrap_or(0)
+            .rescale(rescale::TIME_BASE, time_base);
+        packet.set_pts(packet.pts().map(|p| p + shift));
+        packet.set_dts(packet.dts().map(|d| d + shift));
+        packet.set_position(-1);
+        packet.set_stream(index);
+
+        let octx = self.muxer.as_mut().unwrap().octx_mut();
+        let stream_tb = octx.stream(index).unwrap().time_base();
+        packet.rescale_ts(time_base, stream_tb);
+        packet.write_interleaved(octx)?;
+        Ok(())
+    }
+
+    /// Stamp and encode whatever the decoder has finished
+    fn encode_frames(
+        &mut self,
+        decoder: &mut ffmpeg::decoder::Video,
+        scaler: &mut Context,
+        placement: &mut Placement,
+    ) -> Result<()> {
+        let proc = match self.muxer.as_mut() {
+            Some(Muxer::Encode(proc)) => proc,
+            _ => return Ok(()),
+        };
+        let time_base = decoder.time_base();
+        let mut decoded = Video::empty();
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let pts_us = match decoded.timestamp().or(decoded.pts()) {
+                Some(p) => p.rescale(time_base, rescale::TIME_BASE),
+                None => continue,
+            };
+            // Decoded from the keyframe before the start, but cut exactly:
+            if pts_us < placement.from_us || pts_us > placement.until_us {
+                continue;
+            }
+            let shift_us = *placement.shift_us.get_or_insert(self.written_us - pts_us);
+
+            let mut bgr = Video::empty();
+            scaler.run(&decoded, &mut bgr)?;
+            let mut img = unsafe {
+                Mat::new_rows_cols_with_data(
+                    bgr.height() as _,
+                    bgr.width() as _,
+                    CV_8UC3,
+                    bgr.data_mut(0).as_mut_ptr() as *mut std::os::raw::c_void,
+                    bgr.stride(0),
+                )?
+            }
+            .clone();
+            if let Some(overlay) = &self.overlay {
+                overlay.draw(
+                    &mut img,
+                    placement.start_time + Duration::microseconds(pts_us),
+                )?;
+            }
+
+            // The encoder only uses times to space frames, so any epoch will do:
+            let frame_time = self.start_time + Duration::microseconds(pts_us + shift_us);
+            proc.process_frame(Arc::new(Frame::new(img, Colorspace::BGR, Some(frame_time))));
+            write_encoded(proc)?;
+            placement.end_us = pts_us + shift_us + DEFAULT_FRAME_US;
+        }
+        Ok(())
+    }
+}
+
+fn micros(duration: Duration) -> i64 {
+    duration.num_microseconds().unwrap_or(i64::MAX)
+}
+
+/// Add a stream to `octx` taking `stream`'s packets unchanged
+fn add_stream_like(octx: &mut Output, stream: &Stream) -> Result<()> {
+    let mut ost = octx.add_stream(codec::encoder::find(codec::Id::None))?;
+    ost.set_parameters(stream.parameters());
+    ost.set_time_base(stream.time_base());
+    // The input container's tag may not be valid in the output's:
+    unsafe {
+        (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+    }
+    Ok(())
+}
+
+fn write_encoded(proc: &mut VideoProc) -> Result<()> {
+    let source_tb = Rational::new(1, ENCODER_FPS);
+    let stream_tb = proc.octx().stream(VIDEO_STREAM_INDEX).unwrap().time_base();
+    let mut encoded = Packet::empty();
+    while proc.encoder.receive_packet(&mut encoded).is_ok() {
+        encoded.set_stream(VIDEO_STREAM_INDEX);
+        encoded.rescale_ts(source_tb, stream_tb);
+        encoded.write_interleaved(proc.octx_mut())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: &str, state: ExportState) -> ExportJob {
+        ExportJob {
+            id: id.to_string(),
+            label: "front".to_string(),
+            start_time: Utc::now(),
+            end_time: Utc::now(),
+            timestamp: false,
+            state,
+            progress: 0.0,
+            error: None,
+            owner: "admin".to_string(),
+            created: Utc::now(),
+            path: PathBuf::from("/nonexistent/export.mp4"),
+        }
+    }
+
+    #[test]
+    fn removing_a_running_export_cancels_it() {
+        let exports = Exports::default();
+        exports
+            .jobs
+            .lock()
+            .unwrap()
+            .insert("a".to_string(), job("a", ExportState::Running));
+
+        assert_eq!(exports.remove("a").unwrap().state, ExportState::Cancelled);
+        assert!(exports.get("a").is_none());
+        assert!(exports.list().is_empty());
+        assert!(exports.remove("a").is_none());
+        // Still counted until its worker stops
+        assert_eq!(
+            exports.jobs.lock().unwrap()["a"].state,
+            ExportState::Cancelled
+        );
+    }
+
+    #[test]
+    fn removing_a_finished_export_forgets_it() {
+        let exports = Exports::default();
+        exports
+            .jobs
+            .lock()
+            .unwrap()
+            .insert("a".to_string(), job("a", ExportState::Failed));
+
+        assert_eq!(exports.remove("a").unwrap().state, ExportState::Failed);
+        assert!(exports.jobs.lock().unwrap().is_empty());
+    }
+}
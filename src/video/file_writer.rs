@@ -1,6 +1,10 @@
 use super::init_encoder;
+use super::osd::Overlay;
+use super::thumbnail::Thumbnailer;
 use super::VideoProc;
+use crate::audio::{AudioEncoder, AudioReceiver};
 use crate::config;
+use crate::event::{EventMetadata, EventTrigger};
 use crate::frame::VideoFrame;
 use crate::FileSourceType;
 
@@ -9,20 +13,37 @@ use chrono::{DateTime, Utc};
 use ffmpeg::{format, util::rational::Rational, Packet};
 use ffmpeg_next as ffmpeg;
 
-use log::{debug, trace};
+use log::{debug, error, trace, warn};
 use std::error::Error;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::Receiver;
+use tokio::sync::broadcast::error::TryRecvError;
+
+const AUDIO_STREAM_INDEX: usize = 1;
+
 pub struct VideoFileWriter {
     video_proc: VideoProc,
+    label: String,
+    start_time: DateTime<Utc>,
+    triggers: Vec<EventTrigger>,
     path: PathBuf,
     fps: i32,
     _temp_path: &'static str,
+    audio: Option<(AudioEncoder, AudioReceiver)>,
+    thumbnailer: Option<Thumbnailer>,
+    /// nothing is written until the encoder has produced a keyframe
+    keyframe_written: bool,
 }
 
 impl VideoFileWriter {
     // FIXME -- return result:
-    pub fn new(label: String, start_time: DateTime<Utc>, width: u32, height: u32) -> Self {
+    pub fn new(
+        label: String,
+        start_time: DateTime<Utc>,
+        width: u32,
+        height: u32,
+        audio_rx: Option<AudioReceiver>,
+    ) -> Self {
         let temp_path = "/tmp";
         let config = config::load_config(None);
         let f_name = format!(
@@ -39,21 +60,62 @@ impl VideoFileWriter {
         let p = Path::new(&f).to_path_buf();
         let fps = 90000;
         let mut octx = format::output(&p).unwrap();
-        let encoder = init_encoder(width, height, &mut octx, fps, true);
+        let encoder = init_encoder(width, height, &mut octx, fps, true, None);
+
+        let audio = audio_rx.and_then(|rx| {
+            match AudioEncoder::new(
+                config.storage.video_file_type.audio_codec(),
+                Some(&mut octx),
+                true,
+            ) {
+                Ok(mut e) => {
+                    e.set_start_time(start_time);
+                    Some((e, rx))
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to create audio encoder -- recording video only: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        });
 
         format::context::output::dump(&octx, 0, Some(&f));
         octx.write_header().unwrap();
 
+        let mut video_proc = VideoProc::new(fps, octx, encoder);
+        video_proc.set_overlay(
+            config
+                .osd
+                .recording
+                .as_ref()
+                .map(|o| Overlay::new(o, &label)),
+        );
+
         Self {
-            video_proc: VideoProc::new(fps, octx, encoder),
+            video_proc,
+            label,
+            start_time,
+            triggers: Vec::new(),
             path: p,
             fps,
             _temp_path: temp_path,
+            audio,
+            thumbnailer: Some(Thumbnailer::new()),
+            keyframe_written: false,
         }
     }
 
     fn close_file(&mut self) {
         self.video_proc.encoder.send_eof().unwrap();
+        if let Some((encoder, _)) = &mut self.audio {
+            if let Err(e) = encoder.send_eof() {
+                error!("Failed to flush audio encoder: {}", e);
+            }
+        }
+        self.write_audio_packets_to_ctx();
         self.video_proc.octx_mut().write_trailer().unwrap();
     }
 
@@ -61,15 +123,35 @@ impl VideoFileWriter {
         &mut self,
         receiver: Receiver<VideoFrame>,
     ) -> Result<String, Box<dyn Error>> {
+        let mut end_time = self.start_time;
         loop {
-            let video_frame = receiver.recv().unwrap();
+            let video_frame = match receiver.recv() {
+                Ok(f) => f,
+                // The camera was stopped mid-event:
+                Err(_) => {
+                    warn!("Recording interrupted, closing {}", self.path.display());
+                    self.close_file();
+                    self.write_metadata(end_time);
+                    self.write_thumbnails();
+                    break;
+                }
+            };
+            self.triggers.extend(video_frame.triggers);
             let frame = video_frame.frame;
+            if let Some(t) = &mut self.thumbnailer {
+                t.add(&frame, video_frame.motion);
+            }
+            end_time = frame.time();
             let frame_duration = self.video_proc.process_frame(frame);
             trace!("Frame duration: {:?}", frame_duration);
             self.write_packets_to_ctx();
+            self.receive_audio();
+            self.write_audio_packets_to_ctx();
             if video_frame.is_end {
                 debug!("Last frame receieved, sending EOF");
                 self.close_file();
+                self.write_metadata(end_time);
+                self.write_thumbnails();
                 break;
             }
         }
@@ -77,6 +159,34 @@ impl VideoFileWriter {
         Ok(self.path.to_str().unwrap().to_string())
     }
 
+    fn write_metadata(&mut self, end_time: DateTime<Utc>) {
+        let metadata = EventMetadata {
+            label: self.label.clone(),
+            file_name: self
+                .path
+                .file_name()
+                .map(|f| f.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            start_time: self.start_time,
+            end_time,
+            triggers: std::mem::take(&mut self.triggers),
+        };
+        if let Err(e) = metadata.write(&self.path) {
+            error!(
+                "Failed to write metadata for {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    /// Written from frames already in memory, so no decoding is needed
+    fn write_thumbnails(&mut self) {
+        if let Some(thumbnailer) = self.thumbnailer.take() {
+            thumbnailer.write(&self.path);
+        }
+    }
+
     fn write_packets_to_ctx(&mut self) {
         let ost_index = 0;
         let mut encoded = Packet::empty();
@@ -88,6 +198,13 @@ impl VideoFileWriter {
             .unwrap()
             .time_base();
         while self.video_proc.encoder.receive_packet(&mut encoded).is_ok() {
+            if !self.keyframe_written {
+                if !encoded.is_key() {
+                    trace!("Skipping packet before the first keyframe");
+                    continue;
+                }
+                self.keyframe_written = true;
+            }
             trace!("Writing packets...");
             encoded.set_stream(ost_index);
             encoded.rescale_ts(source_tb, stream_tb);
@@ -97,4 +214,45 @@ impl VideoFileWriter {
         }
         trace!("Finished writing packets...");
     }
+
+    /// Encode whatever audio has arrived since the last video frame
+    fn receive_audio(&mut self) {
+        let (encoder, rx) = match &mut self.audio {
+            Some(a) => a,
+            None => return,
+        };
+        loop {
+            match rx.try_recv() {
+                Ok(audio) => {
+                    if let Err(e) = encoder.send(&audio) {
+                        error!("Failed to encode audio: {}", e);
+                    }
+                }
+                Err(TryRecvError::Lagged(n)) => warn!("Recording dropped {} audio frames", n),
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn write_audio_packets_to_ctx(&mut self) {
+        let (encoder, _) = match &mut self.audio {
+            Some(a) => a,
+            None => return,
+        };
+        let source_tb = encoder.time_base();
+        let stream_tb = self
+            .video_proc
+            .octx()
+            .stream(AUDIO_STREAM_INDEX)
+            .unwrap()
+            .time_base();
+        let mut encoded = Packet::empty();
+        while encoder.receive_packet(&mut encoded) {
+            encoded.set_stream(AUDIO_STREAM_INDEX);
+            encoded.rescale_ts(source_tb, stream_tb);
+            if let Err(e) = encoded.write_interleaved(&mut self.video_proc.octx_mut()) {
+                error!("Failed to write audio packet: {}", e);
+            }
+        }
+    }
 }
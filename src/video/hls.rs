@@ -0,0 +1,767 @@
+//! LL-HLS from the live encoder's packets. One muxer runs for the whole
+//! stream and is pointed at a new file for each segment, which is cut on
+//! the first keyframe that would take it past `segment_duration`. Segments
+//! are flushed every `part_duration` as well, each flush becoming a partial
+//! segment addressed by byte range, so clients can play a segment while
+//! it's still being written.
+//!
+//! Playlist requests with `_HLS_msn` (and `_HLS_part`) are held until the
+//! playlist has that segment (or part), see `wait_for`
+
+use super::{add_copy_stream, EncodedPacket};
+use crate::config;
+use crate::config::{HlsConfig, HlsSegmentType};
+
+use anyhow::{anyhow, Result};
+use ffmpeg::{codec::packet, format, format::context::output::Output, Dictionary, Packet};
+use ffmpeg_next as ffmpeg;
+use ffmpeg_sys_next as ffs;
+use futures::executor::block_on;
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio::sync::watch;
+
+pub const HLS_PLAYLIST: &str = "index.m3u8";
+const DEFAULT_HLS_PATH: &str = "/tmp/smartcam-hls";
+const DEFAULT_SEGMENT_DURATION: u32 = 2;
+const DEFAULT_PART_DURATION: f64 = 0.5;
+const DEFAULT_PLAYLIST_SIZE: u32 = 6;
+/// Complete segments whose parts are still listed; older ones only appear whole
+const SEGMENTS_WITH_PARTS: usize = 2;
+/// Segments kept on disk after leaving the playlist, for clients still fetching them
+const EXPIRED_SEGMENTS_KEPT: usize = 2;
+/// Slack for frame timestamps that don't add up exactly, in seconds
+const TIMING_TOLERANCE: f64 = 0.001;
+
+/// Latest progress of each camera's playlist, for blocking reloads
+static PROGRESS: Lazy<Mutex<HashMap<String, watch::Receiver<Progress>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Directory holding the playlist and segments for `label`
+pub fn hls_dir(label: &str) -> PathBuf {
+    let config = config::load_config(None);
+    let base = config.hls.path.as_deref().unwrap_or(DEFAULT_HLS_PATH);
+    Path::new(base).join(label)
+}
+
+/// How far a playlist has got
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Progress {
+    /// media sequence number of the segment being written
+    pub sequence: u64,
+    /// parts of it written so far
+    pub parts: usize,
+}
+
+impl Progress {
+    /// Whether the playlist has segment `msn`, or part `part` of it
+    pub fn has(&self, msn: u64, part: Option<usize>) -> bool {
+        match part {
+            None => self.sequence > msn,
+            Some(part) => self.sequence > msn || (self.sequence == msn && self.parts > part),
+        }
+    }
+}
+
+/// Wait for `label`'s playlist to have segment `msn`, or part `part` of it,
+/// for up to three target durations. False if it didn't get there
+pub async fn wait_for(label: &str, msn: u64, part: Option<usize>) -> bool {
+    let mut rx = match PROGRESS.lock().unwrap().get(label) {
+        Some(rx) => rx.clone(),
+        None => return false,
+    };
+    let segment_duration = config::load_config(None)
+        .hls
+        .segment_duration
+        .unwrap_or(DEFAULT_SEGMENT_DURATION);
+    let timeout = Duration::from_secs(3 * segment_duration as u64);
+
+    let wait = async {
+        loop {
+            if rx.borrow().has(msn, part) {
+                return true;
+            }
+            if rx.changed().await.is_err() {
+                return false;
+            }
+        }
+    };
+    tokio::time::timeout(timeout, wait).await.unwrap_or(false)
+}
+
+#[derive(Debug, PartialEq)]
+struct Part {
+    offset: u64,
+    length: u64,
+    duration: f64,
+    independent: bool,
+}
+
+#[derive(Debug, PartialEq)]
+struct Segment {
+    sequence: u64,
+    file: String,
+    /// fMP4 init section, which changes when the output restarts
+    init: Option<String>,
+    /// first segment after the output restarted
+    discontinuity: bool,
+    parts: Vec<Part>,
+}
+
+impl Segment {
+    fn duration(&self) -> f64 {
+        self.parts.iter().map(|p| p.duration).sum()
+    }
+}
+
+/// The muxer and the segment it's writing
+struct Writer {
+    octx: Output,
+    segment: Segment,
+    segment_start: f64,
+    part_start: f64,
+    part_offset: u64,
+    part_independent: bool,
+    /// written once the next packet gives its duration
+    pending: Option<Arc<EncodedPacket>>,
+}
+
+/// Writes already-encoded packets to a rolling LL-HLS playlist
+pub struct HlsPackager {
+    label: String,
+    dir: PathBuf,
+    segment_type: HlsSegmentType,
+    segment_duration: f64,
+    part_duration: f64,
+    playlist_size: usize,
+    /// only ever grows, as the playlist's target duration mustn't change
+    target_duration: u64,
+    writer: Option<Writer>,
+    segments: VecDeque<Segment>,
+    /// segments no longer in the playlist, deleted after a while
+    expired: VecDeque<Segment>,
+    next_sequence: u64,
+    discontinuity_sequence: u64,
+    /// outputs opened so far, used to name init sections
+    outputs: u64,
+    progress: watch::Sender<Progress>,
+}
+
+impl HlsPackager {
+    pub fn new(label: String) -> Result<Self> {
+        let config = config::load_config(None);
+        Self::with_config(label.clone(), hls_dir(&label), &config.hls)
+    }
+
+    fn with_config(label: String, dir: PathBuf, config: &HlsConfig) -> Result<Self> {
+        // Segments left from a previous run are not referenced by the new playlist:
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+
+        let segment_duration = config.segment_duration.unwrap_or(DEFAULT_SEGMENT_DURATION) as f64;
+        let (progress, rx) = watch::channel(Progress::default());
+        PROGRESS.lock().unwrap().insert(label.clone(), rx);
+
+        Ok(Self {
+            label,
+            dir,
+            segment_type: config
+                .segment_type
+                .clone()
+                .unwrap_or(HlsSegmentType::MpegTs),
+            segment_duration,
+            part_duration: config
+                .part_duration
+                .unwrap_or(DEFAULT_PART_DURATION)
+                .min(segment_duration),
+            playlist_size: config.playlist_size.unwrap_or(DEFAULT_PLAYLIST_SIZE) as usize,
+            target_duration: segment_duration.ceil() as u64,
+            writer: None,
+            segments: VecDeque::new(),
+            expired: VecDeque::new(),
+            next_sequence: 0,
+            discontinuity_sequence: 0,
+            outputs: 0,
+            progress,
+        })
+    }
+
+    fn segment_file(&self, sequence: u64) -> String {
+        format!("segment-{}.{}", sequence, self.segment_type.extension())
+    }
+
+    fn new_segment(&mut self, init: Option<String>, discontinuity: bool) -> Segment {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        Segment {
+            sequence,
+            file: self.segment_file(sequence),
+            init,
+            discontinuity,
+            parts: Vec::new(),
+        }
+    }
+
+    /// Output is opened lazily so the playlist always starts on a keyframe
+    fn open(&mut self, first: &EncodedPacket) -> Result<()> {
+        // fMP4 can't describe the stream without them:
+        if self.segment_type == HlsSegmentType::Fmp4 && first.parameter_sets.is_none() {
+            return Err(anyhow!("keyframe has no SPS and PPS"));
+        }
+
+        let init = match self.segment_type {
+            HlsSegmentType::Fmp4 => Some(format!("init-{}.mp4", self.outputs)),
+            HlsSegmentType::MpegTs => None,
+        };
+        let segment = self.new_segment(init.clone(), self.outputs > 0);
+        // The header goes in the init section, TS has none so it starts the segment:
+        let header_file = init.as_ref().unwrap_or(&segment.file);
+        let mut octx = format::output_as(&self.dir.join(header_file), self.segment_type.muxer())?;
+        add_copy_stream(
+            &mut octx,
+            first.width,
+            first.height,
+            first.time_base,
+            first.parameter_sets.as_deref(),
+        )?;
+
+        let mut opts = Dictionary::new();
+        if self.segment_type == HlsSegmentType::Fmp4 {
+            // Fragments are only written when flushed, one per part:
+            opts.set("movflags", "+frag_custom+empty_moov+default_base_moof");
+        }
+        octx.write_header_with(opts)?;
+        if init.is_some() {
+            switch_file(&mut octx, &self.dir.join(&segment.file))?;
+        }
+
+        info!(
+            "HLS output for {} started at {}",
+            self.label,
+            self.dir.join(HLS_PLAYLIST).display()
+        );
+        self.outputs += 1;
+        let start = seconds(first);
+        self.writer = Some(Writer {
+            octx,
+            segment,
+            segment_start: start,
+            part_start: start,
+            part_offset: 0,
+            part_independent: true,
+            pending: None,
+        });
+        Ok(())
+    }
+
+    pub fn write(&mut self, p: Arc<EncodedPacket>) -> Result<()> {
+        if self.writer.is_none() {
+            if !p.is_key {
+                return Ok(());
+            }
+            self.open(&p)?;
+        }
+        let writer = self.writer.as_mut().unwrap();
+        let time = seconds(&p);
+
+        let (end_part, end_segment) = match writer.pending.take() {
+            Some(previous) => {
+                write_packet(
+                    &mut writer.octx,
+                    &previous,
+                    timestamp(&p) - timestamp(&previous),
+                )?;
+                // Cut before this packet if its frame would take the part past its target:
+                let frame = time - seconds(&previous);
+                let end_part =
+                    time - writer.part_start + frame > self.part_duration + TIMING_TOLERANCE;
+                let end_segment = p.is_key
+                    && time - writer.segment_start + frame
+                        > self.segment_duration + TIMING_TOLERANCE;
+                (end_part || end_segment, end_segment)
+            }
+            None => (false, false),
+        };
+
+        if end_part {
+            self.end_part(time)?;
+        }
+        if end_segment {
+            self.end_segment(time)?;
+        }
+        if end_part {
+            self.write_playlist()?;
+        }
+
+        let writer = self.writer.as_mut().unwrap();
+        if end_part {
+            writer.part_independent = p.is_key;
+        }
+        writer.pending = Some(p);
+        Ok(())
+    }
+
+    /// Finish the current part at `time`
+    fn end_part(&mut self, time: f64) -> Result<()> {
+        let writer = self.writer.as_mut().unwrap();
+        let end = flush(&mut writer.octx)?;
+        writer.segment.parts.push(Part {
+            offset: writer.part_offset,
+            length: end - writer.part_offset,
+            duration: time - writer.part_start,
+            independent: writer.part_independent,
+        });
+        writer.part_offset = end;
+        writer.part_start = time;
+        Ok(())
+    }
+
+    /// Start a new segment at `time`, once its last part has ended
+    fn end_segment(&mut self, time: f64) -> Result<()> {
+        let init = self.writer.as_ref().unwrap().segment.init.clone();
+        let next = self.new_segment(init, false);
+        let writer = self.writer.as_mut().unwrap();
+        switch_file(&mut writer.octx, &self.dir.join(&next.file))?;
+        if self.segment_type == HlsSegmentType::MpegTs {
+            // Each segment has to start with them to be played on its own:
+            resend_ts_headers(&mut writer.octx)?;
+        }
+        let finished = std::mem::replace(&mut writer.segment, next);
+        writer.segment_start = time;
+        writer.part_offset = 0;
+        self.finish_segment(finished);
+        Ok(())
+    }
+
+    /// Add a complete segment to the playlist, dropping the oldest
+    fn finish_segment(&mut self, segment: Segment) {
+        let duration = segment.duration().ceil() as u64;
+        if duration > self.target_duration {
+            warn!(
+                "HLS segment for {} is {}s, over the {}s target",
+                self.label, duration, self.target_duration
+            );
+            self.target_duration = duration;
+        }
+        self.segments.push_back(segment);
+
+        while self.segments.len() > self.playlist_size {
+            let old = self.segments.pop_front().unwrap();
+            if old.discontinuity {
+                self.discontinuity_sequence += 1;
+            }
+            self.expired.push_back(old);
+        }
+        while self.expired.len() > EXPIRED_SEGMENTS_KEPT {
+            let old = self.expired.pop_front().unwrap();
+            self.delete(&old);
+        }
+    }
+
+    fn delete(&self, segment: &Segment) {
+        if let Err(e) = fs::remove_file(self.dir.join(&segment.file)) {
+            warn!("Failed to delete HLS segment {}: {}", segment.file, e);
+        }
+        let init = match &segment.init {
+            Some(i) => i,
+            None => return,
+        };
+        let in_use = self
+            .segments
+            .iter()
+            .chain(self.expired.iter())
+            .chain(self.writer.as_ref().map(|w| &w.segment))
+            .any(|s| s.init.as_ref() == Some(init));
+        if !in_use {
+            if let Err(e) = fs::remove_file(self.dir.join(init)) {
+                warn!("Failed to delete HLS init section {}: {}", init, e);
+            }
+        }
+    }
+
+    fn write_playlist(&self) -> Result<()> {
+        let current = self.writer.as_ref().map(|w| &w.segment);
+        let playlist = render_playlist(
+            self.segments.iter().chain(current),
+            self.segments.len(),
+            self.target_duration,
+            self.part_duration,
+            self.discontinuity_sequence,
+        );
+        // Written alongside and renamed so it's never served half written:
+        let path = self.dir.join(HLS_PLAYLIST);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, playlist)?;
+        fs::rename(&tmp, &path)?;
+
+        let progress = match current {
+            Some(s) => Progress {
+                sequence: s.sequence,
+                parts: s.parts.len(),
+            },
+            None => Progress {
+                sequence: self.next_sequence,
+                parts: 0,
+            },
+        };
+        // Only fails with no receivers, which the registry always holds:
+        let _ = self.progress.send(progress);
+        Ok(())
+    }
+
+    /// Close the current output; the next keyframe starts a new one
+    pub fn reset(&mut self) {
+        let mut writer = match self.writer.take() {
+            Some(w) => w,
+            None => return,
+        };
+        if let Err(e) = writer.octx.write_trailer() {
+            error!("Failed to finish HLS output for {}: {}", self.label, e);
+        }
+        // What's been listed of it can still be played:
+        if writer.segment.parts.is_empty() {
+            self.delete(&writer.segment);
+        } else {
+            self.finish_segment(writer.segment);
+        }
+        if let Err(e) = self.write_playlist() {
+            error!("Failed to write HLS playlist for {}: {}", self.label, e);
+        }
+    }
+}
+
+/// Render the playlist for `segments`, of which the first `complete` are
+/// finished and the last may be in progress
+fn render_playlist<'a>(
+    segments: impl Iterator<Item = &'a Segment>,
+    complete: usize,
+    target_duration: u64,
+    part_duration: f64,
+    discontinuity_sequence: u64,
+) -> String {
+    let segments: Vec<&Segment> = segments.collect();
+    let mut m3u8 = String::new();
+    // Writing to a String can't fail:
+    let _ = writeln!(m3u8, "#EXTM3U");
+    let _ = writeln!(m3u8, "#EXT-X-VERSION:6");
+    let _ = writeln!(m3u8, "#EXT-X-TARGETDURATION:{}", target_duration);
+    let _ = writeln!(
+        m3u8,
+        "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}",
+        3.0 * part_duration
+    );
+    let _ = writeln!(m3u8, "#EXT-X-PART-INF:PART-TARGET={:.3}", part_duration);
+    let _ = writeln!(
+        m3u8,
+        "#EXT-X-MEDIA-SEQUENCE:{}",
+        segments.first().map_or(0, |s| s.sequence)
+    );
+    if discontinuity_sequence > 0 {
+        let _ = writeln!(
+            m3u8,
+            "#EXT-X-DISCONTINUITY-SEQUENCE:{}",
+            discontinuity_sequence
+        );
+    }
+    let _ = writeln!(m3u8, "#EXT-X-INDEPENDENT-SEGMENTS");
+
+    let mut map = None;
+    for (i, segment) in segments.iter().enumerate() {
+        if segment.discontinuity {
+            let _ = writeln!(m3u8, "#EXT-X-DISCONTINUITY");
+        }
+        if segment.init.is_some() && segment.init != map {
+            map = segment.init.clone();
+            let _ = writeln!(m3u8, "#EXT-X-MAP:URI=\"{}\"", map.as_ref().unwrap());
+        }
+        if i + SEGMENTS_WITH_PARTS >= complete {
+            for part in &segment.parts {
+                let _ = writeln!(
+                    m3u8,
+                    "#EXT-X-PART:DURATION={:.5},URI=\"{}\",BYTERANGE=\"{}@{}\"{}",
+                    part.duration,
+                    segment.file,
+                    part.length,
+                    part.offset,
+                    if part.independent {
+                        ",INDEPENDENT=YES"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+        if i < complete {
+            let _ = writeln!(m3u8, "#EXTINF:{:.5},", segment.duration());
+            let _ = writeln!(m3u8, "{}", segment.file);
+        }
+    }
+    m3u8
+}
+
+fn timestamp(p: &EncodedPacket) -> i64 {
+    p.dts.or(p.pts).unwrap_or(0)
+}
+
+fn seconds(p: &EncodedPacket) -> f64 {
+    timestamp(p) as f64 * p.time_base.numerator() as f64 / p.time_base.denominator() as f64
+}
+
+fn write_packet(octx: &mut Output, p: &EncodedPacket, duration: i64) -> Result<()> {
+    let stream_tb = octx.stream(0).unwrap().time_base();
+    let mut packet = Packet::copy(&p.data);
+    packet.set_pts(p.pts);
+    packet.set_dts(p.dts);
+    packet.set_duration(duration);
+    if p.is_key {
+        packet.set_flags(packet::Flags::KEY);
+    }
+    packet.set_stream(0);
+    packet.rescale_ts(p.time_base, stream_tb);
+    packet.write(octx)?;
+    Ok(())
+}
+
+/// Write out everything muxed so far, returning the size of the current file
+fn flush(octx: &mut Output) -> Result<u64> {
+    unsafe {
+        let ctx = octx.as_mut_ptr();
+        // Ends the fragment for fMP4:
+        let ret = ffs::av_write_frame(ctx, ptr::null_mut());
+        if ret < 0 {
+            return Err(ffmpeg::Error::from(ret).into());
+        }
+        ffs::avio_flush((*ctx).pb);
+        let end = ffs::avio_seek((*ctx).pb, 0, libc::SEEK_CUR);
+        if end < 0 {
+            return Err(ffmpeg::Error::from(end as i32).into());
+        }
+        Ok(end as u64)
+    }
+}
+
+/// Point the muxer at a new file, carrying on where it left off
+fn switch_file(octx: &mut Output, path: &Path) -> Result<()> {
+    let path = CString::new(path.to_string_lossy().as_bytes())?;
+    unsafe {
+        let ctx = octx.as_mut_ptr();
+        ffs::avio_flush((*ctx).pb);
+        ffs::avio_closep(&mut (*ctx).pb);
+        let ret = ffs::avio_open(&mut (*ctx).pb, path.as_ptr(), ffs::AVIO_FLAG_WRITE as i32);
+        if ret < 0 {
+            return Err(ffmpeg::Error::from(ret).into());
+        }
+    }
+    Ok(())
+}
+
+/// Have the TS muxer repeat its PAT and PMT before the next packet
+fn resend_ts_headers(octx: &mut Output) -> Result<()> {
+    let name = CString::new("mpegts_flags")?;
+    let value = CString::new("+resend_headers")?;
+    let ret = unsafe {
+        ffs::av_opt_set(
+            (*octx.as_mut_ptr()).priv_data,
+            name.as_ptr(),
+            value.as_ptr(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(ffmpeg::Error::from(ret).into());
+    }
+    Ok(())
+}
+
+pub fn start_hls_packager(label: String, mut rx: Receiver<Arc<EncodedPacket>>) -> JoinHandle<()> {
+    thread::spawn(move || -> () {
+        let mut packager = match HlsPackager::new(label.clone()) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("Failed to start HLS packager for {}: {}", label, e);
+                return;
+            }
+        };
+        debug!("Starting HLS packager for {}", label);
+
+        loop {
+            match block_on(rx.recv()) {
+                Ok(p) => {
+                    if let Err(e) = packager.write(p) {
+                        error!("HLS write failed for {}: {} -- restarting output", label, e);
+                        packager.reset();
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("HLS packager for {} dropped {} packets", label, n);
+                    packager.reset();
+                }
+                Err(RecvError::Closed) => {
+                    packager.reset();
+                    break;
+                }
+            }
+        }
+        debug!("HLS packager for {} stopped", label);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use ffmpeg::Rational;
+
+    fn segment(sequence: u64, init: &str, discontinuity: bool, parts: usize) -> Segment {
+        Segment {
+            sequence,
+            file: format!("segment-{}.m4s", sequence),
+            init: Some(init.to_string()),
+            discontinuity,
+            parts: [(100, 0, true), (50, 100, false)]
+                .iter()
+                .take(parts)
+                .map(|&(length, offset, independent)| Part {
+                    offset,
+                    length,
+                    duration: 0.5,
+                    independent,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn progress() {
+        let progress = Progress {
+            sequence: 4,
+            parts: 2,
+        };
+        assert!(progress.has(3, None));
+        assert!(!progress.has(4, None));
+        assert!(progress.has(3, Some(5)));
+        assert!(progress.has(4, Some(1)));
+        assert!(!progress.has(4, Some(2)));
+        assert!(!progress.has(5, Some(0)));
+    }
+
+    #[test]
+    fn playlist() {
+        let segments = [
+            segment(5, "init-0.mp4", false, 2),
+            segment(6, "init-1.mp4", true, 2),
+            segment(7, "init-1.mp4", false, 1),
+        ];
+        let playlist = render_playlist(segments.iter(), 2, 2, 0.5, 1);
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n\
+             #EXT-X-VERSION:6\n\
+             #EXT-X-TARGETDURATION:2\n\
+             #EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=1.500\n\
+             #EXT-X-PART-INF:PART-TARGET=0.500\n\
+             #EXT-X-MEDIA-SEQUENCE:5\n\
+             #EXT-X-DISCONTINUITY-SEQUENCE:1\n\
+             #EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-MAP:URI=\"init-0.mp4\"\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"segment-5.m4s\",BYTERANGE=\"100@0\",INDEPENDENT=YES\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"segment-5.m4s\",BYTERANGE=\"50@100\"\n\
+             #EXTINF:1.00000,\n\
+             segment-5.m4s\n\
+             #EXT-X-DISCONTINUITY\n\
+             #EXT-X-MAP:URI=\"init-1.mp4\"\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"segment-6.m4s\",BYTERANGE=\"100@0\",INDEPENDENT=YES\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"segment-6.m4s\",BYTERANGE=\"50@100\"\n\
+             #EXTINF:1.00000,\n\
+             segment-6.m4s\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"segment-7.m4s\",BYTERANGE=\"100@0\",INDEPENDENT=YES\n"
+        );
+    }
+
+    #[test]
+    fn older_segments_are_listed_whole() {
+        let segments: Vec<_> = (0..4).map(|i| segment(i, "init-0.mp4", false, 2)).collect();
+        let playlist = render_playlist(segments.iter(), 4, 2, 0.5, 0);
+        assert!(!playlist.contains("URI=\"segment-1.m4s\""));
+        assert!(playlist.contains("URI=\"segment-2.m4s\""));
+        assert!(playlist.contains("\nsegment-1.m4s\n"));
+    }
+
+    /// Packets at 10fps with a keyframe every second
+    fn packet(i: i64) -> Arc<EncodedPacket> {
+        let is_key = i % 10 == 0;
+        let nal = if is_key { 0x65 } else { 0x41 };
+        Arc::new(EncodedPacket {
+            data: Bytes::from(vec![0, 0, 0, 1, nal, 0x88, 0x84, 0x21, 0xa0]),
+            pts: Some(i),
+            dts: Some(i),
+            is_key,
+            parameter_sets: None,
+            time_base: Rational::new(1, 10),
+            width: 640,
+            height: 480,
+        })
+    }
+
+    #[test]
+    fn writes_parts_and_segments() {
+        ffmpeg::init().unwrap();
+        let dir = std::env::temp_dir().join(format!("smartcam-hls-{}", std::process::id()));
+        let config = HlsConfig {
+            segment_type: Some(HlsSegmentType::MpegTs),
+            segment_duration: Some(1),
+            part_duration: Some(0.5),
+            playlist_size: Some(2),
+            ..Default::default()
+        };
+        let label = "hls-test".to_string();
+        let mut packager = HlsPackager::with_config(label.clone(), dir.clone(), &config).unwrap();
+        let progress = PROGRESS.lock().unwrap().get(&label).unwrap().clone();
+        for i in 0..35 {
+            packager.write(packet(i)).unwrap();
+        }
+
+        assert_eq!(
+            *progress.borrow(),
+            Progress {
+                sequence: 3,
+                parts: 0
+            }
+        );
+        let listed: Vec<u64> = packager.segments.iter().map(|s| s.sequence).collect();
+        assert_eq!(listed, [1, 2]);
+        for segment in &packager.segments {
+            assert_eq!(segment.parts.len(), 2);
+            assert!(segment.parts[0].independent);
+            assert!(!segment.parts[1].independent);
+            assert!((segment.duration() - 1.0).abs() < 1e-9);
+            // The parts cover the whole file:
+            let size = fs::metadata(dir.join(&segment.file)).unwrap().len();
+            assert_eq!(segment.parts[0].offset, 0);
+            assert_eq!(segment.parts[1].offset, segment.parts[0].length);
+            assert_eq!(segment.parts[1].offset + segment.parts[1].length, size);
+        }
+
+        let playlist = fs::read_to_string(dir.join(HLS_PLAYLIST)).unwrap();
+        assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:1\n"));
+        assert!(playlist.contains("#EXTINF:1.00000,\nsegment-2.ts\n"));
+        // Kept a while after leaving the playlist:
+        assert!(dir.join("segment-0.ts").exists());
+
+        packager.reset();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
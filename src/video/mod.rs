@@ -1,12 +1,19 @@
+pub mod export;
 mod file_writer;
+pub mod hls;
+pub mod osd;
 mod rtc_stream;
 pub mod rtc_track;
+pub mod thumbnail;
 mod video_proc;
 
+use crate::audio::AudioReceiver;
 use crate::config;
 use crate::config::CameraConfig;
+use crate::event::EventMetadata;
 use crate::frame::VideoFrame;
 use crate::upload;
+use bytes::Bytes;
 use chrono;
 use chrono::{DateTime, Utc};
 use ffmpeg::{
@@ -14,9 +21,13 @@ use ffmpeg::{
     util::rational::Rational, Dictionary,
 };
 use ffmpeg_next as ffmpeg;
+use ffmpeg_sys_next as ffs;
 use log::{debug, error, info, warn};
 use rtc_track::RTCTrack;
+use std::ffi::c_void;
 use std::fs;
+use std::path::Path;
+use std::ptr;
 use std::sync::mpsc::Sender;
 use std::sync::{mpsc, Arc};
 use std::thread;
@@ -26,18 +37,35 @@ pub(crate) use file_writer::VideoFileWriter;
 pub(crate) use rtc_stream::VideoRTCStream;
 pub(crate) use video_proc::VideoProc;
 
+/// Encoded H.264 packet shared between live stream outputs so
+/// each camera is only encoded once
+#[derive(Clone, Debug)]
+pub struct EncodedPacket {
+    pub data: Bytes,
+    pub pts: Option<i64>,
+    pub dts: Option<i64>,
+    pub is_key: bool,
+    /// SPS and PPS, on keyframes that carry them
+    pub parameter_sets: Option<Bytes>,
+    pub time_base: Rational,
+    pub width: u32,
+    pub height: u32,
+}
+
 pub fn start_video_writer(
     camera: Arc<CameraConfig>,
     start_time: DateTime<Utc>,
     width: u32,
     height: u32,
+    audio_rx: Option<AudioReceiver>,
 ) -> Sender<VideoFrame> {
     let (video_tx, video_rx) = mpsc::channel::<VideoFrame>();
 
     let label = camera.label.clone();
     thread::spawn(move || -> () {
         let app_config = config::load_config(None);
-        let mut video_frame_proc = VideoFileWriter::new(label, start_time, width, height);
+        let mut video_frame_proc =
+            VideoFileWriter::new(label, start_time, width, height, audio_rx);
         match video_frame_proc.receive_file(video_rx) {
             Ok(p) => {
                 if let Some(b) = app_config.cloud.enabled {
@@ -56,10 +84,19 @@ pub fn start_video_writer(
 }
 
 fn handle_upload(path: String) -> () {
-    match Runtime::new().unwrap().block_on(upload::upload_file(&path)) {
+    let video = Path::new(&path);
+    let mut sidecars = vec![
+        EventMetadata::path_for(video),
+        thumbnail::thumbnail_path(video),
+    ];
+    if let Some((preview, _)) = thumbnail::find_preview(video) {
+        sidecars.push(preview);
+    }
+    let runtime = Runtime::new().unwrap();
+    match runtime.block_on(upload::upload_file(&path)) {
         Ok(_) => {
             debug!("Deleting file {}", &path);
-            fs::remove_file(path).unwrap();
+            fs::remove_file(&path).unwrap();
         }
         Err(e) => {
             error!("File upload failed: {}", e);
@@ -67,6 +104,19 @@ fn handle_upload(path: String) -> () {
                 "Skipping deletion due to upload failure; video retained at {}",
                 &path
             );
+            return;
+        }
+    }
+
+    for sidecar in sidecars.into_iter().filter(|p| p.exists()) {
+        let sidecar = sidecar.to_string_lossy().into_owned();
+        match runtime.block_on(upload::upload_file(&sidecar)) {
+            Ok(_) => {
+                if let Err(e) = fs::remove_file(&sidecar) {
+                    warn!("Failed to delete {}: {}", sidecar, e);
+                }
+            }
+            Err(e) => error!("Upload of {} failed: {}", sidecar, e),
         }
     }
 }
@@ -89,6 +139,7 @@ pub fn init_encoder<'a>(
     octx: &mut Output,
     fps: i32,
     set_global_hdr: bool,
+    gop: Option<u32>,
 ) -> Video {
     let config = config::load_config(None);
 
@@ -110,6 +161,9 @@ pub fn init_encoder<'a>(
     encoder.set_height(height);
     encoder.set_format(VideoProc::video_format());
     encoder.set_time_base(Rational::new(1, fps.into()));
+    if let Some(g) = gop {
+        encoder.set_gop(g);
+    }
 
     if set_global_hdr {
         let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
@@ -131,3 +185,125 @@ pub fn init_encoder<'a>(
 
     encoder
 }
+
+/// Add an H.264 stream to `octx` for muxing already-encoded packets.
+/// `extradata` is the SPS and PPS, which MP4 needs up front in the header
+pub fn add_copy_stream(
+    octx: &mut Output,
+    width: u32,
+    height: u32,
+    time_base: Rational,
+    extradata: Option<&[u8]>,
+) -> Result<(), ffmpeg::Error> {
+    let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+    let mut ost = octx.add_stream(codec::encoder::find(codec::Id::H264))?;
+    let mut encoder = ost.codec().encoder().video()?;
+    encoder.set_width(width);
+    encoder.set_height(height);
+    encoder.set_format(VideoProc::video_format());
+    encoder.set_time_base(time_base);
+    if global_header {
+        encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    ost.set_parameters(&encoder);
+    ost.set_time_base(time_base);
+
+    if let Some(extradata) = extradata {
+        unsafe {
+            let par = (*ost.as_mut_ptr()).codecpar;
+            // ffmpeg reads past the end, so it must be padded and its own allocation:
+            let buf = ffs::av_mallocz(extradata.len() + ffs::AV_INPUT_BUFFER_PADDING_SIZE as usize)
+                as *mut u8;
+            if buf.is_null() {
+                return Err(ffmpeg::Error::from(ffs::AVERROR(ffs::ENOMEM)));
+            }
+            ptr::copy_nonoverlapping(extradata.as_ptr(), buf, extradata.len());
+            ffs::av_freep(&mut (*par).extradata as *mut *mut u8 as *mut c_void);
+            (*par).extradata = buf;
+            (*par).extradata_size = extradata.len() as i32;
+        }
+    }
+    Ok(())
+}
+
+/// The SPS and PPS in an Annex B H.264 packet, each with its start code. The
+/// live encoder repeats them on keyframes rather than keeping them in
+/// extradata, as WebRTC needs them in the stream
+pub fn h264_parameter_sets(data: &[u8]) -> Option<Bytes> {
+    let mut sets = Vec::new();
+    for nal in annex_b_units(data) {
+        if let Some(header) = nal.first() {
+            // 7 is a sequence parameter set, 8 a picture parameter set:
+            if matches!(header & 0x1f, 7 | 8) {
+                sets.extend_from_slice(&[0, 0, 0, 1]);
+                sets.extend_from_slice(nal);
+            }
+        }
+    }
+    if sets.is_empty() {
+        None
+    } else {
+        Some(Bytes::from(sets))
+    }
+}
+
+/// The NAL units in an Annex B packet, without their start codes
+fn annex_b_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push(i);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, &start)| {
+            let payload = start + 3;
+            let mut end = starts.get(n + 1).copied().unwrap_or(data.len());
+            // Leaves out the next 4 byte start code's leading zero:
+            while end > payload && data[end - 1] == 0 {
+                end -= 1;
+            }
+            &data[payload..end]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: [u8; 5] = [0x67, 0x42, 0xc0, 0x1e, 0x8c];
+    const PPS: [u8; 3] = [0x68, 0xce, 0x3c];
+    const IDR: [u8; 4] = [0x65, 0x88, 0x84, 0x21];
+
+    #[test]
+    fn parameter_sets_from_keyframe() {
+        let mut packet = vec![0, 0, 0, 1];
+        packet.extend_from_slice(&SPS);
+        packet.extend_from_slice(&[0, 0, 0, 1]);
+        packet.extend_from_slice(&PPS);
+        packet.extend_from_slice(&[0, 0, 1]);
+        packet.extend_from_slice(&IDR);
+
+        let mut expected = vec![0, 0, 0, 1];
+        expected.extend_from_slice(&SPS);
+        expected.extend_from_slice(&[0, 0, 0, 1]);
+        expected.extend_from_slice(&PPS);
+        assert_eq!(h264_parameter_sets(&packet).as_deref(), Some(&expected[..]));
+    }
+
+    #[test]
+    fn no_parameter_sets_without_sps_or_pps() {
+        let mut packet = vec![0, 0, 0, 1];
+        packet.extend_from_slice(&IDR);
+        assert_eq!(h264_parameter_sets(&packet), None);
+        assert_eq!(h264_parameter_sets(&[]), None);
+    }
+}
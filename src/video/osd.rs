@@ -0,0 +1,109 @@
+//! Time and camera label drawn onto frames before they're encoded
+
+use crate::config::{OverlayConfig, OverlayPosition};
+use crate::frame::Frame;
+
+use chrono::{DateTime, Local, Utc};
+use log::error;
+use opencv::core::{Point, Scalar};
+use opencv::imgproc::{self, FONT_HERSHEY_SIMPLEX, LINE_AA};
+use opencv::prelude::*;
+use std::sync::Arc;
+
+const DEFAULT_FORMAT: &str = "{label} %Y-%m-%d %H:%M:%S";
+const LABEL_PLACEHOLDER: &str = "{label}";
+/// Font scale for 720p, scaled with the frame height so text looks the same
+/// at any resolution
+const DEFAULT_FONT_SCALE: f64 = 1.0;
+const REFERENCE_HEIGHT: f64 = 720.0;
+const MARGIN: f64 = 10.0;
+const THICKNESS: f64 = 2.0;
+
+pub struct Overlay {
+    /// `format` with the label already filled in
+    format: String,
+    position: OverlayPosition,
+    font_scale: f64,
+    /// BGR, as the frames are
+    color: Scalar,
+    utc: bool,
+}
+
+impl Overlay {
+    pub fn new(config: &OverlayConfig, label: &str) -> Self {
+        let [r, g, b] = config.color.unwrap_or([255, 255, 255]);
+        Self {
+            format: config
+                .format
+                .as_deref()
+                .unwrap_or(DEFAULT_FORMAT)
+                // Escaped in case the label has a `%` in it:
+                .replace(LABEL_PLACEHOLDER, &label.replace('%', "%%")),
+            position: config.position.unwrap_or(OverlayPosition::TopLeft),
+            font_scale: config.font_scale.unwrap_or(DEFAULT_FONT_SCALE),
+            color: Scalar::new(b as f64, g as f64, r as f64, 0.0),
+            utc: config.utc.unwrap_or(false),
+        }
+    }
+
+    /// A copy of `frame` with the overlay drawn on, at the frame's own time
+    pub fn apply(&self, frame: &Arc<Frame>) -> Arc<Frame> {
+        let mut stamped = (**frame).clone();
+        match self.draw(stamped.img_mut(), frame.time()) {
+            Ok(()) => Arc::new(stamped),
+            Err(e) => {
+                error!("Failed to draw overlay: {}", e);
+                Arc::clone(frame)
+            }
+        }
+    }
+
+    /// Draw the text for `time` onto `img`, outlined so it shows on any
+    /// background
+    pub fn draw(&self, img: &mut Mat, time: DateTime<Utc>) -> opencv::Result<()> {
+        let text = if self.utc {
+            time.format(&self.format).to_string()
+        } else {
+            time.with_timezone(&Local).format(&self.format).to_string()
+        };
+
+        let scale = self.font_scale * img.rows() as f64 / REFERENCE_HEIGHT;
+        let thickness = (THICKNESS * scale).ceil().max(1.0) as i32;
+        let outline = thickness + 2;
+        let mut baseline = 0;
+        let size =
+            imgproc::get_text_size(&text, FONT_HERSHEY_SIMPLEX, scale, outline, &mut baseline)?;
+        let margin = (MARGIN * scale).max(2.0) as i32;
+        let x = match self.position {
+            OverlayPosition::TopLeft | OverlayPosition::BottomLeft => margin,
+            OverlayPosition::TopRight | OverlayPosition::BottomRight => {
+                img.cols() - size.width - margin
+            }
+        };
+        // `put_text` places text by its baseline:
+        let y = match self.position {
+            OverlayPosition::TopLeft | OverlayPosition::TopRight => margin + size.height,
+            OverlayPosition::BottomLeft | OverlayPosition::BottomRight => {
+                img.rows() - margin - baseline
+            }
+        };
+
+        for (color, thickness) in &[
+            (Scalar::new(0.0, 0.0, 0.0, 0.0), outline),
+            (self.color, thickness),
+        ] {
+            imgproc::put_text(
+                img,
+                &text,
+                Point::new(x, y),
+                FONT_HERSHEY_SIMPLEX,
+                scale,
+                *color,
+                *thickness,
+                LINE_AA,
+                false,
+            )?;
+        }
+        Ok(())
+    }
+}
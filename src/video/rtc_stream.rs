@@ -1,27 +1,37 @@
 use super::init_encoder;
-use super::{RTCTrack, VideoProc};
+use super::osd::Overlay;
+use super::{h264_parameter_sets, EncodedPacket, RTCTrack, VideoProc};
+use crate::audio::{AudioEncoder, AudioReceiver};
 use crate::config;
 use crate::frame::Frame;
 
 use bytes::Bytes;
 use chrono;
 use chrono::Duration;
-use ffmpeg::{format, Packet};
+use ffmpeg::{codec, format, util::rational::Rational, Packet};
 use ffmpeg_next as ffmpeg;
 use ffmpeg_sys_next as ffs;
 use ffs::avformat_alloc_context;
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
 use std::sync::Arc;
 use tokio::sync::mpsc::Receiver as AsyncReceiver;
-use webrtc::api::media_engine::MIME_TYPE_H264;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, watch};
+use webrtc::api::media_engine::{MIME_TYPE_H264, MIME_TYPE_OPUS};
 use webrtc::media::Sample;
 use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
 
 pub struct VideoRTCStream {
     track: Arc<RTCTrack>,
+    audio_track: Option<Arc<RTCTrack>>,
     camera: config::CameraConfig,
+    latest_tx: watch::Sender<Option<Arc<Frame>>>,
+    latest_rx: watch::Receiver<Option<Arc<Frame>>>,
+    packet_tx: broadcast::Sender<Arc<EncodedPacket>>,
 }
 
+const PACKET_BUFFER_SIZE: usize = 256;
+
 impl VideoRTCStream {
     pub fn new(camera: config::CameraConfig) -> Self {
         // Create a video track
@@ -34,19 +44,55 @@ impl VideoRTCStream {
             camera.label.clone(),
         ));
 
+        let audio_track = if camera.audio.unwrap_or(false) {
+            Some(Arc::new(RTCTrack::new(
+                RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_OPUS.to_owned(),
+                    ..Default::default()
+                },
+                "audio".to_owned(),
+                camera.label.clone(),
+            )))
+        } else {
+            None
+        };
+
+        let (latest_tx, latest_rx) = watch::channel(None);
+        let (packet_tx, _) = broadcast::channel(PACKET_BUFFER_SIZE);
+
         Self {
             track: video_track,
+            audio_track,
             camera: camera,
+            latest_tx,
+            latest_rx,
+            packet_tx,
         }
     }
 
-    pub async fn start(&self, width: u32, height: u32, mut rx: AsyncReceiver<Arc<Frame>>) {
+    /// Encode `first` and the frames after it, which set the stream's size
+    pub async fn start(&self, first: Arc<Frame>, mut rx: AsyncReceiver<Arc<Frame>>) {
+        let (width, height) = (first.width(), first.height());
         let fps = 90000;
         // unsafe:
         let mut octx = unsafe { format::context::output::Output::wrap(avformat_alloc_context()) };
-        let encoder = init_encoder(width, height, &mut octx, fps, false);
+        let app_config = config::load_config(None);
+        // Every live output shares this encoder, so only HLS's segments set its keyframes:
+        let keyframe_interval = if app_config.hls.enabled.unwrap_or(false) {
+            app_config.hls.keyframe_interval
+        } else {
+            None
+        };
+        let encoder = init_encoder(width, height, &mut octx, fps, false, keyframe_interval);
 
         let mut video_proc = VideoProc::new(fps, octx, encoder);
+        video_proc.set_overlay(
+            app_config
+                .osd
+                .live
+                .as_ref()
+                .map(|o| Overlay::new(o, &self.camera.label)),
+        );
 
         // It is important to use a time.Ticker instead of time.Sleep because
         // * avoids accumulating skew, just calling time.Sleep didn't compensate for the time spent parsing the data
@@ -58,13 +104,30 @@ impl VideoRTCStream {
         );
 
         debug!("Receiving stream {}", self.camera.label);
-        while let Some(frame) = rx.recv().await {
+        let mut first = Some(first);
+        loop {
+            let frame = match first.take() {
+                Some(f) => f,
+                None => match rx.recv().await {
+                    Some(f) => f,
+                    None => break,
+                },
+            };
+            if let Err(e) = self.latest_tx.send(Some(Arc::clone(&frame))) {
+                error!("Failed to publish latest frame: {}", e);
+            }
+
             let num_conns = *self.track.num_conns.lock().unwrap();
-            if num_conns == 0 {
+            let num_packet_subscribers = self.packet_tx.receiver_count();
+            if num_conns == 0 && num_packet_subscribers == 0 {
                 trace!("No connections -- continuing");
                 continue;
             } else {
-                trace!("{} active connection", num_conns);
+                trace!(
+                    "{} active connection, {} packet subscribers",
+                    num_conns,
+                    num_packet_subscribers
+                );
             }
 
             trace!("Writing frame to encoder");
@@ -74,12 +137,35 @@ impl VideoRTCStream {
             encoded.set_stream(ost_index);
             while video_proc.encoder.receive_packet(&mut encoded).is_ok() {
                 trace!("Getting bytes from encoder");
+                let data =
+                    Bytes::copy_from_slice(encoded.data().expect("Failed to get encoded data"));
+
+                if num_packet_subscribers > 0 {
+                    // Only fails if every subscriber has gone away in the meantime:
+                    let _ = self.packet_tx.send(Arc::new(EncodedPacket {
+                        data: data.clone(),
+                        pts: encoded.pts(),
+                        dts: encoded.dts(),
+                        is_key: encoded.is_key(),
+                        parameter_sets: if encoded.is_key() {
+                            h264_parameter_sets(&data)
+                        } else {
+                            None
+                        },
+                        time_base: Rational::new(1, fps),
+                        width,
+                        height,
+                    }));
+                }
+
+                if num_conns == 0 {
+                    continue;
+                }
+
                 if let Err(e) = &self
                     .track
                     .write_sample(&Sample {
-                        data: Bytes::copy_from_slice(
-                            encoded.data().expect("Failed to get encoded data"),
-                        ),
+                        data,
                         duration: Duration::milliseconds(duration_ms).to_std().unwrap(),
                         ..Default::default()
                     })
@@ -91,10 +177,98 @@ impl VideoRTCStream {
             }
         }
 
-        error!("Video stream loop terminated");
+        debug!("Stream {} ended", self.camera.label);
+    }
+
+    /// Transcode camera audio to Opus for the audio track
+    pub async fn start_audio(&self, mut rx: AudioReceiver) {
+        let audio_track = match &self.audio_track {
+            Some(t) => Arc::clone(t),
+            None => return,
+        };
+        let mut encoder = match AudioEncoder::new(codec::Id::OPUS, None, false) {
+            Ok(e) => e,
+            Err(e) => {
+                error!("Failed to create Opus encoder for {}: {}", self.camera.label, e);
+                return;
+            }
+        };
+        let duration = Duration::milliseconds(encoder.frame_duration_ms())
+            .to_std()
+            .unwrap();
+
+        debug!("Receiving audio {}", self.camera.label);
+        loop {
+            let audio = match rx.recv().await {
+                Ok(a) => a,
+                Err(RecvError::Lagged(n)) => {
+                    warn!("Audio stream {} dropped {} frames", self.camera.label, n);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            if *audio_track.num_conns.lock().unwrap() == 0 {
+                continue;
+            }
+
+            if let Err(e) = encoder.send(&audio) {
+                error!("Failed to encode audio: {}", e);
+                continue;
+            }
+            let mut encoded = Packet::empty();
+            while encoder.receive_packet(&mut encoded) {
+                if let Err(e) = audio_track
+                    .write_sample(&Sample {
+                        data: Bytes::copy_from_slice(
+                            encoded.data().expect("Failed to get encoded data"),
+                        ),
+                        duration,
+                        ..Default::default()
+                    })
+                    .await
+                {
+                    error!("Failed to write to audio_track: {}", e);
+                }
+            }
+        }
+
+        error!("Audio stream loop terminated");
     }
 
     pub fn track(&self) -> Arc<RTCTrack> {
         Arc::clone(&self.track)
     }
+
+    /// Every track a viewer should receive
+    pub fn tracks(&self) -> Vec<Arc<RTCTrack>> {
+        let mut tracks = vec![Arc::clone(&self.track)];
+        if let Some(a) = &self.audio_track {
+            tracks.push(Arc::clone(a));
+        }
+        tracks
+    }
+
+    /// most recent frame received from the camera, if any
+    pub fn latest_frame(&self) -> Option<Arc<Frame>> {
+        self.latest_rx.borrow().clone()
+    }
+
+    /// receiver for encoded packets, so other outputs can reuse this stream's encoder
+    pub fn subscribe_packets(&self) -> broadcast::Receiver<Arc<EncodedPacket>> {
+        self.packet_tx.subscribe()
+    }
+
+    pub fn label(&self) -> &str {
+        &self.camera.label
+    }
+
+    pub fn camera(&self) -> &config::CameraConfig {
+        &self.camera
+    }
+
+    /// receiver notified each time a new frame arrives
+    pub fn subscribe_frames(&self) -> watch::Receiver<Option<Arc<Frame>>> {
+        self.latest_rx.clone()
+    }
 }
@@ -0,0 +1,245 @@
+//! A poster JPEG of each recording's busiest frame and an optional short
+//! animation of it, made from the frames the recording was written from
+
+use crate::config::{self, PreviewType, ThumbnailConfig};
+use crate::frame::Frame;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use ffmpeg::format::{self, context::output::Output, Pixel};
+use ffmpeg::software::scaling::{context::Context, flag::Flags};
+use ffmpeg::{codec, encoder, frame, Packet, Rational};
+use ffmpeg_next as ffmpeg;
+use log::{debug, error};
+use opencv::core::Size;
+use opencv::imgproc::{resize, INTER_AREA};
+use opencv::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const THUMBNAIL_EXTENSION: &str = "thumbnail.jpg";
+const PREVIEW_EXTENSION: &str = "preview";
+const DEFAULT_WIDTH: u32 = 640;
+const DEFAULT_QUALITY: i32 = 80;
+const DEFAULT_PREVIEW_WIDTH: u32 = 320;
+/// Frames in a preview at most, sampled evenly across the recording
+const PREVIEW_FRAMES: usize = 24;
+const PREVIEW_FPS: i32 = 4;
+/// Time between preview frames to start with, doubled whenever the preview
+/// fills up
+const PREVIEW_INTERVAL_MS: i64 = 500;
+
+pub fn enabled(config: &ThumbnailConfig) -> bool {
+    config.enabled.unwrap_or(true)
+}
+
+fn sidecar_path(video: &Path, extension: &str) -> PathBuf {
+    let mut name = video.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+pub fn thumbnail_path(video: &Path) -> PathBuf {
+    sidecar_path(video, THUMBNAIL_EXTENSION)
+}
+
+pub fn preview_path(video: &Path, preview: PreviewType) -> PathBuf {
+    sidecar_path(
+        video,
+        &format!("{}.{}", PREVIEW_EXTENSION, preview.extension()),
+    )
+}
+
+/// Whichever preview was written for `video`, which may not be the type
+/// currently configured
+pub fn find_preview(video: &Path) -> Option<(PathBuf, PreviewType)> {
+    [PreviewType::WebP, PreviewType::Gif]
+        .iter()
+        .map(|t| (preview_path(video, *t), *t))
+        .find(|(p, _)| p.exists())
+}
+
+/// Collects frames as a recording is written
+pub struct Thumbnailer {
+    config: ThumbnailConfig,
+    /// frame with the most motion so far
+    peak: Option<(f64, Arc<Frame>)>,
+    preview: Vec<Mat>,
+    preview_interval: Duration,
+    last_sampled: Option<DateTime<Utc>>,
+}
+
+impl Thumbnailer {
+    pub fn new() -> Self {
+        Self {
+            config: config::load_config(None).thumbnails.clone(),
+            peak: None,
+            preview: Vec::new(),
+            preview_interval: Duration::milliseconds(PREVIEW_INTERVAL_MS),
+            last_sampled: None,
+        }
+    }
+
+    pub fn add(&mut self, frame: &Arc<Frame>, motion: f64) {
+        if !enabled(&self.config) {
+            return;
+        }
+        // The first frame stands in when nothing moved, e.g. an external trigger:
+        if self.peak.as_ref().map(|(m, _)| motion > *m).unwrap_or(true) {
+            self.peak = Some((motion, Arc::clone(frame)));
+        }
+
+        if self.config.preview.is_none() {
+            return;
+        }
+        if let Some(t) = self.last_sampled {
+            if frame.time() - t < self.preview_interval {
+                return;
+            }
+        }
+        self.last_sampled = Some(frame.time());
+        match scale(
+            frame.img(),
+            self.config.preview_width.unwrap_or(DEFAULT_PREVIEW_WIDTH),
+        ) {
+            Ok(img) => self.preview.push(img),
+            Err(e) => error!("Failed to scale preview frame: {}", e),
+        }
+        // Keep every other frame, so the preview covers the whole recording
+        // however long it gets:
+        if self.preview.len() >= PREVIEW_FRAMES {
+            let mut i = 0;
+            self.preview.retain(|_| {
+                i += 1;
+                i % 2 == 1
+            });
+            self.preview_interval = self.preview_interval * 2;
+        }
+    }
+
+    /// Write the thumbnail and preview for `video`, returning where they went
+    pub fn write(self, video: &Path) -> Vec<PathBuf> {
+        let mut written = Vec::new();
+        let (_, frame) = match self.peak {
+            Some(p) => p,
+            None => return written,
+        };
+
+        let path = thumbnail_path(video);
+        let jpeg = frame.to_jpeg(
+            Some(self.config.width.unwrap_or(DEFAULT_WIDTH)),
+            Some(self.config.quality.unwrap_or(DEFAULT_QUALITY)),
+        );
+        match jpeg.map_err(anyhow::Error::from).and_then(|j| {
+            fs::write(&path, j)?;
+            Ok(())
+        }) {
+            Ok(()) => written.push(path),
+            Err(e) => error!("Failed to write thumbnail for {}: {}", video.display(), e),
+        }
+
+        if let Some(preview) = self.config.preview {
+            if self.preview.is_empty() {
+                return written;
+            }
+            let path = preview_path(video, preview);
+            match write_preview(&path, preview, &self.preview) {
+                Ok(()) => written.push(path),
+                Err(e) => {
+                    error!("Failed to write preview for {}: {}", video.display(), e);
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        written
+    }
+}
+
+/// `img` scaled down to `width` keeping its aspect ratio, with an even
+/// height as the encoders need
+fn scale(img: &Mat, width: u32) -> opencv::Result<Mat> {
+    let size = img.size()?;
+    let width = (width as i32).min(size.width) & !1;
+    let height = ((size.height as f64 * width as f64 / size.width as f64).round() as i32) & !1;
+    let mut dst = Mat::default();
+    resize(
+        img,
+        &mut dst,
+        Size::new(width.max(2), height.max(2)),
+        0.0,
+        0.0,
+        INTER_AREA,
+    )?;
+    Ok(dst)
+}
+
+fn write_preview(path: &Path, preview: PreviewType, frames: &[Mat]) -> Result<()> {
+    let (codec, pixel) = match preview {
+        PreviewType::WebP => (codec::encoder::find_by_name("libwebp_anim"), Pixel::YUV420P),
+        PreviewType::Gif => (codec::encoder::find(codec::Id::GIF), Pixel::RGB8),
+    };
+    let codec = codec
+        .ok_or_else(|| anyhow!("ffmpeg was built without a {} encoder", preview.extension()))?;
+    let size = frames[0].size()?;
+    let (width, height) = (size.width as u32, size.height as u32);
+    let time_base = Rational::new(1, PREVIEW_FPS);
+
+    let mut octx = format::output(&path)?;
+    let mut encoder = octx.add_stream(codec)?.codec().encoder().video()?;
+    encoder.set_width(width);
+    encoder.set_height(height);
+    encoder.set_format(pixel);
+    encoder.set_time_base(time_base);
+    let mut encoder = encoder.open_as(codec)?;
+    octx.write_header()?;
+    let stream_tb = octx.stream(0).unwrap().time_base();
+
+    let mut converter = Context::get(
+        Pixel::BGR24,
+        width,
+        height,
+        pixel,
+        width,
+        height,
+        Flags::BILINEAR,
+    )?;
+    let mut encoded = Packet::empty();
+    let mut write_packets = |encoder: &mut encoder::video::Encoder, octx: &mut Output| {
+        while encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(0);
+            encoded.rescale_ts(time_base, stream_tb);
+            encoded.write_interleaved(octx)?;
+        }
+        Ok::<_, ffmpeg::Error>(())
+    };
+
+    for (i, img) in frames.iter().enumerate() {
+        let bgr = bgr_frame(img, width, height)?;
+        let mut converted = frame::Video::empty();
+        converter.run(&bgr, &mut converted)?;
+        converted.set_pts(Some(i as i64));
+        encoder.send_frame(&converted)?;
+        write_packets(&mut encoder, &mut octx)?;
+    }
+    encoder.send_eof()?;
+    write_packets(&mut encoder, &mut octx)?;
+    octx.write_trailer()?;
+    debug!("Wrote {} frame preview to {}", frames.len(), path.display());
+    Ok(())
+}
+
+/// A BGR image as an ffmpeg frame, copied row by row as the strides differ
+fn bgr_frame(img: &Mat, width: u32, height: u32) -> Result<frame::Video> {
+    let mut video = frame::Video::new(Pixel::BGR24, width, height);
+    let stride = video.stride(0);
+    let row_len = width as usize * 3;
+    let src = img.data_bytes()?;
+    let dst = video.data_mut(0);
+    for row in 0..height as usize {
+        dst[row * stride..row * stride + row_len]
+            .copy_from_slice(&src[row * row_len..(row + 1) * row_len]);
+    }
+    Ok(video)
+}
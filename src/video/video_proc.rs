@@ -1,7 +1,8 @@
+use super::osd::Overlay;
 use crate::frame::Frame;
 use chrono::{DateTime, Utc};
 use ffmpeg::{
-    codec::encoder::video::Video, format::context::output::Output, format::Pixel, frame,
+    codec::encoder::video::Video, format::context::output::Output, format::Pixel, frame, picture,
 };
 use ffmpeg_next as ffmpeg;
 use ffmpeg_sys_next as ffs;
@@ -19,6 +20,8 @@ pub struct VideoProc {
     frame_count: i64,
     octx: Output,
     pub encoder: Video,
+    /// drawn on each frame before it's encoded
+    overlay: Option<Overlay>,
 }
 
 impl VideoProc {
@@ -30,9 +33,14 @@ impl VideoProc {
             frame_count: 0,
             octx: octx,
             encoder: encoder,
+            overlay: None,
         }
     }
 
+    pub fn set_overlay(&mut self, overlay: Option<Overlay>) {
+        self.overlay = overlay;
+    }
+
     fn image_format() -> Pixel {
         Pixel::BGR24
         // Pixel::YUYV422
@@ -58,6 +66,10 @@ impl VideoProc {
 
     /// convert frame to ffmpeg frame and write to encoder, return frame duration
     pub fn process_frame(&mut self, frame: Arc<Frame>) -> Option<i64> {
+        let frame = match &self.overlay {
+            Some(overlay) => overlay.apply(&frame),
+            None => frame,
+        };
         unsafe {
             // unsafe:
             let mut dst = av_frame_alloc();
@@ -100,6 +112,10 @@ impl VideoProc {
             );
             let pts = pts.unwrap_or(0);
             converted.set_pts(Some(pts));
+            // Outputs can start mid-stream, so make sure they start on a keyframe:
+            if self.frame_count == 0 {
+                converted.set_kind(picture::Type::I);
+            }
             self.encoder.send_frame(&converted).unwrap();
 
             self.previous_frame_time = Some(frame.time());
@@ -0,0 +1,42 @@
+use super::auth::Operator;
+use super::cameras::with_pipelines;
+use crate::arming;
+use crate::auth::{audit, User};
+use crate::pipeline::SharedPipelines;
+
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+pub(crate) struct Arming {
+    mode: String,
+    modes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SetMode {
+    mode: String,
+}
+
+#[get("/arming")]
+pub(crate) async fn get_arming(_user: User) -> Json<Arming> {
+    Json(Arming {
+        mode: arming::mode(),
+        modes: arming::modes(),
+    })
+}
+
+/// Switch every camera to another mode, e.g. `away` when leaving the house
+#[put("/arming", format = "json", data = "<request>")]
+pub(crate) async fn set_arming_mode(
+    request: Json<SetMode>,
+    operator: Operator,
+    pipelines: &State<SharedPipelines>,
+) -> Result<Status, (Status, String)> {
+    let mode = request.into_inner().mode;
+    audit::record(&operator.0, "arming", None, Some(&mode));
+    with_pipelines(pipelines, move |p| p.set_arming_mode(&mode)).await?;
+    Ok(Status::NoContent)
+}
@@ -0,0 +1,180 @@
+use crate::auth::{self, audit, LoginSessions, User};
+use crate::config::{self, Role};
+
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::outcome::try_outcome;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+const SESSION_COOKIE: &str = "smartcam_session";
+
+/// Logged in with a session cookie or an `Authorization: Bearer` API token.
+/// Anyone is an admin while auth is disabled
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let config = config::load_config(None);
+        let address = request.client_ip();
+        if !auth::enabled(&config.auth) {
+            return Outcome::Success(User::anonymous(address));
+        }
+
+        let bearer = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "));
+        let user = match bearer {
+            Some(token) => auth::token_user(&config.auth, token.trim(), address),
+            None => request
+                .cookies()
+                .get(SESSION_COOKIE)
+                .zip(request.rocket().state::<Arc<LoginSessions>>())
+                .and_then(|(cookie, sessions)| {
+                    sessions.user(&config.auth, cookie.value(), address)
+                }),
+        };
+        match user {
+            Some(user) => Outcome::Success(user),
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+async fn with_role<'r>(request: &'r Request<'_>, role: Role) -> Outcome<User, ()> {
+    let user = try_outcome!(request.guard::<User>().await);
+    if user.role >= role {
+        Outcome::Success(user)
+    } else {
+        Outcome::Failure((Status::Forbidden, ()))
+    }
+}
+
+/// A user allowed to change how cameras behave
+pub(crate) struct Operator(pub User);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Operator {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        with_role(request, Role::Operator).await.map(Operator)
+    }
+}
+
+/// A user allowed to add and remove cameras
+pub(crate) struct Admin(pub User);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        with_role(request, Role::Admin).await.map(Admin)
+    }
+}
+
+/// Forbidden unless `user` can see camera `label`
+pub(crate) fn check_camera(user: &User, label: &str) -> Result<(), Status> {
+    if user.can_view(label) {
+        Ok(())
+    } else {
+        Err(Status::Forbidden)
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Login {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Me {
+    name: String,
+    role: Role,
+    /// `None` for every camera
+    cameras: Option<Vec<String>>,
+    auth_enabled: bool,
+}
+
+impl Me {
+    fn new(user: User) -> Self {
+        let role = user.role;
+        Self {
+            name: user.name,
+            role,
+            cameras: user.cameras.filter(|_| role != Role::Admin),
+            auth_enabled: auth::enabled(&config::load_config(None).auth),
+        }
+    }
+}
+
+/// Start a browser session, set as a cookie
+#[post("/login", format = "json", data = "<login>")]
+pub(crate) async fn login(
+    login: Json<Login>,
+    address: Option<IpAddr>,
+    cookies: &CookieJar<'_>,
+    sessions: &State<Arc<LoginSessions>>,
+) -> Result<Json<Me>, Status> {
+    let config = config::load_config(None);
+    if !auth::enabled(&config.auth) {
+        return Err(Status::NotFound);
+    }
+
+    let Login { username, password } = login.into_inner();
+    let name = username.clone();
+    let auth_config = config.auth.clone();
+    let user = tokio::task::spawn_blocking(move || {
+        auth::login(&auth_config, &username, &password, address)
+    })
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    match user {
+        Some(user) => {
+            let id = sessions.create(&config.auth, &user.name);
+            cookies.add(
+                Cookie::build(SESSION_COOKIE, id)
+                    .http_only(true)
+                    .same_site(SameSite::Strict)
+                    .secure(config.web.tls.is_some())
+                    .path("/")
+                    .finish(),
+            );
+            audit::record(&user, "login", None, None);
+            Ok(Json(Me::new(user)))
+        }
+        None => {
+            audit::record_failed_login(&name, address);
+            Err(Status::Unauthorized)
+        }
+    }
+}
+
+#[post("/logout")]
+pub(crate) async fn logout(
+    user: Option<User>,
+    cookies: &CookieJar<'_>,
+    sessions: &State<Arc<LoginSessions>>,
+) -> Status {
+    if let Some(cookie) = cookies.get(SESSION_COOKIE) {
+        sessions.remove(cookie.value());
+    }
+    cookies.remove(Cookie::named(SESSION_COOKIE));
+    if let Some(user) = user {
+        audit::record(&user, "logout", None, None);
+    }
+    Status::NoContent
+}
+
+#[get("/me")]
+pub(crate) async fn get_me(user: User) -> Json<Me> {
+    Json(Me::new(user))
+}
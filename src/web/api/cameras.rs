@@ -0,0 +1,118 @@
+use super::auth::{check_camera, Admin, Operator};
+use crate::auth::{audit, User};
+use crate::config::CameraConfig;
+use crate::pipeline::{CameraError, CameraStatus, Pipelines, SharedPipelines};
+
+use log::error;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub(crate) struct Toggle {
+    enabled: bool,
+}
+
+/// Starting and stopping pipelines joins threads, so is kept off the async workers
+pub(crate) async fn with_pipelines<T, F>(
+    pipelines: &SharedPipelines,
+    f: F,
+) -> Result<T, (Status, String)>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Pipelines) -> Result<T, CameraError> + Send + 'static,
+{
+    let pipelines = Arc::clone(pipelines);
+    match tokio::task::spawn_blocking(move || f(&mut pipelines.lock().unwrap())).await {
+        Ok(Ok(t)) => Ok(t),
+        Ok(Err(e)) => {
+            let status = match e {
+                CameraError::NotFound => Status::NotFound,
+                CameraError::Exists => Status::Conflict,
+                CameraError::Invalid(_) => Status::UnprocessableEntity,
+                CameraError::Save(_) => {
+                    error!("{}", e);
+                    Status::InternalServerError
+                }
+            };
+            Err((status, e.to_string()))
+        }
+        Err(e) => {
+            error!("Camera task failed: {}", e);
+            Err((Status::InternalServerError, e.to_string()))
+        }
+    }
+}
+
+#[get("/cameras")]
+pub(crate) async fn get_cameras(
+    user: User,
+    pipelines: &State<SharedPipelines>,
+) -> Result<Json<Vec<CameraStatus>>, (Status, String)> {
+    let mut cameras = with_pipelines(pipelines, |p| Ok(p.cameras())).await?;
+    cameras.retain(|c| user.can_view(&c.label));
+    Ok(Json(cameras))
+}
+
+#[post("/cameras", format = "json", data = "<camera>")]
+pub(crate) async fn add_camera(
+    camera: Json<CameraConfig>,
+    admin: Admin,
+    pipelines: &State<SharedPipelines>,
+) -> Result<Status, (Status, String)> {
+    let camera = camera.into_inner();
+    audit::record(&admin.0, "add_camera", Some(&camera.label), None);
+    with_pipelines(pipelines, move |p| p.add_camera(camera)).await?;
+    Ok(Status::Created)
+}
+
+#[delete("/cameras/<label>")]
+pub(crate) async fn remove_camera(
+    label: String,
+    admin: Admin,
+    pipelines: &State<SharedPipelines>,
+) -> Result<Status, (Status, String)> {
+    audit::record(&admin.0, "remove_camera", Some(&label), None);
+    with_pipelines(pipelines, move |p| p.remove_camera(&label)).await?;
+    Ok(Status::NoContent)
+}
+
+#[put("/cameras/<label>/recording", format = "json", data = "<toggle>")]
+pub(crate) async fn set_recording(
+    label: String,
+    toggle: Json<Toggle>,
+    operator: Operator,
+    pipelines: &State<SharedPipelines>,
+) -> Result<Status, (Status, String)> {
+    check_camera(&operator.0, &label).map_err(|s| (s, "camera not permitted".to_string()))?;
+    let enabled = toggle.enabled;
+    audit::record(
+        &operator.0,
+        "recording",
+        Some(&label),
+        Some(&enabled.to_string()),
+    );
+    with_pipelines(pipelines, move |p| p.set_recording(&label, enabled)).await?;
+    Ok(Status::NoContent)
+}
+
+#[put("/cameras/<label>/detection", format = "json", data = "<toggle>")]
+pub(crate) async fn set_detection(
+    label: String,
+    toggle: Json<Toggle>,
+    operator: Operator,
+    pipelines: &State<SharedPipelines>,
+) -> Result<Status, (Status, String)> {
+    check_camera(&operator.0, &label).map_err(|s| (s, "camera not permitted".to_string()))?;
+    let enabled = toggle.enabled;
+    audit::record(
+        &operator.0,
+        "detection",
+        Some(&label),
+        Some(&enabled.to_string()),
+    );
+    with_pipelines(pipelines, move |p| p.set_detection(&label, enabled)).await?;
+    Ok(Status::NoContent)
+}
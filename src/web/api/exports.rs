@@ -0,0 +1,186 @@
+use super::auth::check_camera;
+use super::videos::{FileSourceState, RangeHeader, RecordingFile};
+use crate::auth::{audit, User};
+use crate::config::Role;
+use crate::video::export::{ExportJob, ExportSource, ExportState, Exports};
+
+use chrono::{DateTime, Duration, Utc};
+use log::error;
+use rocket::http::Status;
+use rocket::response::status::Accepted;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Longest clip that can be asked for
+const MAX_EXPORT_HOURS: i64 = 24;
+
+#[derive(Deserialize)]
+pub(crate) struct ExportRequest {
+    camera: String,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    /// burn the time into the video
+    timestamp: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ExportStatus {
+    #[serde(flatten)]
+    job: ExportJob,
+    /// where to fetch the file once it's done
+    download: Option<String>,
+}
+
+impl From<ExportJob> for ExportStatus {
+    fn from(job: ExportJob) -> Self {
+        let download = if job.state == ExportState::Done {
+            Some(format!("/api/exports/{}/download", job.id))
+        } else {
+            None
+        };
+        Self { job, download }
+    }
+}
+
+/// Exports are only seen by whoever started them, and admins
+fn visible_job(exports: &Exports, user: &User, id: &str) -> Result<ExportJob, Status> {
+    exports
+        .get(id)
+        .filter(|j| user.role == Role::Admin || j.owner == user.name)
+        .ok_or(Status::NotFound)
+}
+
+/// Start cutting `start` to `end` of `camera`'s recordings into one file.
+/// Poll the returned export for progress and a download link
+#[post("/exports", format = "json", data = "<request>")]
+pub(crate) async fn start_export(
+    request: Json<ExportRequest>,
+    user: User,
+    fs: &State<FileSourceState>,
+    exports: &State<Arc<Exports>>,
+) -> Result<Accepted<Json<ExportStatus>>, (Status, String)> {
+    let request = request.into_inner();
+    check_camera(&user, &request.camera).map_err(|s| (s, "camera not permitted".to_string()))?;
+    if request.start >= request.end {
+        return Err((Status::BadRequest, "start must be before end".to_string()));
+    }
+    if request.end - request.start > Duration::hours(MAX_EXPORT_HOURS) {
+        return Err((
+            Status::BadRequest,
+            format!("exports can be at most {} hours", MAX_EXPORT_HOURS),
+        ));
+    }
+
+    let recordings = fs
+        .list_recordings_between_times(&request.camera, request.start, request.end)
+        .await
+        .map_err(|e| {
+            error!("Failed to list recordings for {}: {}", request.camera, e);
+            (Status::InternalServerError, e.to_string())
+        })?;
+    let mut sources = Vec::new();
+    for recording in recordings {
+        match fs.file_path(&request.camera, &recording.file.id).await {
+            Ok(Some(path)) => sources.push(ExportSource {
+                path,
+                start_time: recording.file.start_time,
+            }),
+            Ok(None) => (),
+            Err(e) => {
+                error!("Failed to look up recording {}: {}", recording.file.id, e);
+                return Err((Status::InternalServerError, e.to_string()));
+            }
+        }
+    }
+    if sources.is_empty() {
+        return Err((
+            Status::NotFound,
+            "nothing was recorded in that time".to_string(),
+        ));
+    }
+
+    let job = exports
+        .start(
+            &user.name,
+            &request.camera,
+            request.start,
+            request.end,
+            request.timestamp.unwrap_or(false),
+            sources,
+        )
+        .ok_or_else(|| {
+            (
+                Status::TooManyRequests,
+                "too many exports running, try again later".to_string(),
+            )
+        })?;
+    audit::record(
+        &user,
+        "export",
+        Some(&request.camera),
+        Some(&format!("{} to {}", request.start, request.end)),
+    );
+    Ok(Accepted(Some(Json(job.into()))))
+}
+
+#[get("/exports")]
+pub(crate) async fn get_exports(
+    user: User,
+    exports: &State<Arc<Exports>>,
+) -> Json<Vec<ExportStatus>> {
+    Json(
+        exports
+            .list()
+            .into_iter()
+            .filter(|j| user.role == Role::Admin || j.owner == user.name)
+            .map(ExportStatus::from)
+            .collect(),
+    )
+}
+
+#[get("/exports/<id>")]
+pub(crate) async fn get_export(
+    id: String,
+    user: User,
+    exports: &State<Arc<Exports>>,
+) -> Result<Json<ExportStatus>, Status> {
+    visible_job(exports, &user, &id).map(|j| Json(j.into()))
+}
+
+#[get("/exports/<id>/download")]
+pub(crate) async fn download_export(
+    id: String,
+    range: RangeHeader,
+    user: User,
+    exports: &State<Arc<Exports>>,
+) -> Result<RecordingFile, Status> {
+    let job = visible_job(exports, &user, &id)?;
+    if job.state != ExportState::Done {
+        return Err(Status::Conflict);
+    }
+    check_camera(&user, &job.label)?;
+    if range
+        .0
+        .as_deref()
+        .map(|r| r.starts_with("bytes=0-"))
+        .unwrap_or(true)
+    {
+        audit::record(&user, "export_download", Some(&job.label), Some(&id));
+    }
+    RecordingFile::open(&job.path, range.0.as_deref(), Some(job.file_name())).await
+}
+
+/// Delete an export's file, or stop keeping it once it finishes
+#[delete("/exports/<id>")]
+pub(crate) async fn delete_export(
+    id: String,
+    user: User,
+    exports: &State<Arc<Exports>>,
+) -> Result<Status, Status> {
+    let job = visible_job(exports, &user, &id)?;
+    exports.remove(&id);
+    audit::record(&user, "export_delete", Some(&job.label), Some(&id));
+    Ok(Status::NoContent)
+}
@@ -0,0 +1,65 @@
+use crate::auth::User;
+use crate::config::{self, Config, IceServerConfig};
+
+use log::error;
+use rocket::serde::json::Json;
+use serde::Serialize;
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+
+/// Browser-facing subset of `RTCConfiguration`
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BrowserRTCConfig {
+    ice_servers: Vec<IceServerConfig>,
+}
+
+#[get("/webrtc/config")]
+pub(crate) async fn get_webrtc_config(_user: User) -> Json<BrowserRTCConfig> {
+    Json(BrowserRTCConfig {
+        ice_servers: config::load_config(None).webrtc.ice_servers(),
+    })
+}
+
+pub(crate) fn rtc_configuration(config: &Config) -> RTCConfiguration {
+    RTCConfiguration {
+        ice_servers: config
+            .webrtc
+            .ice_servers()
+            .into_iter()
+            .map(|s| RTCIceServer {
+                urls: s.urls,
+                username: s.username.unwrap_or_default(),
+                credential: s.credential.unwrap_or_default(),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+pub(crate) fn setting_engine(config: &Config) -> SettingEngine {
+    let mut s = SettingEngine::default();
+    let webrtc = &config.webrtc;
+
+    if let (Some(min), Some(max)) = (webrtc.udp_port_min, webrtc.udp_port_max) {
+        if let Err(e) = s.set_ephemeral_udp_port_range(min, max) {
+            error!("Invalid WebRTC UDP port range {}-{}: {}", min, max, e);
+        }
+    }
+
+    if let Some(ips) = &webrtc.nat_1to1_ips {
+        s.set_nat_1to1_ips(ips.clone(), RTCIceCandidateType::Host);
+    }
+
+    if let Some(interfaces) = &webrtc.interfaces {
+        let interfaces = interfaces.clone();
+        s.set_interface_filter(Box::new(move |name: &str| -> bool {
+            interfaces.iter().any(|i| i == name)
+        }));
+    }
+
+    s
+}
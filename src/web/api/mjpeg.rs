@@ -0,0 +1,122 @@
+use super::auth::check_camera;
+use crate::auth::{audit, User};
+use crate::config;
+use crate::web::Streams;
+
+use log::{debug, error};
+use rocket::http::{ContentType, Status};
+use rocket::response::stream::ByteStream;
+use rocket::State;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_MJPEG_FPS: u32 = 5;
+/// Also keeps the frame interval from rounding to zero
+const MAX_MJPEG_FPS: u32 = 60;
+const MJPEG_BOUNDARY: &str = "smartcamframe";
+
+#[get("/cameras/<label>/snapshot.jpg?<width>&<quality>")]
+pub(crate) async fn get_snapshot(
+    label: String,
+    width: Option<u32>,
+    quality: Option<i32>,
+    user: User,
+    state: &State<Streams>,
+) -> Result<(ContentType, Vec<u8>), Status> {
+    check_camera(&user, &label)?;
+    let stream = state
+        .read()
+        .unwrap()
+        .get(&label)
+        .map(Arc::clone)
+        .ok_or(Status::NotFound)?;
+    // No frame received yet:
+    let frame = stream.latest_frame().ok_or(Status::ServiceUnavailable)?;
+    audit::record(&user, "snapshot", Some(&label), None);
+    let quality = quality.or(config::load_config(None).display.jpeg_quality);
+
+    match tokio::task::spawn_blocking(move || frame.to_jpeg(width, quality)).await {
+        Ok(Ok(jpeg)) => Ok((ContentType::JPEG, jpeg)),
+        Ok(Err(e)) => {
+            error!("Failed to encode snapshot for {}: {}", label, e);
+            Err(Status::InternalServerError)
+        }
+        Err(e) => {
+            error!("Snapshot task failed for {}: {}", label, e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[get("/cameras/<label>/mjpeg?<fps>&<width>&<quality>")]
+pub(crate) async fn get_mjpeg(
+    label: String,
+    fps: Option<u32>,
+    width: Option<u32>,
+    quality: Option<i32>,
+    user: User,
+    state: &State<Streams>,
+) -> Result<(ContentType, ByteStream![Vec<u8>]), Status> {
+    check_camera(&user, &label)?;
+    let stream = state
+        .read()
+        .unwrap()
+        .get(&label)
+        .map(Arc::clone)
+        .ok_or(Status::NotFound)?;
+    audit::record(&user, "mjpeg", Some(&label), None);
+    let mut rx = stream.subscribe_frames();
+
+    let config = config::load_config(None);
+    let max_fps = config.display.mjpeg_max_fps.unwrap_or(DEFAULT_MJPEG_FPS);
+    let fps = fps
+        .map(|f| f.min(max_fps))
+        .unwrap_or(max_fps)
+        .clamp(1, MAX_MJPEG_FPS);
+    let quality = quality.or(config.display.jpeg_quality);
+    debug!("Starting mjpeg stream for {} at {} fps", label, fps);
+
+    let content_type =
+        ContentType::with_params("multipart", "x-mixed-replace", ("boundary", MJPEG_BOUNDARY));
+
+    Ok((
+        content_type,
+        ByteStream! {
+            let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / fps as f64));
+            loop {
+                interval.tick().await;
+                // Wait for a frame newer than the last one sent:
+                if rx.changed().await.is_err() {
+                    debug!("Frame source closed -- ending mjpeg stream for {}", label);
+                    break;
+                }
+                let frame = match rx.borrow().clone() {
+                    Some(f) => f,
+                    None => continue,
+                };
+
+                let jpeg = match tokio::task::spawn_blocking(move || frame.to_jpeg(width, quality)).await {
+                    Ok(Ok(jpeg)) => jpeg,
+                    Ok(Err(e)) => {
+                        error!("Failed to encode mjpeg frame for {}: {}", label, e);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Mjpeg encoding task failed for {}: {}", label, e);
+                        break;
+                    }
+                };
+
+                let mut part = format!(
+                    "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                    MJPEG_BOUNDARY,
+                    jpeg.len()
+                )
+                .into_bytes();
+                part.extend_from_slice(&jpeg);
+                part.extend_from_slice(b"\r\n");
+                yield part;
+            }
+        },
+    ))
+}
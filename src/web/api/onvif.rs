@@ -0,0 +1,48 @@
+use super::auth::Admin;
+use crate::onvif::{self, DiscoveredCamera};
+
+use log::error;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Upper bound on the probe time a client can ask for
+const MAX_DISCOVERY_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Deserialize)]
+pub(crate) struct DiscoverRequest {
+    username: Option<String>,
+    password: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct DiscoverResponse {
+    cameras: Vec<DiscoveredCamera>,
+    /// ready-to-paste `settings.toml` entries
+    config: String,
+}
+
+#[post("/onvif/discover", format = "json", data = "<request>")]
+pub(crate) async fn discover(
+    request: Json<DiscoverRequest>,
+    _admin: Admin,
+) -> Result<Json<DiscoverResponse>, Status> {
+    let request = request.into_inner();
+    let timeout = match request.timeout {
+        Some(t) => Duration::from_secs(t.min(MAX_DISCOVERY_TIMEOUT_SECS)),
+        None => onvif::DEFAULT_DISCOVERY_TIMEOUT,
+    };
+
+    match onvif::discover(timeout, request.username, request.password).await {
+        Ok(cameras) => Ok(Json(DiscoverResponse {
+            config: onvif::config_toml(&cameras),
+            cameras,
+        })),
+        Err(e) => {
+            error!("ONVIF discovery failed: {}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use super::auth::{check_camera, Operator};
+use crate::auth::User;
+use crate::onvif::{Preset, PtzController, PtzVector};
+use crate::web::PtzControllers;
+
+use log::error;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Deserialize)]
+pub(crate) struct ContinuousMove {
+    #[serde(flatten)]
+    velocity: PtzVector,
+    /// stop automatically after this long, in case the stop request never arrives
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Zoom {
+    speed: f32,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SavePreset {
+    name: Option<String>,
+    /// overwrite an existing preset instead of creating one
+    token: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SavedPreset {
+    token: String,
+}
+
+fn controller(
+    user: &User,
+    label: &str,
+    controllers: &PtzControllers,
+) -> Result<Arc<PtzController>, Status> {
+    check_camera(user, label)?;
+    controllers
+        .read()
+        .unwrap()
+        .get(label)
+        .map(Arc::clone)
+        .ok_or(Status::NotFound)
+}
+
+fn camera_error(label: &str, e: anyhow::Error) -> Status {
+    error!("PTZ request for {} failed: {}", label, e);
+    Status::BadGateway
+}
+
+#[post("/cameras/<label>/ptz/move", format = "json", data = "<request>")]
+pub(crate) async fn continuous_move(
+    label: String,
+    request: Json<ContinuousMove>,
+    operator: Operator,
+    controllers: &State<PtzControllers>,
+) -> Result<Status, Status> {
+    let timeout = request.timeout_ms.map(Duration::from_millis);
+    controller(&operator.0, &label, controllers)?
+        .continuous_move(&request.velocity, timeout)
+        .await
+        .map_err(|e| camera_error(&label, e))?;
+    Ok(Status::NoContent)
+}
+
+#[post(
+    "/cameras/<label>/ptz/relative",
+    format = "json",
+    data = "<translation>"
+)]
+pub(crate) async fn relative_move(
+    label: String,
+    translation: Json<PtzVector>,
+    operator: Operator,
+    controllers: &State<PtzControllers>,
+) -> Result<Status, Status> {
+    controller(&operator.0, &label, controllers)?
+        .relative_move(&translation)
+        .await
+        .map_err(|e| camera_error(&label, e))?;
+    Ok(Status::NoContent)
+}
+
+#[post("/cameras/<label>/ptz/zoom", format = "json", data = "<request>")]
+pub(crate) async fn zoom(
+    label: String,
+    request: Json<Zoom>,
+    operator: Operator,
+    controllers: &State<PtzControllers>,
+) -> Result<Status, Status> {
+    let velocity = PtzVector {
+        zoom: Some(request.speed),
+        ..Default::default()
+    };
+    let timeout = request.timeout_ms.map(Duration::from_millis);
+    controller(&operator.0, &label, controllers)?
+        .continuous_move(&velocity, timeout)
+        .await
+        .map_err(|e| camera_error(&label, e))?;
+    Ok(Status::NoContent)
+}
+
+#[post("/cameras/<label>/ptz/stop")]
+pub(crate) async fn stop(
+    label: String,
+    operator: Operator,
+    controllers: &State<PtzControllers>,
+) -> Result<Status, Status> {
+    controller(&operator.0, &label, controllers)?
+        .stop()
+        .await
+        .map_err(|e| camera_error(&label, e))?;
+    Ok(Status::NoContent)
+}
+
+#[get("/cameras/<label>/ptz/presets")]
+pub(crate) async fn get_presets(
+    label: String,
+    user: User,
+    controllers: &State<PtzControllers>,
+) -> Result<Json<Vec<Preset>>, Status> {
+    controller(&user, &label, controllers)?
+        .presets()
+        .await
+        .map(Json)
+        .map_err(|e| camera_error(&label, e))
+}
+
+#[post("/cameras/<label>/ptz/presets", format = "json", data = "<request>")]
+pub(crate) async fn save_preset(
+    label: String,
+    request: Json<SavePreset>,
+    operator: Operator,
+    controllers: &State<PtzControllers>,
+) -> Result<Json<SavedPreset>, Status> {
+    controller(&operator.0, &label, controllers)?
+        .set_preset(request.name.as_deref(), request.token.as_deref())
+        .await
+        .map(|token| Json(SavedPreset { token }))
+        .map_err(|e| camera_error(&label, e))
+}
+
+#[post("/cameras/<label>/ptz/presets/<token>/goto")]
+pub(crate) async fn goto_preset(
+    label: String,
+    token: String,
+    operator: Operator,
+    controllers: &State<PtzControllers>,
+) -> Result<Status, Status> {
+    controller(&operator.0, &label, controllers)?
+        .goto_preset(&token)
+        .await
+        .map_err(|e| camera_error(&label, e))?;
+    Ok(Status::NoContent)
+}
@@ -0,0 +1,214 @@
+use super::auth::check_camera;
+use crate::auth::{audit, User};
+use crate::config;
+use crate::web::session::{self, Sessions};
+use crate::web::Streams;
+
+use log::error;
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Serialize;
+use std::io::Cursor;
+use std::sync::Arc;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+const SDP_LIMIT_KIB: u64 = 64;
+
+#[derive(Serialize)]
+pub(crate) struct SessionAnswer {
+    session_id: String,
+    answer: RTCSessionDescription,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LocalCandidates {
+    candidates: Vec<RTCIceCandidateInit>,
+    complete: bool,
+}
+
+/// Start a session for `label` -- the answer is returned straight away and
+/// local candidates are trickled via `get_session_candidates`
+#[post("/streams/<label>", format = "json", data = "<offer>")]
+pub(crate) async fn get_stream(
+    label: String,
+    offer: Json<RTCSessionDescription>,
+    user: User,
+    state: &State<Streams>,
+    sessions: &State<Arc<Sessions>>,
+) -> Result<Json<SessionAnswer>, Status> {
+    check_camera(&user, &label)?;
+    let stream = match state.read().unwrap().get(&label) {
+        Some(stream) => Arc::clone(stream),
+        None => return Err(Status::NotFound),
+    };
+    audit::record(&user, "stream", Some(&label), None);
+
+    let app_config = config::load_config(None);
+    match sessions
+        .create(&stream, offer.into_inner(), &app_config, user.role, false)
+        .await
+    {
+        Ok((session, answer)) => Ok(Json(SessionAnswer {
+            session_id: session.id.clone(),
+            answer,
+        })),
+        Err(e) => {
+            error!("Failed to create session for {}: {}", label, e);
+            Err(Status::BadRequest)
+        }
+    }
+}
+
+#[post("/sessions/<id>/candidates", format = "json", data = "<candidate>")]
+pub(crate) async fn add_session_candidate(
+    id: String,
+    candidate: Json<RTCIceCandidateInit>,
+    _user: User,
+    sessions: &State<Arc<Sessions>>,
+) -> Status {
+    let session = match sessions.get(&id) {
+        Some(s) => s,
+        None => return Status::NotFound,
+    };
+    match session.add_remote_candidate(candidate.into_inner()).await {
+        Ok(_) => Status::NoContent,
+        Err(e) => {
+            error!("Failed to add candidate to session {}: {}", id, e);
+            Status::BadRequest
+        }
+    }
+}
+
+#[get("/sessions/<id>/candidates")]
+pub(crate) async fn get_session_candidates(
+    id: String,
+    _user: User,
+    sessions: &State<Arc<Sessions>>,
+) -> Option<Json<LocalCandidates>> {
+    let session = sessions.get(&id)?;
+    let (candidates, complete) = session.take_local_candidates();
+    Some(Json(LocalCandidates {
+        candidates,
+        complete,
+    }))
+}
+
+#[delete("/sessions/<id>")]
+pub(crate) async fn delete_session(
+    id: String,
+    _user: User,
+    sessions: &State<Arc<Sessions>>,
+) -> Status {
+    if sessions.remove(&id).await {
+        Status::NoContent
+    } else {
+        Status::NotFound
+    }
+}
+
+/// `201 Created` response for a WHEP offer
+pub(crate) struct WhepAnswer {
+    location: String,
+    sdp: String,
+}
+
+impl<'r> Responder<'r, 'static> for WhepAnswer {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .status(Status::Created)
+            .header(ContentType::new("application", "sdp"))
+            .raw_header("Location", self.location)
+            .sized_body(self.sdp.len(), Cursor::new(self.sdp))
+            .ok()
+    }
+}
+
+/// WHEP endpoint -- WHEP has no way to trickle candidates to the client, so
+/// the answer waits for ICE gathering
+#[post("/whep/<label>", format = "application/sdp", data = "<offer>")]
+pub(crate) async fn whep_offer(
+    label: String,
+    offer: Data<'_>,
+    user: User,
+    state: &State<Streams>,
+    sessions: &State<Arc<Sessions>>,
+) -> Result<WhepAnswer, Status> {
+    check_camera(&user, &label)?;
+    let stream = match state.read().unwrap().get(&label) {
+        Some(stream) => Arc::clone(stream),
+        None => return Err(Status::NotFound),
+    };
+    audit::record(&user, "stream", Some(&label), None);
+
+    let offer = match offer.open(SDP_LIMIT_KIB.kibibytes()).into_string().await {
+        Ok(o) if o.is_complete() => o.into_inner(),
+        _ => return Err(Status::PayloadTooLarge),
+    };
+    let offer = session::session_description("offer", offer).map_err(|_| Status::BadRequest)?;
+
+    let app_config = config::load_config(None);
+    match sessions
+        .create(&stream, offer, &app_config, user.role, true)
+        .await
+    {
+        Ok((session, answer)) => Ok(WhepAnswer {
+            location: format!("/api/whep/sessions/{}", session.id),
+            sdp: answer.sdp,
+        }),
+        Err(e) => {
+            error!("Failed to create WHEP session for {}: {}", label, e);
+            Err(Status::BadRequest)
+        }
+    }
+}
+
+#[patch(
+    "/whep/sessions/<id>",
+    format = "application/trickle-ice-sdpfrag",
+    data = "<fragment>"
+)]
+pub(crate) async fn whep_patch(
+    id: String,
+    fragment: Data<'_>,
+    _user: User,
+    sessions: &State<Arc<Sessions>>,
+) -> Status {
+    let session = match sessions.get(&id) {
+        Some(s) => s,
+        None => return Status::NotFound,
+    };
+    let fragment = match fragment.open(SDP_LIMIT_KIB.kibibytes()).into_string().await {
+        Ok(f) if f.is_complete() => f.into_inner(),
+        _ => return Status::PayloadTooLarge,
+    };
+    let candidates = match session::parse_sdp_fragment(&fragment) {
+        Ok(c) => c,
+        Err(_) => return Status::BadRequest,
+    };
+
+    for c in candidates {
+        if let Err(e) = session.add_remote_candidate(c).await {
+            error!("Failed to add candidate to WHEP session {}: {}", id, e);
+            return Status::BadRequest;
+        }
+    }
+    Status::NoContent
+}
+
+#[delete("/whep/sessions/<id>")]
+pub(crate) async fn whep_delete(
+    id: String,
+    _user: User,
+    sessions: &State<Arc<Sessions>>,
+) -> Status {
+    if sessions.remove(&id).await {
+        Status::Ok
+    } else {
+        Status::NotFound
+    }
+}
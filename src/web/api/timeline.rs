@@ -0,0 +1,168 @@
+use super::cameras::with_pipelines;
+use super::videos::FileSourceState;
+use crate::auth::{audit, User};
+use crate::event::EventTrigger;
+use crate::pipeline::SharedPipelines;
+
+use chrono::{DateTime, Duration, Utc};
+use log::error;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Serialize;
+
+/// Window shown when the request doesn't give one
+const DEFAULT_WINDOW_HOURS: i64 = 24;
+/// Longest window that can be asked for, each camera's recordings are read per request
+const MAX_WINDOW_DAYS: i64 = 31;
+
+#[derive(Serialize)]
+pub(crate) struct Segment {
+    id: String,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    /// when each source first triggered the event, to mark on the bar
+    triggers: Vec<EventTrigger>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CameraTimeline {
+    label: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Timeline {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    cameras: Vec<CameraTimeline>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SeekPosition {
+    id: String,
+    /// seconds into the recording
+    offset: f64,
+    /// false when nothing was recorded at the time asked for, so this is the
+    /// start of the next recording
+    exact: bool,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>, (Status, String)> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| {
+            (
+                Status::BadRequest,
+                format!("invalid time '{}', expected RFC 3339: {}", time, e),
+            )
+        })
+}
+
+fn internal_error(e: anyhow::Error) -> (Status, String) {
+    error!("Failed to read recordings: {}", e);
+    (Status::InternalServerError, e.to_string())
+}
+
+/// Recordings and the events in them for each camera between `start` and
+/// `end` (RFC 3339), defaulting to the last day. `camera` can be repeated to
+/// pick cameras, otherwise every camera the user can see is included
+#[get("/timeline?<start>&<end>&<camera>")]
+pub(crate) async fn get_timeline(
+    start: Option<String>,
+    end: Option<String>,
+    camera: Vec<String>,
+    user: User,
+    pipelines: &State<SharedPipelines>,
+    fs: &State<FileSourceState>,
+) -> Result<Json<Timeline>, (Status, String)> {
+    let end = match end {
+        Some(e) => parse_time(&e)?,
+        None => Utc::now(),
+    };
+    let start = match start {
+        Some(s) => parse_time(&s)?,
+        None => end - Duration::hours(DEFAULT_WINDOW_HOURS),
+    };
+    if start >= end {
+        return Err((Status::BadRequest, "start must be before end".to_string()));
+    }
+    if end - start > Duration::days(MAX_WINDOW_DAYS) {
+        return Err((
+            Status::BadRequest,
+            format!("window can be at most {} days", MAX_WINDOW_DAYS),
+        ));
+    }
+
+    let labels: Vec<String> = with_pipelines(pipelines, |p| Ok(p.cameras()))
+        .await?
+        .into_iter()
+        .map(|c| c.label)
+        .filter(|l| user.can_view(l) && (camera.is_empty() || camera.contains(l)))
+        .collect();
+    audit::record(&user, "timeline", None, Some(&labels.join(",")));
+
+    let mut cameras = Vec::new();
+    for label in labels {
+        let segments = fs
+            .list_recordings_between_times(&label, start, end)
+            .await
+            .map_err(internal_error)?
+            .into_iter()
+            .map(|r| Segment {
+                id: r.file.id,
+                start_time: r.file.start_time,
+                end_time: r.end_time,
+                triggers: r.triggers,
+            })
+            .collect();
+        cameras.push(CameraTimeline { label, segments });
+    }
+
+    Ok(Json(Timeline {
+        start,
+        end,
+        cameras,
+    }))
+}
+
+/// The recording and offset to play to see `label` at `time`, or the next
+/// recording after it if nothing was being recorded then
+#[get("/timeline/<label>/seek?<time>")]
+pub(crate) async fn seek(
+    label: String,
+    time: String,
+    user: User,
+    fs: &State<FileSourceState>,
+) -> Result<Json<SeekPosition>, (Status, String)> {
+    if !user.can_view(&label) {
+        return Err((Status::Forbidden, "camera not permitted".to_string()));
+    }
+    let time = parse_time(&time)?;
+
+    let recordings = fs
+        .list_recordings_between_times(&label, time, time + Duration::days(MAX_WINDOW_DAYS))
+        .await
+        .map_err(internal_error)?;
+    // Oldest first, so the first is the one playing at `time` if any is:
+    let recording = recordings
+        .into_iter()
+        .next()
+        .ok_or_else(|| (Status::NotFound, "nothing recorded after then".to_string()))?;
+
+    let exact = recording.file.start_time <= time;
+    let offset = if exact {
+        (time - recording.file.start_time).num_milliseconds() as f64 / 1000.0
+    } else {
+        0.0
+    };
+    Ok(Json(SeekPosition {
+        id: recording.file.id,
+        offset,
+        exact,
+        start_time: recording.file.start_time,
+        end_time: recording.end_time,
+    }))
+}
@@ -0,0 +1,60 @@
+use super::auth::{check_camera, Operator};
+use crate::auth::audit;
+use crate::event::{EventSource, ExternalTrigger, TriggerState};
+use crate::web::Triggers;
+
+use chrono::Utc;
+use log::{debug, error};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub(crate) struct TriggerRequest {
+    /// defaults to a single pulse
+    state: Option<TriggerState>,
+    /// e.g. which rule fired, recorded in the event metadata
+    source: Option<String>,
+}
+
+/// Start, stop or extend a recording from an external system. The body is
+/// optional, so a bare POST works as a one-off trigger
+#[post("/cameras/<label>/trigger", data = "<request>")]
+pub(crate) async fn post_trigger(
+    label: String,
+    request: Option<Json<TriggerRequest>>,
+    operator: Operator,
+    triggers: &State<Triggers>,
+) -> Status {
+    if let Err(status) = check_camera(&operator.0, &label) {
+        return status;
+    }
+    let triggers = triggers.read().unwrap();
+    let tx = match triggers.get(&label) {
+        Some(tx) => tx,
+        None => return Status::NotFound,
+    };
+    let (state, name) = match request {
+        Some(r) => (
+            r.state.unwrap_or(TriggerState::Pulse),
+            r.into_inner().source,
+        ),
+        None => (TriggerState::Pulse, None),
+    };
+    debug!("Webhook trigger {:?} for {}", state, label);
+    audit::record(&operator.0, "trigger", Some(&label), name.as_deref());
+
+    let trigger = ExternalTrigger {
+        source: EventSource::Webhook { name },
+        state,
+        time: Utc::now(),
+    };
+    match tx.lock().unwrap().send(trigger) {
+        Ok(_) => Status::Accepted,
+        Err(_) => {
+            error!("Motion detector for {} is not running", label);
+            Status::ServiceUnavailable
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use super::auth::check_camera;
+use crate::auth::{audit, User};
+use crate::config::VideoFileType;
+use crate::file_source::{FileSource, VideoFile};
+use crate::video::thumbnail;
+
+use log::error;
+use rocket::fs::NamedFile;
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket::State;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+
+pub(crate) type FileSourceState = Arc<dyn FileSource + Send + Sync>;
+
+/// The `Range` header, if any
+pub(crate) struct RangeHeader(pub(crate) Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RangeHeader {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        request::Outcome::Success(RangeHeader(
+            request.headers().get_one("Range").map(str::to_string),
+        ))
+    }
+}
+
+/// The inclusive byte range asked for in a `Range` header, `None` for the
+/// whole file. Only single ranges are supported; a header asking for more is
+/// ignored, which the spec allows
+fn byte_range(header: Option<&str>, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header?.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        // The last `n` bytes:
+        ("", n) => {
+            let n: u64 = n.parse().ok()?;
+            if n == 0 {
+                return Some(Err(()));
+            }
+            (len.saturating_sub(n), len.checked_sub(1)?)
+        }
+        (s, "") => (s.parse().ok()?, len.checked_sub(1)?),
+        (s, e) => (
+            s.parse().ok()?,
+            e.parse::<u64>().ok()?.min(len.checked_sub(1)?),
+        ),
+    };
+    if start > end || start >= len {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end)))
+}
+
+/// A recording, or the part of it asked for in a `Range` header
+pub(crate) struct RecordingFile {
+    status: Status,
+    content_type: ContentType,
+    /// `Content-Range`, when only part of the file is sent
+    content_range: Option<String>,
+    /// file name for `Content-Disposition: attachment`
+    attachment: Option<String>,
+    body: Option<(u64, tokio::io::Take<File>)>,
+}
+
+impl RecordingFile {
+    pub(crate) async fn open(
+        path: &Path,
+        range: Option<&str>,
+        attachment: Option<String>,
+    ) -> Result<Self, Status> {
+        let mut file = File::open(path).await.map_err(|_| Status::NotFound)?;
+        let len = file
+            .metadata()
+            .await
+            .map_err(|_| Status::InternalServerError)?
+            .len();
+        let content_type = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(VideoFileType::from_extension)
+            .and_then(|t| ContentType::parse_flexible(t.mime_type()))
+            .unwrap_or(ContentType::Binary);
+
+        let (status, content_range, start, end) = match byte_range(range, len) {
+            None => (Status::Ok, None, 0, len.saturating_sub(1)),
+            Some(Ok((start, end))) => (
+                Status::PartialContent,
+                Some(format!("bytes {}-{}/{}", start, end, len)),
+                start,
+                end,
+            ),
+            Some(Err(())) => {
+                return Ok(Self {
+                    status: Status::RangeNotSatisfiable,
+                    content_type,
+                    content_range: Some(format!("bytes */{}", len)),
+                    attachment: None,
+                    body: None,
+                })
+            }
+        };
+
+        file.seek(SeekFrom::Start(start))
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+        let body_len = if len == 0 { 0 } else { end - start + 1 };
+        Ok(Self {
+            status,
+            content_type,
+            content_range,
+            attachment,
+            body: Some((body_len, file.take(body_len))),
+        })
+    }
+}
+
+impl<'r> Responder<'r, 'static> for RecordingFile {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .status(self.status)
+            .header(self.content_type)
+            .raw_header("Accept-Ranges", "bytes");
+        if let Some(range) = self.content_range {
+            response.raw_header("Content-Range", range);
+        }
+        if let Some(name) = self.attachment {
+            response.header(Header::new(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", name.replace('"', "")),
+            ));
+        }
+        if let Some((len, body)) = self.body {
+            response
+                .raw_header("Content-Length", len.to_string())
+                .streamed_body(body);
+        }
+        response.ok()
+    }
+}
+
+#[get("/videos/<label>")]
+pub(crate) async fn get_videos(
+    label: String,
+    user: User,
+    fs: &State<FileSourceState>,
+) -> Result<Json<Vec<VideoFile>>, Status> {
+    check_camera(&user, &label)?;
+    audit::record(&user, "list_videos", Some(&label), None);
+    fs.list_files_by_label(&label).await.map(Json).map_err(|e| {
+        error!("Failed to list recordings for {}: {}", label, e);
+        Status::InternalServerError
+    })
+}
+
+/// A recording of `label`'s by ID, with `?download=true` to save it rather
+/// than play it
+#[get("/videos/<label>/<id>?<download>")]
+pub(crate) async fn get_video(
+    label: String,
+    id: String,
+    download: Option<bool>,
+    range: RangeHeader,
+    user: User,
+    fs: &State<FileSourceState>,
+) -> Result<RecordingFile, Status> {
+    check_camera(&user, &label)?;
+    let path = match fs.file_path(&label, &id).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return Err(Status::NotFound),
+        Err(e) => {
+            error!("Failed to look up recording {} for {}: {}", id, label, e);
+            return Err(Status::InternalServerError);
+        }
+    };
+    // Players make many range requests while scrubbing, so only the first is logged:
+    if range
+        .0
+        .as_deref()
+        .map(|r| r.starts_with("bytes=0-"))
+        .unwrap_or(true)
+    {
+        audit::record(&user, "video", Some(&label), Some(&id));
+    }
+
+    let attachment = if download.unwrap_or(false) {
+        Some(id)
+    } else {
+        None
+    };
+    RecordingFile::open(&path, range.0.as_deref(), attachment).await
+}
+
+/// The recording's poster JPEG, or with `?animated=true` its animated
+/// preview when one was made
+#[get("/videos/<label>/<id>/thumbnail?<animated>")]
+pub(crate) async fn get_thumbnail(
+    label: String,
+    id: String,
+    animated: Option<bool>,
+    user: User,
+    fs: &State<FileSourceState>,
+) -> Result<(ContentType, NamedFile), Status> {
+    check_camera(&user, &label)?;
+    let video = match fs.file_path(&label, &id).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return Err(Status::NotFound),
+        Err(e) => {
+            error!("Failed to look up recording {} for {}: {}", id, label, e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    let (path, content_type) = if animated.unwrap_or(false) {
+        let (path, preview) = thumbnail::find_preview(&video).ok_or(Status::NotFound)?;
+        (
+            path,
+            ContentType::parse_flexible(preview.mime_type()).unwrap_or(ContentType::Binary),
+        )
+    } else {
+        (thumbnail::thumbnail_path(&video), ContentType::JPEG)
+    };
+    NamedFile::open(path)
+        .await
+        .map(|f| (content_type, f))
+        .map_err(|_| Status::NotFound)
+}
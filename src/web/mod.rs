@@ -1,36 +1,215 @@
+use crate::audio::AudioSender;
+use crate::auth::LoginSessions;
 use crate::config;
+use crate::event::TriggerSender;
 use crate::file_source;
 use crate::frame::Frame;
-use crate::video::{rtc_track::RTCTrack, VideoRTCStream};
+use crate::onvif::PtzController;
+use crate::pipeline::SharedPipelines;
+use crate::rtsp_server;
+use crate::video::{export, hls, VideoRTCStream};
 
 mod api;
+mod session;
+mod tls;
 
-use log::error;
+use log::{debug, error};
+use rocket::figment::Figment;
 use rocket::fs::FileServer;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
 use tokio::sync::mpsc::Receiver as AsyncReceiver;
-use tokio::task::JoinHandle;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// Frame receivers feeding the live outputs of a single camera
+pub struct LiveFeed {
+    pub frames: AsyncReceiver<Arc<Frame>>,
+    /// frames with motion contours drawn, only present when requested
+    pub annotated: Option<AsyncReceiver<Arc<Frame>>>,
+    pub audio: Option<AudioSender>,
+    pub triggers: TriggerSender,
+}
+
+/// Cameras coming and going as their pipelines are started and stopped
+pub enum FeedUpdate {
+    Added(config::CameraConfig, LiveFeed),
+    Removed(String),
+}
+
+/// Live streams by camera label
+pub(crate) type Streams = Arc<RwLock<HashMap<String, Arc<VideoRTCStream>>>>;
+pub(crate) type PtzControllers = Arc<RwLock<HashMap<String, Arc<PtzController>>>>;
+pub(crate) type Triggers = Arc<RwLock<HashMap<String, Mutex<TriggerSender>>>>;
+
+/// Everything the API looks up by camera label
+struct LiveCameras {
+    streams: Streams,
+    /// `streams` plus the annotated variants, when the RTSP server is enabled
+    rtsp_streams: Option<Streams>,
+    ptz_controllers: PtzControllers,
+    triggers: Triggers,
+    hls_enabled: bool,
+}
+
+impl LiveCameras {
+    fn update(&self, update: FeedUpdate) {
+        match update {
+            FeedUpdate::Added(camera, feed) => self.add(camera, feed),
+            FeedUpdate::Removed(label) => self.remove(&label),
+        }
+    }
+
+    fn add(&self, camera: config::CameraConfig, feed: LiveFeed) {
+        let label = camera.label.clone();
+        debug!("Adding live outputs for {}", label);
+        let stream = start_stream(feed.frames, camera.clone(), self.hls_enabled);
+
+        if let Some(audio_tx) = feed.audio {
+            let stream = Arc::clone(&stream);
+            let rx = audio_tx.subscribe();
+            tokio::spawn(async move {
+                stream.start_audio(rx).await;
+            });
+        }
+
+        if let Some(rtsp_streams) = &self.rtsp_streams {
+            let mut rtsp_streams = rtsp_streams.write().unwrap();
+            if let Some(annotated) = feed.annotated {
+                let mut annotated_camera = camera.clone();
+                annotated_camera.label = rtsp_server::annotated_path(&label);
+                annotated_camera.audio = None;
+                rtsp_streams.insert(
+                    annotated_camera.label.clone(),
+                    start_stream(annotated, annotated_camera, false),
+                );
+            }
+            rtsp_streams.insert(label.clone(), Arc::clone(&stream));
+        }
+
+        if let Some(onvif) = camera.onvif {
+            self.ptz_controllers
+                .write()
+                .unwrap()
+                .insert(label.clone(), Arc::new(PtzController::new(&label, onvif)));
+        }
+        self.triggers
+            .write()
+            .unwrap()
+            .insert(label.clone(), Mutex::new(feed.triggers));
+        self.streams.write().unwrap().insert(label, stream);
+    }
+
+    /// The streams themselves end once the pipeline's senders are dropped
+    fn remove(&self, label: &str) {
+        debug!("Removing live outputs for {}", label);
+        self.streams.write().unwrap().remove(label);
+        if let Some(rtsp_streams) = &self.rtsp_streams {
+            let mut rtsp_streams = rtsp_streams.write().unwrap();
+            rtsp_streams.remove(label);
+            rtsp_streams.remove(&rtsp_server::annotated_path(label));
+        }
+        self.ptz_controllers.write().unwrap().remove(label);
+        self.triggers.write().unwrap().remove(label);
+    }
+}
 
 pub async fn start(
-    receivers: Vec<AsyncReceiver<Arc<Frame>>>,
-    cameras: Vec<config::CameraConfig>,
+    mut updates: UnboundedReceiver<FeedUpdate>,
+    pipelines: SharedPipelines,
 ) -> () {
-    let (streams, _threads) = start_async(receivers, cameras).await;
-    if let Err(e) = rocket::build()
+    let app_config = config::load_config(None);
+    let cameras = Arc::new(LiveCameras {
+        streams: Streams::default(),
+        rtsp_streams: if app_config.rtsp_server.enabled.unwrap_or(false) {
+            Some(Streams::default())
+        } else {
+            None
+        },
+        ptz_controllers: PtzControllers::default(),
+        triggers: Triggers::default(),
+        hls_enabled: app_config.hls.enabled.unwrap_or(false),
+    });
+
+    // Cameras started before the server are already waiting:
+    while let Ok(update) = updates.try_recv() {
+        cameras.update(update);
+    }
+    let live = Arc::clone(&cameras);
+    tokio::spawn(async move {
+        while let Some(update) = updates.recv().await {
+            live.update(update);
+        }
+    });
+
+    if let Some(rtsp_streams) = &cameras.rtsp_streams {
+        tokio::spawn(rtsp_server::start(Arc::clone(rtsp_streams)));
+    }
+
+    let figment = match server_config(&app_config.web) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Failed to configure web server: {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = rocket::custom(figment)
         .mount(
             "/api",
             routes![
-                api::get_stream,
+                api::signaling::get_stream,
+                api::signaling::add_session_candidate,
+                api::signaling::get_session_candidates,
+                api::signaling::delete_session,
+                api::signaling::whep_offer,
+                api::signaling::whep_patch,
+                api::signaling::whep_delete,
                 api::get_streams_list,
-                api::get_videos,
-                api::get_video_by_name,
+                api::videos::get_videos,
+                api::videos::get_video,
+                api::videos::get_thumbnail,
+                api::timeline::get_timeline,
+                api::timeline::seek,
+                api::exports::start_export,
+                api::exports::get_exports,
+                api::exports::get_export,
+                api::exports::download_export,
+                api::exports::delete_export,
+                api::mjpeg::get_snapshot,
+                api::mjpeg::get_mjpeg,
+                api::get_hls_file,
+                api::ice::get_webrtc_config,
+                api::onvif::discover,
+                api::ptz::continuous_move,
+                api::ptz::relative_move,
+                api::ptz::zoom,
+                api::ptz::stop,
+                api::ptz::get_presets,
+                api::ptz::save_preset,
+                api::ptz::goto_preset,
+                api::trigger::post_trigger,
+                api::cameras::get_cameras,
+                api::cameras::add_camera,
+                api::cameras::remove_camera,
+                api::cameras::set_recording,
+                api::cameras::set_detection,
+                api::arming::get_arming,
+                api::arming::set_arming_mode,
+                api::auth::login,
+                api::auth::logout,
+                api::auth::get_me,
             ],
         )
         .mount("/", FileServer::from("web"))
-        .manage(streams)
+        .manage(Arc::clone(&cameras.streams))
+        .manage(Arc::clone(&cameras.ptz_controllers))
+        .manage(Arc::clone(&cameras.triggers))
+        .manage(pipelines)
+        .manage(Arc::new(session::Sessions::default()))
+        .manage(Arc::new(LoginSessions::default()))
+        .manage(Arc::new(export::Exports::default()))
         .manage(file_source::load())
-        .manage(config::load_config(None))
         .launch()
         .await
     {
@@ -38,33 +217,55 @@ pub async fn start(
     }
 }
 
-async fn start_async(
-    receivers: Vec<AsyncReceiver<Arc<Frame>>>,
-    cameras: Vec<config::CameraConfig>,
-) -> (HashMap<String, Arc<RTCTrack>>, Vec<JoinHandle<()>>) {
-    receivers
-        .into_iter()
-        .zip(cameras.into_iter())
-        .map(
-            |(mut rx, camera)| -> (String, Arc<RTCTrack>, JoinHandle<()>) {
-                let label = camera.label.clone();
-                let stream = Arc::new(VideoRTCStream::new(camera));
-                let track = stream.track();
-
-                let thread = tokio::spawn(async move {
-                    let f = rx.recv().await.unwrap();
-                    stream.start(f.width(), f.height(), rx).await;
-                });
-
-                (label, track, thread)
-            },
-        )
-        .fold(
-            (HashMap::new(), Vec::new()),
-            |mut acc, (label, track, thread)| {
-                acc.0.insert(label, track);
-                acc.1.push(thread);
-                acc
-            },
-        )
+/// Rocket's own config with the `[web]` settings on top
+fn server_config(web: &config::WebConfig) -> anyhow::Result<Figment> {
+    let mut figment = rocket::Config::figment();
+    if let Some(address) = &web.address {
+        figment = figment.merge(("address", address.parse::<IpAddr>()?));
+    }
+    if let Some(port) = web.port {
+        figment = figment.merge(("port", port));
+    }
+
+    if let Some(tls_config) = &web.tls {
+        if tls_config.self_signed.unwrap_or(false) {
+            tls::ensure_self_signed(tls_config)?;
+        }
+        figment = figment
+            .merge(("tls.certs", &tls_config.cert))
+            .merge(("tls.key", &tls_config.key));
+
+        if let Some(redirect_port) = tls_config.redirect_port {
+            let server: rocket::Config = figment.extract()?;
+            tokio::spawn(tls::redirect_to_https(
+                server.address,
+                redirect_port,
+                server.port,
+            ));
+        }
+    }
+    Ok(figment)
+}
+
+fn start_stream(
+    mut rx: AsyncReceiver<Arc<Frame>>,
+    camera: config::CameraConfig,
+    hls_enabled: bool,
+) -> Arc<VideoRTCStream> {
+    let label = camera.label.clone();
+    let stream = Arc::new(VideoRTCStream::new(camera));
+
+    if hls_enabled {
+        hls::start_hls_packager(label, stream.subscribe_packets());
+    }
+
+    let s = Arc::clone(&stream);
+    tokio::spawn(async move {
+        // None if the camera was stopped before its first frame:
+        if let Some(f) = rx.recv().await {
+            s.start(f, rx).await;
+        }
+    });
+
+    stream
 }
@@ -0,0 +1,308 @@
+use crate::config::{Config, Role};
+use crate::talkback;
+use crate::video::VideoRTCStream;
+use crate::web::api::ice;
+
+use anyhow::{anyhow, Result};
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::APIBuilder;
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::interceptor::registry::Registry;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::rtp_transceiver::rtp_receiver::RTCRtpReceiver;
+use webrtc::track::track_local::TrackLocal;
+use webrtc::track::track_remote::TrackRemote;
+
+/// Sessions that never connect are dropped after this long
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Upper bound on waiting for ICE gathering when the answer has to carry all candidates
+const GATHER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A single viewer's peer connection to a camera stream
+pub struct Session {
+    pub id: String,
+    pub label: String,
+    pub peer_connection: Arc<RTCPeerConnection>,
+    local_candidates: Mutex<Vec<RTCIceCandidateInit>>,
+    gathering_complete: AtomicBool,
+    connected: AtomicBool,
+}
+
+impl Session {
+    /// Local candidates gathered since the last call, and whether gathering has finished
+    pub fn take_local_candidates(&self) -> (Vec<RTCIceCandidateInit>, bool) {
+        let candidates = self.local_candidates.lock().unwrap().drain(..).collect();
+        (candidates, self.gathering_complete.load(Ordering::SeqCst))
+    }
+
+    pub async fn add_remote_candidate(&self, candidate: RTCIceCandidateInit) -> Result<()> {
+        self.peer_connection.add_ice_candidate(candidate).await?;
+        Ok(())
+    }
+}
+
+/// Registry of active WebRTC sessions, keyed by session ID
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+}
+
+impl Sessions {
+    pub fn get(&self, id: &str) -> Option<Arc<Session>> {
+        self.sessions.lock().unwrap().get(id).map(Arc::clone)
+    }
+
+    /// Close and forget a session, returns false if it didn't exist
+    pub async fn remove(&self, id: &str) -> bool {
+        let session = self.sessions.lock().unwrap().remove(id);
+        match session {
+            Some(s) => {
+                info!("Closing session {} for stream {}", s.id, s.label);
+                if let Err(e) = s.peer_connection.close().await {
+                    error!("Failed to close peer connection for session {}: {}", id, e);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Create a peer connection sending `stream`'s tracks and answer the remote offer.
+    /// Audio from the viewer is forwarded to the camera if it has a backchannel
+    /// and `role` is allowed to talk through it.
+    ///
+    /// With `wait_for_gathering` the answer includes every local candidate,
+    /// for clients that can't receive trickled candidates.
+    pub async fn create(
+        self: &Arc<Self>,
+        stream: &VideoRTCStream,
+        offer: RTCSessionDescription,
+        config: &Config,
+        role: Role,
+        wait_for_gathering: bool,
+    ) -> Result<(Arc<Session>, RTCSessionDescription)> {
+        let mut m = MediaEngine::default();
+        m.register_default_codecs()?;
+
+        let mut registry = Registry::new();
+        registry = register_default_interceptors(registry, &mut m).await?;
+
+        let api = APIBuilder::new()
+            .with_media_engine(m)
+            .with_interceptor_registry(registry)
+            .with_setting_engine(ice::setting_engine(config))
+            .build();
+
+        let peer_connection = Arc::new(
+            api.new_peer_connection(ice::rtc_configuration(config))
+                .await?,
+        );
+
+        let label = stream.label();
+        for track in stream.tracks() {
+            let rtp_sender = close_on_error(
+                &peer_connection,
+                peer_connection
+                    .add_track(track as Arc<dyn TrackLocal + Send + Sync>)
+                    .await
+                    .map_err(Into::into),
+            )
+            .await?;
+
+            // Read incoming RTCP packets so interceptors can process them
+            tokio::spawn(async move {
+                let mut rtcp_buf = vec![0u8; 1500];
+                while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
+            });
+        }
+
+        let session = Arc::new(Session {
+            id: format!("{:032x}", rand::random::<u128>()),
+            label: label.to_string(),
+            peer_connection: Arc::clone(&peer_connection),
+            local_candidates: Mutex::new(Vec::new()),
+            gathering_complete: AtomicBool::new(false),
+            connected: AtomicBool::new(false),
+        });
+
+        // Weak references so the handlers don't keep the session alive:
+        let s = Arc::downgrade(&session);
+        peer_connection
+            .on_ice_candidate(Box::new(move |c: Option<RTCIceCandidate>| {
+                let s = Weak::clone(&s);
+                Box::pin(async move {
+                    let session = match s.upgrade() {
+                        Some(session) => session,
+                        None => return,
+                    };
+                    match c {
+                        Some(c) => match c.to_json().await {
+                            Ok(init) => session.local_candidates.lock().unwrap().push(init),
+                            Err(e) => error!("Failed to serialize local candidate: {}", e),
+                        },
+                        None => session.gathering_complete.store(true, Ordering::SeqCst),
+                    }
+                })
+            }))
+            .await;
+
+        // Viewers only watch and listen:
+        if talkback::enabled(stream.camera()) && role >= Role::Operator {
+            let camera = stream.camera().clone();
+            let id = session.id.clone();
+            peer_connection
+                .on_track(Box::new(
+                    move |track: Option<Arc<TrackRemote>>, _: Option<Arc<RTCRtpReceiver>>| {
+                        if let Some(track) = track {
+                            if track.kind() == RTPCodecType::Audio {
+                                tokio::spawn(talkback::forward_track(
+                                    camera.clone(),
+                                    id.clone(),
+                                    track,
+                                ));
+                            }
+                        }
+                        Box::pin(async {})
+                    },
+                ))
+                .await;
+        }
+
+        let s = Arc::downgrade(&session);
+        let sessions = Arc::downgrade(self);
+        let id = session.id.clone();
+        peer_connection
+            .on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+                debug!(
+                    "Peer Connection State for session {} has changed: {}",
+                    id, state
+                );
+                if state == RTCPeerConnectionState::Connected {
+                    if let Some(session) = s.upgrade() {
+                        session.connected.store(true, Ordering::SeqCst);
+                    }
+                }
+                if state == RTCPeerConnectionState::Disconnected
+                    || state == RTCPeerConnectionState::Failed
+                    || state == RTCPeerConnectionState::Closed
+                {
+                    let sessions = Weak::clone(&sessions);
+                    let id = id.clone();
+                    tokio::spawn(async move {
+                        if let Some(sessions) = sessions.upgrade() {
+                            sessions.remove(&id).await;
+                        }
+                    });
+                }
+                Box::pin(async {})
+            }))
+            .await;
+
+        debug!("Received offer {}", offer.sdp);
+        let answer = close_on_error(
+            &peer_connection,
+            negotiate(&peer_connection, offer, wait_for_gathering).await,
+        )
+        .await?;
+
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session.id.clone(), Arc::clone(&session));
+        info!("Created session {} for stream {}", session.id, label);
+
+        let sessions = Arc::downgrade(self);
+        let s = Arc::downgrade(&session);
+        tokio::spawn(async move {
+            tokio::time::sleep(CONNECT_TIMEOUT).await;
+            if let (Some(sessions), Some(session)) = (sessions.upgrade(), s.upgrade()) {
+                if !session.connected.load(Ordering::SeqCst) {
+                    debug!("Session {} never connected", session.id);
+                    sessions.remove(&session.id).await;
+                }
+            }
+        });
+
+        Ok((session, answer))
+    }
+}
+
+/// Answer `offer`, waiting for every local candidate if asked
+async fn negotiate(
+    peer_connection: &RTCPeerConnection,
+    offer: RTCSessionDescription,
+    wait_for_gathering: bool,
+) -> Result<RTCSessionDescription> {
+    peer_connection.set_remote_description(offer).await?;
+    let answer = peer_connection.create_answer(None).await?;
+    let mut gather_complete = peer_connection.gathering_complete_promise().await;
+    // Sets the LocalDescription, and starts our UDP listeners
+    peer_connection.set_local_description(answer).await?;
+
+    if wait_for_gathering {
+        let _ = tokio::time::timeout(GATHER_TIMEOUT, gather_complete.recv()).await;
+    }
+
+    peer_connection
+        .local_description()
+        .await
+        .ok_or(anyhow!("Missing local description"))
+}
+
+/// Close a connection whose setup failed, so its transports and the
+/// handlers holding the stream's tracks are released
+async fn close_on_error<T>(peer_connection: &RTCPeerConnection, result: Result<T>) -> Result<T> {
+    if result.is_err() {
+        if let Err(e) = peer_connection.close().await {
+            error!("Failed to close peer connection: {}", e);
+        }
+    }
+    result
+}
+
+/// Build a session description from raw SDP
+pub fn session_description(sdp_type: &str, sdp: String) -> Result<RTCSessionDescription> {
+    Ok(serde_json::from_value(serde_json::json!({
+        "type": sdp_type,
+        "sdp": sdp,
+    }))?)
+}
+
+/// Parse the candidates out of an `application/trickle-ice-sdpfrag` body
+pub fn parse_sdp_fragment(frag: &str) -> Result<Vec<RTCIceCandidateInit>> {
+    let mut ufrag = String::new();
+    let mut mid = String::new();
+    let mut mline_index: i32 = -1;
+    let mut candidates = Vec::new();
+
+    for line in frag.lines().map(str::trim) {
+        if let Some(u) = line.strip_prefix("a=ice-ufrag:") {
+            ufrag = u.to_string();
+        } else if line.starts_with("m=") {
+            mline_index += 1;
+        } else if let Some(m) = line.strip_prefix("a=mid:") {
+            mid = m.to_string();
+        } else if let Some(c) = line.strip_prefix("a=") {
+            if !c.starts_with("candidate:") {
+                continue;
+            }
+            candidates.push(serde_json::from_value(serde_json::json!({
+                "candidate": c,
+                "sdpMid": mid,
+                "sdpMLineIndex": mline_index.max(0),
+                "usernameFragment": ufrag,
+            }))?);
+        }
+    }
+
+    Ok(candidates)
+}
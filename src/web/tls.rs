@@ -0,0 +1,107 @@
+//! HTTPS for the web server: the self-signed cert made on first run and the
+//! plain HTTP listener redirecting to it
+
+use crate::config::TlsConfig;
+
+use anyhow::Result;
+use log::{error, info};
+use rocket::http::uri::Origin;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::Redirect;
+use rocket::State;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_HOSTNAME: &str = "localhost";
+const HTTPS_PORT: u16 = 443;
+
+/// Write a self-signed cert and key to the configured paths, unless both
+/// are already there
+pub fn ensure_self_signed(tls: &TlsConfig) -> Result<()> {
+    if Path::new(&tls.cert).exists() && Path::new(&tls.key).exists() {
+        return Ok(());
+    }
+
+    let hostnames = tls
+        .hostnames
+        .clone()
+        .unwrap_or_else(|| vec![DEFAULT_HOSTNAME.to_string()]);
+    info!(
+        "Generating self-signed certificate for {}",
+        hostnames.join(", ")
+    );
+    let cert = rcgen::generate_simple_self_signed(hostnames)?;
+
+    fs::write(&tls.cert, cert.serialize_pem()?)?;
+    // Only readable by us:
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tls.key)?
+        .write_all(cert.serialize_private_key_pem().as_bytes())?;
+    Ok(())
+}
+
+/// The `Host` header, which browsers always send
+struct Host(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Host {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        match request.headers().get_one("Host") {
+            Some(host) => Outcome::Success(Host(host.to_string())),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+struct HttpsPort(u16);
+
+/// `host` without its port, leaving IPv6 addresses' colons alone
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !host.ends_with(']') && port.chars().all(|c| c.is_ascii_digit()) => {
+            name
+        }
+        _ => host,
+    }
+}
+
+#[get("/<_path..>")]
+fn redirect(_path: PathBuf, uri: &Origin<'_>, host: Host, port: &State<HttpsPort>) -> Redirect {
+    let host = strip_port(&host.0);
+    let location = if port.0 == HTTPS_PORT {
+        format!("https://{}{}", host, uri)
+    } else {
+        format!("https://{}:{}{}", host, port.0, uri)
+    };
+    Redirect::permanent(location)
+}
+
+/// Serve plain HTTP on `http_port`, redirecting everything to HTTPS on `https_port`
+pub async fn redirect_to_https(address: IpAddr, http_port: u16, https_port: u16) {
+    info!(
+        "Redirecting HTTP on port {} to HTTPS on {}",
+        http_port, https_port
+    );
+    let config = rocket::Config {
+        address,
+        port: http_port,
+        ..rocket::Config::default()
+    };
+    if let Err(e) = rocket::custom(config)
+        .mount("/", routes![redirect])
+        .manage(HttpsPort(https_port))
+        .launch()
+        .await
+    {
+        error!("Failed to launch HTTP redirect: {}", e);
+    }
+}
@@ -2,19 +2,26 @@
 
 window.startSession = async (s, pc) => {
 
-  let sd = btoa(JSON.stringify(pc.localDescription));
-  console.log("Posting ", sd);
+  console.log("Posting ", pc.localDescription);
   let remote = await fetch(`/api/streams/${s}`,
     {
       method: 'POST',
-      body: sd,
-      headers: { 'Content-Type': 'text/plain' }
+      body: JSON.stringify(pc.localDescription),
+      headers: { 'Content-Type': 'application/json' }
     }
   );
-  let b = await remote.text();
+  if (!remote.ok) {
+    console.error("Failed to start session: ", remote.status);
+    return;
+  }
+  let b = await remote.json();
   console.log(b);
   try {
-    pc.setRemoteDescription(new RTCSessionDescription(JSON.parse(atob(b))))
+    pc.sessionId = b.session_id;
+    await pc.setRemoteDescription(new RTCSessionDescription(b.answer));
+    pc.pendingCandidates.forEach(c => sendCandidate(pc.sessionId, c));
+    pc.pendingCandidates = [];
+    pollCandidates(pc);
   } catch (e) {
     console.error(e);
   }
@@ -22,22 +29,51 @@ window.startSession = async (s, pc) => {
 };
 
 
-function initiatePeerConnection(streamName) {
+function sendCandidate(sessionId, candidate) {
+  return fetch(`/api/sessions/${sessionId}/candidates`, {
+    method: 'POST',
+    body: JSON.stringify(candidate),
+    headers: { 'Content-Type': 'application/json' }
+  });
+}
+
+
+async function pollCandidates(pc) {
+  while (pc.sessionId) {
+    const remote = await fetch(`/api/sessions/${pc.sessionId}/candidates`);
+    if (!remote.ok) {
+      return;
+    }
+    const { candidates, complete } = await remote.json();
+    for (const c of candidates) {
+      await pc.addIceCandidate(c);
+    }
+    if (complete) {
+      console.log("Remote ICE gathering complete");
+      return;
+    }
+    await new Promise(resolve => setTimeout(resolve, 250));
+  }
+}
+
+
+function endSession(pc) {
+  if (!pc.sessionId) {
+    return;
+  }
+  fetch(`/api/sessions/${pc.sessionId}`, { method: 'DELETE', keepalive: true });
+  pc.sessionId = null;
+}
+
+
+function initiatePeerConnection(streamName, rtcConfig) {
 
   const buttonDiv = document.getElementById("buttonHolder");
   const buttonId = `button-${streamName}`;
 
-  // let pc = new RTCPeerConnection();
-
-  let pc = new RTCPeerConnection({
-    iceServers: [
-      {
-        // FIXME -- configuration:
-        urls: 'stun:stun.l.google.com:19302'
-        // urls: 'stun:stun.voipinfocenter.com:3478'
-      }
-    ]
-  });
+  let pc = new RTCPeerConnection(rtcConfig);
+  pc.pendingCandidates = [];
+  window.addEventListener('beforeunload', () => endSession(pc));
 
 
   pc.ontrack = event => {
@@ -51,7 +87,7 @@ function initiatePeerConnection(streamName) {
 
   // Offer to receive 1 audio, and 1 video track
   pc.addTransceiver('video', {'direction': 'sendrecv'})
-  pc.addTransceiver('audio', {'direction': 'sendrecv'})
+  const audio = pc.addTransceiver('audio', {'direction': 'sendrecv'})
 
   pc.createOffer()
     .then(d => {
@@ -65,6 +101,7 @@ function initiatePeerConnection(streamName) {
     console.log("connection state change: ", JSON.stringify(e));
     const state = pc.iceConnectionState;
     if (state == 'disconnected' || state == 'failed') {
+      endSession(pc);
       alert("Disconnected");
       location.reload();
     }
@@ -78,13 +115,11 @@ function initiatePeerConnection(streamName) {
       return;
     }
     console.log("onicecandidate: ", e.candidate);
-    // let cand = btoa(JSON.stringify(e.candidate));
-    // let remote = fetch(`/api/streams/candidate`, {
-    //   method: 'POST',
-    //   body: cand,
-    //   headers: { 'Content-Type': 'text/plain' }
-    // });
-    // let b = await remote.text();
+    if (pc.sessionId) {
+      sendCandidate(pc.sessionId, e.candidate.toJSON());
+    } else {
+      pc.pendingCandidates.push(e.candidate.toJSON());
+    }
   };
 
   const b = document.createElement('button');
@@ -92,6 +127,105 @@ function initiatePeerConnection(streamName) {
   b.innerHTML = streamName;
   b.onclick = () => window.startSession(streamName, pc);
   buttonDiv.appendChild(b);
+
+  const talk = document.createElement('button');
+  talk.innerHTML = `Talk (${streamName})`;
+  talk.onclick = () => toggleTalk(talk, audio.sender);
+  buttonDiv.appendChild(talk);
+
+  addPtzControls(streamName, buttonDiv);
+}
+
+
+function ptzRequest(streamName, path, body) {
+  return fetch(`/api/cameras/${streamName}/ptz/${path}`, {
+    method: 'POST',
+    body: body === undefined ? undefined : JSON.stringify(body),
+    headers: body === undefined ? {} : { 'Content-Type': 'application/json' }
+  });
+}
+
+
+// Only shown for cameras with PTZ configured, i.e. where presets can be listed
+async function addPtzControls(streamName, parent) {
+  const presets = await fetch(`/api/cameras/${streamName}/ptz/presets`);
+  if (!presets.ok) {
+    return;
+  }
+
+  const div = document.createElement('div');
+  div.className = 'ptz';
+
+  // Held buttons move continuously; the timeout stops the camera if the
+  // release is never seen
+  const hold = (label, path, body) => {
+    const b = document.createElement('button');
+    b.innerHTML = label;
+    b.onpointerdown = () => ptzRequest(streamName, path, { ...body, timeout_ms: 5000 });
+    b.onpointerup = b.onpointerleave = () => ptzRequest(streamName, 'stop');
+    div.appendChild(b);
+  };
+  hold('&larr;', 'move', { pan: -0.5, tilt: 0 });
+  hold('&uarr;', 'move', { pan: 0, tilt: 0.5 });
+  hold('&darr;', 'move', { pan: 0, tilt: -0.5 });
+  hold('&rarr;', 'move', { pan: 0.5, tilt: 0 });
+  hold('+', 'zoom', { speed: 0.5 });
+  hold('&minus;', 'zoom', { speed: -0.5 });
+
+  const select = document.createElement('select');
+  const refresh = async () => {
+    const res = await fetch(`/api/cameras/${streamName}/ptz/presets`);
+    if (!res.ok) {
+      return;
+    }
+    select.innerHTML = '';
+    (await res.json()).forEach(p => {
+      const o = document.createElement('option');
+      o.value = p.token;
+      o.text = p.name || p.token;
+      select.appendChild(o);
+    });
+  };
+  div.appendChild(select);
+
+  const goto = document.createElement('button');
+  goto.innerHTML = 'Go to preset';
+  goto.onclick = () => select.value &&
+    ptzRequest(streamName, `presets/${encodeURIComponent(select.value)}/goto`);
+  div.appendChild(goto);
+
+  const save = document.createElement('button');
+  save.innerHTML = 'Save preset';
+  save.onclick = async () => {
+    const name = prompt('Preset name');
+    if (name) {
+      await ptzRequest(streamName, 'presets', { name });
+      refresh();
+    }
+  };
+  div.appendChild(save);
+
+  parent.appendChild(div);
+  refresh();
+}
+
+
+// The audio transceiver is already negotiated as sendrecv, so the
+// microphone can be swapped in and out without renegotiating
+async function toggleTalk(button, sender) {
+  if (sender.track) {
+    sender.track.stop();
+    await sender.replaceTrack(null);
+    button.classList.remove('active');
+    return;
+  }
+  try {
+    const media = await navigator.mediaDevices.getUserMedia({ audio: true });
+    await sender.replaceTrack(media.getAudioTracks()[0]);
+    button.classList.add('active');
+  } catch (e) {
+    console.error("Failed to start talkback: ", e);
+  }
 }
 
 
@@ -101,11 +235,13 @@ function createVideoDiv(label, video) {
   videoTag.width = 320;
   videoTag.height = 240;
   videoTag.controls = true;
-  videoTag.preload = "metadata";
+  videoTag.preload = "none";
+  videoTag.title = `${label} ${video.start_time}`;
 
+  const url = `/api/videos/${encodeURIComponent(label)}/${encodeURIComponent(video.id)}`;
+  videoTag.poster = `${url}/thumbnail`;
   const source = document.createElement("source");
-  source.src = `/api/videos/${label}/${video}`;
-  source.type = "video/mp4";
+  source.src = url;
 
   const div = document.createElement("div");
   div.appendChild(videoTag);
@@ -113,13 +249,43 @@ function createVideoDiv(label, video) {
   ctr.appendChild(div);
 }
 
+// Shown in place of everything else until a login succeeds
+function showLogin() {
+  const form = document.createElement('form');
+  form.innerHTML = `
+    <input name="username" placeholder="Username" autocomplete="username">
+    <input name="password" type="password" placeholder="Password" autocomplete="current-password">
+    <button type="submit">Log in</button>`;
+  form.onsubmit = async e => {
+    e.preventDefault();
+    const res = await fetch('/api/login', {
+      method: 'POST',
+      body: JSON.stringify({ username: form.username.value, password: form.password.value }),
+      headers: { 'Content-Type': 'application/json' }
+    });
+    if (res.ok) {
+      location.reload();
+    } else {
+      alert("Login failed");
+    }
+  };
+  document.getElementById("buttonHolder").appendChild(form);
+}
+
+
 document.addEventListener('DOMContentLoaded', async function(event) {
 
-  const streams = await (await fetch('/api/streams')).json();
+  const streamsRes = await fetch('/api/streams');
+  if (streamsRes.status == 401) {
+    showLogin();
+    return;
+  }
+  const streams = await streamsRes.json();
+  const rtcConfig = await (await fetch('/api/webrtc/config')).json();
 
   console.log("streams: ", streams);
 
-  streams.forEach(it => initiatePeerConnection(it));
+  streams.forEach(it => initiatePeerConnection(it, rtcConfig));
 
   const fetchArray = await Promise.all(streams.map(it => fetch(`/api/videos/${it}`)));
 
@@ -128,6 +294,6 @@ document.addEventListener('DOMContentLoaded', async function(event) {
   const vidsArray = await Promise.all(fetchArray.map(async(it) => await it.json()));
 
   vidsArray.forEach(vidArray =>
-    vidArray.forEach(vid => createVideoDiv("Frontdoor", vid.file_name)));
+    vidArray.forEach(vid => createVideoDiv(vid.label, vid)));
 
 });